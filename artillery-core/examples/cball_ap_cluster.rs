@@ -31,7 +31,7 @@ fn main() {
         sd_config: {
             let mut config = MDNSServiceDiscoveryConfig::default();
             config.local_service_addr.set_port(port);
-            config
+            ServiceDiscoveryBackend::Mdns(config)
         },
         cluster_config: {
             let listen_addr = format!("127.0.0.1:{}", port);
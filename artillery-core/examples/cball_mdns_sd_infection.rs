@@ -58,6 +58,7 @@ fn main() {
     let sd_config = {
         let mut config = MDNSServiceDiscoveryConfig::default();
         config.local_service_addr.set_port(this_node_cluster_port);
+        config.node_id = host_key;
         config
     };
     let sd = MDNSServiceDiscovery::new_service_discovery(sd_config).unwrap();
@@ -71,9 +72,14 @@ fn main() {
         .expect("cannot start cluster-event-poller");
 
     thread::sleep(Duration::from_secs(1));
-    for discovery in sd.events().iter() {
-        if discovery.get().port() != this_node_cluster_port {
-            cluster.add_seed_node(discovery.get());
+    for event in sd.events().iter() {
+        let discovery = match event {
+            MDNSServiceDiscoveryEvent::Discovered(instance) => instance,
+            MDNSServiceDiscoveryEvent::Lost(_) => continue,
+        };
+        // Nodes on other hosts may listen on the same port, they differ by their node id.
+        if discovery.node_id != host_key {
+            cluster.add_seed_node(discovery.addr);
         }
     }
 }
@@ -70,12 +70,17 @@ fn main() {
                 timeout_delta: Duration::seconds(1),
                 discovery_addr: SocketAddr::from(([0, 0, 0, 0], sd_port)),
                 seeking_addr: SocketAddr::from(([0, 0, 0, 0], CONST_SERVICE_DISCOVERY_PORT)),
+                mode: SeekingMode::Broadcast,
+                ..Default::default()
             }
         } else {
             MulticastServiceDiscoveryConfig {
                 timeout_delta: Duration::seconds(1),
                 discovery_addr: SocketAddr::from(([0, 0, 0, 0], CONST_SERVICE_DISCOVERY_PORT)),
                 seeking_addr: SocketAddr::from(([0, 0, 0, 0], sd_port)),
+                mode: SeekingMode::Broadcast,
+                seek_interval: None,
+                ..Default::default()
             }
         }
     };
@@ -85,11 +90,11 @@ fn main() {
         port: get_port(),
     };
 
-    let reply = ServiceDiscoveryReply {
-        serialized_data: serde_json::to_string(&epidemic_sd_config).unwrap(),
-    };
-
-    let sd = MulticastServiceDiscovery::new_service_discovery(service_discovery, reply).unwrap();
+    let sd = MulticastServiceDiscovery::new_service_discovery(
+        service_discovery,
+        epidemic_sd_config.clone(),
+    )
+    .unwrap();
 
     let listen_addr = format!("{}:{}", "127.0.0.1", epidemic_sd_config.port);
     let _listen_addr_sd = listen_addr.clone();
@@ -108,8 +113,11 @@ fn main() {
         .spawn(move || poll_cluster_events(listen_addr.as_str(), host_key))
         .expect("cannot start cluster-event-poller");
 
-    for discovery in discoveries.iter() {
-        let discovery: ExampleSDReply = serde_json::from_str(&discovery.serialized_data).unwrap();
+    for event in discoveries.iter() {
+        let discovery = match event {
+            PeerEvent::Discovered(reply) => reply,
+            PeerEvent::Lost(_) => continue,
+        };
         if discovery.port != epidemic_sd_config.port {
             debug!("Seed node address came");
             let seed_node = format!("{}:{}", epidemic_sd_config.ip, discovery.port);
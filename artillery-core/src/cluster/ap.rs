@@ -1,26 +1,95 @@
+use crate::cluster::cp::prelude::*;
+use crate::cluster::hash_ring::{ClusterHashRing, HashRingConfig};
+use crate::cluster::leader::{LeaderElection, LeaderElectionRule};
+use crate::cluster::pubsub::DistributedPubSub;
+use crate::cluster::singleton::{ClusterSingleton, ClusterSingletonConfig, SingletonInbox};
+use crate::cluster::split_brain::{SplitBrainResolver, SplitBrainStrategy};
 use crate::epidemic::prelude::*;
 use crate::errors::*;
+use crate::service_discovery::discovery::ServiceDiscovery;
+use crate::service_discovery::dns::prelude::*;
+use crate::service_discovery::file::prelude::*;
+use crate::service_discovery::kubernetes::prelude::*;
 use crate::service_discovery::mdns::prelude::*;
+use crate::service_discovery::seed_list::StaticServiceDiscovery;
+use crate::service_discovery::udp_anycast::prelude::*;
 
 use lightproc::prelude::*;
 
 use futures::{select, FutureExt};
 use pin_utils::pin_mut;
-use std::{cell::Cell, sync::Arc};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::{cell::Cell, sync::Arc, time::Duration};
 use uuid::Uuid;
 
+///
+/// Backend the AP cluster discovers its seeds through.
+#[derive(Debug, Clone)]
+pub enum ServiceDiscoveryBackend {
+    /// Advertised under the node id and app name of the cluster.
+    Mdns(MDNSServiceDiscoveryConfig),
+    /// Nodes answer the seeking requests with the listen address of the cluster.
+    UdpAnycast(MulticastServiceDiscoveryConfig),
+    Static(Vec<SocketAddr>),
+    File(FileServiceDiscoveryConfig),
+    Dns(DNSServiceDiscoveryConfig),
+    Kubernetes(KubernetesServiceDiscoveryConfig),
+}
+
+impl Default for ServiceDiscoveryBackend {
+    fn default() -> Self {
+        ServiceDiscoveryBackend::Mdns(MDNSServiceDiscoveryConfig::default())
+    }
+}
+
+impl ServiceDiscoveryBackend {
+    fn start(&self, config: &ArtilleryAPClusterConfig) -> Result<Arc<dyn ServiceDiscovery>> {
+        use ServiceDiscoveryBackend::*;
+
+        Ok(match self {
+            Mdns(sd_config) => Arc::new(MDNSServiceDiscovery::new_service_discovery(
+                MDNSServiceDiscoveryConfig {
+                    node_id: config.node_id,
+                    app_name: config.app_name.clone(),
+                    ..sd_config.clone()
+                },
+            )?),
+            UdpAnycast(sd_config) => {
+                let sd = MulticastServiceDiscovery::new_service_discovery(
+                    sd_config.clone(),
+                    config.cluster_config.listen_addr,
+                )?;
+                sd.set_listen_for_peers(true)?;
+                sd.seek_peers()?;
+                Arc::new(sd)
+            }
+            Static(seeds) => Arc::new(StaticServiceDiscovery::new(seeds.clone())),
+            File(sd_config) => Arc::new(FileServiceDiscovery::new_service_discovery(
+                sd_config.clone(),
+            )?),
+            Dns(sd_config) => Arc::new(DNSServiceDiscovery::new_service_discovery(
+                sd_config.clone(),
+            )?),
+            Kubernetes(sd_config) => Arc::new(KubernetesServiceDiscovery::new_service_discovery(
+                sd_config.clone(),
+            )?),
+        })
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct ArtilleryAPClusterConfig {
     pub app_name: String,
     pub node_id: Uuid,
     pub cluster_config: ClusterConfig,
-    pub sd_config: MDNSServiceDiscoveryConfig,
+    pub sd_config: ServiceDiscoveryBackend,
 }
 
 pub struct ArtilleryAPCluster {
     config: ArtilleryAPClusterConfig,
     cluster: Arc<Cluster>,
-    sd: Arc<MDNSServiceDiscovery>,
+    sd: Arc<dyn ServiceDiscovery>,
     cluster_ev_loop_handle: Cell<RecoverableHandle<()>>,
 }
 
@@ -31,15 +100,23 @@ pub type DiscoveryLaunch = RecoverableHandle<()>;
 
 impl ArtilleryAPCluster {
     pub fn new(config: ArtilleryAPClusterConfig) -> Result<Self> {
-        let sd = MDNSServiceDiscovery::new_service_discovery(config.sd_config.clone())?;
+        let sd = config.sd_config.start(&config)?;
 
+        Self::with_service_discovery(config, sd)
+    }
+
+    /// Cluster discovering its seeds through the given backend, instead of the configured one.
+    pub fn with_service_discovery(
+        config: ArtilleryAPClusterConfig,
+        sd: Arc<dyn ServiceDiscovery>,
+    ) -> Result<Self> {
         let (cluster, cluster_listener) =
             Cluster::new_cluster(config.node_id, config.cluster_config.clone())?;
 
         Ok(Self {
             config,
             cluster: Arc::new(cluster),
-            sd: Arc::new(sd),
+            sd,
             cluster_ev_loop_handle: Cell::new(cluster_listener),
         })
     }
@@ -48,10 +125,66 @@ impl ArtilleryAPCluster {
         self.cluster.clone()
     }
 
-    pub fn service_discovery(&self) -> Arc<MDNSServiceDiscovery> {
+    pub fn service_discovery(&self) -> Arc<dyn ServiceDiscovery> {
         self.sd.clone()
     }
 
+    /// Resolver deciding which side of a partition survives, once membership was stable
+    /// for `stable_after`. It has to be launched next to the cluster.
+    pub fn split_brain_resolver<S>(&self, strategy: S, stable_after: Duration) -> SplitBrainResolver
+    where
+        S: SplitBrainStrategy + 'static,
+    {
+        SplitBrainResolver::new(self.cluster(), strategy, stable_after)
+    }
+
+    /// Leader election over the alive members of this cluster.
+    /// It has to be launched next to the cluster.
+    pub fn leader_election(
+        &self,
+        rule: LeaderElectionRule,
+        stability_delay: Duration,
+    ) -> LeaderElection {
+        LeaderElection::new(&self.cluster, rule, stability_delay)
+    }
+
+    /// Consistent hash ring over the members of this cluster.
+    /// It has to be launched next to the cluster.
+    pub fn hash_ring(&self, config: HashRingConfig) -> ClusterHashRing {
+        ClusterHashRing::new(&self.cluster, config)
+    }
+
+    /// Named publish/subscribe topics over the members of this cluster.
+    /// It has to be launched next to the cluster.
+    pub fn pubsub(&self) -> DistributedPubSub {
+        DistributedPubSub::new(self.cluster())
+    }
+
+    /// Run the future created by `factory` on exactly one node of this cluster.
+    /// Every node has to register the same singleton and launch it next to the cluster.
+    pub fn singleton<F, Fut>(&self, config: ClusterSingletonConfig, factory: F) -> ClusterSingleton
+    where
+        F: Fn(SingletonInbox) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        ClusterSingleton::new(self.cluster(), config, factory)
+    }
+
+    /// Raft replicated state machine over the members of this cluster taking part in the group.
+    /// It has to be launched next to the cluster.
+    pub fn cp_cluster<S, M>(
+        &self,
+        config: RaftConfig,
+        storage: S,
+        machine: M,
+    ) -> Result<ArtilleryCPCluster>
+    where
+        S: RaftStorage + 'static,
+        M: StateMachine + 'static,
+    {
+        ArtilleryCPCluster::new(self.cluster(), config, storage, machine)
+    }
+
     pub fn shutdown(&self) {
         self.cluster().leave_cluster();
     }
@@ -69,17 +202,18 @@ impl ArtilleryAPCluster {
 
         select! {
             ev_loop_res = ev_loop_handle => { dbg!(ev_loop_res); ev_loop_res.unwrap() },
-            _ = discover_nodes_handle => panic!("Node discovery unexpectedly shutdown.")
+            _ = discover_nodes_handle => {
+                // Backends with a fixed set of seeds are done once all of them were added.
+                debug!("Node discovery of {} is over", self.config.app_name);
+                ev_loop_handle.await.unwrap()
+            }
         };
     }
 
     async fn discover_nodes(&self) {
-        self.service_discovery()
-            .events()
-            .iter()
-            .filter(|discovery| {
-                discovery.get().port() != self.config.sd_config.local_service_addr.port()
-            })
-            .for_each(|discovery| self.cluster.add_seed_node(discovery.get()))
+        // Seeds turning out to be this node are told apart by its node id, and dropped.
+        if let Err(e) = self.sd.seed_cluster(&self.cluster) {
+            error!("Node discovery failed: {}", e);
+        }
     }
 }
@@ -0,0 +1,633 @@
+use super::raft::*;
+use super::raft_config::*;
+use super::state::*;
+use super::storage::*;
+use crate::epidemic::prelude::*;
+use crate::errors::*;
+
+use serde::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Metadata key prefix announcing the Raft groups a member takes part in.
+pub const RAFT_METADATA_PREFIX: &str = "artillery.raft.";
+
+/// Receives the response of the state machine once the proposal is applied.
+pub type ProposalResponse = Receiver<Result<Vec<u8>>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RaftEnvelope {
+    #[serde(rename = "g")]
+    group: String,
+    #[serde(rename = "m")]
+    message: RaftMessage,
+}
+
+fn group_key(group: &str) -> String {
+    format!("{}{}", RAFT_METADATA_PREFIX, group)
+}
+
+/// Alive members of the view taking part in the group, according to their metadata.
+pub fn group_members(group: &str, members: &[ArtilleryMember]) -> BTreeSet<Uuid> {
+    let key = group_key(group);
+
+    members
+        .iter()
+        .filter(|m| m.state() == ArtilleryMemberState::Alive && m.metadata().contains_key(&key))
+        .map(ArtilleryMember::host_key)
+        .collect()
+}
+
+/// Metadata value of the members which already took part in the group.
+/// Pristine members advertise the voters they would bootstrap the group with instead.
+const RAFT_JOINED: &str = "joined";
+
+/// Metadata value of the other alive members of the group.
+fn advertised_by(
+    group: &str,
+    host_key: Uuid,
+    members: &[ArtilleryMember],
+) -> BTreeMap<Uuid, String> {
+    let key = group_key(group);
+
+    members
+        .iter()
+        .filter(|m| m.state() == ArtilleryMemberState::Alive && m.host_key() != host_key)
+        .filter_map(|m| Some((m.host_key(), m.metadata().get(&key)?.clone())))
+        .collect()
+}
+
+fn encode_voters(voters: &BTreeSet<Uuid>) -> String {
+    voters
+        .iter()
+        .map(Uuid::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Lowest `expect` nodes of the group a pristine node sees, once it sees enough of them
+/// and none of them took part in the group yet.
+fn bootstrap_proposal(
+    host_key: Uuid,
+    expect: usize,
+    advertised: &BTreeMap<Uuid, String>,
+) -> Option<BTreeSet<Uuid>> {
+    if expect == 0 || advertised.values().any(|value| value == RAFT_JOINED) {
+        return None;
+    }
+
+    let voters: BTreeSet<Uuid> = advertised
+        .keys()
+        .copied()
+        .chain(iter::once(host_key))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .take(expect)
+        .collect();
+
+    if voters.len() == expect {
+        Some(voters)
+    } else {
+        None
+    }
+}
+
+///
+/// Voters a pristine node bootstraps the group with, if it is the one to do it.
+///
+/// Only the lowest node of its proposal bootstraps, once every other voter advertises the very
+/// same proposal. Nodes with diverging views of the cluster never agree, so they wait for their
+/// views to converge instead of bootstrapping separate groups.
+fn bootstrap_voters(
+    host_key: Uuid,
+    expect: usize,
+    advertised: &BTreeMap<Uuid, String>,
+) -> Option<BTreeSet<Uuid>> {
+    let voters = bootstrap_proposal(host_key, expect, advertised)?;
+    let proposal = encode_voters(&voters);
+    let agreed = voters
+        .iter()
+        .filter(|id| **id != host_key)
+        .all(|id| advertised.get(id) == Some(&proposal));
+
+    if agreed && voters.iter().next() == Some(&host_key) {
+        Some(voters)
+    } else {
+        None
+    }
+}
+
+///
+/// Linearizable state machine replicated with Raft over the members of the epidemic cluster.
+///
+/// Nodes of the group find each other through the cluster membership and
+/// exchange Raft messages as cluster payloads.
+pub struct ArtilleryCPCluster {
+    config: RaftConfig,
+    cluster: Arc<Cluster>,
+    membership: Receiver<ArtilleryClusterEvent>,
+    members: Mutex<Vec<ArtilleryMember>>,
+    /// Metadata value of the current node for the group.
+    advertised: Mutex<String>,
+    raft: Mutex<Raft>,
+}
+
+unsafe impl Send for ArtilleryCPCluster {}
+unsafe impl Sync for ArtilleryCPCluster {}
+
+impl ArtilleryCPCluster {
+    /// The state machine has to be empty, it is restored from the storage.
+    pub fn new<S, M>(
+        cluster: Arc<Cluster>,
+        config: RaftConfig,
+        storage: S,
+        machine: M,
+    ) -> Result<Self>
+    where
+        S: RaftStorage + 'static,
+        M: StateMachine + 'static,
+    {
+        let raft = Raft::new(
+            cluster.host_key(),
+            config.clone(),
+            Box::new(storage),
+            Box::new(machine),
+            Instant::now(),
+        )?;
+        let advertised = if raft.is_pristine() {
+            String::new()
+        } else {
+            String::from(RAFT_JOINED)
+        };
+        cluster.set_metadata(group_key(&config.group), &advertised)?;
+
+        Ok(Self {
+            config,
+            membership: cluster.subscribe(),
+            cluster,
+            members: Mutex::new(Vec::new()),
+            advertised: Mutex::new(advertised),
+            raft: Mutex::new(raft),
+        })
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader() == Some(self.cluster.host_key())
+    }
+
+    pub fn leader(&self) -> Option<Uuid> {
+        self.raft.lock().expect("Raft node is poisoned").leader()
+    }
+
+    pub fn term(&self) -> Term {
+        self.raft.lock().expect("Raft node is poisoned").term()
+    }
+
+    /// Latest membership of the group, committed or not.
+    pub fn membership(&self) -> RaftMembership {
+        self.raft
+            .lock()
+            .expect("Raft node is poisoned")
+            .membership()
+            .clone()
+    }
+
+    pub fn commit_index(&self) -> LogIndex {
+        self.raft
+            .lock()
+            .expect("Raft node is poisoned")
+            .commit_index()
+    }
+
+    /// Start the group out of the voters, when `bootstrap_expect` is zero.
+    /// It has to be called on exactly one node.
+    pub fn bootstrap(&self, voters: BTreeSet<Uuid>) -> Result<()> {
+        self.with_raft(|raft| raft.bootstrap(voters))?;
+        self.advertise(String::from(RAFT_JOINED));
+
+        Ok(())
+    }
+
+    /// Replicate the command to the group. Fails with `NotLeader` on other nodes than the leader.
+    pub fn propose(&self, command: Vec<u8>) -> Result<ProposalResponse> {
+        let (tx, rx) = channel();
+        self.with_raft(|raft| raft.propose(command, tx))?;
+
+        Ok(rx)
+    }
+
+    /// Replace the voters of the group, through joint consensus.
+    pub fn change_membership(&self, voters: BTreeSet<Uuid>) -> Result<ProposalResponse> {
+        let (tx, rx) = channel();
+        self.with_raft(|raft| raft.change_membership(voters, tx))?;
+
+        Ok(rx)
+    }
+
+    pub fn add_voter(&self, id: Uuid) -> Result<ProposalResponse> {
+        let mut voters = self.membership().voters;
+        voters.insert(id);
+
+        self.change_membership(voters)
+    }
+
+    pub fn remove_voter(&self, id: Uuid) -> Result<ProposalResponse> {
+        let mut voters = self.membership().voters;
+        voters.remove(&id);
+
+        self.change_membership(voters)
+    }
+
+    pub async fn launch(&self) {
+        let tick = self.config.heartbeat_interval / 2;
+
+        loop {
+            match self.membership.recv_timeout(tick) {
+                Ok((members, event)) => {
+                    self.observe(members);
+                    self.receive(event);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if let Err(e) = self.with_raft(|raft| raft.tick(Instant::now())) {
+                error!("Raft group {} failed to tick: {}", self.config.group, e);
+            }
+            self.reconcile();
+        }
+
+        self.cluster.remove_metadata(group_key(&self.config.group));
+    }
+
+    /// Run the closure on the Raft node and send out the messages it left.
+    fn with_raft<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Raft) -> Result<R>,
+    {
+        let (result, messages) = {
+            let mut raft = self.raft.lock().expect("Raft node is poisoned");
+            let result = f(&mut raft);
+            (result, raft.take_messages())
+        };
+
+        let members = self.members.lock().expect("Group members are poisoned");
+        for (to, message) in messages {
+            if !members.iter().any(|m| m.host_key() == to && m.is_remote()) {
+                debug!("Raft peer {} is not a member of the cluster yet", to);
+                continue;
+            }
+
+            let envelope = RaftEnvelope {
+                group: self.config.group.clone(),
+                message,
+            };
+            match serde_json::to_string(&envelope) {
+                Ok(encoded) => self.cluster.send_payload(to, encoded),
+                Err(e) => error!("Raft message to {} can't be encoded: {}", to, e),
+            }
+        }
+
+        result
+    }
+
+    fn observe(&self, members: Vec<ArtilleryMember>) {
+        let host_key = self.cluster.host_key();
+        let advertised = advertised_by(&self.config.group, host_key, &members);
+        *self.members.lock().expect("Group members are poisoned") = members;
+
+        let pristine = self
+            .raft
+            .lock()
+            .expect("Raft node is poisoned")
+            .is_pristine();
+        if !pristine {
+            self.advertise(String::from(RAFT_JOINED));
+            return;
+        }
+
+        let proposal = bootstrap_proposal(host_key, self.config.bootstrap_expect, &advertised);
+        self.advertise(proposal.as_ref().map_or_else(String::new, encode_voters));
+
+        if let Some(voters) = bootstrap_voters(host_key, self.config.bootstrap_expect, &advertised)
+        {
+            info!(
+                "Bootstrapping Raft group {} with {:?}",
+                self.config.group, voters
+            );
+            if let Err(e) = self.bootstrap(voters) {
+                error!(
+                    "Raft group {} failed to bootstrap: {}",
+                    self.config.group, e
+                );
+            }
+        }
+    }
+
+    /// Gossip the metadata value of the current node for the group, when it changed.
+    fn advertise(&self, value: String) {
+        let mut advertised = self
+            .advertised
+            .lock()
+            .expect("Advertised group state is poisoned");
+
+        if *advertised == value {
+            return;
+        }
+
+        match self
+            .cluster
+            .set_metadata(group_key(&self.config.group), &value)
+        {
+            Ok(()) => *advertised = value,
+            Err(e) => error!(
+                "Raft group {} can't be advertised: {}",
+                self.config.group, e
+            ),
+        }
+    }
+
+    fn receive(&self, event: ArtilleryMemberEvent) {
+        if let ArtilleryMemberEvent::Payload(sender, payload) = event {
+            match serde_json::from_str::<RaftEnvelope>(&payload) {
+                Ok(envelope) if envelope.group == self.config.group => {
+                    let from = sender.host_key();
+                    if let Err(e) =
+                        self.with_raft(|raft| raft.step(from, envelope.message, Instant::now()))
+                    {
+                        error!(
+                            "Raft group {} failed to handle a message of {}: {}",
+                            self.config.group, from, e
+                        );
+                    }
+                }
+                Ok(_) | Err(_) => {}
+            }
+        }
+    }
+
+    /// Let the leader follow the cluster membership with the voters of the group.
+    fn reconcile(&self) {
+        if !self.config.auto_membership || !self.is_leader() {
+            return;
+        }
+
+        let voters = {
+            let members = self.members.lock().expect("Group members are poisoned");
+            let gone: BTreeSet<Uuid> = members
+                .iter()
+                .filter(|m| {
+                    matches!(
+                        m.state(),
+                        ArtilleryMemberState::Down | ArtilleryMemberState::Left
+                    )
+                })
+                .map(ArtilleryMember::host_key)
+                .collect();
+
+            self.membership()
+                .voters
+                .into_iter()
+                .chain(group_members(&self.config.group, &members))
+                .filter(|id| !gone.contains(id) || *id == self.cluster.host_key())
+                .collect::<BTreeSet<_>>()
+        };
+
+        if voters == self.membership().voters {
+            return;
+        }
+
+        info!(
+            "Raft group {} moves to the voters {:?}",
+            self.config.group, voters
+        );
+        if let Err(e) = self.change_membership(voters) {
+            debug!(
+                "Raft group {} can't change its membership yet: {}",
+                self.config.group, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Advertised metadata seen through the views, after every node of the views advertised.
+    fn seen(
+        views: &BTreeMap<Uuid, BTreeSet<Uuid>>,
+        advertised: &BTreeMap<Uuid, String>,
+        id: Uuid,
+    ) -> BTreeMap<Uuid, String> {
+        views[&id]
+            .iter()
+            .filter_map(|peer| Some((*peer, advertised.get(peer)?.clone())))
+            .collect()
+    }
+
+    /// Let every node advertise its proposal, then bootstrap if it agrees with its voters.
+    fn round(
+        views: &BTreeMap<Uuid, BTreeSet<Uuid>>,
+        advertised: &mut BTreeMap<Uuid, String>,
+    ) -> Vec<(Uuid, BTreeSet<Uuid>)> {
+        let pristine: Vec<Uuid> = views
+            .keys()
+            .copied()
+            .filter(|id| advertised[id] != RAFT_JOINED)
+            .collect();
+
+        for id in &pristine {
+            let proposal = bootstrap_proposal(*id, 3, &seen(views, advertised, *id));
+            advertised.insert(
+                *id,
+                proposal.as_ref().map_or_else(String::new, encode_voters),
+            );
+        }
+
+        let bootstrapped: Vec<(Uuid, BTreeSet<Uuid>)> = pristine
+            .into_iter()
+            .filter_map(|id| Some((id, bootstrap_voters(id, 3, &seen(views, advertised, id))?)))
+            .collect();
+        for (id, _) in &bootstrapped {
+            advertised.insert(*id, String::from(RAFT_JOINED));
+        }
+
+        bootstrapped
+    }
+
+    fn views(ids: &[Uuid], seen_by: &[&[usize]]) -> BTreeMap<Uuid, BTreeSet<Uuid>> {
+        ids.iter()
+            .zip(seen_by)
+            .map(|(id, peers)| (*id, peers.iter().map(|peer| ids[*peer]).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_staggered_views_bootstrap_a_single_group() {
+        let mut ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        ids.sort();
+        let mut advertised: BTreeMap<Uuid, String> =
+            ids.iter().map(|id| (*id, String::new())).collect();
+
+        // The two lowest nodes don't see each other yet, their proposals diverge.
+        let diverging = views(&ids, &[&[2, 3], &[2, 3], &[0, 1, 3], &[0, 1, 2]]);
+        for _ in 0..3 {
+            assert!(round(&diverging, &mut advertised).is_empty());
+        }
+
+        // The nodes above the lowest one agree first, and bootstrap the group without it.
+        let staggered = views(&ids, &[&[], &[2, 3], &[1, 3], &[1, 2]]);
+        let bootstrapped = round(&staggered, &mut advertised);
+        assert!(round(&staggered, &mut advertised).is_empty());
+        assert_eq!(bootstrapped.len(), 1);
+        assert_eq!(bootstrapped[0].0, ids[1]);
+
+        // Seeing a joined node, the lowest one waits for the leader to add it.
+        let converged = views(&ids, &[&[1, 2, 3], &[0, 2, 3], &[0, 1, 3], &[0, 1, 2]]);
+        for _ in 0..3 {
+            assert!(round(&converged, &mut advertised).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_converged_views_bootstrap_the_lowest_nodes() {
+        let mut ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        ids.sort();
+        let mut advertised: BTreeMap<Uuid, String> =
+            ids.iter().map(|id| (*id, String::new())).collect();
+        let converged = views(&ids, &[&[1, 2, 3], &[0, 2, 3], &[0, 1, 3], &[0, 1, 2]]);
+
+        assert_eq!(
+            round(&converged, &mut advertised),
+            vec![(ids[0], ids[0..3].iter().copied().collect())]
+        );
+        assert!(round(&converged, &mut advertised).is_empty());
+        assert_eq!(
+            bootstrap_proposal(ids[3], 0, &BTreeMap::new()),
+            None,
+            "Nothing is bootstrapped without an expected amount of nodes"
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct Journal(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Journal {
+        fn commands(&self) -> Vec<Vec<u8>> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl StateMachine for Journal {
+        fn apply(&mut self, _index: LogIndex, command: &[u8]) -> Vec<u8> {
+            let mut commands = self.0.lock().unwrap();
+            commands.push(command.to_vec());
+            commands.len().to_string().into_bytes()
+        }
+
+        fn snapshot(&self) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(&*self.0.lock().unwrap())?)
+        }
+
+        fn restore(&mut self, data: &[u8]) -> Result<()> {
+            *self.0.lock().unwrap() = serde_json::from_slice(data)?;
+            Ok(())
+        }
+    }
+
+    fn node(seeds: &[SocketAddr]) -> Arc<Cluster> {
+        let config = ClusterConfig {
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            ping_interval: chrono::Duration::milliseconds(100),
+            ..Default::default()
+        };
+        let (cluster, _) = Cluster::new_cluster(Uuid::new_v4(), config).unwrap();
+        if !seeds.is_empty() {
+            cluster.join(seeds, Duration::from_secs(5)).unwrap();
+        }
+
+        Arc::new(cluster)
+    }
+
+    fn group(cluster: Arc<Cluster>) -> (Arc<ArtilleryCPCluster>, Journal) {
+        let journal = Journal::default();
+        let config = RaftConfig {
+            election_timeout: Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let cp = Arc::new(
+            ArtilleryCPCluster::new(cluster, config, MemoryStorage::new(), journal.clone())
+                .unwrap(),
+        );
+
+        let launched = cp.clone();
+        thread::spawn(move || futures::executor::block_on(launched.launch()));
+
+        (cp, journal)
+    }
+
+    fn eventually<F: Fn() -> bool>(condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(15);
+        while !condition() {
+            assert!(Instant::now() < deadline, "Condition wasn't met in time");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_group_replicates_over_the_cluster() {
+        let first = node(&[]);
+        let clusters = [
+            first.clone(),
+            node(&[first.listen_addr()]),
+            node(&[first.listen_addr()]),
+        ];
+        let nodes: Vec<(Arc<ArtilleryCPCluster>, Journal)> =
+            clusters.iter().cloned().map(group).collect();
+        let voters: BTreeSet<Uuid> = clusters.iter().map(|c| c.host_key()).collect();
+
+        eventually(|| {
+            nodes.iter().filter(|(cp, _)| cp.is_leader()).count() == 1
+                && nodes.iter().all(|(cp, _)| cp.membership().voters == voters)
+        });
+        let leader = nodes
+            .iter()
+            .find(|(cp, _)| cp.is_leader())
+            .unwrap()
+            .0
+            .clone();
+
+        let applied = leader.propose(b"x".to_vec()).unwrap();
+        assert_eq!(
+            applied
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap()
+                .unwrap(),
+            b"1".to_vec()
+        );
+        eventually(|| {
+            nodes
+                .iter()
+                .all(|(_, journal)| journal.commands() == vec![b"x".to_vec()])
+        });
+
+        // The leader adds the joining node as a voter, which catches up with the log.
+        let joining = node(&[first.listen_addr()]);
+        let (_joined, journal) = group(joining.clone());
+        eventually(|| {
+            leader.membership().voters.contains(&joining.host_key())
+                && !leader.membership().is_joint()
+                && journal.commands() == vec![b"x".to_vec()]
+        });
+        assert!(leader.propose(vec![0; 1 << 13]).is_err());
+    }
+}
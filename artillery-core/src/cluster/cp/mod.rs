@@ -0,0 +1,13 @@
+pub mod cluster;
+pub mod raft;
+pub mod raft_config;
+pub mod state;
+pub mod storage;
+
+pub mod prelude {
+    pub use super::cluster::*;
+    pub use super::raft::*;
+    pub use super::raft_config::*;
+    pub use super::state::*;
+    pub use super::storage::*;
+}
@@ -0,0 +1,1410 @@
+use super::raft_config::*;
+use super::state::*;
+use super::storage::*;
+use crate::errors::*;
+
+use rand::{thread_rng, Rng};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter;
+use std::sync::mpsc::Sender;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Receives the state machine response once the proposed entry is applied.
+pub type ProposalReply = Sender<Result<Vec<u8>>>;
+
+struct Progress {
+    next_index: LogIndex,
+    match_index: LogIndex,
+    /// Last index and offset of the snapshot chunk in flight, while a snapshot is sent.
+    snapshot: Option<(LogIndex, usize)>,
+    last_contact: Instant,
+}
+
+///
+/// Raft consensus of a single node, free of any I/O.
+///
+/// It is driven by `tick` and `step`, and leaves the messages to send in an outbox
+/// drained by `take_messages`. Membership changes go through joint consensus.
+pub struct Raft {
+    id: Uuid,
+    config: RaftConfig,
+    storage: Box<dyn RaftStorage>,
+    machine: Box<dyn StateMachine>,
+    role: RaftRole,
+    hard_state: HardState,
+    leader: Option<Uuid>,
+    leader_contact: Option<Instant>,
+    /// Latest membership in the log, which is in effect whether committed or not.
+    membership: RaftMembership,
+    applied_membership: RaftMembership,
+    commit_index: LogIndex,
+    last_applied: LogIndex,
+    votes: BTreeSet<Uuid>,
+    progress: BTreeMap<Uuid, Progress>,
+    proposals: BTreeMap<LogIndex, (Term, ProposalReply)>,
+    incoming: Option<Snapshot>,
+    now: Instant,
+    election_deadline: Instant,
+    heartbeat_deadline: Instant,
+    quorum_deadline: Instant,
+    outbox: Vec<(Uuid, RaftMessage)>,
+}
+
+impl Raft {
+    /// Restore the node from its storage. The state machine has to be empty.
+    pub fn new(
+        id: Uuid,
+        config: RaftConfig,
+        storage: Box<dyn RaftStorage>,
+        machine: Box<dyn StateMachine>,
+        now: Instant,
+    ) -> Result<Self> {
+        let mut raft = Self {
+            id,
+            hard_state: storage.hard_state()?,
+            config,
+            storage,
+            machine,
+            role: RaftRole::Follower,
+            leader: None,
+            leader_contact: None,
+            membership: RaftMembership::default(),
+            applied_membership: RaftMembership::default(),
+            commit_index: 0,
+            last_applied: 0,
+            votes: BTreeSet::new(),
+            progress: BTreeMap::new(),
+            proposals: BTreeMap::new(),
+            incoming: None,
+            now,
+            election_deadline: now,
+            heartbeat_deadline: now,
+            quorum_deadline: now,
+            outbox: Vec::new(),
+        };
+
+        if let Some(snapshot) = raft.storage.snapshot()? {
+            raft.machine.restore(&snapshot.data)?;
+            raft.commit_index = snapshot.last_index;
+            raft.last_applied = snapshot.last_index;
+            raft.applied_membership = snapshot.membership;
+        }
+        raft.reload_membership()?;
+        raft.reset_election_deadline();
+
+        Ok(raft)
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn role(&self) -> RaftRole {
+        self.role
+    }
+
+    pub fn term(&self) -> Term {
+        self.hard_state.term
+    }
+
+    pub fn leader(&self) -> Option<Uuid> {
+        self.leader
+    }
+
+    /// Latest membership of the group, committed or not.
+    pub fn membership(&self) -> &RaftMembership {
+        &self.membership
+    }
+
+    pub fn commit_index(&self) -> LogIndex {
+        self.commit_index
+    }
+
+    pub fn last_applied(&self) -> LogIndex {
+        self.last_applied
+    }
+
+    /// Whether the node never took part in a group.
+    pub fn is_pristine(&self) -> bool {
+        self.term() == 0 && self.storage.last_index() == 0
+    }
+
+    /// Messages to send since the last call, with their destination.
+    pub fn take_messages(&mut self) -> Vec<(Uuid, RaftMessage)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Start a new group out of the voters, from a pristine node which is one of them.
+    /// Exactly one node may bootstrap a group; the others learn about it from its leader.
+    pub fn bootstrap(&mut self, voters: BTreeSet<Uuid>) -> Result<()> {
+        if !self.is_pristine() {
+            bail!(
+                ArtilleryError::Consensus,
+                "Node {} already took part in a group",
+                self.id
+            );
+        }
+        if !voters.contains(&self.id) {
+            bail!(
+                ArtilleryError::Consensus,
+                "Node {} has to be one of the voters it bootstraps",
+                self.id
+            );
+        }
+
+        let membership = RaftMembership::new(voters);
+        self.storage.append(&[LogEntry {
+            term: 0,
+            index: 1,
+            payload: EntryPayload::Membership(membership.clone()),
+        }])?;
+        self.membership = membership;
+        self.election_deadline = self.now;
+
+        Ok(())
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Result<()> {
+        self.now = now;
+
+        match self.role {
+            RaftRole::Leader => {
+                if now >= self.quorum_deadline {
+                    if !self.has_active_quorum() {
+                        warn!(
+                            "Leader {} lost contact with a quorum in term {}",
+                            self.id,
+                            self.term()
+                        );
+                        self.become_follower(self.term(), None)?;
+                        self.reset_election_deadline();
+                        return Ok(());
+                    }
+                    self.quorum_deadline = now + self.config.election_timeout;
+                }
+
+                if now >= self.heartbeat_deadline {
+                    self.heartbeat_deadline = now + self.config.heartbeat_interval;
+                    self.broadcast_append()?;
+                }
+            }
+            RaftRole::Follower | RaftRole::Candidate => {
+                if now >= self.election_deadline {
+                    self.campaign()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn step(&mut self, from: Uuid, message: RaftMessage, now: Instant) -> Result<()> {
+        self.now = now;
+
+        match message.term.cmp(&self.term()) {
+            Ordering::Greater => {
+                if matches!(message.body, RaftRpc::RequestVote { .. }) && self.in_lease() {
+                    // A leader is still around: the candidate is most likely a removed node.
+                    debug!(
+                        "{} ignores the vote request of {} in term {}",
+                        self.id, from, message.term
+                    );
+                    return Ok(());
+                }
+                self.become_follower(message.term, None)?;
+            }
+            Ordering::Less => {
+                self.reject_stale(from, &message.body);
+                return Ok(());
+            }
+            Ordering::Equal => {}
+        }
+
+        match message.body {
+            RaftRpc::RequestVote {
+                last_log_index,
+                last_log_term,
+            } => self.handle_vote_request(from, last_log_index, last_log_term),
+            RaftRpc::Vote { granted } => self.handle_vote(from, granted),
+            RaftRpc::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.handle_append(from, prev_log_index, prev_log_term, entries, leader_commit),
+            RaftRpc::AppendResponse {
+                success,
+                last_index,
+            } => self.handle_append_response(from, success, last_index),
+            RaftRpc::InstallSnapshot {
+                last_index,
+                last_term,
+                membership,
+                offset,
+                data,
+                done,
+            } => {
+                let chunk = Snapshot {
+                    last_index,
+                    last_term,
+                    membership,
+                    data,
+                };
+                self.handle_snapshot(from, chunk, offset, done)
+            }
+            RaftRpc::SnapshotResponse {
+                last_index,
+                offset,
+                done,
+            } => self.handle_snapshot_response(from, last_index, offset, done),
+        }
+    }
+
+    /// Append a command to the log of the leader.
+    /// The reply receives the response of the state machine once the command is applied.
+    pub fn propose(&mut self, command: Vec<u8>, reply: ProposalReply) -> Result<LogIndex> {
+        self.ensure_leader()?;
+
+        if command.len() > self.config.max_command_size {
+            bail!(
+                ArtilleryError::Consensus,
+                "Command of {} bytes exceeds the limit of {} bytes",
+                command.len(),
+                self.config.max_command_size
+            );
+        }
+
+        let index = self.append_entry(EntryPayload::Command(command))?;
+        self.proposals.insert(index, (self.term(), reply));
+        self.advance_commit()?;
+
+        Ok(index)
+    }
+
+    /// Move the group to the voters, through a joint configuration of the current and the new voters.
+    /// The reply receives an empty response once the new voters are committed on their own.
+    pub fn change_membership(
+        &mut self,
+        voters: BTreeSet<Uuid>,
+        reply: ProposalReply,
+    ) -> Result<LogIndex> {
+        self.ensure_leader()?;
+
+        if voters.is_empty() {
+            bail!(
+                ArtilleryError::Consensus,
+                "A group needs at least one voter"
+            );
+        }
+        if self.membership.is_joint() || self.membership != self.applied_membership {
+            bail!(
+                ArtilleryError::Consensus,
+                "Another membership change is in progress"
+            );
+        }
+
+        let joint = RaftMembership {
+            voters: self.membership.voters.clone(),
+            joint: Some(voters),
+        };
+        let index = self.append_entry(EntryPayload::Membership(joint))?;
+        self.proposals.insert(index, (self.term(), reply));
+        self.advance_commit()?;
+
+        Ok(index)
+    }
+
+    fn ensure_leader(&self) -> Result<()> {
+        match self.role {
+            RaftRole::Leader => Ok(()),
+            RaftRole::Follower | RaftRole::Candidate => Err(ArtilleryError::NotLeader(self.leader)),
+        }
+    }
+
+    fn send(&mut self, to: Uuid, body: RaftRpc) {
+        self.outbox.push((
+            to,
+            RaftMessage {
+                term: self.term(),
+                body,
+            },
+        ));
+    }
+
+    fn set_hard_state(&mut self, hard_state: HardState) -> Result<()> {
+        self.storage.set_hard_state(hard_state)?;
+        self.hard_state = hard_state;
+        Ok(())
+    }
+
+    fn last_term(&self) -> Result<Term> {
+        Ok(self
+            .storage
+            .term(self.storage.last_index())?
+            .unwrap_or_default())
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let jitter = self
+            .config
+            .election_timeout
+            .mul_f64(thread_rng().gen_range(0.0, 1.0));
+        self.election_deadline = self.now + self.config.election_timeout + jitter;
+    }
+
+    /// Whether a leader was heard of recently enough to ignore candidates.
+    fn in_lease(&self) -> bool {
+        match self.role {
+            RaftRole::Leader => true,
+            RaftRole::Follower | RaftRole::Candidate => {
+                matches!(
+                    (self.leader, self.leader_contact),
+                    (Some(_), Some(contact))
+                        if self.now.duration_since(contact) < self.config.election_timeout
+                )
+            }
+        }
+    }
+
+    fn has_active_quorum(&self) -> bool {
+        let active: BTreeSet<Uuid> = self
+            .progress
+            .iter()
+            .filter(|(_, p)| self.now.duration_since(p.last_contact) < self.config.election_timeout)
+            .map(|(id, _)| *id)
+            .chain(iter::once(self.id))
+            .collect();
+
+        self.membership.has_quorum(&active)
+    }
+
+    fn reload_membership(&mut self) -> Result<()> {
+        let entries = self
+            .storage
+            .entries(self.storage.first_index(), self.storage.last_index() + 1)?;
+        let latest = entries.into_iter().rev().find_map(|e| match e.payload {
+            EntryPayload::Membership(membership) => Some(membership),
+            EntryPayload::Noop | EntryPayload::Command(_) => None,
+        });
+
+        self.membership = match latest {
+            Some(membership) => membership,
+            None => self
+                .storage
+                .snapshot()?
+                .map(|s| s.membership)
+                .unwrap_or_default(),
+        };
+        self.sync_progress();
+
+        Ok(())
+    }
+
+    /// Track every member of the membership in effect, and only those.
+    fn sync_progress(&mut self) {
+        if self.role != RaftRole::Leader {
+            return;
+        }
+
+        let members = self.membership.members();
+        let next_index = self.storage.last_index() + 1;
+        let (host, now) = (self.id, self.now);
+
+        self.progress.retain(|id, _| members.contains(id));
+        for id in members.into_iter().filter(|id| *id != host) {
+            self.progress.entry(id).or_insert(Progress {
+                next_index,
+                match_index: 0,
+                snapshot: None,
+                last_contact: now,
+            });
+        }
+    }
+
+    fn become_follower(&mut self, term: Term, leader: Option<Uuid>) -> Result<()> {
+        if term != self.term() {
+            self.set_hard_state(HardState {
+                term,
+                voted_for: None,
+            })?;
+        }
+        if self.role == RaftRole::Leader {
+            info!("{} steps down as leader of term {}", self.id, self.term());
+            for (_, (_, reply)) in std::mem::take(&mut self.proposals) {
+                let _ = reply.send(Err(ArtilleryError::Consensus(String::from(
+                    "Leadership was lost, the outcome of the proposal is unknown",
+                ))));
+            }
+        }
+
+        self.role = RaftRole::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.progress.clear();
+
+        Ok(())
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.reset_election_deadline();
+
+        if !self.membership.contains(&self.id) {
+            return Ok(());
+        }
+
+        let term = self.term() + 1;
+        self.set_hard_state(HardState {
+            term,
+            voted_for: Some(self.id),
+        })?;
+        self.role = RaftRole::Candidate;
+        self.leader = None;
+        self.votes = iter::once(self.id).collect();
+        debug!("{} campaigns for term {}", self.id, term);
+
+        if self.membership.has_quorum(&self.votes) {
+            return self.become_leader();
+        }
+
+        let last_log_index = self.storage.last_index();
+        let last_log_term = self.last_term()?;
+        for peer in self.membership.members() {
+            if peer != self.id {
+                self.send(
+                    peer,
+                    RaftRpc::RequestVote {
+                        last_log_index,
+                        last_log_term,
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!("{} is the leader of term {}", self.id, self.term());
+
+        self.role = RaftRole::Leader;
+        self.leader = Some(self.id);
+        self.votes.clear();
+        self.progress.clear();
+        self.sync_progress();
+        self.heartbeat_deadline = self.now + self.config.heartbeat_interval;
+        self.quorum_deadline = self.now + self.config.election_timeout;
+
+        self.append_entry(EntryPayload::Noop)?;
+        self.finish_joint(None)?;
+        self.advance_commit()
+    }
+
+    fn append_entry(&mut self, payload: EntryPayload) -> Result<LogIndex> {
+        let entry = LogEntry {
+            term: self.term(),
+            index: self.storage.last_index() + 1,
+            payload,
+        };
+        let index = entry.index;
+
+        self.storage.append(std::slice::from_ref(&entry))?;
+        if let EntryPayload::Membership(membership) = entry.payload {
+            self.membership = membership;
+            self.sync_progress();
+        }
+        self.broadcast_append()?;
+
+        Ok(index)
+    }
+
+    /// Leave the joint configuration once it is committed.
+    fn finish_joint(&mut self, proposal: Option<ProposalReply>) -> Result<()> {
+        let voters = match self.membership.joint.as_ref() {
+            Some(voters)
+                if self.role == RaftRole::Leader && self.membership == self.applied_membership =>
+            {
+                voters.clone()
+            }
+            Some(_) | None => return Ok(()),
+        };
+
+        let index = self.append_entry(EntryPayload::Membership(RaftMembership::new(voters)))?;
+        if let Some(reply) = proposal {
+            self.proposals.insert(index, (self.term(), reply));
+        }
+
+        Ok(())
+    }
+
+    fn broadcast_append(&mut self) -> Result<()> {
+        let peers: Vec<Uuid> = self.progress.keys().copied().collect();
+
+        for peer in peers {
+            self.send_append(peer)?;
+        }
+
+        Ok(())
+    }
+
+    fn send_append(&mut self, peer: Uuid) -> Result<()> {
+        let (next_index, sending_snapshot) = match self.progress.get(&peer) {
+            Some(progress) => (progress.next_index, progress.snapshot.is_some()),
+            None => return Ok(()),
+        };
+
+        if sending_snapshot || next_index < self.storage.first_index() {
+            return self.send_snapshot(peer);
+        }
+
+        let prev_log_index = next_index - 1;
+        let prev_log_term = match self.storage.term(prev_log_index)? {
+            Some(term) => term,
+            None => return self.send_snapshot(peer),
+        };
+        let to = self
+            .storage
+            .last_index()
+            .min(prev_log_index + self.config.max_entries_per_message);
+
+        let mut entries = Vec::new();
+        let mut size = 0;
+        for entry in self.storage.entries(next_index, to + 1)? {
+            let entry_size = serde_json::to_vec(&entry)?.len();
+            if !entries.is_empty() && size + entry_size > self.config.max_append_size {
+                break;
+            }
+            size += entry_size;
+            entries.push(entry);
+        }
+
+        self.send(
+            peer,
+            RaftRpc::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit: self.commit_index,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn send_snapshot(&mut self, peer: Uuid) -> Result<()> {
+        let snapshot = match self.storage.snapshot()? {
+            Some(snapshot) => snapshot,
+            None => return Ok(()),
+        };
+        let progress = match self.progress.get_mut(&peer) {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+
+        let offset = match progress.snapshot {
+            Some((last_index, offset)) if last_index == snapshot.last_index => {
+                offset.min(snapshot.data.len())
+            }
+            Some(_) | None => 0,
+        };
+        progress.snapshot = Some((snapshot.last_index, offset));
+
+        let end = snapshot
+            .data
+            .len()
+            .min(offset + self.config.snapshot_chunk_size);
+        let data = snapshot.data[offset..end].to_vec();
+
+        self.send(
+            peer,
+            RaftRpc::InstallSnapshot {
+                last_index: snapshot.last_index,
+                last_term: snapshot.last_term,
+                membership: snapshot.membership,
+                offset,
+                data,
+                done: end == snapshot.data.len(),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn reject_stale(&mut self, from: Uuid, body: &RaftRpc) {
+        match body {
+            RaftRpc::RequestVote { .. } => self.send(from, RaftRpc::Vote { granted: false }),
+            RaftRpc::AppendEntries { .. } => {
+                let last_index = self.storage.last_index();
+                self.send(
+                    from,
+                    RaftRpc::AppendResponse {
+                        success: false,
+                        last_index,
+                    },
+                );
+            }
+            RaftRpc::InstallSnapshot { last_index, .. } => self.send(
+                from,
+                RaftRpc::SnapshotResponse {
+                    last_index: *last_index,
+                    offset: 0,
+                    done: false,
+                },
+            ),
+            RaftRpc::Vote { .. }
+            | RaftRpc::AppendResponse { .. }
+            | RaftRpc::SnapshotResponse { .. } => {}
+        }
+    }
+
+    fn handle_vote_request(
+        &mut self,
+        from: Uuid,
+        last_log_index: LogIndex,
+        last_log_term: Term,
+    ) -> Result<()> {
+        let up_to_date =
+            (last_log_term, last_log_index) >= (self.last_term()?, self.storage.last_index());
+        let granted = up_to_date
+            && (self.hard_state.voted_for.is_none() || self.hard_state.voted_for == Some(from));
+
+        if granted {
+            self.set_hard_state(HardState {
+                term: self.term(),
+                voted_for: Some(from),
+            })?;
+            self.reset_election_deadline();
+        }
+        self.send(from, RaftRpc::Vote { granted });
+
+        Ok(())
+    }
+
+    fn handle_vote(&mut self, from: Uuid, granted: bool) -> Result<()> {
+        if self.role != RaftRole::Candidate || !granted {
+            return Ok(());
+        }
+
+        self.votes.insert(from);
+        if self.membership.has_quorum(&self.votes) {
+            return self.become_leader();
+        }
+
+        Ok(())
+    }
+
+    /// Acknowledge the sender as the leader of the current term.
+    fn follow(&mut self, leader: Uuid) -> Result<()> {
+        if self.role != RaftRole::Follower {
+            self.become_follower(self.term(), Some(leader))?;
+        }
+
+        self.leader = Some(leader);
+        self.leader_contact = Some(self.now);
+        self.reset_election_deadline();
+
+        Ok(())
+    }
+
+    fn handle_append(
+        &mut self,
+        from: Uuid,
+        prev_log_index: LogIndex,
+        prev_log_term: Term,
+        entries: Vec<LogEntry>,
+        leader_commit: LogIndex,
+    ) -> Result<()> {
+        self.follow(from)?;
+
+        let last_index = self.storage.last_index();
+        if prev_log_index > last_index {
+            self.send(
+                from,
+                RaftRpc::AppendResponse {
+                    success: false,
+                    last_index,
+                },
+            );
+            return Ok(());
+        }
+
+        let snapshot_index = self.storage.first_index() - 1;
+        if prev_log_index >= snapshot_index {
+            let term = self.storage.term(prev_log_index)?;
+            if term != Some(prev_log_term) {
+                // Skip the whole conflicting term instead of probing entry by entry.
+                let mut hint = prev_log_index - 1;
+                while hint > self.commit_index && self.storage.term(hint)? == term {
+                    hint -= 1;
+                }
+                self.send(
+                    from,
+                    RaftRpc::AppendResponse {
+                        success: false,
+                        last_index: hint,
+                    },
+                );
+                return Ok(());
+            }
+        }
+
+        let last_new = entries.last().map_or(prev_log_index, |e| e.index);
+        let mut membership_changed = false;
+        let mut appended = Vec::new();
+
+        for entry in entries {
+            if entry.index <= snapshot_index {
+                continue;
+            }
+            if appended.is_empty() && entry.index <= self.storage.last_index() {
+                if self.storage.term(entry.index)? == Some(entry.term) {
+                    continue;
+                }
+                if entry.index <= self.commit_index {
+                    bail!(
+                        ArtilleryError::Consensus,
+                        "Committed entry {} conflicts with the log of leader {}",
+                        entry.index,
+                        from
+                    );
+                }
+                self.storage.truncate(entry.index)?;
+                membership_changed = true;
+            }
+            membership_changed |= matches!(entry.payload, EntryPayload::Membership(_));
+            appended.push(entry);
+        }
+
+        self.storage.append(&appended)?;
+        if membership_changed {
+            self.reload_membership()?;
+        }
+
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(last_new).max(self.commit_index);
+            self.apply()?;
+        }
+
+        self.send(
+            from,
+            RaftRpc::AppendResponse {
+                success: true,
+                last_index: last_new.max(snapshot_index),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn handle_append_response(
+        &mut self,
+        from: Uuid,
+        success: bool,
+        last_index: LogIndex,
+    ) -> Result<()> {
+        if self.role != RaftRole::Leader {
+            return Ok(());
+        }
+
+        let leader_last_index = self.storage.last_index();
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+        progress.last_contact = self.now;
+
+        if success {
+            progress.match_index = progress.match_index.max(last_index);
+            progress.next_index = progress.next_index.max(progress.match_index + 1);
+            let lagging = progress.next_index <= leader_last_index;
+
+            self.advance_commit()?;
+            if lagging {
+                self.send_append(from)?;
+            }
+        } else {
+            progress.next_index = (last_index + 1)
+                .max(progress.match_index + 1)
+                .min(leader_last_index + 1);
+            self.send_append(from)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_snapshot(
+        &mut self,
+        from: Uuid,
+        chunk: Snapshot,
+        offset: usize,
+        done: bool,
+    ) -> Result<()> {
+        self.follow(from)?;
+
+        let last_index = chunk.last_index;
+        if last_index <= self.commit_index {
+            self.incoming = None;
+            self.send(
+                from,
+                RaftRpc::SnapshotResponse {
+                    last_index,
+                    offset: 0,
+                    done: true,
+                },
+            );
+            return Ok(());
+        }
+
+        let mut incoming = match self.incoming.take() {
+            Some(incoming)
+                if incoming.last_index == last_index && incoming.last_term == chunk.last_term =>
+            {
+                incoming
+            }
+            Some(_) | None => Snapshot {
+                data: Vec::new(),
+                ..chunk.clone()
+            },
+        };
+
+        if offset != incoming.data.len() {
+            let expected = incoming.data.len();
+            self.incoming = Some(incoming);
+            self.send(
+                from,
+                RaftRpc::SnapshotResponse {
+                    last_index,
+                    offset: expected,
+                    done: false,
+                },
+            );
+            return Ok(());
+        }
+
+        incoming.data.extend(chunk.data);
+        if !done {
+            let expected = incoming.data.len();
+            self.incoming = Some(incoming);
+            self.send(
+                from,
+                RaftRpc::SnapshotResponse {
+                    last_index,
+                    offset: expected,
+                    done: false,
+                },
+            );
+            return Ok(());
+        }
+
+        debug!("{} installs the snapshot up to {}", self.id, last_index);
+        self.machine.restore(&incoming.data)?;
+        self.applied_membership = incoming.membership.clone();
+        self.commit_index = last_index;
+        self.last_applied = last_index;
+        self.storage.save_snapshot(incoming)?;
+        self.reload_membership()?;
+
+        self.send(
+            from,
+            RaftRpc::SnapshotResponse {
+                last_index,
+                offset: 0,
+                done: true,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn handle_snapshot_response(
+        &mut self,
+        from: Uuid,
+        last_index: LogIndex,
+        offset: usize,
+        done: bool,
+    ) -> Result<()> {
+        if self.role != RaftRole::Leader {
+            return Ok(());
+        }
+
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+        progress.last_contact = self.now;
+
+        match progress.snapshot {
+            Some((sent, _)) if sent == last_index => {}
+            Some(_) | None => return Ok(()),
+        }
+
+        if done {
+            progress.snapshot = None;
+            progress.match_index = progress.match_index.max(last_index);
+            progress.next_index = progress.match_index + 1;
+
+            self.advance_commit()?;
+            self.send_append(from)
+        } else {
+            progress.snapshot = Some((last_index, offset));
+            self.send_snapshot(from)
+        }
+    }
+
+    /// Commit the latest entry of the current term replicated on a quorum, and apply up to it.
+    fn advance_commit(&mut self) -> Result<()> {
+        while self.role == RaftRole::Leader {
+            let term = self.term();
+            let mut index = self.storage.last_index();
+            let mut committed = None;
+
+            while index > self.commit_index && self.storage.term(index)? == Some(term) {
+                let acked: BTreeSet<Uuid> = self
+                    .progress
+                    .iter()
+                    .filter(|(_, p)| p.match_index >= index)
+                    .map(|(id, _)| *id)
+                    .chain(iter::once(self.id))
+                    .collect();
+
+                if self.membership.has_quorum(&acked) {
+                    committed = Some(index);
+                    break;
+                }
+                index -= 1;
+            }
+
+            match committed {
+                Some(committed_index) => {
+                    self.commit_index = committed_index;
+                    // Applying may append the final configuration of a membership change.
+                    self.apply()?;
+                }
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = match self.storage.entries(index, index + 1)?.pop() {
+                Some(entry) => entry,
+                None => bail!(ArtilleryError::Consensus, "Entry {} is missing", index),
+            };
+            self.last_applied = index;
+
+            let proposal = self.proposals.remove(&index).and_then(|(term, reply)| {
+                if term == entry.term {
+                    return Some(reply);
+                }
+                let _ = reply.send(Err(ArtilleryError::Consensus(String::from(
+                    "The proposal was overwritten by another leader",
+                ))));
+                None
+            });
+
+            let response = match entry.payload {
+                EntryPayload::Noop => Vec::new(),
+                EntryPayload::Command(command) => self.machine.apply(index, &command),
+                EntryPayload::Membership(membership) => {
+                    self.applied_membership = membership;
+
+                    if self.applied_membership.is_joint() {
+                        self.finish_joint(proposal)?;
+                        continue;
+                    }
+                    Vec::new()
+                }
+            };
+
+            if let Some(reply) = proposal {
+                let _ = reply.send(Ok(response));
+            }
+
+            if self.role == RaftRole::Leader && !self.applied_membership.contains(&self.id) {
+                info!("{} left the group", self.id);
+                self.become_follower(self.term(), None)?;
+            }
+        }
+
+        self.compact()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        let snapshot_index = self.storage.first_index() - 1;
+        if self.last_applied <= snapshot_index
+            || self.last_applied - snapshot_index < self.config.snapshot_threshold
+        {
+            return Ok(());
+        }
+
+        let snapshot = Snapshot {
+            last_index: self.last_applied,
+            last_term: self.storage.term(self.last_applied)?.unwrap_or_default(),
+            membership: self.applied_membership.clone(),
+            data: self.machine.snapshot()?,
+        };
+        debug!("{} compacts the log up to {}", self.id, snapshot.last_index);
+
+        self.storage.save_snapshot(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct Journal(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Journal {
+        fn commands(&self) -> Vec<Vec<u8>> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl StateMachine for Journal {
+        fn apply(&mut self, _index: LogIndex, command: &[u8]) -> Vec<u8> {
+            let mut commands = self.0.lock().unwrap();
+            commands.push(command.to_vec());
+            commands.len().to_string().into_bytes()
+        }
+
+        fn snapshot(&self) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(&*self.0.lock().unwrap())?)
+        }
+
+        fn restore(&mut self, data: &[u8]) -> Result<()> {
+            *self.0.lock().unwrap() = serde_json::from_slice(data)?;
+            Ok(())
+        }
+    }
+
+    /// Nodes exchanging messages instantly, on a clock moving in steps of 10ms.
+    struct Network {
+        config: RaftConfig,
+        now: Instant,
+        nodes: BTreeMap<Uuid, Raft>,
+        journals: BTreeMap<Uuid, Journal>,
+        isolated: BTreeSet<Uuid>,
+    }
+
+    impl Network {
+        fn new(size: usize, config: RaftConfig) -> Self {
+            let mut network = Network {
+                config,
+                now: Instant::now(),
+                nodes: BTreeMap::new(),
+                journals: BTreeMap::new(),
+                isolated: BTreeSet::new(),
+            };
+
+            let ids: BTreeSet<Uuid> = (0..size).map(|_| network.add()).collect();
+            let first = *ids.iter().next().unwrap();
+            network
+                .nodes
+                .get_mut(&first)
+                .unwrap()
+                .bootstrap(ids)
+                .unwrap();
+
+            network
+        }
+
+        fn add(&mut self) -> Uuid {
+            let id = Uuid::new_v4();
+            let journal = Journal::default();
+            let raft = Raft::new(
+                id,
+                self.config.clone(),
+                Box::new(MemoryStorage::new()),
+                Box::new(journal.clone()),
+                self.now,
+            )
+            .unwrap();
+
+            self.nodes.insert(id, raft);
+            self.journals.insert(id, journal);
+            id
+        }
+
+        fn run(&mut self, duration: Duration) {
+            let until = self.now + duration;
+
+            while self.now < until {
+                self.now += Duration::from_millis(10);
+                let now = self.now;
+
+                for raft in self.nodes.values_mut() {
+                    raft.tick(now).unwrap();
+                }
+
+                loop {
+                    let messages: Vec<(Uuid, Uuid, RaftMessage)> = self
+                        .nodes
+                        .values_mut()
+                        .flat_map(|raft| {
+                            let from = raft.id();
+                            raft.take_messages()
+                                .into_iter()
+                                .map(move |(to, message)| (from, to, message))
+                        })
+                        .collect();
+                    if messages.is_empty() {
+                        break;
+                    }
+
+                    for (from, to, message) in messages {
+                        if self.isolated.contains(&from) || self.isolated.contains(&to) {
+                            continue;
+                        }
+                        if let Some(raft) = self.nodes.get_mut(&to) {
+                            raft.step(from, message, now).unwrap();
+                        }
+                    }
+                }
+            }
+        }
+
+        fn leader(&self) -> Uuid {
+            self.nodes
+                .values()
+                .filter(|raft| {
+                    raft.role() == RaftRole::Leader && !self.isolated.contains(&raft.id())
+                })
+                .max_by_key(|raft| raft.term())
+                .map(Raft::id)
+                .expect("No leader was elected")
+        }
+
+        fn propose(&mut self, id: Uuid, command: &str) -> Receiver<Result<Vec<u8>>> {
+            let (tx, rx) = channel();
+            self.nodes
+                .get_mut(&id)
+                .unwrap()
+                .propose(command.as_bytes().to_vec(), tx)
+                .unwrap();
+            rx
+        }
+
+        fn commands(&self, id: Uuid) -> Vec<Vec<u8>> {
+            self.journals[&id].commands()
+        }
+    }
+
+    fn config() -> RaftConfig {
+        RaftConfig {
+            election_timeout: Duration::from_millis(100),
+            heartbeat_interval: Duration::from_millis(20),
+            max_entries_per_message: 4,
+            snapshot_chunk_size: 8,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_leader_replicates_commands() {
+        let mut network = Network::new(3, config());
+        network.run(Duration::from_secs(1));
+
+        let leader = network.leader();
+        let follower = *network.nodes.keys().find(|id| **id != leader).unwrap();
+        let (tx, _rx) = channel();
+        assert!(matches!(
+            network
+                .nodes
+                .get_mut(&follower)
+                .unwrap()
+                .propose(b"x".to_vec(), tx),
+            Err(ArtilleryError::NotLeader(Some(id))) if id == leader
+        ));
+
+        let replies: Vec<_> = (0..10)
+            .map(|i| network.propose(leader, &i.to_string()))
+            .collect();
+        network.run(Duration::from_millis(200));
+
+        for (i, reply) in replies.iter().enumerate() {
+            assert_eq!(
+                reply.try_recv().unwrap().unwrap(),
+                (i + 1).to_string().into_bytes()
+            );
+        }
+        for id in network.nodes.keys() {
+            assert_eq!(network.commands(*id).len(), 10);
+            assert_eq!(network.commands(*id), network.commands(leader));
+        }
+    }
+
+    #[test]
+    fn test_new_leader_overwrites_uncommitted_entries() {
+        let mut network = Network::new(3, config());
+        network.run(Duration::from_secs(1));
+
+        let old_leader = network.leader();
+        network.isolated.insert(old_leader);
+        let lost = network.propose(old_leader, "lost");
+        network.run(Duration::from_secs(1));
+
+        let new_leader = network.leader();
+        assert_ne!(new_leader, old_leader);
+        let kept = network.propose(new_leader, "kept");
+        network.run(Duration::from_millis(200));
+        assert!(kept.try_recv().unwrap().is_ok());
+
+        network.isolated.clear();
+        network.run(Duration::from_secs(1));
+
+        assert!(lost.try_recv().unwrap().is_err());
+        for id in network.nodes.keys() {
+            assert_eq!(network.commands(*id), vec![b"kept".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn test_lagging_follower_is_sent_a_snapshot() {
+        let mut network = Network::new(
+            3,
+            RaftConfig {
+                snapshot_threshold: 8,
+                ..config()
+            },
+        );
+        network.run(Duration::from_secs(1));
+
+        let leader = network.leader();
+        let lagging = *network.nodes.keys().find(|id| **id != leader).unwrap();
+        network.isolated.insert(lagging);
+
+        for i in 0..30 {
+            network.propose(leader, &i.to_string());
+        }
+        network.run(Duration::from_millis(500));
+        assert!(network.commands(lagging).is_empty());
+
+        network.isolated.clear();
+        network.run(Duration::from_secs(1));
+
+        assert_eq!(network.commands(lagging).len(), 30);
+        assert_eq!(network.commands(lagging), network.commands(leader));
+        assert_eq!(
+            network.nodes[&lagging].last_applied(),
+            network.nodes[&leader].last_applied()
+        );
+    }
+
+    #[test]
+    fn test_membership_changes_through_joint_consensus() {
+        let mut network = Network::new(3, config());
+        network.run(Duration::from_secs(1));
+
+        let old_leader = network.leader();
+        network.propose(old_leader, "before");
+        let joining = network.add();
+        let voters: BTreeSet<Uuid> = network
+            .nodes
+            .keys()
+            .copied()
+            .filter(|id| *id != old_leader)
+            .collect();
+
+        let (tx, changed) = channel();
+        network
+            .nodes
+            .get_mut(&old_leader)
+            .unwrap()
+            .change_membership(voters.clone(), tx)
+            .unwrap();
+        let (refused_tx, _refused) = channel();
+        assert!(network
+            .nodes
+            .get_mut(&old_leader)
+            .unwrap()
+            .change_membership(voters.clone(), refused_tx)
+            .is_err());
+
+        network.run(Duration::from_secs(1));
+        assert!(changed.try_recv().unwrap().is_ok());
+
+        // The removed node steps down and stays out of the elections.
+        network.run(Duration::from_secs(1));
+        assert_eq!(network.nodes[&old_leader].role(), RaftRole::Follower);
+        let new_leader = network.leader();
+        assert!(voters.contains(&new_leader));
+        assert_eq!(
+            network.nodes[&new_leader].membership(),
+            &RaftMembership::new(voters.clone())
+        );
+
+        let after = network.propose(new_leader, "after");
+        network.run(Duration::from_millis(200));
+        assert!(after.try_recv().unwrap().is_ok());
+        assert_eq!(
+            network.commands(joining),
+            vec![b"before".to_vec(), b"after".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_messages_are_bounded_in_size() {
+        let mut network = Network::new(
+            3,
+            RaftConfig {
+                max_entries_per_message: 100,
+                max_append_size: 200,
+                max_command_size: 64,
+                ..config()
+            },
+        );
+        network.run(Duration::from_secs(1));
+
+        let leader = network.leader();
+        let (tx, _rx) = channel();
+        assert!(network
+            .nodes
+            .get_mut(&leader)
+            .unwrap()
+            .propose(vec![0; 65], tx)
+            .is_err());
+
+        let command = "x".repeat(8);
+        for _ in 0..10 {
+            network.propose(leader, &command);
+        }
+        let appended: Vec<Vec<LogEntry>> = network
+            .nodes
+            .get_mut(&leader)
+            .unwrap()
+            .take_messages()
+            .into_iter()
+            .filter_map(|(_, message)| match message.body {
+                RaftRpc::AppendEntries { entries, .. } => Some(entries),
+                RaftRpc::RequestVote { .. }
+                | RaftRpc::Vote { .. }
+                | RaftRpc::AppendResponse { .. }
+                | RaftRpc::InstallSnapshot { .. }
+                | RaftRpc::SnapshotResponse { .. } => None,
+            })
+            .collect();
+        assert!(appended.iter().any(|entries| entries.len() > 1));
+        for entries in appended {
+            let size: usize = entries
+                .iter()
+                .map(|entry| serde_json::to_vec(entry).unwrap().len())
+                .sum();
+            assert!(entries.len() == 1 || size <= 200);
+        }
+
+        network.run(Duration::from_millis(500));
+        for id in network.nodes.keys() {
+            assert_eq!(network.commands(*id).len(), 10);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// Name of the Raft group, unique within the cluster.
+    pub group: String,
+    /// Followers not hearing from a leader for this long, plus a random part of it, start an election.
+    /// Leaders not hearing from a quorum for this long step down.
+    pub election_timeout: Duration,
+    pub heartbeat_interval: Duration,
+    /// Upper bound of entries carried by a single `AppendEntries` message.
+    pub max_entries_per_message: u64,
+    /// Upper bound of the encoded entries carried by a single `AppendEntries` message, in bytes.
+    /// A single entry is always sent, however large it is.
+    pub max_append_size: usize,
+    /// Largest command accepted by `propose`, in bytes.
+    /// Raft messages travel as cluster payloads, which have to fit a packet.
+    pub max_command_size: usize,
+    /// Bytes of snapshot data carried by a single `InstallSnapshot` message.
+    pub snapshot_chunk_size: usize,
+    /// Amount of applied entries kept in the log before it is compacted into a snapshot.
+    pub snapshot_threshold: u64,
+    /// Amount of nodes which have to be seen, and agree on the voters, before a pristine group
+    /// bootstraps itself.
+    /// Zero leaves bootstrapping to `ArtilleryCPCluster::bootstrap`.
+    pub bootstrap_expect: usize,
+    /// Let the leader add alive nodes of the group as voters, and remove the ones down or left.
+    pub auto_membership: bool,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            group: String::from("raft"),
+            election_timeout: Duration::from_secs(1),
+            heartbeat_interval: Duration::from_millis(250),
+            max_entries_per_message: 16,
+            max_append_size: 1 << 14,
+            max_command_size: 1 << 12,
+            snapshot_chunk_size: 1 << 12,
+            snapshot_threshold: 1 << 10,
+            bootstrap_expect: 3,
+            auto_membership: true,
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use crate::errors::*;
+
+use serde::*;
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+pub type Term = u64;
+pub type LogIndex = u64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+///
+/// Voters of the group. While `joint` is set the group runs under joint consensus,
+/// and every decision needs a majority of both `voters` and `joint`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RaftMembership {
+    #[serde(rename = "v")]
+    pub voters: BTreeSet<Uuid>,
+    #[serde(rename = "j")]
+    pub joint: Option<BTreeSet<Uuid>>,
+}
+
+impl RaftMembership {
+    pub fn new(voters: BTreeSet<Uuid>) -> Self {
+        Self {
+            voters,
+            joint: None,
+        }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        self.joint.is_some()
+    }
+
+    /// Every node taking part in one of the configurations.
+    pub fn members(&self) -> BTreeSet<Uuid> {
+        self.voters
+            .iter()
+            .chain(self.joint.iter().flatten())
+            .copied()
+            .collect()
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.voters.contains(id) || self.joint.iter().any(|j| j.contains(id))
+    }
+
+    /// Whether the nodes form a majority of every configuration in effect.
+    pub fn has_quorum(&self, nodes: &BTreeSet<Uuid>) -> bool {
+        fn majority(voters: &BTreeSet<Uuid>, nodes: &BTreeSet<Uuid>) -> bool {
+            !voters.is_empty() && voters.intersection(nodes).count() * 2 > voters.len()
+        }
+
+        majority(&self.voters, nodes) && self.joint.iter().all(|j| majority(j, nodes))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum EntryPayload {
+    /// Appended by every new leader to commit the entries of its predecessors.
+    Noop,
+    Command(Vec<u8>),
+    Membership(RaftMembership),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    #[serde(rename = "t")]
+    pub term: Term,
+    #[serde(rename = "i")]
+    pub index: LogIndex,
+    #[serde(rename = "p")]
+    pub payload: EntryPayload,
+}
+
+///
+/// State machine contents covering the log up to `last_index`, inclusive.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub last_index: LogIndex,
+    pub last_term: Term,
+    /// Membership committed at `last_index`.
+    pub membership: RaftMembership,
+    pub data: Vec<u8>,
+}
+
+/// State which has to survive restarts before any message is answered.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HardState {
+    pub term: Term,
+    pub voted_for: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RaftMessage {
+    #[serde(rename = "t")]
+    pub term: Term,
+    #[serde(rename = "b")]
+    pub body: RaftRpc,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RaftRpc {
+    RequestVote {
+        last_log_index: LogIndex,
+        last_log_term: Term,
+    },
+    Vote {
+        granted: bool,
+    },
+    AppendEntries {
+        prev_log_index: LogIndex,
+        prev_log_term: Term,
+        entries: Vec<LogEntry>,
+        leader_commit: LogIndex,
+    },
+    /// On success `last_index` is the last entry known to match the leader,
+    /// otherwise the index the leader should retry after.
+    AppendResponse {
+        success: bool,
+        last_index: LogIndex,
+    },
+    /// A chunk of the leader's snapshot, starting at `offset` of its data.
+    InstallSnapshot {
+        last_index: LogIndex,
+        last_term: Term,
+        membership: RaftMembership,
+        offset: usize,
+        data: Vec<u8>,
+        done: bool,
+    },
+    /// `offset` is where the next chunk is expected to start.
+    SnapshotResponse {
+        last_index: LogIndex,
+        offset: usize,
+        done: bool,
+    },
+}
+
+///
+/// Deterministic state machine replicated by the group.
+/// Every node applies the same commands in the same order.
+pub trait StateMachine: Send {
+    /// Apply a committed command and return the response handed to its proposer.
+    fn apply(&mut self, index: LogIndex, command: &[u8]) -> Vec<u8>;
+
+    /// Serialize the whole state, to compact the log.
+    fn snapshot(&self) -> Result<Vec<u8>>;
+
+    /// Replace the whole state with a snapshot taken by `snapshot`.
+    fn restore(&mut self, data: &[u8]) -> Result<()>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_joint_quorum_needs_both_majorities() {
+        let ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        let old: BTreeSet<_> = ids[0..3].iter().copied().collect();
+        let new: BTreeSet<_> = ids[2..5].iter().copied().collect();
+        let membership = RaftMembership {
+            voters: old,
+            joint: Some(new),
+        };
+
+        let old_majority: BTreeSet<_> = ids[0..2].iter().copied().collect();
+        assert!(!membership.has_quorum(&old_majority));
+
+        let both: BTreeSet<_> = ids[1..4].iter().copied().collect();
+        assert!(membership.has_quorum(&both));
+        assert_eq!(membership.members().len(), 5);
+        assert!(!RaftMembership::default().has_quorum(&both));
+    }
+}
@@ -0,0 +1,214 @@
+use super::state::*;
+use crate::errors::*;
+
+use std::convert::TryFrom;
+
+///
+/// Durable storage of the Raft log, the latest snapshot and the hard state.
+///
+/// Indexes are contiguous: the log holds every entry after the snapshot's last index.
+pub trait RaftStorage: Send {
+    fn hard_state(&self) -> Result<HardState>;
+
+    fn set_hard_state(&mut self, state: HardState) -> Result<()>;
+
+    /// Index of the first entry kept in the log.
+    fn first_index(&self) -> LogIndex;
+
+    /// Index of the last entry, or the snapshot's last index if the log is empty.
+    fn last_index(&self) -> LogIndex;
+
+    /// Term of the entry at the index, the snapshot's last index included.
+    /// `None` if the entry was compacted or doesn't exist yet.
+    fn term(&self, index: LogIndex) -> Result<Option<Term>>;
+
+    /// Entries in `[from, to)`.
+    fn entries(&self, from: LogIndex, to: LogIndex) -> Result<Vec<LogEntry>>;
+
+    /// Append entries following the last one.
+    fn append(&mut self, entries: &[LogEntry]) -> Result<()>;
+
+    /// Drop every entry from the index on.
+    fn truncate(&mut self, from: LogIndex) -> Result<()>;
+
+    fn snapshot(&self) -> Result<Option<Snapshot>>;
+
+    /// Keep the snapshot and drop the entries it covers.
+    /// The rest of the log survives only if it continues the snapshot.
+    fn save_snapshot(&mut self, snapshot: Snapshot) -> Result<()>;
+}
+
+///
+/// Storage which keeps everything in memory, losing it with the process.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    hard_state: HardState,
+    snapshot: Option<Snapshot>,
+    entries: Vec<LogEntry>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn offset(&self) -> LogIndex {
+        self.snapshot.as_ref().map_or(0, |s| s.last_index)
+    }
+
+    fn position(&self, index: LogIndex) -> Option<usize> {
+        let offset = self.offset();
+
+        if index <= offset || index > self.last_index() {
+            return None;
+        }
+
+        usize::try_from(index - offset - 1).ok()
+    }
+}
+
+impl RaftStorage for MemoryStorage {
+    fn hard_state(&self) -> Result<HardState> {
+        Ok(self.hard_state)
+    }
+
+    fn set_hard_state(&mut self, state: HardState) -> Result<()> {
+        self.hard_state = state;
+        Ok(())
+    }
+
+    fn first_index(&self) -> LogIndex {
+        self.offset() + 1
+    }
+
+    fn last_index(&self) -> LogIndex {
+        self.entries.last().map_or(self.offset(), |e| e.index)
+    }
+
+    fn term(&self, index: LogIndex) -> Result<Option<Term>> {
+        match self.snapshot.as_ref() {
+            Some(snapshot) if snapshot.last_index == index => Ok(Some(snapshot.last_term)),
+            None if index == 0 => Ok(Some(0)),
+            Some(_) | None => Ok(self.position(index).map(|p| self.entries[p].term)),
+        }
+    }
+
+    fn entries(&self, from: LogIndex, to: LogIndex) -> Result<Vec<LogEntry>> {
+        if from >= to {
+            return Ok(Vec::new());
+        }
+
+        match (self.position(from), self.position(to - 1)) {
+            (Some(start), Some(end)) => Ok(self.entries[start..=end].to_vec()),
+            (Some(_), None) | (None, Some(_)) | (None, None) => bail!(
+                ArtilleryError::Consensus,
+                "Entries [{}, {}) are not in the log [{}, {}]",
+                from,
+                to,
+                self.first_index(),
+                self.last_index()
+            ),
+        }
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> Result<()> {
+        for entry in entries {
+            if entry.index != self.last_index() + 1 {
+                bail!(
+                    ArtilleryError::Consensus,
+                    "Entry {} doesn't follow the last entry {}",
+                    entry.index,
+                    self.last_index()
+                );
+            }
+            self.entries.push(entry.clone());
+        }
+
+        Ok(())
+    }
+
+    fn truncate(&mut self, from: LogIndex) -> Result<()> {
+        if from <= self.offset() {
+            bail!(
+                ArtilleryError::Consensus,
+                "Entry {} is already compacted",
+                from
+            );
+        }
+
+        if let Some(position) = self.position(from) {
+            self.entries.truncate(position);
+        }
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Option<Snapshot>> {
+        Ok(self.snapshot.clone())
+    }
+
+    fn save_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        let continues = self.term(snapshot.last_index)? == Some(snapshot.last_term);
+        let kept = match self.position(snapshot.last_index) {
+            Some(position) if continues => self.entries.split_off(position + 1),
+            Some(_) | None => Vec::new(),
+        };
+
+        self.entries = kept;
+        self.snapshot = Some(snapshot);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(term: Term, indexes: std::ops::RangeInclusive<LogIndex>) -> Vec<LogEntry> {
+        indexes
+            .map(|index| LogEntry {
+                term,
+                index,
+                payload: EntryPayload::Noop,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_memory_storage_compacts_and_truncates() {
+        let mut storage = MemoryStorage::new();
+        storage.append(&entries(1, 1..=5)).unwrap();
+        assert!(storage.append(&entries(1, 7..=7)).is_err());
+
+        storage.truncate(4).unwrap();
+        storage.append(&entries(2, 4..=6)).unwrap();
+        assert_eq!(storage.term(3).unwrap(), Some(1));
+        assert_eq!(storage.term(4).unwrap(), Some(2));
+
+        storage
+            .save_snapshot(Snapshot {
+                last_index: 4,
+                last_term: 2,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(storage.first_index(), 5);
+        assert_eq!(storage.last_index(), 6);
+        assert_eq!(storage.term(4).unwrap(), Some(2));
+        assert_eq!(storage.term(3).unwrap(), None);
+        assert_eq!(storage.entries(5, 7).unwrap().len(), 2);
+        assert!(storage.entries(3, 7).is_err());
+
+        // A snapshot from another history replaces the whole log.
+        storage
+            .save_snapshot(Snapshot {
+                last_index: 5,
+                last_term: 3,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(storage.last_index(), 5);
+        assert_eq!(storage.first_index(), 6);
+    }
+}
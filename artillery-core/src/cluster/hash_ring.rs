@@ -0,0 +1,365 @@
+use crate::epidemic::prelude::*;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::RwLock;
+use uuid::Uuid;
+
+/// Position of a key or a virtual node on the ring.
+pub type RingToken = u64;
+
+#[derive(Debug, Clone)]
+pub struct HashRingConfig {
+    /// Amount of virtual nodes per member with weight `1`.
+    pub virtual_nodes: usize,
+    /// Metadata key holding the integral weight of a member. Members without it weigh `1`.
+    pub weight_key: Option<String>,
+    /// Upper bound of member weights, heavier members count as this weight.
+    pub max_weight: usize,
+}
+
+impl Default for HashRingConfig {
+    fn default() -> Self {
+        HashRingConfig {
+            virtual_nodes: 64,
+            weight_key: None,
+            max_weight: 16,
+        }
+    }
+}
+
+///
+/// Keys in `(start, end]` changed owner, wrapping around the ring when `start >= end`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRangeMove {
+    pub start: RingToken,
+    pub end: RingToken,
+    pub from: Option<Uuid>,
+    pub to: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RingRebalanced {
+    pub moved: Vec<KeyRangeMove>,
+}
+
+///
+/// Consistent hash ring with virtual nodes.
+#[derive(Debug, Clone, Default)]
+pub struct HashRing {
+    config: HashRingConfig,
+    tokens: BTreeMap<RingToken, Uuid>,
+    members: HashMap<Uuid, ArtilleryMember>,
+}
+
+impl HashRing {
+    pub fn new(config: HashRingConfig) -> Self {
+        HashRing {
+            config,
+            tokens: BTreeMap::new(),
+            members: HashMap::new(),
+        }
+    }
+
+    pub fn token_of<K: AsRef<[u8]>>(key: K) -> RingToken {
+        fnv1a(key.as_ref())
+    }
+
+    pub fn members(&self) -> Vec<ArtilleryMember> {
+        self.members.values().cloned().collect()
+    }
+
+    pub fn owner<K: AsRef<[u8]>>(&self, key: K) -> Option<ArtilleryMember> {
+        self.owner_of_token(Self::token_of(key))
+            .and_then(|id| self.members.get(&id))
+            .cloned()
+    }
+
+    /// First `n` distinct members found walking the ring clockwise from the key.
+    pub fn preference_list<K: AsRef<[u8]>>(&self, key: K, n: usize) -> Vec<ArtilleryMember> {
+        let token = Self::token_of(key);
+        let mut preferred: Vec<ArtilleryMember> = Vec::with_capacity(n);
+
+        let clockwise = self.tokens.range(token..).chain(self.tokens.range(..token));
+
+        for (_, id) in clockwise {
+            if preferred.len() >= n || preferred.len() == self.members.len() {
+                break;
+            }
+
+            if !preferred.iter().any(|m| m.host_key() == *id) {
+                if let Some(member) = self.members.get(id) {
+                    preferred.push(member.clone());
+                }
+            }
+        }
+
+        preferred
+    }
+
+    /// Rebuild the ring from a membership view. Alive and suspected members own keys.
+    pub fn update(&mut self, members: &[ArtilleryMember]) -> Option<RingRebalanced> {
+        let previous = self.tokens.clone();
+
+        self.members = members
+            .iter()
+            .filter(|m| {
+                m.state() == ArtilleryMemberState::Alive
+                    || m.state() == ArtilleryMemberState::Suspect
+            })
+            .map(|m| (m.host_key(), m.clone()))
+            .collect();
+
+        self.tokens.clear();
+        for member in self.members.values() {
+            for vnode in 0..self.virtual_nodes_of(member) {
+                let token = fnv1a(format!("{}-{}", member.host_key(), vnode).as_bytes());
+                self.tokens.insert(token, member.host_key());
+            }
+        }
+
+        let moved = moved_ranges(&previous, &self.tokens);
+
+        if moved.is_empty() {
+            None
+        } else {
+            Some(RingRebalanced { moved })
+        }
+    }
+
+    fn virtual_nodes_of(&self, member: &ArtilleryMember) -> usize {
+        let weight = self
+            .config
+            .weight_key
+            .as_ref()
+            .and_then(|key| member.metadata().get(key))
+            .and_then(|weight| weight.parse::<usize>().ok())
+            .unwrap_or(1)
+            .clamp(1, self.config.max_weight.max(1));
+
+        self.config.virtual_nodes.saturating_mul(weight)
+    }
+
+    fn owner_of_token(&self, token: RingToken) -> Option<Uuid> {
+        owner_in(&self.tokens, token)
+    }
+}
+
+fn owner_in(tokens: &BTreeMap<RingToken, Uuid>, token: RingToken) -> Option<Uuid> {
+    tokens
+        .range(token..)
+        .next()
+        .or_else(|| tokens.iter().next())
+        .map(|(_, id)| *id)
+}
+
+fn moved_ranges(
+    previous: &BTreeMap<RingToken, Uuid>,
+    current: &BTreeMap<RingToken, Uuid>,
+) -> Vec<KeyRangeMove> {
+    let mut boundaries: Vec<_> = previous.keys().chain(current.keys()).cloned().collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut moved: Vec<KeyRangeMove> = Vec::new();
+    let mut start = match boundaries.last() {
+        Some(last) => *last,
+        None => return moved,
+    };
+
+    for end in boundaries {
+        let from = owner_in(previous, end);
+        let to = owner_in(current, end);
+
+        if from != to {
+            match moved.last_mut() {
+                Some(last) if last.end == start && last.from == from && last.to == to => {
+                    last.end = end
+                }
+                _ => moved.push(KeyRangeMove {
+                    start,
+                    end,
+                    from,
+                    to,
+                }),
+            }
+        }
+
+        start = end;
+    }
+
+    moved
+}
+
+/// 64-bit FNV-1a, stable across processes and platforms.
+fn fnv1a(bytes: &[u8]) -> RingToken {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+///
+/// Hash ring kept up to date with the membership events of a cluster.
+pub struct ClusterHashRing {
+    pub events: Receiver<RingRebalanced>,
+    membership: Receiver<ArtilleryClusterEvent>,
+    event_tx: Sender<RingRebalanced>,
+    ring: RwLock<HashRing>,
+}
+
+unsafe impl Send for ClusterHashRing {}
+unsafe impl Sync for ClusterHashRing {}
+
+impl ClusterHashRing {
+    pub fn new(cluster: &Cluster, config: HashRingConfig) -> Self {
+        let (event_tx, events) = channel();
+
+        Self {
+            events,
+            membership: cluster.subscribe(),
+            event_tx,
+            ring: RwLock::new(HashRing::new(config)),
+        }
+    }
+
+    pub fn owner<K: AsRef<[u8]>>(&self, key: K) -> Option<ArtilleryMember> {
+        self.ring.read().expect("Ring lock is poisoned").owner(key)
+    }
+
+    pub fn preference_list<K: AsRef<[u8]>>(&self, key: K, n: usize) -> Vec<ArtilleryMember> {
+        self.ring
+            .read()
+            .expect("Ring lock is poisoned")
+            .preference_list(key, n)
+    }
+
+    pub async fn launch(&self) {
+        for (members, _) in &self.membership {
+            let update = self
+                .ring
+                .write()
+                .expect("Ring lock is poisoned")
+                .update(&members);
+
+            if let Some(rebalanced) = update {
+                debug!("Ring rebalanced: {} ranges moved", rebalanced.moved.len());
+                let _ = self.event_tx.send(rebalanced);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn member(port: u16) -> ArtilleryMember {
+        ArtilleryMember::new(
+            Uuid::new_v4(),
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+            0,
+            ArtilleryMemberState::Alive,
+        )
+    }
+
+    #[test]
+    fn test_owner_and_preference_list() {
+        let members: Vec<_> = (1..=4).map(member).collect();
+        let mut ring = HashRing::new(HashRingConfig::default());
+        assert!(ring.owner("shard-1").is_none());
+
+        ring.update(&members).unwrap();
+
+        let owner = ring.owner("shard-1").unwrap();
+        let preferred = ring.preference_list("shard-1", 3);
+        assert_eq!(preferred.len(), 3);
+        assert_eq!(preferred[0], owner);
+        assert!(preferred[1..].iter().all(|m| *m != owner));
+        assert_eq!(ring.preference_list("shard-1", 10).len(), 4);
+    }
+
+    #[test]
+    fn test_join_only_moves_ranges_to_new_member() {
+        let mut members: Vec<_> = (1..=3).map(member).collect();
+        let mut ring = HashRing::new(HashRingConfig::default());
+        ring.update(&members).unwrap();
+
+        let keys: Vec<_> = (0..1000).map(|k| format!("key-{}", k)).collect();
+        let before: Vec<_> = keys.iter().map(|k| ring.owner(k).unwrap()).collect();
+
+        let joined = member(4);
+        members.push(joined.clone());
+        let rebalanced = ring.update(&members).unwrap();
+        assert!(rebalanced
+            .moved
+            .iter()
+            .all(|m| m.to == Some(joined.host_key())));
+
+        for (key, owner) in keys.iter().zip(before.iter()) {
+            let now = ring.owner(key).unwrap();
+            assert!(now == *owner || now == joined);
+        }
+
+        assert!(ring.update(&members).is_none());
+    }
+
+    #[test]
+    fn test_weighted_members_own_more_tokens() {
+        let config = HashRingConfig {
+            weight_key: Some("weight".to_string()),
+            ..Default::default()
+        };
+        let heavy = member(1).with_metadata(
+            vec![("weight".to_string(), "3".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        let light = member(2);
+
+        let mut ring = HashRing::new(config);
+        ring.update(&[heavy.clone(), light]).unwrap();
+
+        let owned = ring
+            .tokens
+            .values()
+            .filter(|id| **id == heavy.host_key())
+            .count();
+        assert_eq!(owned, 3 * 64);
+    }
+
+    #[test]
+    fn test_weights_are_bounded() {
+        let config = HashRingConfig {
+            weight_key: Some("weight".to_string()),
+            max_weight: 4,
+            ..Default::default()
+        };
+        let weighing = |weight: &str| {
+            member(1).with_metadata(
+                vec![("weight".to_string(), weight.to_string())]
+                    .into_iter()
+                    .collect(),
+            )
+        };
+        let owned = |ring: &HashRing, member: &ArtilleryMember| {
+            ring.tokens
+                .values()
+                .filter(|id| **id == member.host_key())
+                .count()
+        };
+
+        let mut ring = HashRing::new(config);
+        for (weight, tokens) in [
+            ("1000000000", 4 * 64),
+            ("18446744073709551615", 4 * 64),
+            ("0", 64),
+        ] {
+            let hostile = weighing(weight);
+            ring.update(&[hostile.clone(), member(2)]).unwrap();
+            assert_eq!(owned(&ring, &hostile), tokens);
+        }
+    }
+}
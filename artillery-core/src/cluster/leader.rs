@@ -0,0 +1,225 @@
+use crate::epidemic::prelude::*;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeaderElectionRule {
+    /// Member which is up for the longest time leads, ties are broken by the lowest id.
+    #[default]
+    OldestMember,
+    /// Member with the lowest id leads.
+    LowestId,
+}
+
+impl LeaderElectionRule {
+    /// Elect a leader among the alive members of the given view.
+    pub fn elect(self, members: &[ArtilleryMember]) -> Option<ArtilleryMember> {
+        let alive = members
+            .iter()
+            .filter(|m| m.state() == ArtilleryMemberState::Alive);
+
+        match self {
+            LeaderElectionRule::OldestMember => alive.min_by_key(|m| (m.up_since(), m.host_key())),
+            LeaderElectionRule::LowestId => alive.min_by_key(|m| m.host_key()),
+        }
+        .cloned()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaderChanged {
+    pub previous: Option<ArtilleryMember>,
+    pub leader: Option<ArtilleryMember>,
+}
+
+///
+/// Applies the election rule to membership views and only settles on a leader
+/// once the same candidate won for the whole stability delay.
+#[derive(Debug)]
+pub struct LeaderElectionState {
+    rule: LeaderElectionRule,
+    stability_delay: Duration,
+    candidate: Option<ArtilleryMember>,
+    candidate_since: Instant,
+    leader: Option<ArtilleryMember>,
+}
+
+impl LeaderElectionState {
+    pub fn new(rule: LeaderElectionRule, stability_delay: Duration) -> Self {
+        LeaderElectionState {
+            rule,
+            stability_delay,
+            candidate: None,
+            candidate_since: Instant::now(),
+            leader: None,
+        }
+    }
+
+    pub fn observe(&mut self, members: &[ArtilleryMember]) {
+        let candidate = self.rule.elect(members);
+
+        if candidate.as_ref().map(ArtilleryMember::host_key)
+            != self.candidate.as_ref().map(ArtilleryMember::host_key)
+        {
+            self.candidate_since = Instant::now();
+        }
+
+        self.candidate = candidate;
+    }
+
+    pub fn leader(&self) -> Option<&ArtilleryMember> {
+        self.leader.as_ref()
+    }
+
+    pub fn poll(&mut self) -> Option<LeaderChanged> {
+        let unchanged = self.candidate.as_ref().map(ArtilleryMember::host_key)
+            == self.leader.as_ref().map(ArtilleryMember::host_key);
+
+        if unchanged || self.candidate_since.elapsed() < self.stability_delay {
+            return None;
+        }
+
+        let previous = std::mem::replace(&mut self.leader, self.candidate.clone());
+
+        Some(LeaderChanged {
+            previous,
+            leader: self.leader.clone(),
+        })
+    }
+}
+
+///
+/// Leader election over the membership view of a cluster.
+pub struct LeaderElection {
+    pub events: Receiver<LeaderChanged>,
+    host_key: Uuid,
+    membership: Receiver<ArtilleryClusterEvent>,
+    event_tx: Sender<LeaderChanged>,
+    rule: LeaderElectionRule,
+    stability_delay: Duration,
+    leader: RwLock<Option<Uuid>>,
+    is_leader: AtomicBool,
+}
+
+unsafe impl Send for LeaderElection {}
+unsafe impl Sync for LeaderElection {}
+
+impl LeaderElection {
+    pub fn new(cluster: &Cluster, rule: LeaderElectionRule, stability_delay: Duration) -> Self {
+        let (event_tx, events) = channel();
+
+        Self {
+            events,
+            host_key: cluster.host_key(),
+            membership: cluster.subscribe(),
+            event_tx,
+            rule,
+            stability_delay,
+            leader: RwLock::new(None),
+            is_leader: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the current node is the settled leader.
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::SeqCst)
+    }
+
+    /// Id of the settled leader, if any.
+    pub fn leader(&self) -> Option<Uuid> {
+        *self.leader.read().expect("Leader lock is poisoned")
+    }
+
+    pub async fn launch(&self) {
+        let mut state = LeaderElectionState::new(self.rule, self.stability_delay);
+        let tick = self.stability_delay / 4;
+
+        loop {
+            match self.membership.recv_timeout(tick) {
+                Ok((members, _)) => state.observe(&members),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if let Some(changed) = state.poll() {
+                let leader = changed.leader.as_ref().map(ArtilleryMember::host_key);
+                info!("Leader changed to {:?}", leader);
+
+                *self.leader.write().expect("Leader lock is poisoned") = leader;
+                self.is_leader
+                    .store(leader == Some(self.host_key), Ordering::SeqCst);
+
+                let _ = self.event_tx.send(changed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{Duration as ChronoDuration, Utc};
+
+    fn member(port: u16, state: ArtilleryMemberState, age_secs: i64) -> ArtilleryMember {
+        ArtilleryMember::new(
+            Uuid::new_v4(),
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+            0,
+            state,
+        )
+        .with_up_since(Utc::now() - ChronoDuration::seconds(age_secs))
+    }
+
+    #[test]
+    fn test_election_rules() {
+        let members = vec![
+            member(1, ArtilleryMemberState::Alive, 10),
+            member(2, ArtilleryMemberState::Alive, 20),
+            member(3, ArtilleryMemberState::Down, 30),
+        ];
+
+        let oldest = LeaderElectionRule::OldestMember.elect(&members).unwrap();
+        assert_eq!(oldest, members[1]);
+
+        let lowest = LeaderElectionRule::LowestId.elect(&members).unwrap();
+        assert_eq!(
+            lowest.host_key(),
+            members[..2]
+                .iter()
+                .map(ArtilleryMember::host_key)
+                .min()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_leader_settles_after_stability_delay() {
+        let mut members = vec![
+            member(1, ArtilleryMemberState::Alive, 10),
+            member(2, ArtilleryMemberState::Alive, 20),
+        ];
+
+        let mut state =
+            LeaderElectionState::new(LeaderElectionRule::OldestMember, Duration::from_millis(50));
+        state.observe(&members);
+        assert!(state.poll().is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+        let elected = state.poll().unwrap();
+        assert_eq!(elected.previous, None);
+        assert_eq!(elected.leader.as_ref(), Some(&members[1]));
+        assert!(state.poll().is_none());
+
+        members[1].set_state(ArtilleryMemberState::Down);
+        state.observe(&members);
+        assert!(state.poll().is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+        let changed = state.poll().unwrap();
+        assert_eq!(changed.leader.as_ref(), Some(&members[0]));
+    }
+}
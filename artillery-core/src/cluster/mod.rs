@@ -1 +1,7 @@
 pub mod ap;
+pub mod cp;
+pub mod hash_ring;
+pub mod leader;
+pub mod pubsub;
+pub mod singleton;
+pub mod split_brain;
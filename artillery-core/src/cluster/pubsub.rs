@@ -0,0 +1,344 @@
+use crate::epidemic::prelude::*;
+use crate::errors::*;
+
+use serde::*;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use uuid::Uuid;
+
+/// Metadata key prefix announcing the topics a member is subscribed to.
+pub const TOPIC_METADATA_PREFIX: &str = "artillery.topic.";
+
+/// Amount of recently delivered message ids remembered to drop duplicates.
+const SEEN_MESSAGES_CAPACITY: usize = 1 << 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicMessage {
+    pub topic: String,
+    pub publisher: Uuid,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicEvent {
+    Subscribed(String, ArtilleryMember),
+    Unsubscribed(String, ArtilleryMember),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TopicEnvelope {
+    #[serde(rename = "i")]
+    id: Uuid,
+    #[serde(rename = "t")]
+    topic: String,
+    #[serde(rename = "d")]
+    data: Vec<u8>,
+}
+
+type TopicSubscribers = BTreeMap<String, BTreeMap<Uuid, ArtilleryMember>>;
+
+fn topic_key(topic: &str) -> String {
+    format!("{}{}", TOPIC_METADATA_PREFIX, topic)
+}
+
+/// Topics every alive member of the view is subscribed to, according to its metadata.
+pub fn topic_subscribers(members: &[ArtilleryMember]) -> TopicSubscribers {
+    let mut subscribers = TopicSubscribers::new();
+
+    for member in members
+        .iter()
+        .filter(|m| m.state() == ArtilleryMemberState::Alive)
+    {
+        for key in member.metadata().keys() {
+            if let Some(topic) = key.strip_prefix(TOPIC_METADATA_PREFIX) {
+                subscribers
+                    .entry(topic.to_string())
+                    .or_default()
+                    .insert(member.host_key(), member.clone());
+            }
+        }
+    }
+
+    subscribers
+}
+
+fn subscription_changes(
+    previous: &TopicSubscribers,
+    current: &TopicSubscribers,
+) -> Vec<TopicEvent> {
+    let topics: BTreeSet<_> = previous.keys().chain(current.keys()).collect();
+    let empty = BTreeMap::new();
+    let mut changes = Vec::new();
+
+    for topic in topics {
+        let before = previous.get(topic).unwrap_or(&empty);
+        let after = current.get(topic).unwrap_or(&empty);
+
+        changes.extend(
+            after
+                .iter()
+                .filter(|(id, _)| !before.contains_key(id))
+                .map(|(_, m)| TopicEvent::Subscribed(topic.clone(), m.clone())),
+        );
+        changes.extend(
+            before
+                .iter()
+                .filter(|(id, _)| !after.contains_key(id))
+                .map(|(_, m)| TopicEvent::Unsubscribed(topic.clone(), m.clone())),
+        );
+    }
+
+    changes
+}
+
+struct SeenMessages {
+    ids: HashSet<Uuid>,
+    order: VecDeque<Uuid>,
+}
+
+impl SeenMessages {
+    /// Returns `false` if the message was already seen.
+    fn insert(&mut self, id: Uuid) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+
+        self.order.push_back(id);
+        if self.order.len() > SEEN_MESSAGES_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+///
+/// Named topics on top of the epidemic cluster.
+///
+/// Subscriptions are gossiped as member metadata, messages are sent with at-most-once
+/// semantics through cluster payloads.
+pub struct DistributedPubSub {
+    pub events: Receiver<TopicEvent>,
+    cluster: Arc<Cluster>,
+    membership: Receiver<ArtilleryClusterEvent>,
+    event_tx: Sender<TopicEvent>,
+    subscribers: RwLock<TopicSubscribers>,
+    local: Mutex<BTreeMap<String, Vec<Sender<TopicMessage>>>>,
+    seen: Mutex<SeenMessages>,
+}
+
+unsafe impl Send for DistributedPubSub {}
+unsafe impl Sync for DistributedPubSub {}
+
+impl DistributedPubSub {
+    pub fn new(cluster: Arc<Cluster>) -> Self {
+        let (event_tx, events) = channel();
+
+        Self {
+            events,
+            membership: cluster.subscribe(),
+            cluster,
+            event_tx,
+            subscribers: RwLock::new(TopicSubscribers::new()),
+            local: Mutex::new(BTreeMap::new()),
+            seen: Mutex::new(SeenMessages {
+                ids: HashSet::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Subscribe the current node to the topic.
+    /// Fails when the topic doesn't fit the metadata of the current member anymore.
+    pub fn subscribe<T: AsRef<str>>(&self, topic: T) -> Result<Receiver<TopicMessage>> {
+        self.cluster.set_metadata(topic_key(topic.as_ref()), "1")?;
+        let (tx, rx) = channel();
+
+        self.local
+            .lock()
+            .expect("Local subscriptions are poisoned")
+            .entry(topic.as_ref().to_string())
+            .or_default()
+            .push(tx);
+
+        Ok(rx)
+    }
+
+    /// Drop every local subscription of the topic.
+    pub fn unsubscribe<T: AsRef<str>>(&self, topic: T) {
+        self.local
+            .lock()
+            .expect("Local subscriptions are poisoned")
+            .remove(topic.as_ref());
+        self.cluster.remove_metadata(topic_key(topic.as_ref()));
+    }
+
+    /// Members currently subscribed to the topic.
+    pub fn subscribers<T: AsRef<str>>(&self, topic: T) -> Vec<ArtilleryMember> {
+        self.subscribers
+            .read()
+            .expect("Topic subscribers are poisoned")
+            .get(topic.as_ref())
+            .map(|s| s.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Send the data to every current subscriber of the topic, at most once.
+    /// Returns the amount of subscribers the data was sent to.
+    pub fn publish<T: AsRef<str>>(&self, topic: T, data: &[u8]) -> Result<usize> {
+        let envelope = TopicEnvelope {
+            id: Uuid::new_v4(),
+            topic: topic.as_ref().to_string(),
+            data: data.to_vec(),
+        };
+        let encoded = serde_json::to_string(&envelope)?;
+        let subscribers = self.subscribers(topic);
+
+        for subscriber in &subscribers {
+            if subscriber.is_current() {
+                self.deliver(self.cluster.host_key(), envelope.clone());
+            } else {
+                self.cluster.send_payload(subscriber.host_key(), &encoded);
+            }
+        }
+
+        Ok(subscribers.len())
+    }
+
+    pub async fn launch(&self) {
+        for (members, event) in &self.membership {
+            let current = topic_subscribers(&members);
+            let previous = std::mem::replace(
+                &mut *self
+                    .subscribers
+                    .write()
+                    .expect("Topic subscribers are poisoned"),
+                current.clone(),
+            );
+
+            for change in subscription_changes(&previous, &current) {
+                let _ = self.event_tx.send(change);
+            }
+
+            if let ArtilleryMemberEvent::Payload(publisher, payload) = event {
+                if let Ok(envelope) = serde_json::from_str::<TopicEnvelope>(&payload) {
+                    self.deliver(publisher.host_key(), envelope);
+                }
+            }
+        }
+    }
+
+    fn deliver(&self, publisher: Uuid, envelope: TopicEnvelope) {
+        if !self
+            .seen
+            .lock()
+            .expect("Seen messages are poisoned")
+            .insert(envelope.id)
+        {
+            return;
+        }
+
+        let mut local = self.local.lock().expect("Local subscriptions are poisoned");
+
+        if let Some(receivers) = local.get_mut(&envelope.topic) {
+            let message = TopicMessage {
+                topic: envelope.topic,
+                publisher,
+                data: envelope.data,
+            };
+
+            receivers.retain(|tx| tx.send(message.clone()).is_ok());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn member(port: u16, topics: &[&str]) -> ArtilleryMember {
+        ArtilleryMember::new(
+            Uuid::new_v4(),
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+            0,
+            ArtilleryMemberState::Alive,
+        )
+        .with_metadata(
+            topics
+                .iter()
+                .map(|t| (topic_key(t), "1".to_string()))
+                .chain(Some(("version".to_string(), "1".to_string())))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_subscription_changes() {
+        let mut members = vec![member(1, &["jobs"]), member(2, &["jobs", "logs"])];
+        let before = topic_subscribers(&members);
+        assert_eq!(before["jobs"].len(), 2);
+        assert_eq!(before["logs"].len(), 1);
+        assert!(!before.contains_key("version"));
+
+        members[1].set_state(ArtilleryMemberState::Down);
+        let after = topic_subscribers(&members);
+
+        let changes = subscription_changes(&before, &after);
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(
+            |c| matches!(c, TopicEvent::Unsubscribed(_, m) if m.host_key() == members[1].host_key())
+        ));
+    }
+
+    fn node() -> Arc<DistributedPubSub> {
+        let config = ClusterConfig {
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            ping_interval: chrono::Duration::milliseconds(100),
+            ..Default::default()
+        };
+        let (cluster, _) = Cluster::new_cluster(Uuid::new_v4(), config).unwrap();
+        let pubsub = Arc::new(DistributedPubSub::new(Arc::new(cluster)));
+
+        let launched = pubsub.clone();
+        thread::spawn(move || futures::executor::block_on(launched.launch()));
+
+        pubsub
+    }
+
+    #[test]
+    fn test_messages_carry_their_publisher() {
+        let (publisher, subscriber) = (node(), node());
+        subscriber
+            .cluster
+            .join(&[publisher.cluster.listen_addr()], Duration::from_secs(5))
+            .unwrap();
+
+        let remote = subscriber.subscribe("jobs").unwrap();
+        let local = publisher.subscribe("jobs").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while publisher.subscribers("jobs").len() < 2 {
+            assert!(Instant::now() < deadline, "Subscriptions weren't gossiped");
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        assert_eq!(publisher.publish("jobs", b"build").unwrap(), 2);
+        let expected = TopicMessage {
+            topic: String::from("jobs"),
+            publisher: publisher.cluster.host_key(),
+            data: b"build".to_vec(),
+        };
+        assert_eq!(
+            remote.recv_timeout(Duration::from_secs(5)).unwrap(),
+            expected
+        );
+        assert_eq!(
+            local.recv_timeout(Duration::from_secs(5)).unwrap(),
+            expected
+        );
+    }
+}
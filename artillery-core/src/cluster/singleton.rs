@@ -0,0 +1,419 @@
+use crate::cluster::leader::{LeaderElectionRule, LeaderElectionState};
+use crate::epidemic::prelude::*;
+use crate::errors::*;
+
+use bastion_executor::prelude::*;
+use futures::future::{abortable, AbortHandle};
+use lightproc::proc_stack::ProcStack;
+use serde::*;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Messages delivered to a running singleton, together with the id of the sending node.
+pub type SingletonInbox = Receiver<(Uuid, String)>;
+
+/// Creates the singleton future every time the current node takes the singleton over.
+pub type SingletonFactory =
+    Box<dyn Fn(SingletonInbox) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct ClusterSingletonConfig {
+    /// Name of the singleton, unique within the cluster.
+    pub name: String,
+    /// Rule electing the node that runs the singleton.
+    pub rule: LeaderElectionRule,
+    /// How long the elected node has to stay the same before the singleton moves.
+    pub stability_delay: Duration,
+}
+
+impl Default for ClusterSingletonConfig {
+    fn default() -> Self {
+        ClusterSingletonConfig {
+            name: String::from("singleton"),
+            rule: LeaderElectionRule::OldestMember,
+            stability_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SingletonEnvelope {
+    #[serde(rename = "s")]
+    singleton: String,
+    /// Node the message originates from, which isn't the forwarding node for buffered messages.
+    #[serde(rename = "f")]
+    sender: Uuid,
+    #[serde(rename = "m")]
+    msg: String,
+}
+
+struct SingletonRoute {
+    owner: Option<Uuid>,
+    inbox: Option<Sender<(Uuid, String)>>,
+    owner_gone: bool,
+    buffered: Vec<(Uuid, String)>,
+}
+
+///
+/// Routes messages to wherever the singleton currently runs.
+/// Messages sent while the singleton is handed over, or while its owner is down,
+/// are buffered until a new owner is settled.
+#[derive(Clone)]
+pub struct SingletonProxy {
+    name: String,
+    cluster: Arc<Cluster>,
+    route: Arc<Mutex<SingletonRoute>>,
+}
+
+unsafe impl Send for SingletonProxy {}
+unsafe impl Sync for SingletonProxy {}
+
+impl SingletonProxy {
+    pub fn send<T: AsRef<str>>(&self, msg: T) -> Result<()> {
+        self.route_from(self.cluster.host_key(), msg.as_ref().to_string())
+    }
+
+    /// Node currently running the singleton.
+    pub fn owner(&self) -> Option<Uuid> {
+        self.route
+            .lock()
+            .expect("Singleton route is poisoned")
+            .owner
+    }
+
+    fn route_from(&self, sender: Uuid, msg: String) -> Result<()> {
+        let mut route = self.route.lock().expect("Singleton route is poisoned");
+
+        match route.owner {
+            Some(_) if route.owner_gone => route.buffered.push((sender, msg)),
+            Some(owner) if owner == self.cluster.host_key() => {
+                if let Some(inbox) = route.inbox.as_ref() {
+                    inbox.send((sender, msg))?;
+                } else {
+                    route.buffered.push((sender, msg));
+                }
+            }
+            Some(owner) => {
+                let envelope = SingletonEnvelope {
+                    singleton: self.name.clone(),
+                    sender,
+                    msg,
+                };
+                self.cluster
+                    .send_payload(owner, serde_json::to_string(&envelope)?);
+            }
+            None => route.buffered.push((sender, msg)),
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Stop sending to an owner that went down or left, until it comes back or
+    /// the election settles on another node.
+    fn observe(&self, members: &[ArtilleryMember]) {
+        let buffered = {
+            let mut route = self.route.lock().expect("Singleton route is poisoned");
+            // Members that left aren't part of the view anymore.
+            let owner_gone = route.owner.is_some_and(|owner| {
+                !members.iter().any(|m| {
+                    m.host_key() == owner
+                        && matches!(
+                            m.state(),
+                            ArtilleryMemberState::Alive | ArtilleryMemberState::Suspect
+                        )
+                })
+            });
+
+            if owner_gone == route.owner_gone {
+                return;
+            }
+
+            route.owner_gone = owner_gone;
+            if owner_gone {
+                debug!("Buffering messages of singleton {}", self.name);
+                return;
+            }
+
+            std::mem::take(&mut route.buffered)
+        };
+
+        self.flush(buffered);
+    }
+
+    fn hand_over(&self, owner: Option<Uuid>, inbox: Option<Sender<(Uuid, String)>>) {
+        let buffered = {
+            let mut route = self.route.lock().expect("Singleton route is poisoned");
+            route.owner = owner;
+            route.inbox = inbox;
+            route.owner_gone = false;
+            std::mem::take(&mut route.buffered)
+        };
+
+        self.flush(buffered);
+    }
+
+    fn flush(&self, buffered: Vec<(Uuid, String)>) {
+        for (sender, msg) in buffered {
+            if let Err(e) = self.route_from(sender, msg) {
+                error!("Singleton {} lost a message on hand-over: {}", self.name, e);
+            }
+        }
+    }
+}
+
+///
+/// Runs a future on exactly one node of the cluster, moving it when that node leaves or goes down.
+/// Every node registers the same singleton; only the elected one runs it.
+pub struct ClusterSingleton {
+    config: ClusterSingletonConfig,
+    membership: Receiver<ArtilleryClusterEvent>,
+    factory: SingletonFactory,
+    proxy: SingletonProxy,
+    running: Mutex<Option<AbortHandle>>,
+}
+
+unsafe impl Send for ClusterSingleton {}
+unsafe impl Sync for ClusterSingleton {}
+
+impl ClusterSingleton {
+    pub fn new<F, Fut>(cluster: Arc<Cluster>, config: ClusterSingletonConfig, factory: F) -> Self
+    where
+        F: Fn(SingletonInbox) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let membership = cluster.subscribe();
+        let proxy = SingletonProxy {
+            name: config.name.clone(),
+            cluster,
+            route: Arc::new(Mutex::new(SingletonRoute {
+                owner: None,
+                inbox: None,
+                owner_gone: false,
+                buffered: Vec::new(),
+            })),
+        };
+
+        Self {
+            config,
+            membership,
+            factory: Box::new(move |inbox| Box::pin(factory(inbox))),
+            proxy,
+            running: Mutex::new(None),
+        }
+    }
+
+    pub fn proxy(&self) -> SingletonProxy {
+        self.proxy.clone()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+            .lock()
+            .expect("Singleton handle is poisoned")
+            .is_some()
+    }
+
+    pub async fn launch(&self) {
+        let mut election = LeaderElectionState::new(self.config.rule, self.config.stability_delay);
+        let tick = self.config.stability_delay / 4;
+
+        loop {
+            match self.membership.recv_timeout(tick) {
+                Ok((members, event)) => {
+                    election.observe(&members);
+                    self.proxy.observe(&members);
+                    self.receive(event);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if let Some(changed) = election.poll() {
+                let owner = changed.leader.as_ref().map(ArtilleryMember::host_key);
+                info!(
+                    "Singleton {} is handed over to {:?}",
+                    self.config.name, owner
+                );
+
+                if owner == Some(self.proxy.cluster.host_key()) {
+                    self.start();
+                } else {
+                    self.stop();
+                    self.proxy.hand_over(owner, None);
+                }
+            }
+        }
+
+        self.stop();
+    }
+
+    fn receive(&self, event: ArtilleryMemberEvent) {
+        if let ArtilleryMemberEvent::Payload(_, payload) = event {
+            match serde_json::from_str::<SingletonEnvelope>(&payload) {
+                Ok(envelope) if envelope.singleton == self.config.name => {
+                    if let Err(e) = self.proxy.route_from(envelope.sender, envelope.msg) {
+                        error!("Singleton {} dropped a message: {}", self.config.name, e);
+                    }
+                }
+                Ok(_) | Err(_) => {}
+            }
+        }
+    }
+
+    fn start(&self) {
+        let mut running = self.running.lock().expect("Singleton handle is poisoned");
+        if running.is_some() {
+            return;
+        }
+
+        let (inbox_tx, inbox_rx) = channel();
+        let (singleton, abort_handle) = abortable((self.factory)(inbox_rx));
+
+        debug!("Starting singleton {}", self.config.name);
+        spawn(
+            async move {
+                let _ = singleton.await;
+            },
+            ProcStack::default(),
+        );
+
+        *running = Some(abort_handle);
+        drop(running);
+
+        self.proxy
+            .hand_over(Some(self.proxy.cluster.host_key()), Some(inbox_tx));
+    }
+
+    fn stop(&self) {
+        if let Some(abort_handle) = self
+            .running
+            .lock()
+            .expect("Singleton handle is poisoned")
+            .take()
+        {
+            debug!("Stopping singleton {}", self.config.name);
+            abort_handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    fn node() -> Arc<Cluster> {
+        let config = ClusterConfig {
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            ping_interval: chrono::Duration::milliseconds(100),
+            ..Default::default()
+        };
+        let (cluster, _) = Cluster::new_cluster(Uuid::new_v4(), config).unwrap();
+
+        Arc::new(cluster)
+    }
+
+    /// Singleton forwarding the messages it gets to the returned receiver.
+    fn singleton(cluster: Arc<Cluster>) -> (Arc<ClusterSingleton>, Receiver<(Uuid, String)>) {
+        let (forward_tx, rx) = channel();
+        let forward = Mutex::new(forward_tx);
+        let config = ClusterSingletonConfig {
+            stability_delay: Duration::from_millis(200),
+            ..Default::default()
+        };
+
+        let singleton = ClusterSingleton::new(cluster, config, move |inbox: SingletonInbox| {
+            let tx = forward.lock().expect("Test sender is poisoned").clone();
+            thread::spawn(move || inbox.iter().for_each(|received| drop(tx.send(received))));
+            async {}
+        });
+
+        (Arc::new(singleton), rx)
+    }
+
+    fn launch(singleton: &Arc<ClusterSingleton>) {
+        let launched = singleton.clone();
+        thread::spawn(move || futures::executor::block_on(launched.launch()));
+    }
+
+    fn eventually<F: Fn() -> bool>(condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "Condition wasn't met in time");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_messages_are_buffered_while_the_owner_is_down() {
+        let cluster = node();
+        let (singleton, received) = singleton(cluster.clone());
+        let proxy = singleton.proxy();
+
+        let owner = ArtilleryMember::new(
+            Uuid::new_v4(),
+            "127.0.0.1:1".parse().unwrap(),
+            0,
+            ArtilleryMemberState::Down,
+        );
+        proxy.hand_over(Some(owner.host_key()), None);
+        proxy.observe(&[owner]);
+        proxy.send("buffered").unwrap();
+
+        // Taking the singleton over delivers what was sent in the meantime.
+        singleton.start();
+        assert_eq!(
+            received.recv_timeout(Duration::from_secs(1)).unwrap(),
+            (cluster.host_key(), String::from("buffered"))
+        );
+    }
+
+    #[test]
+    fn test_singleton_is_handed_over_when_its_owner_leaves() {
+        let oldest = node();
+        thread::sleep(Duration::from_millis(10));
+        let youngest = node();
+        youngest
+            .join(&[oldest.listen_addr()], Duration::from_secs(5))
+            .unwrap();
+
+        let (first, first_received) = singleton(oldest.clone());
+        let (second, second_received) = singleton(youngest.clone());
+        launch(&first);
+        launch(&second);
+
+        eventually(|| second.proxy().owner() == Some(oldest.host_key()));
+        second.proxy().send("routed").unwrap();
+        // The owner sees the node the message comes from.
+        assert_eq!(
+            first_received.recv_timeout(Duration::from_secs(5)).unwrap(),
+            (youngest.host_key(), String::from("routed"))
+        );
+
+        oldest.leave_cluster();
+        eventually(|| {
+            second
+                .proxy
+                .route
+                .lock()
+                .expect("Singleton route is poisoned")
+                .owner_gone
+        });
+        second.proxy().send("buffered").unwrap();
+
+        assert_eq!(
+            second_received
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap(),
+            (youngest.host_key(), String::from("buffered"))
+        );
+        assert_eq!(second.proxy().owner(), Some(youngest.host_key()));
+        assert!(first_received.try_recv().is_err());
+    }
+}
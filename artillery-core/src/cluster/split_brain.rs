@@ -0,0 +1,364 @@
+use crate::epidemic::prelude::*;
+
+use std::collections::{BTreeSet, HashSet};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+///
+/// Membership as seen from this side of a partition.
+#[derive(Debug, Clone)]
+pub struct PartitionView {
+    /// This node.
+    pub myself: ArtilleryMember,
+    /// Alive members, including this node.
+    pub reachable: Vec<ArtilleryMember>,
+    /// Suspected or down members which haven't been resolved yet.
+    pub unreachable: Vec<ArtilleryMember>,
+}
+
+impl PartitionView {
+    pub fn total(&self) -> usize {
+        self.reachable.len() + self.unreachable.len()
+    }
+
+    pub fn is_reachable(&self, id: &Uuid) -> bool {
+        self.reachable.iter().any(|m| m.host_key() == *id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SplitBrainDecision {
+    /// This side lost, the current node leaves the cluster.
+    DownSelf,
+    /// This side survives, the given unreachable members are downed.
+    DownUnreachable(Vec<ArtilleryMember>),
+}
+
+///
+/// Decides which side of a partition survives.
+pub trait SplitBrainStrategy: Send {
+    fn decide(&self, view: &PartitionView) -> SplitBrainDecision;
+}
+
+/// Side with the majority of the members survives.
+/// On an even split, the side containing the member with the lowest id survives.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeepMajority;
+
+/// Side containing the member which is up for the longest time survives.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeepOldest;
+
+/// Side that has at least `quorum_size` reachable members survives.
+#[derive(Debug, Clone, Copy)]
+pub struct StaticQuorum {
+    pub quorum_size: usize,
+}
+
+/// Side that can reach the referee member survives.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepReferee {
+    pub referee: Uuid,
+}
+
+fn survive_if(survives: bool, view: &PartitionView) -> SplitBrainDecision {
+    if survives {
+        SplitBrainDecision::DownUnreachable(view.unreachable.clone())
+    } else {
+        SplitBrainDecision::DownSelf
+    }
+}
+
+impl SplitBrainStrategy for KeepMajority {
+    fn decide(&self, view: &PartitionView) -> SplitBrainDecision {
+        let reachable = view.reachable.len() * 2;
+
+        let survives = if reachable == view.total() {
+            view.reachable
+                .iter()
+                .chain(view.unreachable.iter())
+                .map(ArtilleryMember::host_key)
+                .min()
+                .is_some_and(|lowest| view.is_reachable(&lowest))
+        } else {
+            reachable > view.total()
+        };
+
+        survive_if(survives, view)
+    }
+}
+
+impl SplitBrainStrategy for KeepOldest {
+    fn decide(&self, view: &PartitionView) -> SplitBrainDecision {
+        let survives = view
+            .reachable
+            .iter()
+            .chain(view.unreachable.iter())
+            .min_by_key(|m| (m.up_since(), m.host_key()))
+            .is_some_and(|oldest| view.is_reachable(&oldest.host_key()));
+
+        survive_if(survives, view)
+    }
+}
+
+impl SplitBrainStrategy for StaticQuorum {
+    fn decide(&self, view: &PartitionView) -> SplitBrainDecision {
+        survive_if(view.reachable.len() >= self.quorum_size, view)
+    }
+}
+
+impl SplitBrainStrategy for KeepReferee {
+    fn decide(&self, view: &PartitionView) -> SplitBrainDecision {
+        survive_if(view.is_reachable(&self.referee), view)
+    }
+}
+
+///
+/// Tracks reachability changes and tells when the membership was stable long enough to decide.
+#[derive(Debug)]
+pub struct SplitBrainState {
+    stable_after: Duration,
+    members: Vec<ArtilleryMember>,
+    unreachable: BTreeSet<Uuid>,
+    downed: HashSet<Uuid>,
+    last_change: Instant,
+    decided: bool,
+}
+
+impl SplitBrainState {
+    pub fn new(stable_after: Duration) -> Self {
+        SplitBrainState {
+            stable_after,
+            members: Vec::new(),
+            unreachable: BTreeSet::new(),
+            downed: HashSet::new(),
+            last_change: Instant::now(),
+            decided: false,
+        }
+    }
+
+    pub fn observe(&mut self, members: &[ArtilleryMember]) {
+        let downed = &mut self.downed;
+        downed.retain(|id| {
+            !members
+                .iter()
+                .any(|m| m.host_key() == *id && m.state() == ArtilleryMemberState::Alive)
+        });
+
+        let unreachable: BTreeSet<_> = members
+            .iter()
+            .filter(|m| !is_alive(m) && !downed.contains(&m.host_key()))
+            .map(ArtilleryMember::host_key)
+            .collect();
+
+        if unreachable != self.unreachable || members.len() != self.members.len() {
+            self.last_change = Instant::now();
+            self.decided = false;
+        }
+
+        self.unreachable = unreachable;
+        self.members = members.to_vec();
+    }
+
+    pub fn view(&self) -> Option<PartitionView> {
+        let myself = self.members.iter().find(|m| m.is_current())?.clone();
+
+        let reachable = self.members.iter().filter(|m| is_alive(m));
+        let unreachable = self
+            .members
+            .iter()
+            .filter(|m| self.unreachable.contains(&m.host_key()));
+
+        Some(PartitionView {
+            myself,
+            reachable: reachable.cloned().collect(),
+            unreachable: unreachable.cloned().collect(),
+        })
+    }
+
+    pub fn decide(&mut self, strategy: &dyn SplitBrainStrategy) -> Option<SplitBrainDecision> {
+        if self.decided
+            || self.unreachable.is_empty()
+            || self.last_change.elapsed() < self.stable_after
+        {
+            return None;
+        }
+
+        let decision = strategy.decide(&self.view()?);
+        self.decided = true;
+
+        if let SplitBrainDecision::DownUnreachable(ref members) = decision {
+            self.downed
+                .extend(members.iter().map(ArtilleryMember::host_key));
+            self.unreachable.clear();
+        }
+
+        Some(decision)
+    }
+}
+
+fn is_alive(member: &ArtilleryMember) -> bool {
+    member.state() == ArtilleryMemberState::Alive
+}
+
+///
+/// Split-brain resolver running on top of the membership events of a cluster.
+pub struct SplitBrainResolver {
+    pub decisions: Receiver<SplitBrainDecision>,
+    cluster: Arc<Cluster>,
+    membership: Receiver<ArtilleryClusterEvent>,
+    decision_tx: Sender<SplitBrainDecision>,
+    strategy: Box<dyn SplitBrainStrategy>,
+    stable_after: Duration,
+}
+
+unsafe impl Send for SplitBrainResolver {}
+unsafe impl Sync for SplitBrainResolver {}
+
+impl SplitBrainResolver {
+    pub fn new<S>(cluster: Arc<Cluster>, strategy: S, stable_after: Duration) -> Self
+    where
+        S: SplitBrainStrategy + 'static,
+    {
+        let (decision_tx, decisions) = channel();
+        let membership = cluster.subscribe();
+
+        Self {
+            decisions,
+            cluster,
+            membership,
+            decision_tx,
+            strategy: Box::new(strategy),
+            stable_after,
+        }
+    }
+
+    pub async fn launch(&self) {
+        let mut state = SplitBrainState::new(self.stable_after);
+        let tick = self.stable_after / 4;
+
+        loop {
+            match self.membership.recv_timeout(tick) {
+                Ok((members, _)) => state.observe(&members),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if let Some(decision) = state.decide(self.strategy.as_ref()) {
+                warn!("Split brain resolved: {:?}", decision);
+
+                match decision {
+                    SplitBrainDecision::DownSelf => self.cluster.leave_cluster(),
+                    SplitBrainDecision::DownUnreachable(ref members) => members
+                        .iter()
+                        .for_each(|m| self.cluster.down_member(m.host_key())),
+                }
+
+                let _ = self.decision_tx.send(decision);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{Duration as ChronoDuration, Utc};
+
+    fn member(port: u16, state: ArtilleryMemberState, age_secs: i64) -> ArtilleryMember {
+        ArtilleryMember::new(
+            Uuid::new_v4(),
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+            0,
+            state,
+        )
+        .with_up_since(Utc::now() - ChronoDuration::seconds(age_secs))
+    }
+
+    fn view(reachable: u16, unreachable: u16) -> PartitionView {
+        let myself = ArtilleryMember::current(Uuid::new_v4());
+        let mut alive = vec![myself.clone()];
+        alive.extend((1..reachable).map(|p| member(p, ArtilleryMemberState::Alive, 10)));
+
+        PartitionView {
+            myself,
+            reachable: alive,
+            unreachable: (0..unreachable)
+                .map(|p| member(100 + p, ArtilleryMemberState::Down, 10))
+                .collect(),
+        }
+    }
+
+    fn survives(decision: &SplitBrainDecision) -> bool {
+        matches!(*decision, SplitBrainDecision::DownUnreachable(_))
+    }
+
+    #[test]
+    fn test_keep_majority() {
+        assert!(survives(&KeepMajority.decide(&view(3, 2))));
+        assert!(!survives(&KeepMajority.decide(&view(2, 3))));
+
+        let even = view(2, 2);
+        let lowest = even
+            .reachable
+            .iter()
+            .chain(even.unreachable.iter())
+            .map(ArtilleryMember::host_key)
+            .min()
+            .unwrap();
+        assert_eq!(
+            survives(&KeepMajority.decide(&even)),
+            even.is_reachable(&lowest)
+        );
+    }
+
+    #[test]
+    fn test_keep_oldest() {
+        let mut partitioned = view(3, 1);
+        assert!(survives(&KeepOldest.decide(&partitioned)));
+
+        partitioned.unreachable = vec![member(100, ArtilleryMemberState::Suspect, 3600)];
+        assert!(!survives(&KeepOldest.decide(&partitioned)));
+    }
+
+    #[test]
+    fn test_static_quorum_and_referee() {
+        let partitioned = view(2, 3);
+        assert!(survives(
+            &StaticQuorum { quorum_size: 2 }.decide(&partitioned)
+        ));
+        assert!(!survives(
+            &StaticQuorum { quorum_size: 3 }.decide(&partitioned)
+        ));
+
+        let reachable = KeepReferee {
+            referee: partitioned.reachable[1].host_key(),
+        };
+        assert!(survives(&reachable.decide(&partitioned)));
+
+        let unreachable = KeepReferee {
+            referee: partitioned.unreachable[0].host_key(),
+        };
+        assert!(!survives(&unreachable.decide(&partitioned)));
+    }
+
+    #[test]
+    fn test_decides_once_after_stable_window() {
+        let partitioned = view(3, 2);
+        let mut members = partitioned.reachable.clone();
+        members.extend(partitioned.unreachable.clone());
+
+        let mut state = SplitBrainState::new(Duration::from_millis(50));
+        state.observe(&members);
+        assert!(state.decide(&KeepMajority).is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(survives(&state.decide(&KeepMajority).unwrap()));
+
+        state.observe(&members);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(state.decide(&KeepMajority).is_none());
+    }
+}
@@ -10,3 +10,17 @@ pub const CONST_INFECTION_PORT: u16 = 27845;
 // Behave like this is the size. Normally 512 is enough.
 /// Default UDP cast packet size
 pub const CONST_PACKET_SIZE: usize = 1 << 16;
+
+// Organization-local scope (RFC 2365), kept within the site's multicast routers.
+/// Default Service Discovery Multicast Group
+pub const CONST_SERVICE_DISCOVERY_GROUP: [u8; 4] = [239, 255, 34, 72];
+
+/// Default DNS-SD service type of artillery nodes
+pub const CONST_MDNS_SERVICE_TYPE: &str = "_artillery._udp.local";
+
+// RFC 6762, link-local mDNS group and port.
+/// Default mDNS group
+pub const CONST_MDNS_GROUP: [u8; 4] = [224, 0, 0, 251];
+
+/// Default mDNS port
+pub const CONST_MDNS_PORT: u16 = 5353;
@@ -1,9 +1,12 @@
 use super::state::ArtilleryEpidemic;
 use crate::epidemic::cluster_config::ClusterConfig;
+use crate::epidemic::delegate::{ArtilleryMemberDelegate, DefaultMemberDelegate};
+use crate::epidemic::query::{ArtilleryQuery, ArtilleryQueryParams, ArtilleryQueryResponses};
 use crate::epidemic::state::{ArtilleryClusterEvent, ArtilleryClusterRequest};
 use crate::errors::*;
 use bastion_executor::prelude::*;
 use lightproc::{proc_stack::ProcStack, recoverable_handle::RecoverableHandle};
+use std::collections::HashMap;
 use std::convert::AsRef;
 use std::net::SocketAddr;
 use std::{
@@ -11,12 +14,15 @@ use std::{
     pin::Pin,
     sync::mpsc::{channel, Receiver, Sender},
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct Cluster {
     pub events: Receiver<ArtilleryClusterEvent>,
+    host_key: Uuid,
+    listen_addr: SocketAddr,
     comm: Sender<ArtilleryClusterRequest>,
 }
 
@@ -25,11 +31,29 @@ impl Cluster {
         host_key: Uuid,
         config: ClusterConfig,
     ) -> Result<(Self, RecoverableHandle<()>)> {
+        Self::new_cluster_with_delegate(host_key, config, DefaultMemberDelegate)
+    }
+
+    /// Create a cluster whose membership decisions are vetted by the given delegate.
+    pub fn new_cluster_with_delegate<D>(
+        host_key: Uuid,
+        config: ClusterConfig,
+        delegate: D,
+    ) -> Result<(Self, RecoverableHandle<()>)>
+    where
+        D: ArtilleryMemberDelegate + 'static,
+    {
         let (event_tx, event_rx) = channel::<ArtilleryClusterEvent>();
         let (internal_tx, mut internal_rx) = channel::<ArtilleryClusterRequest>();
 
-        let (poll, state) =
-            ArtilleryEpidemic::new(host_key, config, event_tx, internal_tx.clone())?;
+        let (poll, state) = ArtilleryEpidemic::new(
+            host_key,
+            config,
+            event_tx,
+            internal_tx.clone(),
+            Box::new(delegate),
+        )?;
+        let listen_addr = state.listen_addr();
 
         debug!("Starting Artillery Cluster");
         let cluster_handle = spawn_blocking(
@@ -43,6 +67,8 @@ impl Cluster {
         Ok((
             Self {
                 events: event_rx,
+                host_key,
+                listen_addr,
                 comm: internal_tx,
             },
             cluster_handle,
@@ -53,6 +79,28 @@ impl Cluster {
         let _ = self.comm.send(ArtilleryClusterRequest::AddSeed(addr));
     }
 
+    /// Join the cluster through the given seeds, waiting until one of them sent its membership back.
+    /// Returns the amount of alive remote members known once joined.
+    pub fn join(&self, seeds: &[SocketAddr], timeout: Duration) -> Result<usize> {
+        if seeds.is_empty() {
+            bail!(ArtilleryError::Join, "No seed nodes to join through");
+        }
+
+        let (tx, rx) = channel();
+        self.comm.send(ArtilleryClusterRequest::Join(
+            seeds.to_vec(),
+            Instant::now() + timeout,
+            tx,
+        ))?;
+
+        rx.recv_timeout(timeout).map_err(|_| {
+            ArtilleryError::Join(format!(
+                "None of the seeds {:?} acknowledged the join within {:?}",
+                seeds, timeout
+            ))
+        })
+    }
+
     pub fn send_payload<T: AsRef<str>>(&self, id: Uuid, msg: T) {
         self.comm
             .send(ArtilleryClusterRequest::Payload(
@@ -62,6 +110,95 @@ impl Cluster {
             .unwrap();
     }
 
+    /// Ask every member matching the query filter and collect their answers until the deadline.
+    /// Fails when the query doesn't fit a packet.
+    pub fn query<T: AsRef<str>>(
+        &self,
+        name: &str,
+        msg: T,
+        params: ArtilleryQueryParams,
+    ) -> Result<ArtilleryQueryResponses> {
+        let (tx, rx) = channel();
+        let (sent_tx, sent_rx) = channel();
+        let query = ArtilleryQuery::new(self.host_key, name, msg.as_ref().to_string(), params);
+        let responses = ArtilleryQueryResponses::new(&query, rx);
+
+        self.comm
+            .send(ArtilleryClusterRequest::Query(query, tx, sent_tx))?;
+        sent_rx.recv()??;
+
+        Ok(responses)
+    }
+
+    /// Answer a query received through `ArtilleryMemberEvent::Query`.
+    /// Fails when the response doesn't fit a packet.
+    pub fn respond_to_query<T: AsRef<str>>(&self, query_id: Uuid, msg: T) -> Result<()> {
+        let (tx, rx) = channel();
+        self.comm.send(ArtilleryClusterRequest::RespondQuery(
+            query_id,
+            msg.as_ref().to_string(),
+            tx,
+        ))?;
+
+        rx.recv()?
+    }
+
+    /// Receive a copy of every membership event, next to the ones delivered through `events`.
+    /// The first event replays the current view as the current node joining.
+    pub fn subscribe(&self) -> Receiver<ArtilleryClusterEvent> {
+        let (tx, rx) = channel();
+
+        self.comm
+            .send(ArtilleryClusterRequest::Subscribe(tx))
+            .unwrap();
+
+        rx
+    }
+
+    /// Declare a remote member as down without waiting for the failure detector.
+    pub fn down_member(&self, id: Uuid) {
+        let _ = self.comm.send(ArtilleryClusterRequest::DownMember(id));
+    }
+
+    /// Set a metadata entry of the current member and gossip it to the cluster.
+    /// Fails when the metadata would grow beyond `max_metadata_size`.
+    pub fn set_metadata<K: AsRef<str>, V: AsRef<str>>(&self, key: K, value: V) -> Result<()> {
+        let (tx, rx) = channel();
+        self.comm.send(ArtilleryClusterRequest::SetMetadata(
+            key.as_ref().to_string(),
+            value.as_ref().to_string(),
+            tx,
+        ))?;
+
+        rx.recv()?
+    }
+
+    /// Remove a metadata entry of the current member and gossip the removal to the cluster.
+    pub fn remove_metadata<K: AsRef<str>>(&self, key: K) {
+        let _ = self.comm.send(ArtilleryClusterRequest::RemoveMetadata(
+            key.as_ref().to_string(),
+        ));
+    }
+
+    /// Current phi value of every remote member, when the phi-accrual failure detector is used.
+    pub fn phi_values(&self) -> HashMap<Uuid, f64> {
+        let (tx, rx) = channel();
+
+        self.comm
+            .send(ArtilleryClusterRequest::PhiValues(tx))
+            .unwrap();
+
+        rx.recv().unwrap_or_default()
+    }
+
+    pub fn host_key(&self) -> Uuid {
+        self.host_key
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
     pub fn leave_cluster(&self) {
         let _ = self.comm.send(ArtilleryClusterRequest::LeaveCluster);
     }
@@ -90,3 +227,147 @@ impl Drop for Cluster {
         rx.recv().unwrap();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::epidemic::prelude::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Node on an ephemeral port, probing often.
+    fn node(metadata: &[(&str, &str)]) -> Arc<Cluster> {
+        let config = ClusterConfig {
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            ping_interval: chrono::Duration::milliseconds(100),
+            metadata: metadata
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        let (cluster, _) = Cluster::new_cluster(Uuid::new_v4(), config).unwrap();
+
+        Arc::new(cluster)
+    }
+
+    #[test]
+    fn test_join_through_seed() {
+        let seed = node(&[]);
+        let node = node(&[]);
+
+        let joined = node.join(&[seed.listen_addr()], Duration::from_secs(10));
+        assert_eq!(joined.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_seeding_itself_is_ignored() {
+        let node = node(&[]);
+        let events = node.subscribe();
+        // The current view is replayed first.
+        events.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        node.add_seed_node(node.listen_addr());
+        assert!(events.recv_timeout(Duration::from_secs(2)).is_err());
+    }
+
+    #[test]
+    fn test_join_times_out_without_seeds() {
+        let node = node(&[]);
+        // Bound, so that nothing else answers from there.
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let joined = node.join(&[silent.local_addr().unwrap()], Duration::from_millis(500));
+        assert!(matches!(joined, Err(ArtilleryError::Join(_))));
+        assert!(matches!(
+            node.join(&[], Duration::from_millis(500)),
+            Err(ArtilleryError::Join(_))
+        ));
+    }
+
+    /// Wait until the node sees the amount of alive members, itself included.
+    fn await_members(events: &Receiver<ArtilleryClusterEvent>, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Ok((members, _)) = events.recv_timeout(Duration::from_millis(100)) {
+                if members
+                    .iter()
+                    .filter(|m| m.state() == ArtilleryMemberState::Alive)
+                    .count()
+                    == count
+                {
+                    return;
+                }
+            }
+        }
+        panic!("Members didn't converge to {}", count);
+    }
+
+    /// Answer every query with the host key, and count the queries received.
+    fn answer_queries(cluster: Arc<Cluster>) -> thread::JoinHandle<usize> {
+        let events = cluster.subscribe();
+
+        thread::spawn(move || {
+            let mut received = 0;
+            while let Ok((_, event)) = events.recv_timeout(Duration::from_secs(3)) {
+                if let ArtilleryMemberEvent::Query(_, query) = event {
+                    received += 1;
+                    cluster
+                        .respond_to_query(query.id(), cluster.host_key().to_string())
+                        .unwrap();
+                }
+            }
+            received
+        })
+    }
+
+    #[test]
+    fn test_queries_are_disseminated_once_and_aggregated() {
+        let origin = node(&[]);
+        let events = origin.subscribe();
+        let members = [node(&[("role", "db")]), node(&[("role", "web")])];
+        for member in &members {
+            member
+                .join(&[origin.listen_addr()], Duration::from_secs(5))
+                .unwrap();
+        }
+        await_members(&events, 3);
+        drop(events);
+
+        let answering: Vec<_> = members.iter().cloned().map(answer_queries).collect();
+        let params = ArtilleryQueryParams {
+            timeout: chrono::Duration::seconds(1),
+            // Every member gets the query directly and relayed by the other one.
+            relay_factor: 2,
+            relay_hops: 1,
+            ..Default::default()
+        };
+        let responders: HashSet<String> = origin
+            .query("who", "?", params.clone())
+            .unwrap()
+            .map(|(_, response)| response)
+            .collect();
+        let expected: HashSet<String> = members.iter().map(|m| m.host_key().to_string()).collect();
+        assert_eq!(responders, expected);
+
+        let filtered: Vec<_> = origin
+            .query(
+                "who",
+                "?",
+                ArtilleryQueryParams {
+                    filter: vec![("role".to_string(), "db".to_string())]
+                        .into_iter()
+                        .collect(),
+                    ..params
+                },
+            )
+            .unwrap()
+            .map(|(member, _)| member.host_key())
+            .collect();
+        assert_eq!(filtered, vec![members[0].host_key()]);
+
+        let received: Vec<usize> = answering.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(received, vec![2, 1]);
+    }
+}
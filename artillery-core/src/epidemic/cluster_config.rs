@@ -1,4 +1,6 @@
 use crate::constants::*;
+use crate::epidemic::failure_detector::FailureDetectorConfig;
+use crate::epidemic::member::ArtilleryMemberMetadata;
 use chrono::Duration;
 use std::net::{SocketAddr, ToSocketAddrs};
 
@@ -9,7 +11,21 @@ pub struct ClusterConfig {
     pub network_mtu: usize,
     pub ping_request_host_count: usize,
     pub ping_timeout: Duration,
+    /// Failure detector deciding when unanswered heartbeats make a member suspected.
+    pub failure_detector: FailureDetectorConfig,
+    /// Upper bound of the local health score, which multiplies probe intervals and timeouts.
+    pub max_local_health: usize,
     pub listen_addr: SocketAddr,
+    pub metadata: ArtilleryMemberMetadata,
+    /// Upper bound of the encoded metadata of the current member, in bytes.
+    /// Metadata travels along with every message, and has to leave room for the rest of it.
+    pub max_metadata_size: usize,
+    /// How often down members and forgotten seeds are probed again to heal partitions.
+    pub reconnect_interval: Duration,
+    /// Amount of down members probed at every reconnect round.
+    pub reconnect_host_count: usize,
+    /// Members that have been down for longer than this are not probed anymore.
+    pub reconnect_timeout: Duration,
 }
 
 impl Default for ClusterConfig {
@@ -22,7 +38,14 @@ impl Default for ClusterConfig {
             network_mtu: CONST_PACKET_SIZE,
             ping_request_host_count: 3,
             ping_timeout: Duration::seconds(3),
+            failure_detector: FailureDetectorConfig::default(),
+            max_local_health: 8,
             listen_addr: directed.to_socket_addrs().unwrap().next().unwrap(),
+            metadata: ArtilleryMemberMetadata::new(),
+            max_metadata_size: CONST_PACKET_SIZE / 8,
+            reconnect_interval: Duration::seconds(10),
+            reconnect_host_count: 3,
+            reconnect_timeout: Duration::hours(6),
         }
     }
 }
@@ -0,0 +1,37 @@
+use super::member::ArtilleryMember;
+
+///
+/// Application hooks into the membership decisions of the epidemic event loop.
+///
+/// Every method is called synchronously from inside the event loop,
+/// so implementations should return quickly and never block.
+pub trait ArtilleryMemberDelegate: Send {
+    /// Decide whether a member we haven't seen before is admitted to the cluster.
+    /// Rejected members are neither added to the member list nor answered.
+    fn allow_join(&mut self, _candidate: &ArtilleryMember) -> bool {
+        true
+    }
+
+    /// Decide whether the membership view gossiped by a remote node is merged into ours.
+    fn allow_merge(&mut self, _peers: &[ArtilleryMember]) -> bool {
+        true
+    }
+
+    /// Called when a member claims an address or an identity that is already taken.
+    fn notify_conflict(&mut self, _existing: &ArtilleryMember, _other: &ArtilleryMember) {}
+
+    /// Called after a member has joined the cluster.
+    fn notify_join(&mut self, _member: &ArtilleryMember) {}
+
+    /// Called after a member went up or became suspected.
+    fn notify_update(&mut self, _member: &ArtilleryMember) {}
+
+    /// Called after a member went down or left the cluster.
+    fn notify_leave(&mut self, _member: &ArtilleryMember) {}
+}
+
+/// Delegate which admits every member and ignores all notifications.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultMemberDelegate;
+
+impl ArtilleryMemberDelegate for DefaultMemberDelegate {}
@@ -0,0 +1,299 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+
+///
+/// Decides which probed hosts are unreachable.
+///
+/// The epidemic event loop reports every heartbeat it sends and every ack it receives,
+/// and asks for the unreachable hosts before each outgoing request.
+pub trait ArtilleryFailureDetector: Send {
+    /// A heartbeat was sent to the host, which has `timeout` to answer it.
+    fn ping_sent(&mut self, host: SocketAddr, now: DateTime<Utc>, timeout: Duration);
+
+    /// The host answered a heartbeat, either directly or through an indirect ping.
+    fn ack_received(&mut self, host: SocketAddr, now: DateTime<Utc>);
+
+    /// Hosts which should be suspected, or downed if they already are.
+    fn unreachable_hosts(&mut self, now: DateTime<Utc>) -> HashSet<SocketAddr>;
+
+    /// Suspicion level of the host, for detectors that have one.
+    fn phi(&self, _host: &SocketAddr, _now: DateTime<Utc>) -> Option<f64> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum FailureDetectorConfig {
+    /// Hosts that don't answer a heartbeat within the ping timeout are unreachable.
+    #[default]
+    Timeout,
+    /// Hosts are unreachable once their phi value crosses the threshold.
+    PhiAccrual(PhiAccrualConfig),
+}
+
+impl FailureDetectorConfig {
+    pub fn build(&self) -> Box<dyn ArtilleryFailureDetector> {
+        match self {
+            FailureDetectorConfig::Timeout => Box::new(TimeoutFailureDetector::default()),
+            FailureDetectorConfig::PhiAccrual(config) => {
+                Box::new(PhiAccrualFailureDetector::new(config.clone()))
+            }
+        }
+    }
+}
+
+///
+/// Plain SWIM failure detection: a missing ack within the timeout makes the host unreachable.
+#[derive(Debug, Default)]
+pub struct TimeoutFailureDetector {
+    deadlines: HashMap<SocketAddr, DateTime<Utc>>,
+}
+
+impl ArtilleryFailureDetector for TimeoutFailureDetector {
+    fn ping_sent(&mut self, host: SocketAddr, now: DateTime<Utc>, timeout: Duration) {
+        self.deadlines.entry(host).or_insert(now + timeout);
+    }
+
+    fn ack_received(&mut self, host: SocketAddr, _now: DateTime<Utc>) {
+        self.deadlines.remove(&host);
+    }
+
+    fn unreachable_hosts(&mut self, now: DateTime<Utc>) -> HashSet<SocketAddr> {
+        let expired: HashSet<_> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline < now)
+            .map(|(host, _)| *host)
+            .collect();
+
+        self.deadlines.retain(|host, _| !expired.contains(host));
+
+        expired
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PhiAccrualConfig {
+    /// Phi value above which a host with an unanswered heartbeat is unreachable.
+    pub threshold: f64,
+    /// Amount of heartbeat inter-arrival times kept per host.
+    pub max_sample_size: usize,
+    /// Lower bound of the standard deviation, so that very regular hosts aren't suspected too early.
+    pub min_std_deviation: Duration,
+    /// Pause on top of the mean inter-arrival time which is always tolerated.
+    pub acceptable_heartbeat_pause: Duration,
+    /// Inter-arrival time assumed for hosts we haven't heard from yet.
+    pub first_heartbeat_estimate: Duration,
+}
+
+impl Default for PhiAccrualConfig {
+    fn default() -> Self {
+        PhiAccrualConfig {
+            threshold: 8.0,
+            max_sample_size: 200,
+            min_std_deviation: Duration::milliseconds(500),
+            acceptable_heartbeat_pause: Duration::seconds(3),
+            first_heartbeat_estimate: Duration::seconds(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct HeartbeatHistory {
+    intervals: VecDeque<i64>,
+    last_heartbeat: DateTime<Utc>,
+    awaiting_ack: bool,
+}
+
+impl HeartbeatHistory {
+    /// History seeded with two samples around the first estimate,
+    /// so that its mean is the estimate and its deviation a quarter of it.
+    fn new(config: &PhiAccrualConfig, now: DateTime<Utc>) -> Self {
+        let estimate = config.first_heartbeat_estimate.num_milliseconds();
+        let deviation = estimate / 4;
+
+        HeartbeatHistory {
+            intervals: vec![estimate - deviation, estimate + deviation]
+                .into_iter()
+                .collect(),
+            last_heartbeat: now,
+            awaiting_ack: false,
+        }
+    }
+
+    fn record(&mut self, now: DateTime<Utc>, max_sample_size: usize) {
+        let interval = (now - self.last_heartbeat).num_milliseconds();
+
+        if self.intervals.len() >= max_sample_size {
+            self.intervals.pop_front();
+        }
+
+        self.intervals.push_back(interval);
+        self.last_heartbeat = now;
+    }
+
+    #[allow(clippy::float_arithmetic, clippy::cast_precision_loss)]
+    fn phi(&self, config: &PhiAccrualConfig, now: DateTime<Utc>) -> f64 {
+        let samples = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<i64>() as f64 / samples;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|i| (*i as f64 - mean).powi(2))
+            .sum::<f64>()
+            / samples;
+
+        let elapsed = (now - self.last_heartbeat).num_milliseconds() as f64;
+        let pause = config.acceptable_heartbeat_pause.num_milliseconds() as f64;
+        let std_deviation = variance
+            .sqrt()
+            .max(config.min_std_deviation.num_milliseconds() as f64);
+
+        phi(elapsed, mean + pause, std_deviation)
+    }
+}
+
+///
+/// Phi of the elapsed time for a normal distribution of inter-arrival times,
+/// using the logistic approximation of its cumulative distribution function.
+#[allow(clippy::float_arithmetic)]
+fn phi(elapsed: f64, mean: f64, std_deviation: f64) -> f64 {
+    let y = (elapsed - mean) / std_deviation;
+    let e = (-y * (1.5976 + 0.070_566 * y * y)).exp();
+
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+///
+/// Phi-accrual failure detector, which adapts to the heartbeat inter-arrival times of each host.
+///
+/// Only hosts with an unanswered heartbeat are reported unreachable,
+/// since members are probed at varying intervals.
+#[derive(Debug)]
+pub struct PhiAccrualFailureDetector {
+    config: PhiAccrualConfig,
+    histories: HashMap<SocketAddr, HeartbeatHistory>,
+}
+
+impl PhiAccrualFailureDetector {
+    pub fn new(config: PhiAccrualConfig) -> Self {
+        PhiAccrualFailureDetector {
+            config,
+            histories: HashMap::new(),
+        }
+    }
+}
+
+impl ArtilleryFailureDetector for PhiAccrualFailureDetector {
+    fn ping_sent(&mut self, host: SocketAddr, now: DateTime<Utc>, _timeout: Duration) {
+        let config = &self.config;
+
+        self.histories
+            .entry(host)
+            .or_insert_with(|| HeartbeatHistory::new(config, now))
+            .awaiting_ack = true;
+    }
+
+    fn ack_received(&mut self, host: SocketAddr, now: DateTime<Utc>) {
+        let config = &self.config;
+        let history = self
+            .histories
+            .entry(host)
+            .or_insert_with(|| HeartbeatHistory::new(config, now));
+
+        // A host coming back after being unreachable starts over,
+        // its outage would otherwise skew the distribution.
+        if history.phi(config, now) > config.threshold {
+            *history = HeartbeatHistory::new(config, now);
+        } else {
+            history.record(now, config.max_sample_size);
+        }
+
+        history.awaiting_ack = false;
+    }
+
+    fn unreachable_hosts(&mut self, now: DateTime<Utc>) -> HashSet<SocketAddr> {
+        let config = &self.config;
+
+        self.histories
+            .iter()
+            .filter(|(_, history)| {
+                history.awaiting_ack && history.phi(config, now) > config.threshold
+            })
+            .map(|(host, _)| *host)
+            .collect()
+    }
+
+    fn phi(&self, host: &SocketAddr, now: DateTime<Utc>) -> Option<f64> {
+        self.histories
+            .get(host)
+            .map(|history| history.phi(&self.config, now))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn host() -> SocketAddr {
+        "127.0.0.1:1337".parse().unwrap()
+    }
+
+    #[test]
+    fn test_timeout_detector() {
+        let start = Utc::now();
+        let timeout = Duration::seconds(3);
+        let mut detector = TimeoutFailureDetector::default();
+
+        detector.ping_sent(host(), start, timeout);
+        assert!(detector
+            .unreachable_hosts(start + Duration::seconds(2))
+            .is_empty());
+        assert!(detector
+            .unreachable_hosts(start + Duration::seconds(4))
+            .contains(&host()));
+
+        detector.ping_sent(host(), start + Duration::seconds(5), timeout);
+        detector.ack_received(host(), start + Duration::seconds(6));
+        assert!(detector
+            .unreachable_hosts(start + Duration::seconds(10))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_phi_grows_with_silence() {
+        let start = Utc::now();
+        let mut detector = PhiAccrualFailureDetector::new(PhiAccrualConfig::default());
+
+        for i in 0..20 {
+            let at = start + Duration::seconds(i);
+            detector.ping_sent(host(), at, Duration::seconds(3));
+            detector.ack_received(host(), at + Duration::milliseconds(10));
+        }
+
+        let last = start + Duration::seconds(19) + Duration::milliseconds(10);
+        let phi_at = |after: i64| {
+            detector
+                .phi(&host(), last + Duration::seconds(after))
+                .unwrap()
+        };
+        assert!(phi_at(1) < 1.0);
+        assert!(phi_at(3) < phi_at(5));
+        assert!(phi_at(10) > 8.0);
+
+        let later = last + Duration::seconds(10);
+        assert!(detector.unreachable_hosts(later).is_empty());
+
+        detector.ping_sent(host(), later, Duration::seconds(3));
+        assert!(detector.unreachable_hosts(later).contains(&host()));
+
+        detector.ack_received(host(), later);
+        assert!(detector.phi(&host(), later).unwrap() < 1.0);
+        assert!(detector.unreachable_hosts(later).is_empty());
+    }
+}
@@ -0,0 +1,62 @@
+use chrono::Duration;
+use std::convert::TryFrom;
+
+///
+/// Lifeguard's local health multiplier.
+///
+/// The score grows while the current node misses acks and nacks it should have received,
+/// which hints at a slow or overloaded node rather than at failing members.
+/// Probe intervals and timeouts are stretched by the score, so that a struggling node
+/// stops accusing healthy members.
+#[derive(Debug, Clone)]
+pub struct LocalHealth {
+    score: usize,
+    max_score: usize,
+}
+
+impl LocalHealth {
+    pub fn new(max_score: usize) -> Self {
+        LocalHealth {
+            score: 0,
+            max_score,
+        }
+    }
+
+    pub fn score(&self) -> usize {
+        self.score
+    }
+
+    /// A probe failed, nacks went missing or the current node had to refute a suspicion.
+    pub fn degrade(&mut self) {
+        self.score = self.max_score.min(self.score + 1);
+    }
+
+    /// A probe was answered in time.
+    pub fn improve(&mut self) {
+        self.score = self.score.saturating_sub(1);
+    }
+
+    pub fn scale(&self, duration: Duration) -> Duration {
+        duration * i32::try_from(self.score + 1).unwrap_or(i32::MAX)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_score_is_bounded_and_scales_durations() {
+        let mut health = LocalHealth::new(2);
+        health.improve();
+        assert_eq!(health.score(), 0);
+        assert_eq!(health.scale(Duration::seconds(1)), Duration::seconds(1));
+
+        (0..5).for_each(|_| health.degrade());
+        assert_eq!(health.score(), 2);
+        assert_eq!(health.scale(Duration::seconds(1)), Duration::seconds(3));
+
+        health.improve();
+        assert_eq!(health.scale(Duration::seconds(1)), Duration::seconds(2));
+    }
+}
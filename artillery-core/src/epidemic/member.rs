@@ -1,4 +1,5 @@
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::net::SocketAddr;
@@ -23,6 +24,9 @@ pub enum ArtilleryMemberState {
     Left,
 }
 
+/// Application defined key-value pairs gossiped along with the member.
+pub type ArtilleryMemberMetadata = BTreeMap<String, String>;
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct ArtilleryMember {
     #[serde(rename = "h")]
@@ -35,6 +39,10 @@ pub struct ArtilleryMember {
     member_state: ArtilleryMemberState,
     #[serde(rename = "t")]
     last_state_change: DateTime<Utc>,
+    #[serde(rename = "x", default)]
+    metadata: ArtilleryMemberMetadata,
+    #[serde(rename = "u", default = "Utc::now")]
+    up_since: DateTime<Utc>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
@@ -55,6 +63,8 @@ impl ArtilleryMember {
             incarnation_number,
             member_state: known_state,
             last_state_change: Utc::now(),
+            metadata: ArtilleryMemberMetadata::new(),
+            up_since: Utc::now(),
         }
     }
 
@@ -65,9 +75,15 @@ impl ArtilleryMember {
             incarnation_number: 0,
             member_state: ArtilleryMemberState::Alive,
             last_state_change: Utc::now(),
+            metadata: ArtilleryMemberMetadata::new(),
+            up_since: Utc::now(),
         }
     }
 
+    pub fn with_metadata(self, metadata: ArtilleryMemberMetadata) -> Self {
+        ArtilleryMember { metadata, ..self }
+    }
+
     pub fn host_key(&self) -> Uuid {
         self.host_key
     }
@@ -76,6 +92,23 @@ impl ArtilleryMember {
         self.remote_host
     }
 
+    pub fn with_up_since(self, up_since: DateTime<Utc>) -> Self {
+        ArtilleryMember { up_since, ..self }
+    }
+
+    /// Time at which the member process started, as reported by the member itself.
+    pub fn up_since(&self) -> DateTime<Utc> {
+        self.up_since
+    }
+
+    pub fn metadata(&self) -> &ArtilleryMemberMetadata {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut ArtilleryMemberMetadata {
+        &mut self.metadata
+    }
+
     pub fn is_remote(&self) -> bool {
         self.remote_host.is_some()
     }
@@ -157,6 +190,7 @@ impl Debug for ArtilleryMember {
             .field("incarnation_number", &self.incarnation_number)
             .field("host", &self.host_key)
             .field("state", &self.member_state)
+            .field("metadata", &self.metadata)
             .field(
                 "drift_time_ms",
                 &(Utc::now() - self.last_state_change).num_milliseconds(),
@@ -190,6 +224,8 @@ pub fn most_uptodate_member_data<'a>(
         (ArtilleryMemberState::Alive, i, ArtilleryMemberState::Alive, j) => i > j,
         (ArtilleryMemberState::Suspect, i, ArtilleryMemberState::Suspect, j) => i > j,
         (ArtilleryMemberState::Suspect, i, ArtilleryMemberState::Alive, j) => i >= j,
+        // Members come back from down by refuting it with a newer incarnation.
+        (ArtilleryMemberState::Alive, i, ArtilleryMemberState::Down, j) => i > j,
         (ArtilleryMemberState::Down, _, ArtilleryMemberState::Alive, _) => true,
         (ArtilleryMemberState::Down, _, ArtilleryMemberState::Suspect, _) => true,
         (ArtilleryMemberState::Left, _, _, _) => true,
@@ -220,6 +256,10 @@ mod test {
             incarnation_number: 123,
             member_state: ArtilleryMemberState::Alive,
             last_state_change: Utc::now() - Duration::days(1),
+            metadata: vec![("version".to_string(), "1.0.0".to_string())]
+                .into_iter()
+                .collect(),
+            up_since: Utc::now() - Duration::days(2),
         };
 
         let encoded = bincode::serialize(&member).unwrap();
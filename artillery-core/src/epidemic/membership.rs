@@ -1,10 +1,12 @@
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::net::SocketAddr;
 
 use chrono::Duration;
 use uuid::Uuid;
 
+use super::delegate::ArtilleryMemberDelegate;
 use super::member::{ArtilleryMember, ArtilleryMemberState, ArtilleryStateChange};
 use crate::epidemic::member;
 use bastion_utils::math;
@@ -13,6 +15,8 @@ use kaos::flunk;
 
 pub struct ArtilleryMemberList {
     members: Vec<ArtilleryMember>,
+    /// Randomized round-robin order in which remote members are probed.
+    probe_order: Vec<Uuid>,
     periodic_index: usize,
 }
 
@@ -20,6 +24,7 @@ impl ArtilleryMemberList {
     pub fn new(current: ArtilleryMember) -> Self {
         ArtilleryMemberList {
             members: vec![current],
+            probe_order: Vec::new(),
             periodic_index: 0,
         }
     }
@@ -39,6 +44,14 @@ impl ArtilleryMemberList {
             .collect()
     }
 
+    pub fn myself(&self) -> ArtilleryMember {
+        self.members
+            .iter()
+            .find(|m| m.is_current())
+            .cloned()
+            .expect("Could not find this instance as registered member")
+    }
+
     fn mut_myself(&mut self) -> &mut ArtilleryMember {
         for member in &mut self.members {
             if member.is_current() {
@@ -56,6 +69,26 @@ impl ArtilleryMemberList {
         myself.clone()
     }
 
+    ///
+    /// Change the metadata of the current member. Reincarnates, so that the change wins over
+    /// the older data that other members gossip.
+    pub fn update_metadata<F>(&mut self, update: F) -> Option<ArtilleryMember>
+    where
+        F: FnOnce(&mut member::ArtilleryMemberMetadata),
+    {
+        let myself = self.mut_myself();
+        let previous = myself.metadata().clone();
+        update(myself.metadata_mut());
+
+        if *myself.metadata() == previous {
+            return None;
+        }
+
+        myself.reincarnate();
+
+        Some(myself.clone())
+    }
+
     pub fn leave(&mut self) -> ArtilleryMember {
         let myself = self.mut_myself();
         myself.set_state(ArtilleryMemberState::Left);
@@ -64,20 +97,53 @@ impl ArtilleryMemberList {
         myself.clone()
     }
 
+    ///
+    /// Next member to probe. Every probeable member is visited once per round,
+    /// in an order that is shuffled again when the round is over.
     pub fn next_random_member(&mut self) -> Option<ArtilleryMember> {
-        if self.periodic_index == 0 {
-            math::shuffle_linear(&mut self.members);
-        }
-
-        let other_members: Vec<_> = self.members.iter().filter(|&m| m.is_remote()).collect();
+        loop {
+            if self.periodic_index >= self.probe_order.len() {
+                self.probe_order = self
+                    .members
+                    .iter()
+                    .filter(|m| is_probeable(m))
+                    .map(ArtilleryMember::host_key)
+                    .collect();
+                math::shuffle_linear(&mut self.probe_order);
+                self.periodic_index = 0;
+
+                if self.probe_order.is_empty() {
+                    return None;
+                }
+            }
 
-        if other_members.is_empty() {
-            None
-        } else {
             flunk!("epidemic-periodic-index-fp");
-            self.periodic_index = (self.periodic_index + 1) % other_members.len();
-            Some(other_members[self.periodic_index].clone())
+            let id = self.probe_order[self.periodic_index];
+            self.periodic_index += 1;
+
+            // Members that left or went down since the round started are skipped.
+            if let Some(member) = self.members.iter().find(|m| m.host_key() == id) {
+                if is_probeable(member) {
+                    return Some(member.clone());
+                }
+            }
+        }
+    }
+
+    ///
+    /// Schedule a new or returning member at a random position among the members
+    /// which haven't been probed yet in this round.
+    fn add_probe_target(&mut self, id: Uuid) {
+        if self.probe_order[self.periodic_index..].contains(&id) {
+            return;
         }
+
+        let remaining = self.probe_order.len() - self.periodic_index;
+        let offset = u32::try_from(remaining + 1)
+            .map(math::random)
+            .map_or(0, |offset| usize::try_from(offset).unwrap_or_default());
+
+        self.probe_order.insert(self.periodic_index + offset, id);
     }
 
     pub fn time_out_nodes(
@@ -115,12 +181,14 @@ impl ArtilleryMemberList {
         (suspect_members, down_members)
     }
 
-    pub fn mark_node_alive(&mut self, src_addr: &SocketAddr) -> Option<ArtilleryMember> {
+    pub fn down_member(&mut self, id: &Uuid) -> Option<ArtilleryMember> {
         for member in &mut self.members {
-            if member.remote_host() == Some(*src_addr)
-                && member.state() != ArtilleryMemberState::Alive
+            if member.host_key() == *id
+                && member.is_remote()
+                && (member.state() == ArtilleryMemberState::Alive
+                    || member.state() == ArtilleryMemberState::Suspect)
             {
-                member.set_state(ArtilleryMemberState::Alive);
+                member.set_state(ArtilleryMemberState::Down);
 
                 return Some(member.clone());
             }
@@ -129,15 +197,40 @@ impl ArtilleryMemberList {
         None
     }
 
+    pub fn mark_node_alive(&mut self, src_addr: &SocketAddr) -> Option<ArtilleryMember> {
+        let member = self.members.iter_mut().find(|m| {
+            // Members that left only come back by rejoining with a new incarnation.
+            m.remote_host() == Some(*src_addr)
+                && m.state() != ArtilleryMemberState::Alive
+                && m.state() != ArtilleryMemberState::Left
+        })?;
+        let was_down = member.state() == ArtilleryMemberState::Down;
+        member.set_state(ArtilleryMemberState::Alive);
+        let alive = member.clone();
+
+        if was_down {
+            self.add_probe_target(alive.host_key());
+        }
+
+        Some(alive)
+    }
+
     pub fn apply_state_changes(
         &mut self,
         state_changes: Vec<ArtilleryStateChange>,
         from: &SocketAddr,
-    ) -> (Vec<ArtilleryMember>, Vec<ArtilleryMember>) {
+        delegate: &mut dyn ArtilleryMemberDelegate,
+    ) -> (
+        Vec<ArtilleryMember>,
+        Vec<ArtilleryMember>,
+        Vec<ArtilleryMember>,
+    ) {
         let mut current_members = self.to_map();
 
         let mut changed_nodes = Vec::new();
         let mut new_nodes = Vec::new();
+        let mut updated_nodes = Vec::new();
+        let mut returning_nodes = Vec::new();
 
         let my_host_key = self.mut_myself().host_key();
 
@@ -153,6 +246,14 @@ impl ArtilleryMemberList {
             } else {
                 match old_member_data {
                     Entry::Occupied(mut entry) => {
+                        if let (Some(known_host), Some(claimed_host)) =
+                            (entry.get().remote_host(), new_member_data.remote_host())
+                        {
+                            if known_host != claimed_host {
+                                delegate.notify_conflict(entry.get(), new_member_data);
+                            }
+                        }
+
                         let new_member =
                             member::most_uptodate_member_data(new_member_data, entry.get()).clone();
                         let new_host = new_member
@@ -161,15 +262,35 @@ impl ArtilleryMemberList {
                             .unwrap();
                         let new_member = new_member.member_by_changing_host(new_host);
 
-                        if new_member.state() != entry.get().state() {
-                            entry.insert(new_member.clone());
-                            changed_nodes.push(new_member);
+                        match (
+                            new_member.state() != entry.get().state(),
+                            new_member.metadata() != entry.get().metadata(),
+                        ) {
+                            (true, _) => {
+                                if entry.get().state() == ArtilleryMemberState::Down
+                                    && new_member.state() == ArtilleryMemberState::Alive
+                                {
+                                    returning_nodes.push(new_member.host_key());
+                                }
+                                entry.insert(new_member.clone());
+                                changed_nodes.push(new_member);
+                            }
+                            (false, true) => {
+                                entry.insert(new_member.clone());
+                                updated_nodes.push(new_member);
+                            }
+                            (false, false) => {}
                         }
                     }
                     Entry::Vacant(entry) => {
                         let new_host = new_member_data.remote_host().unwrap_or(*from);
                         let new_member = new_member_data.member_by_changing_host(new_host);
 
+                        if !delegate.allow_join(&new_member) {
+                            debug!("Delegate rejected gossiped member {:?}", new_member);
+                            continue;
+                        }
+
                         entry.insert(new_member.clone());
                         new_nodes.push(new_member);
                     }
@@ -178,8 +299,13 @@ impl ArtilleryMemberList {
         }
 
         self.members = current_members.values().cloned().collect();
+        new_nodes
+            .iter()
+            .map(ArtilleryMember::host_key)
+            .chain(returning_nodes)
+            .for_each(|id| self.add_probe_target(id));
 
-        (new_nodes, changed_nodes)
+        (new_nodes, changed_nodes, updated_nodes)
     }
 
     ///
@@ -210,13 +336,47 @@ impl ArtilleryMemberList {
         possible_members.iter().take(host_count).cloned().collect()
     }
 
+    ///
+    /// Random sample of members that went down within the given duration.
+    pub fn recently_down_hosts(&self, host_count: usize, within: Duration) -> Vec<SocketAddr> {
+        let mut possible_members: Vec<_> = self
+            .members
+            .iter()
+            .filter(|m| {
+                m.state() == ArtilleryMemberState::Down && !m.state_change_older_than(within)
+            })
+            .filter_map(ArtilleryMember::remote_host)
+            .collect();
+
+        math::shuffle_linear(&mut possible_members);
+
+        possible_members.iter().take(host_count).cloned().collect()
+    }
+
+    pub fn has_alive_member(&self, remote_host: &SocketAddr) -> bool {
+        self.members.iter().any(|m| {
+            m.remote_host() == Some(*remote_host) && m.state() == ArtilleryMemberState::Alive
+        })
+    }
+
     pub fn has_member(&self, remote_host: &SocketAddr) -> bool {
         self.members
             .iter()
             .any(|m| m.remote_host() == Some(*remote_host))
     }
 
+    pub fn member_at(&self, remote_host: &SocketAddr) -> Option<ArtilleryMember> {
+        self.members
+            .iter()
+            .find(|m| m.remote_host() == Some(*remote_host))
+            .cloned()
+    }
+
     pub fn add_member(&mut self, member: ArtilleryMember) {
+        if is_probeable(&member) {
+            self.add_probe_target(member.host_key());
+        }
+
         self.members.push(member)
     }
 
@@ -236,3 +396,170 @@ impl ArtilleryMemberList {
         Some(member[0].clone())
     }
 }
+
+fn is_probeable(member: &ArtilleryMember) -> bool {
+    member.is_remote()
+        && (member.state() == ArtilleryMemberState::Alive
+            || member.state() == ArtilleryMemberState::Suspect)
+}
+
+#[cfg(test)]
+mod test {
+    use super::ArtilleryMemberList;
+    use crate::epidemic::delegate::{ArtilleryMemberDelegate, DefaultMemberDelegate};
+    use crate::epidemic::member::{ArtilleryMember, ArtilleryMemberState, ArtilleryStateChange};
+    use std::collections::HashSet;
+    use uuid::Uuid;
+
+    struct VersionGate;
+
+    impl ArtilleryMemberDelegate for VersionGate {
+        fn allow_join(&mut self, candidate: &ArtilleryMember) -> bool {
+            candidate.metadata().get("version").map(String::as_str) == Some("2")
+        }
+    }
+
+    fn gossiped(version: &str) -> ArtilleryStateChange {
+        let member = ArtilleryMember::new(
+            Uuid::new_v4(),
+            "127.0.0.1:1337".parse().unwrap(),
+            0,
+            ArtilleryMemberState::Alive,
+        )
+        .with_metadata(
+            vec![("version".to_string(), version.to_string())]
+                .into_iter()
+                .collect(),
+        );
+
+        ArtilleryStateChange::new(member)
+    }
+
+    fn remote(port: u16) -> ArtilleryMember {
+        ArtilleryMember::new(
+            Uuid::new_v4(),
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+            0,
+            ArtilleryMemberState::Alive,
+        )
+    }
+
+    fn probe(members: &mut ArtilleryMemberList, count: usize) -> Vec<Uuid> {
+        (0..count)
+            .map(|_| members.next_random_member().unwrap().host_key())
+            .collect()
+    }
+
+    #[test]
+    fn test_every_member_is_probed_once_per_round() {
+        let mut members = ArtilleryMemberList::new(ArtilleryMember::current(Uuid::new_v4()));
+        assert!(members.next_random_member().is_none());
+
+        let remotes: HashSet<_> = (1..=5)
+            .map(|port| {
+                let member = remote(port);
+                members.add_member(member.clone());
+                member.host_key()
+            })
+            .collect();
+
+        for _ in 0..10 {
+            let round: HashSet<_> = probe(&mut members, 5).into_iter().collect();
+            assert_eq!(round, remotes);
+        }
+    }
+
+    #[test]
+    fn test_new_member_is_probed_within_the_round() {
+        for _ in 0..50 {
+            let mut members = ArtilleryMemberList::new(ArtilleryMember::current(Uuid::new_v4()));
+            (1..=5).for_each(|port| members.add_member(remote(port)));
+
+            let mut round = probe(&mut members, 2);
+
+            let joined = remote(6);
+            members.add_member(joined.clone());
+            round.extend(probe(&mut members, 4));
+
+            let probed: HashSet<_> = round.into_iter().collect();
+            assert_eq!(probed.len(), 6);
+            assert!(probed.contains(&joined.host_key()));
+        }
+    }
+
+    #[test]
+    fn test_returning_member_is_probed_within_the_round() {
+        for _ in 0..50 {
+            let mut members = ArtilleryMemberList::new(ArtilleryMember::current(Uuid::new_v4()));
+            (1..=5).for_each(|port| members.add_member(remote(port)));
+
+            // Already probed in this round before going down.
+            let back = probe(&mut members, 2)[0];
+            let down = members.down_member(&back).unwrap();
+            members.mark_node_alive(&down.remote_host().unwrap());
+
+            assert!(probe(&mut members, 4).contains(&back));
+        }
+    }
+
+    #[test]
+    fn test_member_gossiped_back_is_probed_within_the_round() {
+        for _ in 0..50 {
+            let mut members = ArtilleryMemberList::new(ArtilleryMember::current(Uuid::new_v4()));
+            (1..=5).for_each(|port| members.add_member(remote(port)));
+
+            let back = probe(&mut members, 2)[0];
+            let down = members.down_member(&back).unwrap();
+            let mut gossip = |incarnation| {
+                let alive = ArtilleryMember::new(
+                    back,
+                    down.remote_host().unwrap(),
+                    incarnation,
+                    ArtilleryMemberState::Alive,
+                );
+                let (_, changed, _) = members.apply_state_changes(
+                    vec![ArtilleryStateChange::new(alive)],
+                    &"127.0.0.1:1".parse().unwrap(),
+                    &mut DefaultMemberDelegate,
+                );
+                changed.len()
+            };
+            // Stale gossip doesn't bring it back, its refutation does.
+            assert_eq!(gossip(0), 0);
+            assert_eq!(gossip(1), 1);
+
+            assert!(probe(&mut members, 4).contains(&back));
+        }
+    }
+
+    #[test]
+    fn test_down_members_are_not_probed() {
+        let mut members = ArtilleryMemberList::new(ArtilleryMember::current(Uuid::new_v4()));
+        let down = remote(1);
+        members.add_member(down.clone());
+        members.add_member(remote(2));
+
+        members.down_member(&down.host_key()).unwrap();
+        let probed = probe(&mut members, 4);
+        assert!(probed.iter().all(|id| *id != down.host_key()));
+    }
+
+    #[test]
+    fn test_delegate_vetoes_gossiped_members() {
+        let from = "127.0.0.1:1338".parse().unwrap();
+        let mut members = ArtilleryMemberList::new(ArtilleryMember::current(Uuid::new_v4()));
+
+        let (new, _, _) = members.apply_state_changes(
+            vec![gossiped("1"), gossiped("2")],
+            &from,
+            &mut VersionGate,
+        );
+        assert_eq!(new.len(), 1);
+        assert_eq!(members.available_nodes().len(), 2);
+
+        let (accepted, _, _) =
+            members.apply_state_changes(vec![gossiped("1")], &from, &mut DefaultMemberDelegate);
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(members.available_nodes().len(), 3);
+    }
+}
@@ -3,14 +3,22 @@
 
 pub mod cluster;
 pub mod cluster_config;
+pub mod delegate;
+pub mod failure_detector;
+pub mod health;
 pub mod member;
 pub mod membership;
+pub mod query;
 pub mod state;
 
 pub mod prelude {
     pub use super::cluster::*;
     pub use super::cluster_config::*;
+    pub use super::delegate::*;
+    pub use super::failure_detector::*;
+    pub use super::health::*;
     pub use super::member::*;
     pub use super::membership::*;
+    pub use super::query::*;
     pub use super::state::*;
 }
@@ -0,0 +1,232 @@
+use super::member::{ArtilleryMember, ArtilleryMemberMetadata};
+use chrono::{DateTime, Duration, Utc};
+use serde::*;
+use std::collections::HashSet;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use uuid::Uuid;
+
+/// Response of a single member to a cluster query.
+pub type ArtilleryQueryResponse = (ArtilleryMember, String);
+
+///
+/// Cluster-wide question disseminated to every member matching the filter.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArtilleryQuery {
+    #[serde(rename = "i")]
+    id: Uuid,
+    #[serde(rename = "o")]
+    origin: Uuid,
+    #[serde(rename = "n")]
+    name: String,
+    #[serde(rename = "p")]
+    payload: String,
+    #[serde(rename = "f")]
+    filter: ArtilleryMemberMetadata,
+    #[serde(rename = "t", with = "time_left")]
+    deadline: DateTime<Utc>,
+    #[serde(rename = "r")]
+    relay_factor: usize,
+    #[serde(rename = "h")]
+    relay_hops: u8,
+}
+
+/// Deadlines travel as the time left until them, so that they don't depend on synchronized clocks.
+mod time_left {
+    use chrono::{DateTime, Duration, Utc};
+    use serde::*;
+
+    pub fn serialize<S: Serializer>(deadline: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+        let left = (*deadline - Utc::now()).num_milliseconds().max(0);
+        s.serialize_i64(left)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<Utc>, D::Error> {
+        let left = i64::deserialize(d)?;
+        Ok(Utc::now() + Duration::milliseconds(left))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArtilleryQueryParams {
+    /// Only members whose metadata contains all of these pairs answer the query.
+    pub filter: ArtilleryMemberMetadata,
+    /// How long responses are collected.
+    pub timeout: Duration,
+    /// Amount of random members every receiver forwards the query to.
+    pub relay_factor: usize,
+    /// How many times the query can be forwarded before it is dropped.
+    pub relay_hops: u8,
+}
+
+impl Default for ArtilleryQueryParams {
+    fn default() -> Self {
+        ArtilleryQueryParams {
+            filter: ArtilleryMemberMetadata::new(),
+            timeout: Duration::seconds(5),
+            relay_factor: 0,
+            relay_hops: 1,
+        }
+    }
+}
+
+impl ArtilleryQuery {
+    pub fn new(origin: Uuid, name: &str, payload: String, params: ArtilleryQueryParams) -> Self {
+        ArtilleryQuery {
+            id: Uuid::new_v4(),
+            origin,
+            name: name.to_string(),
+            payload,
+            filter: params.filter,
+            deadline: Utc::now() + params.timeout,
+            relay_factor: params.relay_factor,
+            relay_hops: params.relay_hops,
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn origin(&self) -> Uuid {
+        self.origin
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn payload(&self) -> &str {
+        &self.payload
+    }
+
+    pub fn deadline(&self) -> DateTime<Utc> {
+        self.deadline
+    }
+
+    pub fn relay_factor(&self) -> usize {
+        self.relay_factor
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.deadline < Utc::now()
+    }
+
+    pub fn matches(&self, metadata: &ArtilleryMemberMetadata) -> bool {
+        self.filter.iter().all(|(k, v)| metadata.get(k) == Some(v))
+    }
+
+    /// Copy of the query which can be forwarded once more, if the relay limit allows it.
+    pub fn relayed(&self) -> Option<ArtilleryQuery> {
+        if self.relay_hops == 0 || self.relay_factor == 0 {
+            return None;
+        }
+
+        Some(ArtilleryQuery {
+            relay_hops: self.relay_hops - 1,
+            ..self.clone()
+        })
+    }
+}
+
+///
+/// Responses of a cluster query, ending once the query deadline passes.
+/// Iterating blocks the calling thread while waiting for the responses.
+pub struct ArtilleryQueryResponses {
+    id: Uuid,
+    deadline: DateTime<Utc>,
+    responders: HashSet<Uuid>,
+    responses: Receiver<ArtilleryQueryResponse>,
+}
+
+impl ArtilleryQueryResponses {
+    pub(crate) fn new(query: &ArtilleryQuery, responses: Receiver<ArtilleryQueryResponse>) -> Self {
+        ArtilleryQueryResponses {
+            id: query.id,
+            deadline: query.deadline,
+            responders: HashSet::new(),
+            responses,
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn next_response(&mut self) -> Option<ArtilleryQueryResponse> {
+        loop {
+            let remaining = (self.deadline - Utc::now()).to_std().ok()?;
+
+            match self.responses.recv_timeout(remaining) {
+                Ok((member, response)) => {
+                    if self.responders.insert(member.host_key()) {
+                        return Some((member, response));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                    return None
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for ArtilleryQueryResponses {
+    type Item = ArtilleryQueryResponse;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ArtilleryQuery, ArtilleryQueryParams};
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_query_filter_and_relay_limit() {
+        let params = ArtilleryQueryParams {
+            filter: vec![("env".to_string(), "prod".to_string())]
+                .into_iter()
+                .collect(),
+            relay_factor: 2,
+            relay_hops: 1,
+            ..Default::default()
+        };
+        let query = ArtilleryQuery::new(Uuid::new_v4(), "shard", "42".into(), params);
+
+        let mut metadata = vec![("env".to_string(), "staging".to_string())]
+            .into_iter()
+            .collect();
+        assert!(!query.matches(&metadata));
+
+        metadata.insert("env".to_string(), "prod".to_string());
+        metadata.insert("version".to_string(), "1".to_string());
+        assert!(query.matches(&metadata));
+
+        let relayed = query.relayed().unwrap();
+        assert_eq!(relayed.id(), query.id());
+        assert!(relayed.relayed().is_none());
+    }
+
+    #[test]
+    fn test_query_deadline_travels_as_time_left() {
+        let query = ArtilleryQuery::new(
+            Uuid::new_v4(),
+            "shard",
+            "42".into(),
+            ArtilleryQueryParams::default(),
+        );
+
+        let mut encoded: serde_json::Value = serde_json::to_value(&query).unwrap();
+        let left = encoded["t"].as_i64().unwrap();
+        assert!(left > 4_000 && left <= 5_000);
+
+        // Whatever the clock of the receiver, the deadline is as far away as it was sent.
+        encoded["t"] = serde_json::Value::from(5_000);
+        let decoded: ArtilleryQuery = serde_json::from_value(encoded).unwrap();
+        let remaining = decoded.deadline() - Utc::now();
+        assert!(remaining > Duration::seconds(4) && remaining <= Duration::seconds(5));
+    }
+}
@@ -1,6 +1,12 @@
 use super::cluster_config::ClusterConfig;
+use super::delegate::ArtilleryMemberDelegate;
+use super::failure_detector::ArtilleryFailureDetector;
+use super::health::LocalHealth;
 use super::membership::ArtilleryMemberList;
-use crate::epidemic::member::{ArtilleryMember, ArtilleryMemberState, ArtilleryStateChange};
+use super::query::{ArtilleryQuery, ArtilleryQueryResponse};
+use crate::epidemic::member::{
+    ArtilleryMember, ArtilleryMemberMetadata, ArtilleryMemberState, ArtilleryStateChange,
+};
 use crate::errors::*;
 use chrono::{DateTime, Utc};
 use cuneiform_fields::prelude::*;
@@ -27,7 +33,7 @@ use crate::constants::*;
 pub type ArtilleryClusterEvent = (Vec<ArtilleryMember>, ArtilleryMemberEvent);
 pub type WaitList = HashMap<SocketAddr, Vec<SocketAddr>>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ArtilleryMemberEvent {
     Joined(ArtilleryMember),
     WentUp(ArtilleryMember),
@@ -35,11 +41,20 @@ pub enum ArtilleryMemberEvent {
     WentDown(ArtilleryMember),
     Left(ArtilleryMember),
     Payload(ArtilleryMember, String),
+    Query(ArtilleryMember, ArtilleryQuery),
+    MetadataUpdated(ArtilleryMember),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ArtilleryMessage {
     sender: Uuid,
+    #[serde(default)]
+    sender_metadata: ArtilleryMemberMetadata,
+    #[serde(default = "Utc::now")]
+    sender_up_since: DateTime<Utc>,
+    /// Requester's probe timeout in milliseconds, carried along with indirect pings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    probe_timeout: Option<i64>,
     cluster_key: Vec<u8>,
     request: Request,
     state_changes: Vec<ArtilleryStateChange>,
@@ -52,9 +67,24 @@ struct EncSocketAddr(SocketAddr);
 enum Request {
     Heartbeat,
     Ack,
+    /// Indirect probe of the address.
     Ping(EncSocketAddr),
     AckHost(ArtilleryMember),
+    Nack(EncSocketAddr),
+    Join,
+    /// Part of the seed's membership, numbered among the count of parts.
+    JoinAck(usize, usize, Vec<ArtilleryStateChange>),
     Payload(Uuid, String),
+    Query(ArtilleryQuery),
+    QueryResponse(Uuid, String),
+}
+
+/// Indirect probe of a suspected member, sent through a couple of relays.
+#[derive(Debug, Clone)]
+struct IndirectProbe {
+    deadline: DateTime<Utc>,
+    expected_nacks: usize,
+    nacks: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -71,21 +101,49 @@ pub enum ArtilleryClusterRequest {
     LeaveCluster,
     Exit(Sender<()>),
     Payload(Uuid, String),
+    Query(
+        ArtilleryQuery,
+        Sender<ArtilleryQueryResponse>,
+        Sender<Result<()>>,
+    ),
+    RespondQuery(Uuid, String, Sender<Result<()>>),
+    Subscribe(Sender<ArtilleryClusterEvent>),
+    DownMember(Uuid),
+    SetMetadata(String, String, Sender<Result<()>>),
+    RemoveMetadata(String),
+    PhiValues(Sender<HashMap<Uuid, f64>>),
+    Join(Vec<SocketAddr>, Instant, Sender<usize>),
 }
 
 const UDP_SERVER: Token = Token(0);
+/// Acknowledged probes carrying a state change, per order of magnitude of the cluster size.
+const RETRANSMIT_MULTIPLIER: usize = 4;
 
 pub struct ArtilleryEpidemic {
     host_key: Uuid,
     config: ClusterConfig,
     members: ArtilleryMemberList,
     seed_queue: Vec<SocketAddr>,
+    known_seeds: HashSet<SocketAddr>,
+    pending_joins: Vec<(Vec<SocketAddr>, Instant, Sender<usize>)>,
+    join_parts: HashMap<SocketAddr, HashSet<usize>>,
     pending_responses: Vec<(DateTime<Utc>, SocketAddr, Vec<ArtilleryStateChange>)>,
     state_changes: Vec<ArtilleryStateChange>,
+    /// Acknowledged probes which piggybacked the state change, until it is dropped.
+    state_change_acks: Vec<(ArtilleryStateChange, usize)>,
     wait_list: WaitList,
+    nack_deadlines: Vec<(DateTime<Utc>, SocketAddr, SocketAddr)>,
+    indirect_probes: HashMap<SocketAddr, IndirectProbe>,
+    health: LocalHealth,
+    seen_queries: HashMap<Uuid, DateTime<Utc>>,
+    incoming_queries: HashMap<Uuid, ArtilleryQuery>,
+    outgoing_queries: HashMap<Uuid, (DateTime<Utc>, Sender<ArtilleryQueryResponse>)>,
     server_socket: UdpSocket,
     request_tx: ArchPadding<Sender<ArtilleryClusterRequest>>,
     event_tx: ArchPadding<Sender<ArtilleryClusterEvent>>,
+    subscribers: Vec<Sender<ArtilleryClusterEvent>>,
+    delegate: Box<dyn ArtilleryMemberDelegate>,
+    failure_detector: Box<dyn ArtilleryFailureDetector>,
     running: AtomicBool,
 }
 
@@ -94,36 +152,68 @@ pub type ClusterReactor = (Poll, ArtilleryEpidemic);
 impl ArtilleryEpidemic {
     pub fn new(
         host_key: Uuid,
-        config: ClusterConfig,
+        mut config: ClusterConfig,
         event_tx: Sender<ArtilleryClusterEvent>,
         internal_tx: Sender<ArtilleryClusterRequest>,
+        delegate: Box<dyn ArtilleryMemberDelegate>,
     ) -> Result<ClusterReactor> {
+        let size = metadata_size(&config.metadata);
+        if size > config.max_metadata_size {
+            bail!(
+                ArtilleryError::Metadata,
+                "Metadata of {} bytes exceeds the limit of {} bytes",
+                size,
+                config.max_metadata_size
+            );
+        }
+
         let poll: Poll = Poll::new()?;
 
         let interests = Interest::READABLE.add(Interest::WRITABLE);
         let mut server_socket = UdpSocket::bind(config.listen_addr)?;
         poll.registry()
             .register(&mut server_socket, UDP_SERVER, interests)?;
+        // Port zero binds an ephemeral port.
+        config.listen_addr = server_socket.local_addr()?;
 
-        let me = ArtilleryMember::current(host_key);
+        let me = ArtilleryMember::current(host_key).with_metadata(config.metadata.clone());
+        let failure_detector = config.failure_detector.build();
+        let health = LocalHealth::new(config.max_local_health);
 
         let state = ArtilleryEpidemic {
             host_key,
             config,
             members: ArtilleryMemberList::new(me.clone()),
             seed_queue: Vec::new(),
+            known_seeds: HashSet::new(),
+            pending_joins: Vec::new(),
+            join_parts: HashMap::new(),
             pending_responses: Vec::new(),
             state_changes: vec![ArtilleryStateChange::new(me)],
+            state_change_acks: Vec::new(),
             wait_list: HashMap::new(),
+            nack_deadlines: Vec::new(),
+            indirect_probes: HashMap::new(),
+            health,
+            seen_queries: HashMap::new(),
+            incoming_queries: HashMap::new(),
+            outgoing_queries: HashMap::new(),
             server_socket,
             request_tx: ArchPadding::new(internal_tx),
             event_tx: ArchPadding::new(event_tx),
+            subscribers: Vec::new(),
+            delegate,
+            failure_detector,
             running: AtomicBool::new(true),
         };
 
         Ok((poll, state))
     }
 
+    pub(crate) fn listen_addr(&self) -> SocketAddr {
+        self.config.listen_addr
+    }
+
     pub(crate) fn event_loop(
         receiver: &mut Receiver<ArtilleryClusterRequest>,
         mut poll: Poll,
@@ -133,21 +223,37 @@ impl ArtilleryEpidemic {
         let mut buf = [0_u8; CONST_PACKET_SIZE];
 
         let mut start = Instant::now();
-        let timeout = Duration::from_millis(u64::try_from(
-            state.config.ping_interval.num_milliseconds(),
+
+        let mut last_reconnect = Instant::now();
+        let reconnect_interval = Duration::from_millis(u64::try_from(
+            state.config.reconnect_interval.num_milliseconds(),
         )?);
 
         debug!("Starting Event Loop");
         // Our event loop.
         loop {
+            // Probe less often while the local health is degraded.
+            let timeout = Duration::from_millis(u64::try_from(
+                state
+                    .health
+                    .scale(state.config.ping_interval)
+                    .num_milliseconds(),
+            )?);
             let elapsed = start.elapsed();
 
             if elapsed >= timeout {
                 state.enqueue_seed_nodes();
+                state.enqueue_joins();
                 state.enqueue_random_ping();
+                state.prune_expired_queries();
                 start = Instant::now();
             }
 
+            if last_reconnect.elapsed() >= reconnect_interval {
+                state.enqueue_reconnects();
+                last_reconnect = Instant::now();
+            }
+
             if !state.running.load(Ordering::SeqCst) {
                 debug!("Stopping artillery epidemic evloop");
                 break;
@@ -175,11 +281,19 @@ impl ArtilleryEpidemic {
                     loop {
                         match state.server_socket.recv_from(&mut buf) {
                             Ok((packet_size, source_address)) => {
-                                let message = serde_json::from_slice(&buf[..packet_size])?;
-                                state.request_tx.send(ArtilleryClusterRequest::Respond(
-                                    source_address,
-                                    message,
-                                ))?;
+                                // Members running other versions may send messages we can't
+                                // read, they don't stop the event loop.
+                                match serde_json::from_slice(&buf[..packet_size]) {
+                                    Ok(message) => state.request_tx.send(
+                                        ArtilleryClusterRequest::Respond(source_address, message),
+                                    )?,
+                                    Err(e) => {
+                                        warn!(
+                                            "Dropping unreadable message from {}: {}",
+                                            source_address, e
+                                        )
+                                    }
+                                }
                             }
                             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                                 // If we get a `WouldBlock` error we know our socket
@@ -213,20 +327,29 @@ impl ArtilleryEpidemic {
     fn process_request(&mut self, request: &TargetedRequest) {
         use Request::*;
 
-        let timeout = Utc::now() + self.config.ping_timeout;
+        let now = Utc::now();
+        let ping_timeout = self.health.scale(self.config.ping_timeout);
         // It was Ping before
         let should_add_pending = request.request == Heartbeat;
+        let probe_timeout =
+            matches!(request.request, Ping(_)).then_some(ping_timeout.num_milliseconds());
         let message = build_message(
-            &self.host_key,
+            &self.members.myself(),
             &self.config.cluster_key,
             &request.request,
-            &self.state_changes,
+            probe_timeout,
+            &self.piggybacked_state_changes(request.target),
             self.config.network_mtu,
         );
 
         if should_add_pending {
-            self.pending_responses
-                .push((timeout, request.target, message.state_changes.clone()));
+            self.failure_detector
+                .ping_sent(request.target, now, ping_timeout);
+            self.pending_responses.push((
+                now + ping_timeout,
+                request.target,
+                message.state_changes.clone(),
+            ));
         }
 
         let encoded = serde_json::to_string(&message).unwrap();
@@ -237,6 +360,24 @@ impl ArtilleryEpidemic {
         self.server_socket.send_to(buf, request.target).unwrap();
     }
 
+    ///
+    /// State changes to piggyback on a message to the target. A suspected target gets
+    /// its own suspicion first, so that it can refute it right away.
+    fn piggybacked_state_changes(&self, target: SocketAddr) -> Vec<ArtilleryStateChange> {
+        let mut state_changes = self.state_changes.clone();
+
+        if let Some(suspect) = self
+            .members
+            .member_at(&target)
+            .filter(|m| m.state() == ArtilleryMemberState::Suspect)
+        {
+            state_changes.retain(|sc| sc.member().host_key() != suspect.host_key());
+            state_changes.insert(0, ArtilleryStateChange::new(suspect));
+        }
+
+        state_changes
+    }
+
     fn enqueue_seed_nodes(&self) {
         for seed_node in &self.seed_queue {
             self.request_tx
@@ -248,6 +389,55 @@ impl ArtilleryEpidemic {
         }
     }
 
+    ///
+    /// Ask the seeds of every join in progress for their membership, until one of them answers.
+    fn enqueue_joins(&mut self) {
+        let now = Instant::now();
+        self.pending_joins
+            .retain(|(_, deadline, _)| *deadline > now);
+        let pending_joins = &self.pending_joins;
+        self.join_parts.retain(|seed, _| {
+            pending_joins
+                .iter()
+                .any(|(seeds, _, _)| seeds.contains(seed))
+        });
+
+        for (seeds, _, _) in &self.pending_joins {
+            for seed in seeds {
+                self.request_tx
+                    .send(ArtilleryClusterRequest::React(TargetedRequest {
+                        request: Request::Join,
+                        target: *seed,
+                    }))
+                    .unwrap();
+            }
+        }
+    }
+
+    ///
+    /// Probe a sample of recently down members and the seeds we don't have a live member for.
+    /// Once a partition heals, these probes merge both sides of it back together.
+    fn enqueue_reconnects(&self) {
+        let mut targets = self.members.recently_down_hosts(
+            self.config.reconnect_host_count,
+            self.config.reconnect_timeout,
+        );
+
+        targets.extend(self.known_seeds.iter().filter(|&seed| {
+            !self.members.has_alive_member(seed) && !self.seed_queue.contains(seed)
+        }));
+
+        for target in targets {
+            debug!("Reconnecting to {}", target);
+            self.request_tx
+                .send(ArtilleryClusterRequest::React(TargetedRequest {
+                    request: Request::Heartbeat,
+                    target,
+                }))
+                .unwrap();
+        }
+    }
+
     fn enqueue_random_ping(&mut self) {
         if let Some(member) = self.members.next_random_member() {
             self.request_tx
@@ -262,15 +452,24 @@ impl ArtilleryEpidemic {
     fn prune_timed_out_responses(&mut self) {
         let now = Utc::now();
 
-        let (expired, remaining): (Vec<_>, Vec<_>) = self
+        let (unanswered, pending): (Vec<_>, Vec<_>) = self
             .pending_responses
-            .iter()
-            .cloned()
+            .drain(..)
             .partition(|&(t, _, _)| t < now);
+        self.pending_responses = pending;
 
-        let expired_hosts: HashSet<SocketAddr> = expired.iter().map(|&(_, a, _)| a).collect();
+        // Every unanswered probe counts once, however long the failure detector
+        // keeps reporting its target.
+        let failed_probes = unanswered
+            .iter()
+            .filter(|(_, host, _)| self.counts_toward_health(host))
+            .count();
+        (0..failed_probes).for_each(|_| self.health.degrade());
 
-        self.pending_responses = remaining;
+        let expired_hosts = self.failure_detector.unreachable_hosts(now);
+
+        self.send_nacks(now);
+        self.finish_indirect_probes(now);
 
         let (suspect, down) = self.members.time_out_nodes(&expired_hosts);
 
@@ -287,12 +486,35 @@ impl ArtilleryEpidemic {
         }
     }
 
-    fn send_ping_requests(&self, target: &ArtilleryMember) {
+    ///
+    /// Only probes of live members tell about the local health. Reconnect probes of down
+    /// members and forgotten seeds are expected to go unanswered.
+    fn counts_toward_health(&self, host: &SocketAddr) -> bool {
+        self.members.member_at(host).is_some_and(|m| {
+            matches!(
+                m.state(),
+                ArtilleryMemberState::Alive | ArtilleryMemberState::Suspect
+            )
+        })
+    }
+
+    fn send_ping_requests(&mut self, target: &ArtilleryMember) {
         if let Some(target_host) = target.remote_host() {
-            for relay in self
+            let relays = self
                 .members
-                .hosts_for_indirect_ping(self.config.ping_request_host_count, &target_host)
-            {
+                .hosts_for_indirect_ping(self.config.ping_request_host_count, &target_host);
+            let timeout = self.health.scale(self.config.ping_timeout);
+
+            self.indirect_probes.insert(
+                target_host,
+                IndirectProbe {
+                    deadline: Utc::now() + timeout,
+                    expected_nacks: relays.len(),
+                    nacks: 0,
+                },
+            );
+
+            for relay in relays {
                 self.request_tx
                     .send(ArtilleryClusterRequest::React(TargetedRequest {
                         request: Request::Ping(EncSocketAddr::from_addr(&target_host)),
@@ -303,16 +525,107 @@ impl ArtilleryEpidemic {
         }
     }
 
+    /// Longest probe timeout of a member, with its local health fully degraded.
+    fn longest_probe_timeout(&self) -> i64 {
+        let factor =
+            i64::try_from(self.config.max_local_health.saturating_add(1)).unwrap_or(i64::MAX);
+        self.config
+            .ping_timeout
+            .num_milliseconds()
+            .saturating_mul(factor)
+    }
+
+    ///
+    /// Tell the requesters of indirect pings whose target didn't answer in time,
+    /// so that they don't blame their own health for the missing ack.
+    fn send_nacks(&mut self, now: DateTime<Utc>) {
+        let (expired, remaining): (Vec<_>, Vec<_>) = self
+            .nack_deadlines
+            .iter()
+            .partition(|&&(deadline, _, _)| deadline < now);
+        self.nack_deadlines = remaining;
+
+        for (_, target, requester) in expired {
+            if let Some(waiting) = self.wait_list.get_mut(&target) {
+                if !waiting.contains(&requester) {
+                    continue;
+                }
+
+                waiting.retain(|addr| *addr != requester);
+                self.request_tx
+                    .send(ArtilleryClusterRequest::React(TargetedRequest {
+                        request: Request::Nack(EncSocketAddr::from_addr(&target)),
+                        target: requester,
+                    }))
+                    .unwrap();
+            }
+        }
+    }
+
+    ///
+    /// Indirect probes which ran out of time without all the relays answering
+    /// hint that the current node itself has trouble receiving messages.
+    fn finish_indirect_probes(&mut self, now: DateTime<Utc>) {
+        let health = &mut self.health;
+
+        self.indirect_probes.retain(|target, probe| {
+            if probe.deadline >= now {
+                return true;
+            }
+
+            if probe.nacks < probe.expected_nacks {
+                debug!(
+                    "Missing {} nacks for the probe of {}",
+                    probe.expected_nacks - probe.nacks,
+                    target
+                );
+                health.degrade();
+            }
+
+            false
+        });
+    }
+
     fn process_internal_request(&mut self, message: ArtilleryClusterRequest) -> Option<Sender<()>> {
         use ArtilleryClusterRequest::*;
 
         match message {
-            AddSeed(addr) => self.seed_queue.push(addr),
+            AddSeed(addr) => {
+                self.known_seeds.insert(addr);
+                self.seed_queue.push(addr);
+            }
+            Join(seeds, deadline, tx) => {
+                self.known_seeds.extend(seeds.iter().cloned());
+                self.pending_joins.push((seeds, deadline, tx));
+                self.enqueue_joins();
+            }
             Respond(src_addr, message) => self.respond_to_message(src_addr, message),
             React(request) => {
                 self.prune_timed_out_responses();
                 self.process_request(&request);
             }
+            SetMetadata(key, value, tx) => {
+                let mut metadata = self.members.myself().metadata().clone();
+                metadata.insert(key, value);
+
+                let size = metadata_size(&metadata);
+                if size > self.config.max_metadata_size {
+                    let _ = tx.send(Err(ArtilleryError::Metadata(format!(
+                        "Metadata of {} bytes exceeds the limit of {} bytes",
+                        size, self.config.max_metadata_size
+                    ))));
+                } else {
+                    let updated = self.members.update_metadata(|current| *current = metadata);
+                    self.announce_metadata(updated);
+                    let _ = tx.send(Ok(()));
+                }
+            }
+            RemoveMetadata(key) => {
+                let updated = self.members.update_metadata(|metadata| {
+                    metadata.remove(&key);
+                });
+                self.announce_metadata(updated);
+            }
             LeaveCluster => {
                 let myself = self.members.leave();
                 enqueue_state_change(&mut self.state_changes, &[myself]);
@@ -324,8 +637,9 @@ impl ArtilleryEpidemic {
                         return None;
                     }
 
+                    // Receivers resolve the sending member from the id carried along.
                     self.process_request(&TargetedRequest {
-                        request: Request::Payload(id, msg),
+                        request: Request::Payload(self.host_key, msg),
                         target: target_peer
                             .remote_host()
                             .expect("Expected target peer addr"),
@@ -337,44 +651,270 @@ impl ArtilleryEpidemic {
                     id
                 );
             }
+            Query(query, tx, sent_tx) => {
+                let _ = sent_tx.send(self.start_query(query, tx));
+            }
+            RespondQuery(id, msg, tx) => {
+                let _ = tx.send(self.respond_to_query(id, msg));
+            }
+            Subscribe(tx) => {
+                let current_view = (
+                    self.members.available_nodes(),
+                    ArtilleryMemberEvent::Joined(self.members.myself()),
+                );
+
+                if tx.send(current_view).is_ok() {
+                    self.subscribers.push(tx);
+                }
+            }
+            DownMember(id) => {
+                if let Some(member) = self.members.down_member(&id) {
+                    enqueue_state_change(&mut self.state_changes, std::slice::from_ref(&member));
+                    self.send_member_event(ArtilleryMemberEvent::WentDown(member));
+                }
+            }
+            PhiValues(tx) => {
+                let now = Utc::now();
+                let phi_values = self
+                    .members
+                    .available_nodes()
+                    .into_iter()
+                    .filter_map(|m| {
+                        let phi = self.failure_detector.phi(&m.remote_host()?, now)?;
+                        Some((m.host_key(), phi))
+                    })
+                    .collect();
+
+                let _ = tx.send(phi_values);
+            }
             Exit(tx) => return Some(tx),
         };
 
         None
     }
 
+    fn announce_metadata(&mut self, updated: Option<ArtilleryMember>) {
+        if let Some(myself) = updated {
+            enqueue_state_change(&mut self.state_changes, std::slice::from_ref(&myself));
+            self.send_member_event(ArtilleryMemberEvent::MetadataUpdated(myself));
+        }
+    }
+
+    fn start_query(
+        &mut self,
+        query: ArtilleryQuery,
+        tx: Sender<ArtilleryQueryResponse>,
+    ) -> Result<()> {
+        self.check_size(&Request::Query(query.clone()))?;
+
+        self.seen_queries.insert(query.id(), query.deadline());
+        self.outgoing_queries
+            .insert(query.id(), (query.deadline(), tx));
+
+        let targets: Vec<_> = self
+            .members
+            .available_nodes()
+            .iter()
+            .filter(|m| m.state() == ArtilleryMemberState::Alive)
+            .filter_map(ArtilleryMember::remote_host)
+            .collect();
+
+        for target in targets {
+            self.process_request(&TargetedRequest {
+                request: Request::Query(query.clone()),
+                target,
+            });
+        }
+
+        self.deliver_query(query);
+        Ok(())
+    }
+
+    ///
+    /// Queries and their responses must fit a packet along with the largest metadata allowed,
+    /// so that the members relaying them can send them as well.
+    fn check_size(&self, request: &Request) -> Result<()> {
+        let message = build_message(
+            &ArtilleryMember::current(self.host_key),
+            &self.config.cluster_key,
+            request,
+            None,
+            &[],
+            self.config.network_mtu,
+        );
+        let size = serde_json::to_string(&message)?.len() + self.config.max_metadata_size;
+
+        if size >= self.config.network_mtu {
+            bail!(
+                ArtilleryError::Query,
+                "Message of {} bytes exceeds the packet size of {} bytes",
+                size,
+                self.config.network_mtu
+            );
+        }
+
+        Ok(())
+    }
+
+    fn deliver_query(&mut self, query: ArtilleryQuery) {
+        if !query.matches(self.members.myself().metadata()) {
+            return;
+        }
+
+        if let Some(origin) = self.members.get_member(&query.origin()) {
+            self.incoming_queries.insert(query.id(), query.clone());
+            self.send_member_event(ArtilleryMemberEvent::Query(origin, query));
+        } else {
+            warn!(
+                "Got query {} from an unknown peer {}",
+                query.id(),
+                query.origin()
+            );
+        }
+    }
+
+    fn relay_query(&mut self, query: &ArtilleryQuery, src_addr: SocketAddr) {
+        if let Some(relayed) = query.relayed() {
+            for relay in self
+                .members
+                .hosts_for_indirect_ping(relayed.relay_factor(), &src_addr)
+            {
+                self.process_request(&TargetedRequest {
+                    request: Request::Query(relayed.clone()),
+                    target: relay,
+                });
+            }
+        }
+    }
+
+    fn respond_to_query(&mut self, id: Uuid, msg: String) -> Result<()> {
+        self.check_size(&Request::QueryResponse(id, msg.clone()))?;
+
+        let query = if let Some(query) = self.incoming_queries.remove(&id) {
+            query
+        } else {
+            warn!("Unable to respond to the unknown or expired query {}", id);
+            return Ok(());
+        };
+
+        if query.is_expired() {
+            warn!("Dropping response to the expired query {}", id);
+            return Ok(());
+        }
+
+        if query.origin() == self.host_key {
+            let myself = self.members.myself();
+            self.collect_query_response(id, myself, msg);
+        } else if let Some(target) = self
+            .members
+            .get_member(&query.origin())
+            .and_then(|m| m.remote_host())
+        {
+            self.process_request(&TargetedRequest {
+                request: Request::QueryResponse(id, msg),
+                target,
+            });
+        } else {
+            warn!("Query origin {} is not reachable anymore", query.origin());
+        }
+
+        Ok(())
+    }
+
+    fn collect_query_response(&mut self, id: Uuid, responder: ArtilleryMember, msg: String) {
+        let delivered = if let Some((_, tx)) = self.outgoing_queries.get(&id) {
+            tx.send((responder, msg)).is_ok()
+        } else {
+            debug!("Discarding response to the finished query {}", id);
+            return;
+        };
+
+        if !delivered {
+            self.outgoing_queries.remove(&id);
+        }
+    }
+
+    fn prune_expired_queries(&mut self) {
+        let now = Utc::now();
+
+        self.seen_queries.retain(|_, deadline| *deadline >= now);
+        self.incoming_queries
+            .retain(|_, query| query.deadline() >= now);
+        self.outgoing_queries
+            .retain(|_, (deadline, _)| *deadline >= now);
+    }
+
     fn respond_to_message(&mut self, src_addr: SocketAddr, message: ArtilleryMessage) {
         use Request::*;
 
         if message.cluster_key == self.config.cluster_key {
+            // Discovery backends may hand our own address out as a seed.
+            if message.sender == self.host_key {
+                debug!("Forgetting seed {}, it is this node", src_addr);
+                remove_potential_seed(&mut self.seed_queue, src_addr);
+                self.known_seeds.remove(&src_addr);
+                return;
+            }
+
+            let sender =
+                ArtilleryMember::new(message.sender, src_addr, 0, ArtilleryMemberState::Alive)
+                    .with_metadata(message.sender_metadata)
+                    .with_up_since(message.sender_up_since);
+
+            if !self.ensure_node_is_member(sender) {
+                debug!("Ignoring message from rejected node {}", src_addr);
+                return;
+            }
+
             self.apply_state_changes(message.state_changes, src_addr);
             remove_potential_seed(&mut self.seed_queue, src_addr);
 
-            self.ensure_node_is_member(src_addr, message.sender);
-
             let response = match message.request {
                 Heartbeat => Some(TargetedRequest {
                     request: Ack,
                     target: src_addr,
                 }),
                 Ack => {
+                    if self.counts_toward_health(&src_addr) {
+                        self.health.improve();
+                    }
+                    self.failure_detector.ack_received(src_addr, Utc::now());
                     self.ack_response(src_addr);
                     self.mark_node_alive(src_addr);
                     None
                 }
-                Ping(dest_addr) => {
-                    let EncSocketAddr(dest_addr) = dest_addr;
+                Ping(EncSocketAddr(dest_addr)) => {
                     add_to_wait_list(&mut self.wait_list, &dest_addr, &src_addr);
+                    // Lifeguard nacks the requester at 80% of its probe timeout, which can't
+                    // exceed ours. Requesters which don't tell it don't expect nacks.
+                    if let Some(timeout) = message.probe_timeout {
+                        let within = timeout.clamp(0, self.longest_probe_timeout());
+                        self.nack_deadlines.push((
+                            Utc::now() + chrono::Duration::milliseconds(within) * 4 / 5,
+                            dest_addr,
+                            src_addr,
+                        ));
+                    }
                     Some(TargetedRequest {
                         request: Heartbeat,
                         target: dest_addr,
                     })
                 }
                 AckHost(member) => {
+                    self.indirect_probes.remove(&member.remote_host().unwrap());
+                    self.failure_detector
+                        .ack_received(member.remote_host().unwrap(), Utc::now());
                     self.ack_response(member.remote_host().unwrap());
                     self.mark_node_alive(member.remote_host().unwrap());
                     None
                 }
+                Nack(EncSocketAddr(target)) => {
+                    if let Some(probe) = self.indirect_probes.get_mut(&target) {
+                        debug!("{} couldn't reach {} either", src_addr, target);
+                        probe.nacks += 1;
+                    }
+                    None
+                }
                 Payload(peer_id, msg) => {
                     if let Some(member) = self.members.get_member(&peer_id) {
                         self.send_member_event(ArtilleryMemberEvent::Payload(member, msg));
@@ -383,6 +923,31 @@ impl ArtilleryEpidemic {
                     }
                     None
                 }
+                Query(query) => {
+                    if !query.is_expired() && !self.seen_queries.contains_key(&query.id()) {
+                        self.seen_queries.insert(query.id(), query.deadline());
+                        self.relay_query(&query, src_addr);
+                        self.deliver_query(query);
+                    }
+                    None
+                }
+                QueryResponse(id, msg) => {
+                    if let Some(member) = self.members.get_member(&message.sender) {
+                        self.collect_query_response(id, member, msg);
+                    } else {
+                        warn!("Got query response from an unknown peer {}", message.sender);
+                    }
+                    None
+                }
+                Join => {
+                    self.send_membership(src_addr);
+                    None
+                }
+                JoinAck(part, parts, state_changes) => {
+                    self.apply_state_changes(state_changes, src_addr);
+                    self.complete_joins(src_addr, part, parts);
+                    None
+                }
             };
 
             if let Some(response) = response {
@@ -395,8 +960,82 @@ impl ArtilleryEpidemic {
         }
     }
 
+    ///
+    /// Send our whole membership to a joining node, split so that every part fits a packet.
+    fn send_membership(&self, target: SocketAddr) {
+        let budget = self.config.network_mtu / 2;
+        let mut chunks: Vec<Vec<ArtilleryStateChange>> = vec![Vec::new()];
+        let mut chunk_size = 0;
+
+        for state_change in self
+            .members
+            .available_nodes()
+            .into_iter()
+            .map(ArtilleryStateChange::new)
+        {
+            let size = serde_json::to_string(&state_change).map_or(0, |s| s.len());
+
+            if chunk_size + size > budget && chunk_size > 0 {
+                chunks.push(Vec::new());
+                chunk_size = 0;
+            }
+
+            chunk_size += size;
+            if let Some(chunk) = chunks.last_mut() {
+                chunk.push(state_change);
+            }
+        }
+
+        let parts = chunks.len();
+        for (part, chunk) in chunks.into_iter().enumerate() {
+            self.request_tx
+                .send(ArtilleryClusterRequest::React(TargetedRequest {
+                    request: Request::JoinAck(part, parts, chunk),
+                    target,
+                }))
+                .unwrap();
+        }
+    }
+
+    ///
+    /// Joins through the seed complete once every part of its membership arrived.
+    /// Lost parts are sent again when the join is retried.
+    fn complete_joins(&mut self, seed: SocketAddr, part: usize, parts: usize) {
+        if !self
+            .pending_joins
+            .iter()
+            .any(|(seeds, _, _)| seeds.contains(&seed))
+        {
+            return;
+        }
+
+        let received = self.join_parts.entry(seed).or_default();
+        received.insert(part);
+        if received.len() < parts {
+            return;
+        }
+        self.join_parts.remove(&seed);
+
+        let joined = self
+            .members
+            .available_nodes()
+            .iter()
+            .filter(|m| m.is_remote() && m.state() == ArtilleryMemberState::Alive)
+            .count();
+
+        self.pending_joins.retain(|(seeds, _, tx)| {
+            if !seeds.contains(&seed) {
+                return true;
+            }
+
+            let _ = tx.send(joined);
+            false
+        });
+    }
+
     fn ack_response(&mut self, src_addr: SocketAddr) {
         let mut to_remove = Vec::new();
+        let mut acked = Vec::new();
 
         for &(ref t, ref addr, ref state_changes) in &self.pending_responses {
             if src_addr != *addr {
@@ -405,50 +1044,131 @@ impl ArtilleryEpidemic {
 
             to_remove.push((*t, *addr, state_changes.clone()));
 
-            self.state_changes.retain(|os| {
-                !state_changes
+            // Changes updated after the message was sent still have to be disseminated.
+            acked.extend(
+                state_changes
                     .iter()
-                    .any(|is| is.member().host_key() == os.member().host_key())
-            })
+                    .filter(|is| self.state_changes.contains(is))
+                    .cloned(),
+            );
+        }
+
+        for state_change in acked {
+            match self
+                .state_change_acks
+                .iter_mut()
+                .find(|(os, _)| *os == state_change)
+            {
+                Some((_, acks)) => *acks += 1,
+                None => self.state_change_acks.push((state_change, 1)),
+            }
         }
 
+        // The first member acknowledging a change isn't the only one which has to hear about it.
+        let limit = self.retransmit_limit();
+        let acks = &self.state_change_acks;
+        self.state_changes.retain(|os| {
+            acks.iter()
+                .find(|(is, _)| is == os)
+                .is_none_or(|(_, count)| *count < limit)
+        });
+        let state_changes = &self.state_changes;
+        self.state_change_acks
+            .retain(|(is, _)| state_changes.contains(is));
+
         self.pending_responses
             .retain(|op| !to_remove.iter().any(|ip| ip == op));
     }
 
-    fn ensure_node_is_member(&mut self, src_addr: SocketAddr, sender: Uuid) {
-        if self.members.has_member(&src_addr) {
-            return;
+    ///
+    /// Acknowledged probes a state change is piggybacked on before it is dropped,
+    /// growing with the logarithm of the cluster size like SWIM suggests.
+    fn retransmit_limit(&self) -> usize {
+        let mut members = self.members.available_nodes().len();
+        let mut digits = 1;
+        while members >= 10 {
+            members /= 10;
+            digits += 1;
+        }
+
+        RETRANSMIT_MULTIPLIER * digits
+    }
+
+    fn ensure_node_is_member(&mut self, new_member: ArtilleryMember) -> bool {
+        let src_addr = new_member.remote_host().expect("Expected sender addr");
+
+        if let Some(existing) = self.members.member_at(&src_addr) {
+            if existing.host_key() != new_member.host_key() {
+                self.delegate.notify_conflict(&existing, &new_member);
+            }
+
+            return true;
         }
 
-        let new_member = ArtilleryMember::new(sender, src_addr, 0, ArtilleryMemberState::Alive);
+        if !self.delegate.allow_join(&new_member) {
+            warn!("Delegate rejected the join of {:?}", new_member);
+            return false;
+        }
 
         self.members.add_member(new_member.clone());
         enqueue_state_change(&mut self.state_changes, &[new_member.clone()]);
         self.send_member_event(ArtilleryMemberEvent::Joined(new_member));
+
+        true
     }
 
-    fn send_member_event(&self, event: ArtilleryMemberEvent) {
+    fn send_member_event(&mut self, event: ArtilleryMemberEvent) {
         use ArtilleryMemberEvent::*;
 
         match event {
-            Joined(_) | Payload(..) => {}
-            WentUp(ref m) => assert_eq!(m.state(), ArtilleryMemberState::Alive),
-            WentDown(ref m) => assert_eq!(m.state(), ArtilleryMemberState::Down),
-            SuspectedDown(ref m) => assert_eq!(m.state(), ArtilleryMemberState::Suspect),
-            Left(ref m) => assert_eq!(m.state(), ArtilleryMemberState::Left),
+            Payload(..) | Query(..) => {}
+            Joined(ref m) => self.delegate.notify_join(m),
+            WentUp(ref m) => {
+                assert_eq!(m.state(), ArtilleryMemberState::Alive);
+                self.delegate.notify_update(m);
+            }
+            MetadataUpdated(ref m) => self.delegate.notify_update(m),
+            WentDown(ref m) => {
+                assert_eq!(m.state(), ArtilleryMemberState::Down);
+                self.delegate.notify_leave(m);
+            }
+            SuspectedDown(ref m) => {
+                assert_eq!(m.state(), ArtilleryMemberState::Suspect);
+                self.delegate.notify_update(m);
+            }
+            Left(ref m) => {
+                assert_eq!(m.state(), ArtilleryMemberState::Left);
+                self.delegate.notify_leave(m);
+            }
         };
 
-        self.event_tx
-            .send((self.members.available_nodes(), event))
-            .unwrap();
+        let available_nodes = self.members.available_nodes();
+
+        self.subscribers
+            .retain(|tx| tx.send((available_nodes.clone(), event.clone())).is_ok());
+
+        self.event_tx.send((available_nodes, event)).unwrap();
     }
 
     fn apply_state_changes(&mut self, state_changes: Vec<ArtilleryStateChange>, from: SocketAddr) {
-        let (new, changed) = self.members.apply_state_changes(state_changes, &from);
+        let peers: Vec<_> = state_changes.iter().map(|sc| sc.member().clone()).collect();
+        if !self.delegate.allow_merge(&peers) {
+            warn!("Delegate rejected the membership view gossiped by {}", from);
+            return;
+        }
+
+        let (new, changed, updated) =
+            self.members
+                .apply_state_changes(state_changes, &from, self.delegate.as_mut());
+
+        if changed.iter().any(ArtilleryMember::is_current) {
+            // Refuting a suspicion means we were too slow to answer.
+            self.health.degrade();
+        }
 
         enqueue_state_change(&mut self.state_changes, &new);
         enqueue_state_change(&mut self.state_changes, &changed);
+        enqueue_state_change(&mut self.state_changes, &updated);
 
         for member in new {
             self.send_member_event(ArtilleryMemberEvent::Joined(member));
@@ -457,10 +1177,15 @@ impl ArtilleryEpidemic {
         for member in changed {
             self.send_member_event(determine_member_event(member));
         }
+
+        for member in updated {
+            self.send_member_event(ArtilleryMemberEvent::MetadataUpdated(member));
+        }
     }
 
     fn mark_node_alive(&mut self, src_addr: SocketAddr) {
-        if let Some(member) = self.members.mark_node_alive(&src_addr) {
+        // Indirect pings are answered whatever state we had the member in.
+        if let Some(member) = self.members.member_at(&src_addr) {
             if let Some(wait_list) = self.wait_list.get_mut(&src_addr) {
                 for remote in wait_list.iter() {
                     self.request_tx
@@ -473,7 +1198,9 @@ impl ArtilleryEpidemic {
 
                 wait_list.clear();
             }
+        }
 
+        if let Some(member) = self.members.mark_node_alive(&src_addr) {
             enqueue_state_change(&mut self.state_changes, &[member.clone()]);
             self.send_member_event(ArtilleryMemberEvent::WentUp(member));
         }
@@ -481,14 +1208,18 @@ impl ArtilleryEpidemic {
 }
 
 fn build_message(
-    sender: &Uuid,
+    sender: &ArtilleryMember,
     cluster_key: &[u8],
     request: &Request,
+    probe_timeout: Option<i64>,
     state_changes: &[ArtilleryStateChange],
     network_mtu: usize,
 ) -> ArtilleryMessage {
     let mut message = ArtilleryMessage {
-        sender: *sender,
+        sender: sender.host_key(),
+        sender_metadata: sender.metadata().clone(),
+        sender_up_since: sender.up_since(),
+        probe_timeout,
         cluster_key: cluster_key.into(),
         request: request.clone(),
         state_changes: Vec::new(),
@@ -496,22 +1227,33 @@ fn build_message(
 
     for i in 0..=state_changes.len() {
         flunk!("epidemic-state-change-tail-follow-fp");
-        message = ArtilleryMessage {
-            sender: *sender,
+        let candidate = ArtilleryMessage {
+            sender: sender.host_key(),
+            sender_metadata: sender.metadata().clone(),
+            sender_up_since: sender.up_since(),
+            probe_timeout,
             cluster_key: cluster_key.into(),
             request: request.clone(),
             state_changes: (&state_changes[..i]).to_vec(),
         };
 
-        let encoded = serde_json::to_string(&message).unwrap();
+        // Only piggyback the state changes that still fit the packet.
+        let encoded = serde_json::to_string(&candidate).unwrap();
         if encoded.len() >= network_mtu {
             return message;
         }
+
+        message = candidate;
     }
 
     message
 }
 
+/// Encoded size of the metadata, as carried along with every message.
+fn metadata_size(metadata: &ArtilleryMemberMetadata) -> usize {
+    serde_json::to_string(metadata).map_or(usize::MAX, |encoded| encoded.len())
+}
+
 fn add_to_wait_list(wait_list: &mut WaitList, wait_addr: &SocketAddr, notify_addr: &SocketAddr) {
     match wait_list.entry(*wait_addr) {
         Entry::Occupied(mut entry) => {
@@ -541,14 +1283,13 @@ fn enqueue_state_change(
     members: &[ArtilleryMember],
 ) {
     for member in members {
-        for state_change in state_changes.iter_mut() {
-            if state_change.member().host_key() == member.host_key() {
-                state_change.update(member.clone());
-                return;
-            }
+        match state_changes
+            .iter_mut()
+            .find(|state_change| state_change.member().host_key() == member.host_key())
+        {
+            Some(state_change) => state_change.update(member.clone()),
+            None => state_changes.push(ArtilleryStateChange::new(member.clone())),
         }
-
-        state_changes.push(ArtilleryStateChange::new(member.clone()));
     }
 }
 
@@ -557,3 +1298,480 @@ impl EncSocketAddr {
         EncSocketAddr(*addr)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::epidemic::delegate::DefaultMemberDelegate;
+    use crate::epidemic::failure_detector::{FailureDetectorConfig, PhiAccrualConfig};
+    use crate::epidemic::query::ArtilleryQueryParams;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    /// Reactor driven by hand.
+    struct Node {
+        state: ArtilleryEpidemic,
+        requests: Receiver<ArtilleryClusterRequest>,
+        events: Receiver<ArtilleryClusterEvent>,
+    }
+
+    fn config() -> ClusterConfig {
+        ClusterConfig {
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            ping_timeout: chrono::Duration::milliseconds(10),
+            ..Default::default()
+        }
+    }
+
+    impl Node {
+        fn new(config: ClusterConfig) -> Self {
+            let (event_tx, events) = channel();
+            let (request_tx, requests) = channel();
+            let (_, state) = ArtilleryEpidemic::new(
+                Uuid::new_v4(),
+                config,
+                event_tx,
+                request_tx,
+                Box::new(DefaultMemberDelegate),
+            )
+            .unwrap();
+
+            Node {
+                state,
+                requests,
+                events,
+            }
+        }
+
+        /// Send the requests the node reacted with so far.
+        fn react(&mut self) -> Vec<TargetedRequest> {
+            let mut sent = Vec::new();
+            while let Ok(queued) = self.requests.try_recv() {
+                if let ArtilleryClusterRequest::React(request) = queued {
+                    self.state.process_request(&request);
+                    sent.push(request);
+                }
+            }
+            sent
+        }
+
+        fn receive(&mut self, from: &Peer, request: &Request) {
+            self.receive_probe(from, request, None);
+        }
+
+        fn receive_probe(&mut self, from: &Peer, request: &Request, probe_timeout: Option<i64>) {
+            let message = build_message(
+                &from.member,
+                &self.state.config.cluster_key,
+                request,
+                probe_timeout,
+                &[],
+                self.state.config.network_mtu,
+            );
+            self.state.respond_to_message(from.addr(), message);
+        }
+
+        fn went_up(&self, peer: &Peer) -> bool {
+            self.events.try_iter().any(|(_, event)| {
+                matches!(event, ArtilleryMemberEvent::WentUp(m) if m.host_key() == peer.member.host_key())
+            })
+        }
+    }
+
+    /// Remote node which only answers when told to.
+    struct Peer {
+        member: ArtilleryMember,
+        _socket: std::net::UdpSocket,
+    }
+
+    impl Peer {
+        fn new() -> Self {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let member = ArtilleryMember::new(
+                Uuid::new_v4(),
+                socket.local_addr().unwrap(),
+                0,
+                ArtilleryMemberState::Alive,
+            );
+
+            Peer {
+                member,
+                _socket: socket,
+            }
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.member.remote_host().unwrap()
+        }
+    }
+
+    fn probes(sent: &[TargetedRequest], peer: &Peer) -> usize {
+        sent.iter()
+            .filter(|r| r.request == Request::Heartbeat && r.target == peer.addr())
+            .count()
+    }
+
+    fn nacks(sent: &[TargetedRequest], requester: &Peer, target: &Peer) -> usize {
+        let nack = Request::Nack(EncSocketAddr(target.addr()));
+
+        sent.iter()
+            .filter(|r| r.request == nack && r.target == requester.addr())
+            .count()
+    }
+
+    #[test]
+    fn test_down_members_are_probed_again_and_rejoin() {
+        let mut node = Node::new(config());
+        let peer = Peer::new();
+        node.receive(&peer, &Request::Heartbeat);
+        node.react();
+
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::DownMember(peer.member.host_key()));
+        node.state.enqueue_reconnects();
+        assert_eq!(probes(&node.react(), &peer), 1);
+
+        node.receive(&peer, &Request::Ack);
+        assert!(node.went_up(&peer));
+    }
+
+    #[test]
+    fn test_oversized_queries_are_refused() {
+        let mut node = Node::new(config());
+        let peer = Peer::new();
+        node.receive(&peer, &Request::Heartbeat);
+        node.react();
+        let oversized = "?".repeat(node.state.config.network_mtu);
+
+        let query = |payload: &str| {
+            ArtilleryQuery::new(
+                node.state.host_key,
+                "who",
+                payload.to_string(),
+                ArtilleryQueryParams::default(),
+            )
+        };
+        let (oversized_query, small_query) = (query(&oversized), query("?"));
+        let (responses_tx, _responses) = channel();
+        let (refused_tx, refused) = channel();
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::Query(
+                oversized_query,
+                responses_tx.clone(),
+                refused_tx,
+            ));
+        assert!(matches!(
+            refused.recv().unwrap(),
+            Err(ArtilleryError::Query(_))
+        ));
+        assert!(node.react().is_empty());
+
+        let (sent_tx, sent) = channel();
+        let id = small_query.id();
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::Query(
+                small_query,
+                responses_tx,
+                sent_tx,
+            ));
+        assert!(sent.recv().unwrap().is_ok());
+
+        let (responded_tx, responded) = channel();
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::RespondQuery(
+                id,
+                oversized,
+                responded_tx,
+            ));
+        assert!(matches!(
+            responded.recv().unwrap(),
+            Err(ArtilleryError::Query(_))
+        ));
+    }
+
+    #[test]
+    fn test_oversized_metadata_is_refused() {
+        let mut node = Node::new(config());
+        let limit = node.state.config.max_metadata_size;
+
+        let (refused_tx, refused_rx) = channel();
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::SetMetadata(
+                "k".into(),
+                "v".repeat(limit),
+                refused_tx,
+            ));
+        assert!(refused_rx.recv().unwrap().is_err());
+        assert!(node.state.members.myself().metadata().is_empty());
+
+        let (accepted_tx, accepted_rx) = channel();
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::SetMetadata(
+                "k".into(),
+                "v".into(),
+                accepted_tx,
+            ));
+        assert!(accepted_rx.recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_state_changes_outlive_the_first_ack() {
+        let mut node = Node::new(config());
+        let peer = Peer::new();
+        node.receive(&peer, &Request::Heartbeat);
+        node.react();
+
+        let (tx, _rx) = channel();
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::SetMetadata(
+                "k".into(),
+                "v".into(),
+                tx,
+            ));
+        let piggybacked = |probed: &Node| {
+            probed
+                .state
+                .state_changes
+                .iter()
+                .any(|sc| sc.member().metadata().contains_key("k"))
+        };
+
+        for _ in 0..RETRANSMIT_MULTIPLIER {
+            assert!(piggybacked(&node));
+            node.state.process_request(&TargetedRequest {
+                request: Request::Heartbeat,
+                target: peer.addr(),
+            });
+            node.receive(&peer, &Request::Ack);
+        }
+        assert!(!piggybacked(&node));
+    }
+
+    #[test]
+    fn test_seeds_are_probed_again_after_a_partition() {
+        let mut node = Node::new(config());
+        let seed = Peer::new();
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::AddSeed(seed.addr()));
+        // Seeds are probed by the seed queue until they answer.
+        node.state.enqueue_reconnects();
+        assert_eq!(probes(&node.react(), &seed), 0);
+
+        node.receive(&seed, &Request::Heartbeat);
+        node.react();
+
+        // The partition outlived the down members worth reconnecting to, the seed is still known.
+        node.state.config.reconnect_host_count = 0;
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::DownMember(seed.member.host_key()));
+        node.state.enqueue_reconnects();
+        assert_eq!(probes(&node.react(), &seed), 1);
+
+        node.receive(&seed, &Request::Ack);
+        assert!(node.went_up(&seed));
+    }
+
+    #[test]
+    fn test_reconnect_probes_dont_count_toward_local_health() {
+        let mut node = Node::new(config());
+        let (down, alive) = (Peer::new(), Peer::new());
+        node.receive(&down, &Request::Heartbeat);
+        node.receive(&alive, &Request::Heartbeat);
+        node.react();
+
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::DownMember(down.member.host_key()));
+        node.state.enqueue_reconnects();
+        node.react();
+        thread::sleep(Duration::from_millis(50));
+        node.state.prune_timed_out_responses();
+        assert_eq!(node.state.health.score(), 0);
+
+        node.state.process_request(&TargetedRequest {
+            request: Request::Heartbeat,
+            target: alive.addr(),
+        });
+        thread::sleep(Duration::from_millis(50));
+        node.state.prune_timed_out_responses();
+        assert_eq!(node.state.health.score(), 1);
+    }
+
+    #[test]
+    fn test_failed_probe_degrades_health_once() {
+        let mut node = Node::new(ClusterConfig {
+            failure_detector: FailureDetectorConfig::PhiAccrual(PhiAccrualConfig {
+                min_std_deviation: chrono::Duration::milliseconds(1),
+                acceptable_heartbeat_pause: chrono::Duration::zero(),
+                first_heartbeat_estimate: chrono::Duration::milliseconds(8),
+                ..Default::default()
+            }),
+            ..config()
+        });
+        let peer = Peer::new();
+        node.receive(&peer, &Request::Heartbeat);
+        node.react();
+
+        node.state.process_request(&TargetedRequest {
+            request: Request::Heartbeat,
+            target: peer.addr(),
+        });
+        thread::sleep(Duration::from_millis(50));
+        // Phi keeps reporting the host until it answers.
+        node.state.prune_timed_out_responses();
+        node.state.prune_timed_out_responses();
+        assert_eq!(node.state.health.score(), 1);
+    }
+
+    #[test]
+    fn test_relays_nack_at_the_requester_timeout() {
+        let mut node = Node::new(ClusterConfig {
+            ping_timeout: chrono::Duration::milliseconds(50),
+            ..config()
+        });
+        let (requester, target) = (Peer::new(), Peer::new());
+
+        node.receive_probe(
+            &requester,
+            &Request::Ping(EncSocketAddr(target.addr())),
+            Some(200),
+        );
+        assert_eq!(probes(&node.react(), &target), 1);
+
+        thread::sleep(Duration::from_millis(100));
+        node.state.prune_timed_out_responses();
+        assert_eq!(nacks(&node.react(), &requester, &target), 0);
+
+        thread::sleep(Duration::from_millis(100));
+        node.state.prune_timed_out_responses();
+        assert_eq!(nacks(&node.react(), &requester, &target), 1);
+    }
+
+    #[test]
+    fn test_relays_bound_the_requester_timeout() {
+        let mut node = Node::new(config());
+        let (target, longest) = (Peer::new(), node.state.longest_probe_timeout());
+
+        for timeout in [i64::MAX, i64::MIN, -1] {
+            node.receive_probe(
+                &Peer::new(),
+                &Request::Ping(EncSocketAddr(target.addr())),
+                Some(timeout),
+            );
+        }
+        let bound = Utc::now() + chrono::Duration::milliseconds(longest);
+        assert_eq!(node.state.nack_deadlines.len(), 3);
+        assert!(node
+            .state
+            .nack_deadlines
+            .iter()
+            .all(|(at, _, _)| *at <= bound));
+
+        // Requesters which don't tell their timeout don't get nacks.
+        node.receive(&Peer::new(), &Request::Ping(EncSocketAddr(target.addr())));
+        assert_eq!(node.state.nack_deadlines.len(), 3);
+    }
+
+    #[test]
+    fn test_pings_keep_their_wire_shape() {
+        let addr: SocketAddr = "127.0.0.1:7946".parse().unwrap();
+        let encoded = serde_json::to_value(Request::Ping(EncSocketAddr(addr))).unwrap();
+        assert_eq!(encoded, serde_json::json!({ "Ping": "127.0.0.1:7946" }));
+    }
+
+    #[test]
+    fn test_relays_dont_nack_answered_pings() {
+        let mut node = Node::new(config());
+        let (requester, target) = (Peer::new(), Peer::new());
+
+        node.receive_probe(
+            &requester,
+            &Request::Ping(EncSocketAddr(target.addr())),
+            Some(50),
+        );
+        node.react();
+        node.receive(&target, &Request::Ack);
+        let acked = node.react();
+        assert!(acked.iter().any(|r| {
+            r.target == requester.addr()
+                && matches!(&r.request, Request::AckHost(m) if m.host_key() == target.member.host_key())
+        }));
+
+        thread::sleep(Duration::from_millis(100));
+        node.state.prune_timed_out_responses();
+        assert_eq!(nacks(&node.react(), &requester, &target), 0);
+    }
+
+    #[test]
+    fn test_missing_nacks_degrade_health() {
+        for (nacking, score) in [(2, 0), (1, 1)] {
+            let mut node = Node::new(config());
+            let (suspect, relays) = (Peer::new(), [Peer::new(), Peer::new()]);
+            for peer in relays.iter().chain(Some(&suspect)) {
+                node.receive(peer, &Request::Heartbeat);
+            }
+            node.react();
+
+            node.state.send_ping_requests(&suspect.member);
+            let pings = node.react();
+            for relay in &relays {
+                assert!(pings.iter().any(|r| r.target == relay.addr()
+                    && matches!(r.request, Request::Ping(EncSocketAddr(addr)) if addr == suspect.addr())));
+            }
+
+            for relay in &relays[..nacking] {
+                node.receive(relay, &Request::Nack(EncSocketAddr(suspect.addr())));
+            }
+            thread::sleep(Duration::from_millis(50));
+            node.state.finish_indirect_probes(Utc::now());
+            assert_eq!(node.state.health.score(), score);
+        }
+    }
+
+    #[test]
+    fn test_suspects_get_their_suspicion_first() {
+        let mut node = Node::new(config());
+        let (suspect, other) = (Peer::new(), Peer::new());
+        node.receive(&suspect, &Request::Heartbeat);
+        node.receive(&other, &Request::Heartbeat);
+
+        let expired = vec![suspect.addr()].into_iter().collect();
+        let (suspected, _) = node.state.members.time_out_nodes(&expired);
+        enqueue_state_change(&mut node.state.state_changes, &suspected);
+
+        let buddy = node.state.piggybacked_state_changes(suspect.addr());
+        assert_eq!(buddy[0].member().host_key(), suspect.member.host_key());
+        assert_eq!(buddy[0].member().state(), ArtilleryMemberState::Suspect);
+        assert_eq!(
+            buddy.len(),
+            node.state.piggybacked_state_changes(other.addr()).len()
+        );
+
+        let gossip = node.state.piggybacked_state_changes(other.addr());
+        assert_ne!(gossip[0].member().host_key(), suspect.member.host_key());
+    }
+
+    #[test]
+    fn test_join_completes_with_the_last_part_of_the_membership() {
+        let mut node = Node::new(config());
+        let seed = Peer::new();
+        let (joined_tx, joined) = channel();
+        node.state
+            .process_internal_request(ArtilleryClusterRequest::Join(
+                vec![seed.addr()],
+                Instant::now() + Duration::from_secs(10),
+                joined_tx,
+            ));
+
+        let parts: Vec<_> = [Peer::new(), Peer::new(), Peer::new()]
+            .iter()
+            .map(|p| vec![ArtilleryStateChange::new(p.member.clone())])
+            .collect();
+        for (part, state_changes) in parts.into_iter().enumerate().rev() {
+            assert!(joined.try_recv().is_err());
+            node.receive(&seed, &Request::JoinAck(part, 3, state_changes));
+        }
+
+        // The seed and the members of the three parts.
+        assert_eq!(joined.try_recv().unwrap(), 4);
+    }
+}
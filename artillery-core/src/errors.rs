@@ -3,6 +3,7 @@ use std::io;
 
 use std::result;
 use std::sync::mpsc::{RecvError, SendError};
+use uuid::Uuid;
 
 /// Result type for operations that could result in an `ArtilleryError`
 pub type Result<T> = result::Result<T, ArtilleryError>;
@@ -26,6 +27,18 @@ pub enum ArtilleryError {
     Decoding(String),
     #[fail(display = "Artillery :: Numeric Cast Error: {}", _0)]
     NumericCast(String),
+    #[fail(display = "Artillery :: Cluster Join Error: {}", _0)]
+    Join(String),
+    #[fail(display = "Artillery :: Service Discovery Error: {}", _0)]
+    ServiceDiscovery(String),
+    #[fail(display = "Artillery :: Consensus Error: {}", _0)]
+    Consensus(String),
+    #[fail(display = "Artillery :: Not the leader, known leader: {:?}", _0)]
+    NotLeader(Option<Uuid>),
+    #[fail(display = "Artillery :: Member Metadata Error: {}", _0)]
+    Metadata(String),
+    #[fail(display = "Artillery :: Cluster Query Error: {}", _0)]
+    Query(String),
 }
 
 impl From<io::Error> for ArtilleryError {
@@ -52,12 +65,36 @@ impl From<std::sync::mpsc::RecvError> for ArtilleryError {
     }
 }
 
+impl From<std::sync::mpsc::RecvTimeoutError> for ArtilleryError {
+    fn from(e: std::sync::mpsc::RecvTimeoutError) -> Self {
+        ArtilleryError::Receive(e.to_string())
+    }
+}
+
 impl From<std::str::Utf8Error> for ArtilleryError {
     fn from(e: std::str::Utf8Error) -> Self {
         ArtilleryError::Decoding(e.to_string())
     }
 }
 
+impl From<dns_parser::Error> for ArtilleryError {
+    fn from(e: dns_parser::Error) -> Self {
+        ArtilleryError::Decoding(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for ArtilleryError {
+    fn from(e: bincode::Error) -> Self {
+        ArtilleryError::Decoding(e.to_string())
+    }
+}
+
+impl From<ureq::Error> for ArtilleryError {
+    fn from(e: ureq::Error) -> Self {
+        ArtilleryError::ServiceDiscovery(e.to_string())
+    }
+}
+
 impl From<std::num::TryFromIntError> for ArtilleryError {
     fn from(e: std::num::TryFromIntError) -> Self {
         ArtilleryError::NumericCast(e.to_string())
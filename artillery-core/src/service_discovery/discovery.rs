@@ -0,0 +1,127 @@
+use crate::epidemic::cluster::Cluster;
+use crate::errors::*;
+
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServiceDiscoveryEvent {
+    /// Endpoint became reachable through the discovery backend.
+    Discovered(SocketAddr),
+    /// Endpoint isn't advertised by the discovery backend anymore.
+    Lost(SocketAddr),
+}
+
+///
+/// Common interface of the service discovery backends.
+pub trait ServiceDiscovery: Send + Sync {
+    /// Stream of discovered and lost endpoints.
+    /// Endpoints known at the time of the call are replayed as discovered first.
+    fn subscribe(&self) -> Result<Receiver<ServiceDiscoveryEvent>>;
+
+    /// Add every discovered endpoint, except the listen address of the cluster itself,
+    /// as a seed of the cluster. Blocks until the discovery is shut down.
+    fn seed_cluster(&self, cluster: &Cluster) -> Result<()> {
+        for event in &self.subscribe()? {
+            match event {
+                ServiceDiscoveryEvent::Discovered(addr) if addr != cluster.listen_addr() => {
+                    cluster.add_seed_node(addr)
+                }
+                ServiceDiscoveryEvent::Discovered(_) | ServiceDiscoveryEvent::Lost(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Endpoints currently known by a backend, and the subscribers to notify about changes.
+#[derive(Debug, Default)]
+pub struct DiscoveredEndpoints {
+    known: BTreeSet<SocketAddr>,
+    subscribers: Vec<Sender<ServiceDiscoveryEvent>>,
+}
+
+impl DiscoveredEndpoints {
+    pub fn known(&self) -> &BTreeSet<SocketAddr> {
+        &self.known
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<ServiceDiscoveryEvent> {
+        let (tx, rx) = channel();
+        self.add_subscriber(tx);
+
+        rx
+    }
+
+    pub fn add_subscriber(&mut self, tx: Sender<ServiceDiscoveryEvent>) {
+        let replayed = self
+            .known
+            .iter()
+            .all(|addr| tx.send(ServiceDiscoveryEvent::Discovered(*addr)).is_ok());
+
+        if replayed {
+            self.subscribers.push(tx);
+        }
+    }
+
+    pub fn discovered(&mut self, addr: SocketAddr) {
+        if self.known.insert(addr) {
+            self.notify(ServiceDiscoveryEvent::Discovered(addr));
+        }
+    }
+
+    pub fn lost(&mut self, addr: SocketAddr) {
+        if self.known.remove(&addr) {
+            self.notify(ServiceDiscoveryEvent::Lost(addr));
+        }
+    }
+
+    /// Replace the known endpoints, notifying the difference.
+    pub fn replace(&mut self, current: &BTreeSet<SocketAddr>) {
+        let lost: Vec<_> = self.known.difference(current).cloned().collect();
+        let discovered: Vec<_> = current.difference(&self.known).cloned().collect();
+
+        lost.into_iter().for_each(|addr| self.lost(addr));
+        discovered
+            .into_iter()
+            .for_each(|addr| self.discovered(addr));
+    }
+
+    fn notify(&mut self, event: ServiceDiscoveryEvent) {
+        self.subscribers.retain(|tx| tx.send(event).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_replace_notifies_the_difference() {
+        let mut endpoints = DiscoveredEndpoints::default();
+        endpoints.discovered(addr(1));
+        endpoints.discovered(addr(2));
+
+        let events = endpoints.subscribe();
+        endpoints.discovered(addr(2));
+        endpoints.replace(&vec![addr(2), addr(3)].into_iter().collect());
+        drop(endpoints);
+
+        assert_eq!(
+            events.iter().collect::<Vec<_>>(),
+            vec![
+                ServiceDiscoveryEvent::Discovered(addr(1)),
+                ServiceDiscoveryEvent::Discovered(addr(2)),
+                ServiceDiscoveryEvent::Lost(addr(1)),
+                ServiceDiscoveryEvent::Discovered(addr(3)),
+            ]
+        );
+    }
+}
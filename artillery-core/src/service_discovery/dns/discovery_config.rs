@@ -0,0 +1,33 @@
+use crate::constants::*;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct DNSServiceDiscoveryConfig {
+    /// Name to resolve, e.g. the headless service of the cluster.
+    /// Its SRV records are resolved along with its A and AAAA records.
+    pub name: String,
+    /// Port of the endpoints resolved through A and AAAA records.
+    pub port: u16,
+    /// Nameserver to ask, the first one of `/etc/resolv.conf` if not given.
+    pub nameserver: Option<SocketAddr>,
+    /// Domains a relative name is searched in, those of `/etc/resolv.conf` if not given.
+    pub search: Option<Vec<String>>,
+    /// How often the name is resolved again.
+    pub poll_interval: Duration,
+    /// How long to wait for each answer of the nameserver.
+    pub query_timeout: Duration,
+}
+
+impl Default for DNSServiceDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            name: "artillery".into(),
+            port: CONST_INFECTION_PORT,
+            nameserver: None,
+            search: None,
+            poll_interval: Duration::from_secs(10),
+            query_timeout: Duration::from_secs(2),
+        }
+    }
+}
@@ -0,0 +1,7 @@
+pub mod discovery_config;
+pub mod sd;
+
+pub mod prelude {
+    pub use super::discovery_config::*;
+    pub use super::sd::*;
+}
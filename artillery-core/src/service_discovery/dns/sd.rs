@@ -0,0 +1,723 @@
+use crate::errors::*;
+use crate::service_discovery::discovery::{
+    DiscoveredEndpoints, ServiceDiscovery, ServiceDiscoveryEvent,
+};
+use crate::service_discovery::dns::discovery_config::DNSServiceDiscoveryConfig;
+use bastion_executor::blocking::spawn_blocking;
+use dns_parser::{Builder, Packet, QueryClass, QueryType, RData, ResourceRecord, ResponseCode};
+use lightproc::proc_stack::ProcStack;
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+///
+/// Seeds resolved from the A, AAAA and SRV records of a name, e.g. a headless service.
+/// The name is resolved periodically and endpoints which disappeared are reported as lost.
+pub struct DNSServiceDiscovery {
+    endpoints: Arc<Mutex<DiscoveredEndpoints>>,
+    running: Arc<AtomicBool>,
+}
+
+unsafe impl Send for DNSServiceDiscovery {}
+unsafe impl Sync for DNSServiceDiscovery {}
+
+impl DNSServiceDiscovery {
+    pub fn new_service_discovery(mut config: DNSServiceDiscoveryConfig) -> Result<Self> {
+        let nameserver = match config.nameserver {
+            Some(nameserver) => nameserver,
+            None => ResolvConf::read()?.nameserver()?,
+        };
+        if config.search.is_none() {
+            config.search = Some(
+                ResolvConf::read()
+                    .map(|conf| conf.search)
+                    .unwrap_or_default(),
+            );
+        }
+        let endpoints = Arc::new(Mutex::new(DiscoveredEndpoints::default()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        query_name(&config.name)?;
+        refresh(&config, nameserver, &endpoints);
+
+        let resolved_endpoints = endpoints.clone();
+        let resolving = running.clone();
+        debug!("Resolving seeds of {} through {}", config.name, nameserver);
+        let _resolver_handle = spawn_blocking(
+            async move {
+                loop {
+                    std::thread::sleep(config.poll_interval);
+
+                    if !resolving.load(Ordering::SeqCst) {
+                        debug!("Stopping DNS seed resolution");
+                        break;
+                    }
+
+                    refresh(&config, nameserver, &resolved_endpoints);
+                }
+            },
+            ProcStack::default(),
+        );
+
+        Ok(Self { endpoints, running })
+    }
+
+    /// Endpoints resolved by the latest successful resolution.
+    pub fn endpoints(&self) -> BTreeSet<SocketAddr> {
+        self.endpoints
+            .lock()
+            .expect("Discovered endpoints are poisoned")
+            .known()
+            .clone()
+    }
+
+    /// Shutdown Service Discovery
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.running.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl ServiceDiscovery for DNSServiceDiscovery {
+    fn subscribe(&self) -> Result<Receiver<ServiceDiscoveryEvent>> {
+        Ok(self
+            .endpoints
+            .lock()
+            .expect("Discovered endpoints are poisoned")
+            .subscribe())
+    }
+}
+
+impl Drop for DNSServiceDiscovery {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+fn refresh(
+    config: &DNSServiceDiscoveryConfig,
+    nameserver: SocketAddr,
+    endpoints: &Mutex<DiscoveredEndpoints>,
+) {
+    // Failed resolutions keep the previous endpoints,
+    // an unreachable nameserver doesn't mean the members are gone.
+    match resolve(config, nameserver) {
+        Ok(current) => endpoints
+            .lock()
+            .expect("Discovered endpoints are poisoned")
+            .replace(&current),
+        Err(e) => warn!("Can't resolve seeds of {}: {}", config.name, e),
+    }
+}
+
+/// Resolve the endpoints behind the SRV records of the configured name,
+/// and its A and AAAA records along with the configured port.
+/// The first of the searched names which has any endpoint wins.
+pub fn resolve(
+    config: &DNSServiceDiscoveryConfig,
+    nameserver: SocketAddr,
+) -> Result<BTreeSet<SocketAddr>> {
+    let resolver = Resolver::new(nameserver, config.query_timeout)?;
+    let search = config.search.as_deref().unwrap_or(&[]);
+
+    for name in search_names(&config.name, search) {
+        let endpoints = resolver.endpoints(&name, config.port)?;
+        if !endpoints.is_empty() {
+            return Ok(endpoints);
+        }
+    }
+
+    Ok(BTreeSet::new())
+}
+
+///
+/// Names to try for the given one, in order. Like the system resolver does by default,
+/// relative names with a dot are tried as given first, and after the search domains otherwise.
+fn search_names(name: &str, search: &[String]) -> Vec<String> {
+    if name.ends_with('.') {
+        return vec![name.to_string()];
+    }
+
+    let searched = search
+        .iter()
+        .map(|domain| format!("{}.{}", name, domain.trim_end_matches('.')));
+
+    if name.contains('.') {
+        Some(name.to_string()).into_iter().chain(searched).collect()
+    } else {
+        searched.chain(Some(name.to_string())).collect()
+    }
+}
+
+/// Nameservers and search domains of `/etc/resolv.conf`.
+#[derive(Debug, Default, PartialEq)]
+struct ResolvConf {
+    nameservers: Vec<SocketAddr>,
+    search: Vec<String>,
+}
+
+impl ResolvConf {
+    fn read() -> Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string("/etc/resolv.conf")?))
+    }
+
+    fn parse(contents: &str) -> Self {
+        let mut conf = ResolvConf::default();
+
+        for line in contents.lines() {
+            let mut fields = line.split_whitespace();
+
+            match fields.next() {
+                Some("nameserver") => conf.nameservers.extend(
+                    fields
+                        .next()
+                        .and_then(|ip| ip.parse::<IpAddr>().ok())
+                        .map(|ip| SocketAddr::new(ip, 53)),
+                ),
+                // The last search or domain line wins.
+                Some("search") | Some("domain") => {
+                    conf.search = fields.map(String::from).collect();
+                }
+                Some(_) | None => {}
+            }
+        }
+
+        conf
+    }
+
+    fn nameserver(&self) -> Result<SocketAddr> {
+        self.nameservers
+            .first()
+            .copied()
+            .ok_or_else(|| ArtilleryError::Unexpected("No nameserver in /etc/resolv.conf".into()))
+    }
+}
+
+/// Name without its root label, as expected by the query builder.
+fn query_name(name: &str) -> Result<&str> {
+    let relative = name.trim_end_matches('.');
+
+    if relative
+        .split('.')
+        .any(|label| label.is_empty() || label.len() > 63)
+    {
+        bail!(ArtilleryError::Unexpected, "Invalid DNS name {:?}", name);
+    }
+
+    Ok(relative)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Record {
+    Address(String, IpAddr),
+    Service(String, u16),
+}
+
+impl Record {
+    fn from_resource(record: &ResourceRecord) -> Option<Self> {
+        let name = record.name.to_string();
+
+        match &record.data {
+            RData::A(a) => Some(Record::Address(name, IpAddr::V4(a.0))),
+            RData::AAAA(aaaa) => Some(Record::Address(name, IpAddr::V6(aaaa.0))),
+            RData::SRV(srv) => Some(Record::Service(srv.target.to_string(), srv.port)),
+            RData::CNAME(_)
+            | RData::MX(_)
+            | RData::NS(_)
+            | RData::PTR(_)
+            | RData::SOA(_)
+            | RData::TXT(_)
+            | RData::Unknown(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Answer {
+    answers: Vec<Record>,
+    additional: Vec<Record>,
+}
+
+impl Answer {
+    fn services(&self) -> Vec<(String, u16)> {
+        self.answers
+            .iter()
+            .filter_map(|record| match record {
+                Record::Service(target, port) => Some((target.clone(), *port)),
+                Record::Address(..) => None,
+            })
+            .collect()
+    }
+
+    /// Addresses of the name given along with the answers, sparing a query per SRV target.
+    fn addresses_of(&self, name: &str) -> Vec<IpAddr> {
+        let relative = name.trim_end_matches('.');
+
+        self.answers
+            .iter()
+            .chain(&self.additional)
+            .filter_map(|record| match record {
+                Record::Address(owner, ip)
+                    if owner.trim_end_matches('.').eq_ignore_ascii_case(relative) =>
+                {
+                    Some(*ip)
+                }
+                Record::Address(..) | Record::Service(..) => None,
+            })
+            .collect()
+    }
+}
+
+struct Resolver {
+    socket: UdpSocket,
+    nameserver: SocketAddr,
+    timeout: Duration,
+}
+
+impl Resolver {
+    fn new(nameserver: SocketAddr, timeout: Duration) -> Result<Self> {
+        let bind_addr = if nameserver.is_ipv4() {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+        } else {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.set_read_timeout(Some(timeout))?;
+
+        Ok(Self {
+            socket,
+            nameserver,
+            timeout,
+        })
+    }
+
+    /// Endpoints of the SRV targets of the name, and of its own addresses along with the port.
+    fn endpoints(&self, name: &str, port: u16) -> Result<BTreeSet<SocketAddr>> {
+        let mut endpoints = BTreeSet::new();
+
+        let services = self.query(name, QueryType::SRV)?;
+        for (target, target_port) in services.services() {
+            let mut addrs = services.addresses_of(&target);
+            if addrs.is_empty() {
+                // A single broken target doesn't hide the other ones.
+                addrs = match self.addresses(&target) {
+                    Ok(resolved) => resolved,
+                    Err(e) => {
+                        warn!("Skipping SRV target {} of {}: {}", target, name, e);
+                        continue;
+                    }
+                };
+            }
+
+            endpoints.extend(addrs.into_iter().map(|ip| SocketAddr::new(ip, target_port)));
+        }
+
+        endpoints.extend(
+            self.addresses(name)?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port)),
+        );
+
+        Ok(endpoints)
+    }
+
+    /// Addresses of the name, from its A and AAAA records.
+    fn addresses(&self, name: &str) -> Result<Vec<IpAddr>> {
+        let mut addrs = Vec::new();
+
+        for qtype in &[QueryType::A, QueryType::AAAA] {
+            addrs.extend(
+                self.query(name, *qtype)?
+                    .answers
+                    .into_iter()
+                    .filter_map(|record| match record {
+                        Record::Address(_, ip) => Some(ip),
+                        Record::Service(..) => None,
+                    }),
+            );
+        }
+
+        Ok(addrs)
+    }
+
+    fn query(&self, name: &str, qtype: QueryType) -> Result<Answer> {
+        let id = rand::random();
+        let mut builder = Builder::new_query(id, true);
+        builder.add_question(query_name(name)?, false, qtype, QueryClass::IN);
+        let query = match builder.build() {
+            Ok(query) => query,
+            Err(_) => bail!(
+                ArtilleryError::Unexpected,
+                "DNS query for {} is too long",
+                name
+            ),
+        };
+
+        let mut response = self.exchange_udp(&query, id)?;
+        if Packet::parse(&response).is_ok_and(|packet| packet.header.truncated) {
+            debug!("Answer for {} is truncated, asking again over TCP", name);
+            response = self.exchange_tcp(&query)?;
+        }
+
+        let packet = Packet::parse(&response)?;
+        if packet.header.id != id {
+            bail!(
+                ArtilleryError::Unexpected,
+                "Nameserver answered another query than the one for {}",
+                name
+            );
+        }
+
+        match packet.header.response_code {
+            ResponseCode::NoError => Ok(Answer {
+                answers: packet
+                    .answers
+                    .iter()
+                    .filter_map(Record::from_resource)
+                    .collect(),
+                additional: packet
+                    .additional
+                    .iter()
+                    .filter_map(Record::from_resource)
+                    .collect(),
+            }),
+            ResponseCode::NameError => Ok(Answer::default()),
+            ResponseCode::FormatError
+            | ResponseCode::ServerFailure
+            | ResponseCode::NotImplemented
+            | ResponseCode::Refused
+            | ResponseCode::Reserved(_) => Err(ArtilleryError::Unexpected(format!(
+                "Nameserver answered {:?} for {}",
+                packet.header.response_code, name
+            ))),
+        }
+    }
+
+    fn exchange_udp(&self, query: &[u8], id: u16) -> Result<Vec<u8>> {
+        self.socket.send_to(query, self.nameserver)?;
+
+        let mut buf = [0_u8; 4096];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf)?;
+            let response = buf.get(..len).unwrap_or_default();
+            if let Ok(packet) = Packet::parse(response) {
+                if from == self.nameserver && packet.header.id == id {
+                    return Ok(response.to_vec());
+                }
+            }
+        }
+    }
+
+    /// Messages sent over TCP are prefixed with their length.
+    fn exchange_tcp(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(&self.nameserver, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        stream.write_all(&u16::try_from(query.len())?.to_be_bytes())?;
+        stream.write_all(query)?;
+
+        let mut len = [0_u8; 2];
+        stream.read_exact(&mut len)?;
+        let mut response = vec![0_u8; usize::from(u16::from_be_bytes(len))];
+        stream.read_exact(&mut response)?;
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::net::TcpListener;
+    use std::thread;
+
+    type Records = Arc<Mutex<HashMap<(String, u16), Vec<Vec<u8>>>>>;
+
+    ///
+    /// Nameserver answering the questions it has records for, and NXDOMAIN otherwise.
+    /// When truncating, answers over UDP are empty with the TC bit set and only TCP carries them.
+    struct StubNameserver {
+        addr: SocketAddr,
+        records: Records,
+        failing: Arc<Mutex<HashSet<String>>>,
+        truncating: Arc<AtomicBool>,
+    }
+
+    impl StubNameserver {
+        fn start() -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let addr = socket.local_addr().unwrap();
+            let listener = TcpListener::bind(addr).unwrap();
+            let nameserver = StubNameserver {
+                addr,
+                records: Arc::default(),
+                failing: Arc::default(),
+                truncating: Arc::default(),
+            };
+
+            let (udp_records, udp_failing, truncating) = nameserver.served();
+            thread::spawn(move || {
+                let mut buf = [0_u8; 512];
+                while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                    let mut response = answer(&buf[..len], &udp_records, &udp_failing);
+                    if truncating.load(Ordering::SeqCst) {
+                        response.truncate(len);
+                        response[2] |= 0x02;
+                        response[7] = 0;
+                    }
+
+                    let _ = socket.send_to(&response, from);
+                }
+            });
+
+            let (tcp_records, tcp_failing, _) = nameserver.served();
+            thread::spawn(move || {
+                for mut stream in listener.incoming().filter_map(std::result::Result::ok) {
+                    let mut len = [0_u8; 2];
+                    stream.read_exact(&mut len).unwrap();
+                    let mut query = vec![0_u8; usize::from(u16::from_be_bytes(len))];
+                    stream.read_exact(&mut query).unwrap();
+
+                    let response = answer(&query, &tcp_records, &tcp_failing);
+                    let response_len = u16::try_from(response.len()).unwrap();
+                    stream.write_all(&response_len.to_be_bytes()).unwrap();
+                    stream.write_all(&response).unwrap();
+                }
+            });
+
+            nameserver
+        }
+
+        fn served(&self) -> (Records, Arc<Mutex<HashSet<String>>>, Arc<AtomicBool>) {
+            (
+                self.records.clone(),
+                self.failing.clone(),
+                self.truncating.clone(),
+            )
+        }
+
+        fn add(&self, name: &str, qtype: QueryType, rdata: Vec<u8>) {
+            let mut rr = encode_name(name);
+            rr.extend(&(qtype as u16).to_be_bytes());
+            rr.extend(&1_u16.to_be_bytes());
+            rr.extend(&60_u32.to_be_bytes());
+            rr.extend(&u16::try_from(rdata.len()).unwrap().to_be_bytes());
+            rr.extend(rdata);
+
+            self.records
+                .lock()
+                .unwrap()
+                .entry((name.to_string(), qtype as u16))
+                .or_default()
+                .push(rr);
+        }
+
+        /// Answer SERVFAIL to every question about the name.
+        fn fail(&self, name: &str) {
+            self.failing.lock().unwrap().insert(name.to_string());
+        }
+
+        fn clear(&self) {
+            self.records.lock().unwrap().clear();
+        }
+    }
+
+    fn answer(query: &[u8], records: &Records, failing: &Mutex<HashSet<String>>) -> Vec<u8> {
+        let question = Packet::parse(query).unwrap().questions.remove(0);
+        let name = question.qname.to_string();
+        let found = records
+            .lock()
+            .unwrap()
+            .get(&(name.clone(), question.qtype as u16))
+            .cloned();
+
+        let mut response = query.to_vec();
+        // Response flag, and NXDOMAIN when there is nothing to answer.
+        response[2] |= 0x80;
+        response[3] = match (failing.lock().unwrap().contains(&name), &found) {
+            (true, _) => 0x02,
+            (false, Some(_)) => 0x00,
+            (false, None) => 0x03,
+        };
+        let rrs = found.unwrap_or_default();
+        response[7] = u8::try_from(rrs.len()).unwrap();
+        rrs.iter().for_each(|rr| response.extend(rr));
+
+        response
+    }
+
+    fn encode_name(name: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for label in name.split('.') {
+            encoded.push(u8::try_from(label.len()).unwrap());
+            encoded.extend(label.as_bytes());
+        }
+        encoded.push(0);
+        encoded
+    }
+
+    fn srv(port: u16, target: &str) -> Vec<u8> {
+        let mut rdata = vec![0, 10, 0, 10];
+        rdata.extend(&port.to_be_bytes());
+        rdata.extend(encode_name(target));
+        rdata
+    }
+
+    fn config(nameserver: &StubNameserver) -> DNSServiceDiscoveryConfig {
+        DNSServiceDiscoveryConfig {
+            name: "artillery.default.svc.cluster.local".into(),
+            port: 27845,
+            nameserver: Some(nameserver.addr),
+            search: None,
+            poll_interval: Duration::from_millis(50),
+            query_timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn test_resolve_address_and_service_records() {
+        let nameserver = StubNameserver::start();
+        let name = "artillery.default.svc.cluster.local";
+        nameserver.add(name, QueryType::A, vec![10, 0, 0, 1]);
+        nameserver.add(name, QueryType::SRV, srv(28000, "node-2.artillery.local"));
+        nameserver.add("node-2.artillery.local", QueryType::A, vec![10, 0, 0, 2]);
+        nameserver.add(
+            "node-2.artillery.local",
+            QueryType::AAAA,
+            Ipv6Addr::LOCALHOST.octets().to_vec(),
+        );
+
+        let endpoints = resolve(&config(&nameserver), nameserver.addr).unwrap();
+
+        assert_eq!(
+            endpoints,
+            vec![
+                "10.0.0.1:27845".parse().unwrap(),
+                "10.0.0.2:28000".parse().unwrap(),
+                "[::1]:28000".parse().unwrap(),
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn test_changed_records_are_discovered_and_lost() {
+        let nameserver = StubNameserver::start();
+        let name = "artillery.default.svc.cluster.local";
+        nameserver.add(name, QueryType::A, vec![10, 0, 0, 1]);
+
+        let sd = DNSServiceDiscovery::new_service_discovery(config(&nameserver)).unwrap();
+        let events = sd.subscribe().unwrap();
+        let timeout = Duration::from_secs(5);
+        let first: SocketAddr = "10.0.0.1:27845".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:27845".parse().unwrap();
+
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Discovered(first)
+        );
+
+        nameserver.clear();
+        nameserver.add(name, QueryType::A, vec![10, 0, 0, 2]);
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Lost(first)
+        );
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Discovered(second)
+        );
+        assert_eq!(sd.endpoints(), vec![second].into_iter().collect());
+    }
+
+    #[test]
+    fn test_labels_up_to_63_bytes_are_valid() {
+        assert!(query_name(&format!("{}.local", "a".repeat(63))).is_ok());
+        assert!(query_name(&format!("{}.local", "a".repeat(64))).is_err());
+        assert!(query_name("artillery..local").is_err());
+    }
+
+    #[test]
+    fn test_truncated_answers_are_asked_again_over_tcp() {
+        let nameserver = StubNameserver::start();
+        let name = "artillery.default.svc.cluster.local";
+        nameserver.add(name, QueryType::A, vec![10, 0, 0, 1]);
+        nameserver.truncating.store(true, Ordering::SeqCst);
+
+        let endpoints = resolve(&config(&nameserver), nameserver.addr).unwrap();
+        assert_eq!(
+            endpoints,
+            vec!["10.0.0.1:27845".parse().unwrap()]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_failing_service_targets_are_skipped() {
+        let nameserver = StubNameserver::start();
+        let name = "artillery.default.svc.cluster.local";
+        nameserver.add(name, QueryType::SRV, srv(28000, "node-1.artillery.local"));
+        nameserver.add(name, QueryType::SRV, srv(28000, "node-2.artillery.local"));
+        nameserver.add("node-2.artillery.local", QueryType::A, vec![10, 0, 0, 2]);
+        nameserver.fail("node-1.artillery.local");
+
+        let endpoints = resolve(&config(&nameserver), nameserver.addr).unwrap();
+        assert_eq!(
+            endpoints,
+            vec!["10.0.0.2:28000".parse().unwrap()]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_relative_names_are_searched() {
+        let conf = ResolvConf::parse(
+            "# generated\nnameserver 10.96.0.10\nsearch default.svc.cluster.local svc.cluster.local\noptions ndots:5\n",
+        );
+        assert_eq!(conf.nameserver().unwrap(), "10.96.0.10:53".parse().unwrap());
+        assert_eq!(
+            search_names("artillery", &conf.search),
+            vec![
+                "artillery.default.svc.cluster.local",
+                "artillery.svc.cluster.local",
+                "artillery",
+            ]
+        );
+        assert_eq!(
+            search_names("artillery.default", &conf.search)[0],
+            "artillery.default"
+        );
+        assert_eq!(search_names("artillery.", &conf.search), vec!["artillery."]);
+
+        let nameserver = StubNameserver::start();
+        nameserver.add(
+            "artillery.svc.cluster.local",
+            QueryType::A,
+            vec![10, 0, 0, 1],
+        );
+        let config = DNSServiceDiscoveryConfig {
+            name: "artillery".into(),
+            search: Some(conf.search),
+            ..config(&nameserver)
+        };
+
+        let endpoints = resolve(&config, nameserver.addr).unwrap();
+        assert_eq!(
+            endpoints,
+            vec!["10.0.0.1:27845".parse().unwrap()]
+                .into_iter()
+                .collect()
+        );
+    }
+}
@@ -0,0 +1,19 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct FileServiceDiscoveryConfig {
+    /// File listing one `host:port` endpoint per line. Empty lines and `#` comments are ignored.
+    pub path: PathBuf,
+    /// How often the file is read again.
+    pub poll_interval: Duration,
+}
+
+impl Default for FileServiceDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("seeds"),
+            poll_interval: Duration::from_secs(5),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::errors::*;
+use crate::service_discovery::discovery::{
+    DiscoveredEndpoints, ServiceDiscovery, ServiceDiscoveryEvent,
+};
+use crate::service_discovery::file::discovery_config::FileServiceDiscoveryConfig;
+use bastion_executor::blocking::spawn_blocking;
+use lightproc::proc_stack::ProcStack;
+
+use std::collections::BTreeSet;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+
+///
+/// Seeds read from a file which is watched for changes.
+/// Endpoints removed from the file are reported as lost.
+pub struct FileServiceDiscovery {
+    endpoints: Arc<Mutex<DiscoveredEndpoints>>,
+    running: Arc<AtomicBool>,
+}
+
+unsafe impl Send for FileServiceDiscovery {}
+unsafe impl Sync for FileServiceDiscovery {}
+
+impl FileServiceDiscovery {
+    pub fn new_service_discovery(config: FileServiceDiscoveryConfig) -> Result<Self> {
+        let endpoints = Arc::new(Mutex::new(DiscoveredEndpoints::default()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        refresh(&config.path, &endpoints);
+
+        let watched_endpoints = endpoints.clone();
+        let watching = running.clone();
+        debug!("Watching seeds file {:?}", config.path);
+        let _watcher_handle = spawn_blocking(
+            async move {
+                loop {
+                    std::thread::sleep(config.poll_interval);
+
+                    if !watching.load(Ordering::SeqCst) {
+                        debug!("Stopping seeds file watcher");
+                        break;
+                    }
+
+                    refresh(&config.path, &watched_endpoints);
+                }
+            },
+            ProcStack::default(),
+        );
+
+        Ok(Self { endpoints, running })
+    }
+
+    /// Endpoints currently listed in the file.
+    pub fn endpoints(&self) -> BTreeSet<SocketAddr> {
+        self.endpoints
+            .lock()
+            .expect("Discovered endpoints are poisoned")
+            .known()
+            .clone()
+    }
+
+    /// Shutdown Service Discovery
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.running.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl ServiceDiscovery for FileServiceDiscovery {
+    fn subscribe(&self) -> Result<Receiver<ServiceDiscoveryEvent>> {
+        Ok(self
+            .endpoints
+            .lock()
+            .expect("Discovered endpoints are poisoned")
+            .subscribe())
+    }
+}
+
+impl Drop for FileServiceDiscovery {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+fn refresh(path: &Path, endpoints: &Mutex<DiscoveredEndpoints>) {
+    let current = match std::fs::read_to_string(path) {
+        Ok(contents) => parse_endpoints(&contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+        Err(e) => {
+            warn!("Can't read seeds file {:?}: {}", path, e);
+            return;
+        }
+    };
+
+    endpoints
+        .lock()
+        .expect("Discovered endpoints are poisoned")
+        .replace(&current);
+}
+
+/// Resolve the endpoints listed one per line, skipping empty lines and `#` comments.
+pub fn parse_endpoints(contents: &str) -> BTreeSet<SocketAddr> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .flat_map(|line| match line.to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                warn!("Skipping seed {}: {}", line, e);
+                Vec::new()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_endpoints() {
+        let endpoints = parse_endpoints(
+            "# seeds\n127.0.0.1:27845\n\n  127.0.0.1:27846 # second\nnot an endpoint\n",
+        );
+
+        assert_eq!(
+            endpoints.into_iter().collect::<Vec<_>>(),
+            vec![
+                SocketAddr::from(([127, 0, 0, 1], 27845)),
+                SocketAddr::from(([127, 0, 0, 1], 27846)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_changes_are_discovered() {
+        let path = std::env::temp_dir().join(format!("artillery-seeds-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "127.0.0.1:27845\n").unwrap();
+
+        let sd = FileServiceDiscovery::new_service_discovery(FileServiceDiscoveryConfig {
+            path: path.clone(),
+            poll_interval: Duration::from_millis(50),
+        })
+        .unwrap();
+        let events = sd.subscribe().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Discovered(SocketAddr::from(([127, 0, 0, 1], 27845)))
+        );
+
+        std::fs::write(&path, "127.0.0.1:27846\n").unwrap();
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Lost(SocketAddr::from(([127, 0, 0, 1], 27845)))
+        );
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Discovered(SocketAddr::from(([127, 0, 0, 1], 27846)))
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
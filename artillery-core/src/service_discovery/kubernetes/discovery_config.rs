@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KubernetesResource {
+    /// `v1/Endpoints`, labeled like the service they belong to.
+    Endpoints,
+    /// `discovery.k8s.io/v1/EndpointSlice`, which also carry the `kubernetes.io/service-name` label.
+    EndpointSlices,
+}
+
+#[derive(Debug, Clone)]
+pub struct KubernetesServiceDiscoveryConfig {
+    /// Base URL of the API server.
+    /// Built from `KUBERNETES_SERVICE_HOST` and `KUBERNETES_SERVICE_PORT` if not given.
+    pub api_server: Option<String>,
+    /// Namespace to look into, the one of the service account if not given.
+    pub namespace: Option<String>,
+    /// Label selector of the watched resources, e.g. `app=artillery-ap`.
+    pub label_selector: String,
+    pub resource: KubernetesResource,
+    /// Name of the endpoint port peers listen on, the first port of each endpoint if not given.
+    pub port_name: Option<String>,
+    /// Directory of the service account `token`, `ca.crt` and `namespace`.
+    /// Requests are sent without a bearer token if there is no token.
+    pub service_account_dir: PathBuf,
+    /// How long a watch lasts before the API server closes it and it is started again.
+    pub watch_timeout: Duration,
+    /// How long to wait before listing again after a failed request.
+    pub retry_interval: Duration,
+}
+
+impl Default for KubernetesServiceDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            api_server: None,
+            namespace: None,
+            label_selector: "app=artillery-ap".into(),
+            resource: KubernetesResource::Endpoints,
+            port_name: None,
+            service_account_dir: PathBuf::from("/var/run/secrets/kubernetes.io/serviceaccount"),
+            watch_timeout: Duration::from_secs(60),
+            retry_interval: Duration::from_secs(5),
+        }
+    }
+}
@@ -0,0 +1,559 @@
+use crate::errors::*;
+use crate::service_discovery::discovery::{
+    DiscoveredEndpoints, ServiceDiscovery, ServiceDiscoveryEvent,
+};
+use crate::service_discovery::kubernetes::discovery_config::{
+    KubernetesResource, KubernetesServiceDiscoveryConfig,
+};
+use bastion_executor::blocking::spawn_blocking;
+use lightproc::proc_stack::ProcStack;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use ureq::tls::{PemItem, RootCerts, TlsConfig};
+use ureq::Agent;
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+///
+/// Peers listed and watched through the Kubernetes API,
+/// from the `Endpoints` or `EndpointSlices` matching a label selector.
+/// Only ready addresses are reported, and the ones which aren't anymore are reported as lost.
+pub struct KubernetesServiceDiscovery {
+    endpoints: Arc<Mutex<DiscoveredEndpoints>>,
+    running: Arc<AtomicBool>,
+}
+
+unsafe impl Send for KubernetesServiceDiscovery {}
+unsafe impl Sync for KubernetesServiceDiscovery {}
+
+impl KubernetesServiceDiscovery {
+    pub fn new_service_discovery(config: KubernetesServiceDiscoveryConfig) -> Result<Self> {
+        let client = ApiClient::new(&config)?;
+        let endpoints = Arc::new(Mutex::new(DiscoveredEndpoints::default()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let watched_endpoints = endpoints.clone();
+        let watching = running.clone();
+        debug!(
+            "Watching {:?} matching {} at {}",
+            config.resource, config.label_selector, client.collection_url
+        );
+        let _watcher_handle = spawn_blocking(
+            async move {
+                while watching.load(Ordering::SeqCst) {
+                    let synced = match config.resource {
+                        KubernetesResource::Endpoints => {
+                            client.sync::<Endpoints>(&watched_endpoints, &watching)
+                        }
+                        KubernetesResource::EndpointSlices => {
+                            client.sync::<EndpointSlice>(&watched_endpoints, &watching)
+                        }
+                    };
+
+                    if let Err(e) = synced {
+                        warn!("Kubernetes discovery failed, listing again: {}", e);
+                        std::thread::sleep(config.retry_interval);
+                    }
+                }
+
+                debug!("Stopping Kubernetes discovery");
+            },
+            ProcStack::default(),
+        );
+
+        Ok(Self { endpoints, running })
+    }
+
+    /// Peers of the latest known state of the watched resources.
+    pub fn endpoints(&self) -> BTreeSet<SocketAddr> {
+        self.endpoints
+            .lock()
+            .expect("Discovered endpoints are poisoned")
+            .known()
+            .clone()
+    }
+
+    /// Shutdown Service Discovery.
+    /// The ongoing watch ends at the latest once its `watch_timeout` elapsed.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.running.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl ServiceDiscovery for KubernetesServiceDiscovery {
+    fn subscribe(&self) -> Result<Receiver<ServiceDiscoveryEvent>> {
+        Ok(self
+            .endpoints
+            .lock()
+            .expect("Discovered endpoints are poisoned")
+            .subscribe())
+    }
+}
+
+impl Drop for KubernetesServiceDiscovery {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+///
+/// Watched resource kind, and how its peers are read.
+trait PeerSource: DeserializeOwned {
+    fn name(&self) -> &str;
+
+    fn peers(&self, port_name: Option<&str>) -> BTreeSet<SocketAddr>;
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ObjectMeta {
+    name: String,
+    resource_version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ObjectList<T> {
+    metadata: ObjectMeta,
+    #[serde(default = "Vec::new")]
+    items: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    object: serde_json::Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct EndpointPort {
+    name: Option<String>,
+    port: u16,
+}
+
+/// Port peers listen on, the named one or the first one.
+fn select_port(ports: &[EndpointPort], port_name: Option<&str>) -> Option<u16> {
+    ports
+        .iter()
+        .find(|port| port_name.is_none_or(|name| port.name.as_deref() == Some(name)))
+        .map(|port| port.port)
+}
+
+fn peers_of<'a>(ips: impl Iterator<Item = &'a String>, port: Option<u16>) -> BTreeSet<SocketAddr> {
+    match port {
+        Some(number) => ips
+            .filter_map(|ip| ip.parse::<IpAddr>().ok())
+            .map(|ip| SocketAddr::new(ip, number))
+            .collect(),
+        None => BTreeSet::new(),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Endpoints {
+    metadata: ObjectMeta,
+    subsets: Vec<EndpointSubset>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct EndpointSubset {
+    addresses: Vec<EndpointAddress>,
+    ports: Vec<EndpointPort>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct EndpointAddress {
+    ip: String,
+}
+
+impl PeerSource for Endpoints {
+    fn name(&self) -> &str {
+        &self.metadata.name
+    }
+
+    fn peers(&self, port_name: Option<&str>) -> BTreeSet<SocketAddr> {
+        // Addresses which aren't ready are listed apart, under `notReadyAddresses`.
+        self.subsets
+            .iter()
+            .flat_map(|subset| {
+                peers_of(
+                    subset.addresses.iter().map(|address| &address.ip),
+                    select_port(&subset.ports, port_name),
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct EndpointSlice {
+    metadata: ObjectMeta,
+    endpoints: Vec<SliceEndpoint>,
+    ports: Option<Vec<EndpointPort>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SliceEndpoint {
+    addresses: Vec<String>,
+    conditions: EndpointConditions,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct EndpointConditions {
+    ready: Option<bool>,
+}
+
+impl PeerSource for EndpointSlice {
+    fn name(&self) -> &str {
+        &self.metadata.name
+    }
+
+    fn peers(&self, port_name: Option<&str>) -> BTreeSet<SocketAddr> {
+        let port = select_port(self.ports.as_deref().unwrap_or_default(), port_name);
+
+        // An unknown readiness has to be interpreted as ready.
+        self.endpoints
+            .iter()
+            .filter(|endpoint| endpoint.conditions.ready.unwrap_or(true))
+            .flat_map(|endpoint| peers_of(endpoint.addresses.iter(), port))
+            .collect()
+    }
+}
+
+struct ApiClient {
+    agent: Agent,
+    collection_url: String,
+    label_selector: String,
+    port_name: Option<String>,
+    token_path: PathBuf,
+    watch_timeout: Duration,
+}
+
+impl ApiClient {
+    fn new(config: &KubernetesServiceDiscoveryConfig) -> Result<Self> {
+        let api_server = match &config.api_server {
+            Some(api_server) => api_server.trim_end_matches('/').to_string(),
+            None => in_cluster_api_server()?,
+        };
+        let namespace = match &config.namespace {
+            Some(namespace) => namespace.clone(),
+            None => std::fs::read_to_string(config.service_account_dir.join("namespace"))?
+                .trim()
+                .to_string(),
+        };
+        let collection_url = match config.resource {
+            KubernetesResource::Endpoints => {
+                format!("{}/api/v1/namespaces/{}/endpoints", api_server, namespace)
+            }
+            KubernetesResource::EndpointSlices => format!(
+                "{}/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices",
+                api_server, namespace
+            ),
+        };
+
+        let mut tls_config = TlsConfig::builder();
+        if let Ok(ca) = std::fs::read(config.service_account_dir.join("ca.crt")) {
+            let certs = ureq::tls::parse_pem(&ca)
+                .filter_map(|item| match item {
+                    Ok(PemItem::Certificate(cert)) => Some(cert),
+                    Ok(_) | Err(_) => None,
+                })
+                .collect::<Vec<_>>();
+            tls_config = tls_config.root_certs(RootCerts::new_with_certs(&certs));
+        }
+
+        let agent = Agent::new_with_config(
+            Agent::config_builder()
+                .tls_config(tls_config.build())
+                .timeout_connect(Some(config.retry_interval))
+                .timeout_recv_response(Some(config.retry_interval))
+                // Leave the API server some time to close the watch on its own.
+                .timeout_recv_body(Some(config.watch_timeout * 2))
+                .build(),
+        );
+
+        Ok(Self {
+            agent,
+            collection_url,
+            label_selector: config.label_selector.clone(),
+            port_name: config.port_name.clone(),
+            token_path: config.service_account_dir.join("token"),
+            watch_timeout: config.watch_timeout,
+        })
+    }
+
+    /// List the resources, then watch them until the watch can't be resumed anymore.
+    fn sync<T: PeerSource>(
+        &self,
+        endpoints: &Mutex<DiscoveredEndpoints>,
+        running: &AtomicBool,
+    ) -> Result<()> {
+        let list: ObjectList<T> = serde_json::from_reader(self.get(false, "")?)?;
+        let mut resource_version = list.metadata.resource_version;
+        let mut peers: HashMap<String, BTreeSet<SocketAddr>> = list
+            .items
+            .iter()
+            .map(|item| {
+                (
+                    item.name().to_string(),
+                    item.peers(self.port_name.as_deref()),
+                )
+            })
+            .collect();
+        publish(&peers, endpoints);
+
+        while running.load(Ordering::SeqCst) {
+            let events = BufReader::new(self.get(true, &resource_version)?);
+
+            for line in events.lines() {
+                let event: WatchEvent = serde_json::from_str(&line?)?;
+                let object_version = event
+                    .object
+                    .pointer("/metadata/resourceVersion")
+                    .and_then(|version| version.as_str())
+                    .map(ToString::to_string);
+
+                match event.kind.as_str() {
+                    "ADDED" | "MODIFIED" => {
+                        let item: T = serde_json::from_value(event.object)?;
+                        peers.insert(
+                            item.name().to_string(),
+                            item.peers(self.port_name.as_deref()),
+                        );
+                    }
+                    "DELETED" => {
+                        let item: T = serde_json::from_value(event.object)?;
+                        peers.remove(item.name());
+                    }
+                    "BOOKMARK" => {}
+                    // Mostly `410 Gone`, the resource version is too old to resume from.
+                    _ => {
+                        debug!("Watch ended with {}: {}", event.kind, event.object);
+                        return Ok(());
+                    }
+                }
+
+                resource_version = object_version.unwrap_or(resource_version);
+                publish(&peers, endpoints);
+
+                if !running.load(Ordering::SeqCst) {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, watch: bool, resource_version: &str) -> Result<impl std::io::Read> {
+        let mut request = self
+            .agent
+            .get(&self.collection_url)
+            .query("labelSelector", &self.label_selector);
+
+        if watch {
+            request = request
+                .query("watch", "true")
+                .query("allowWatchBookmarks", "true")
+                .query("resourceVersion", resource_version)
+                .query("timeoutSeconds", self.watch_timeout.as_secs().to_string());
+        }
+
+        // Service account tokens are rotated, they are read again for every request.
+        if let Ok(token) = std::fs::read_to_string(&self.token_path) {
+            request = request.header("Authorization", format!("Bearer {}", token.trim()));
+        }
+
+        Ok(request
+            .call()?
+            .into_body()
+            .into_with_config()
+            .limit(u64::MAX)
+            .reader())
+    }
+}
+
+fn publish(peers: &HashMap<String, BTreeSet<SocketAddr>>, endpoints: &Mutex<DiscoveredEndpoints>) {
+    let current = peers.values().flatten().cloned().collect();
+
+    endpoints
+        .lock()
+        .expect("Discovered endpoints are poisoned")
+        .replace(&current);
+}
+
+fn in_cluster_api_server() -> Result<String> {
+    match (
+        std::env::var("KUBERNETES_SERVICE_HOST"),
+        std::env::var("KUBERNETES_SERVICE_PORT"),
+    ) {
+        (Ok(host), Ok(port)) if host.contains(':') => Ok(format!("https://[{}]:{}", host, port)),
+        (Ok(host), Ok(port)) => Ok(format!("https://{}:{}", host, port)),
+        (Err(_), _) | (_, Err(_)) => bail!(
+            ArtilleryError::ServiceDiscovery,
+            "No API server given, and not running in a Kubernetes cluster"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    ///
+    /// API server answering lists with the given body, and the first watch with the given events.
+    /// Requests are recorded so that their query and headers can be checked.
+    fn mock_api_server(list: &'static str, events: &'static str) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        thread::spawn(move || {
+            let mut watches = 0;
+            for incoming in listener.incoming() {
+                let mut stream = incoming.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0_u8; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).unwrap();
+                    head.extend(&buf[..len]);
+                }
+                let request = String::from_utf8(head).unwrap();
+
+                let body = match (request.contains("watch=true"), watches) {
+                    (false, _) => list,
+                    (true, 0) => {
+                        watches += 1;
+                        events
+                    }
+                    // Later watches stay idle, like an API server without changes.
+                    (true, _) => {
+                        thread::sleep(Duration::from_millis(200));
+                        ""
+                    }
+                };
+                let _ = tx.send(request);
+
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+
+        (url, rx)
+    }
+
+    fn config(
+        api_server: String,
+        resource: KubernetesResource,
+    ) -> KubernetesServiceDiscoveryConfig {
+        let service_account_dir =
+            std::env::temp_dir().join(format!("artillery-sa-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&service_account_dir).unwrap();
+        std::fs::write(service_account_dir.join("token"), "test-token\n").unwrap();
+        std::fs::write(service_account_dir.join("namespace"), "artillery").unwrap();
+
+        KubernetesServiceDiscoveryConfig {
+            api_server: Some(api_server),
+            label_selector: "app=artillery-ap".into(),
+            resource,
+            port_name: Some("gossip".into()),
+            service_account_dir,
+            watch_timeout: Duration::from_secs(5),
+            retry_interval: Duration::from_millis(100),
+            ..Default::default()
+        }
+    }
+
+    fn addr(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 27845)
+    }
+
+    #[test]
+    fn test_endpoints_are_listed_and_watched() {
+        let list = r#"{"metadata":{"resourceVersion":"10"},"items":[
+            {"metadata":{"name":"artillery-ap"},"subsets":[{
+                "addresses":[{"ip":"10.0.0.1"}],"notReadyAddresses":[{"ip":"10.0.0.9"}],
+                "ports":[{"name":"metrics","port":9000},{"name":"gossip","port":27845}]}]}]}"#;
+        let events = concat!(
+            r#"{"type":"ADDED","object":{"metadata":{"name":"other","resourceVersion":"11"},"subsets":[{"addresses":[{"ip":"10.0.0.2"}],"ports":[{"name":"gossip","port":27845}]}]}}"#,
+            "\n",
+            r#"{"type":"DELETED","object":{"metadata":{"name":"artillery-ap","resourceVersion":"12"}}}"#,
+            "\n"
+        );
+        let (url, requests) = mock_api_server(list, events);
+
+        let sd = KubernetesServiceDiscovery::new_service_discovery(config(
+            url,
+            KubernetesResource::Endpoints,
+        ))
+        .unwrap();
+        let discovered = sd.subscribe().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(
+            discovered.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Discovered(addr("10.0.0.1"))
+        );
+        assert_eq!(
+            discovered.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Discovered(addr("10.0.0.2"))
+        );
+        assert_eq!(
+            discovered.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Lost(addr("10.0.0.1"))
+        );
+
+        let list_request = requests.recv_timeout(timeout).unwrap();
+        assert!(list_request.starts_with(
+            "GET /api/v1/namespaces/artillery/endpoints?labelSelector=app%3Dartillery-ap "
+        ));
+        assert!(list_request.contains("authorization: Bearer test-token\r\n"));
+
+        let watch_request = requests.recv_timeout(timeout).unwrap();
+        assert!(watch_request.contains("watch=true"));
+        assert!(watch_request.contains("resourceVersion=10"));
+    }
+
+    #[test]
+    fn test_only_ready_slice_endpoints_are_peers() {
+        let slice: EndpointSlice = serde_json::from_str(
+            r#"{"metadata":{"name":"artillery-ap-x7k2p"},
+                "ports":[{"name":"gossip","port":27845}],
+                "endpoints":[
+                    {"addresses":["10.0.0.1"],"conditions":{"ready":true}},
+                    {"addresses":["10.0.0.2"],"conditions":{"ready":false}},
+                    {"addresses":["10.0.0.3"]}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            slice.peers(Some("gossip")),
+            vec![addr("10.0.0.1"), addr("10.0.0.3")]
+                .into_iter()
+                .collect()
+        );
+        assert!(slice.peers(Some("metrics")).is_empty());
+    }
+}
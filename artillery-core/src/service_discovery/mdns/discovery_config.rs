@@ -1,11 +1,26 @@
 use crate::constants::*;
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::time::Duration;
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct MDNSServiceDiscoveryConfig {
     pub reply_ttl: Duration,
     pub local_service_addr: SocketAddr,
+    /// DNS-SD service type nodes are advertised and browsed under.
+    pub service_type: String,
+    /// Instance name of the node, advertised in its `node_id` TXT entry.
+    pub node_id: Uuid,
+    /// Advertised in the `app_name` TXT entry, only nodes of the same app are discovered.
+    pub app_name: String,
+    /// Additional TXT entries advertised along with the node.
+    pub metadata: BTreeMap<String, String>,
+    /// Interval between two browsings of the service type.
+    pub query_interval: Duration,
+    pub mdns_addr: SocketAddrV4,
+    /// Interface joining the mDNS group, all of them by default.
+    pub interface: Ipv4Addr,
 }
 
 impl Default for MDNSServiceDiscoveryConfig {
@@ -15,6 +30,13 @@ impl Default for MDNSServiceDiscoveryConfig {
         Self {
             reply_ttl: Duration::from_secs(120),
             local_service_addr,
+            service_type: CONST_MDNS_SERVICE_TYPE.into(),
+            node_id: Uuid::new_v4(),
+            app_name: "artillery".into(),
+            metadata: BTreeMap::new(),
+            query_interval: Duration::from_secs(20),
+            mdns_addr: SocketAddrV4::new(CONST_MDNS_GROUP.into(), CONST_MDNS_PORT),
+            interface: Ipv4Addr::UNSPECIFIED,
         }
     }
 }
@@ -1,4 +1,6 @@
 pub mod discovery_config;
+mod peers;
+mod records;
 pub mod sd;
 pub mod state;
 
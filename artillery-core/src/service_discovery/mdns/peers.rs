@@ -0,0 +1,135 @@
+use crate::service_discovery::mdns::state::{MDNSServiceDiscoveryEvent, ServiceInstance};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+struct Known {
+    instance: ServiceInstance,
+    expires: Instant,
+}
+
+///
+/// Instances announced on the network, keyed by their node id.
+/// They're forgotten when they say goodbye, or when their records expire.
+#[derive(Debug, Default)]
+pub(crate) struct InstanceTable {
+    instances: HashMap<Uuid, Known>,
+}
+
+impl InstanceTable {
+    /// Record an announcement of the instance, returning what changed.
+    /// A zero TTL is a goodbye.
+    pub(crate) fn heard(
+        &mut self,
+        instance: ServiceInstance,
+        ttl: Duration,
+        now: Instant,
+    ) -> Vec<MDNSServiceDiscoveryEvent> {
+        if ttl == Duration::default() {
+            return self
+                .instances
+                .remove(&instance.node_id)
+                .map(|known| MDNSServiceDiscoveryEvent::Lost(known.instance))
+                .into_iter()
+                .collect();
+        }
+
+        let previous = self.instances.insert(
+            instance.node_id,
+            Known {
+                instance: instance.clone(),
+                expires: now + ttl,
+            },
+        );
+
+        match previous {
+            Some(known) if known.instance == instance => Vec::new(),
+            Some(known) => vec![
+                MDNSServiceDiscoveryEvent::Lost(known.instance),
+                MDNSServiceDiscoveryEvent::Discovered(instance),
+            ],
+            None => vec![MDNSServiceDiscoveryEvent::Discovered(instance)],
+        }
+    }
+
+    /// Forget the instances whose records expired.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<MDNSServiceDiscoveryEvent> {
+        let expired: Vec<Uuid> = self
+            .instances
+            .iter()
+            .filter(|(_, known)| known.expires <= now)
+            .map(|(node_id, _)| *node_id)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|node_id| self.instances.remove(&node_id))
+            .map(|known| MDNSServiceDiscoveryEvent::Lost(known.instance))
+            .collect()
+    }
+
+    /// Whether an instance is still reachable at this address.
+    pub(crate) fn advertises(&self, addr: SocketAddr) -> bool {
+        self.instances
+            .values()
+            .any(|known| known.instance.addr == addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn instance(node_id: Uuid, port: u16) -> ServiceInstance {
+        ServiceInstance {
+            node_id,
+            app_name: "app".into(),
+            metadata: BTreeMap::new(),
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    #[test]
+    fn test_instances_are_deduplicated_and_forgotten() {
+        let start = Instant::now();
+        let ttl = Duration::from_secs(120);
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut table = InstanceTable::default();
+
+        assert_eq!(
+            table.heard(instance(first, 1), ttl, start),
+            vec![MDNSServiceDiscoveryEvent::Discovered(instance(first, 1))]
+        );
+        assert!(table.heard(instance(first, 1), ttl, start).is_empty());
+        assert_eq!(
+            table.heard(instance(first, 2), ttl, start),
+            vec![
+                MDNSServiceDiscoveryEvent::Lost(instance(first, 1)),
+                MDNSServiceDiscoveryEvent::Discovered(instance(first, 2))
+            ]
+        );
+
+        // Goodbye records.
+        assert_eq!(
+            table.heard(instance(first, 2), Duration::default(), start),
+            vec![MDNSServiceDiscoveryEvent::Lost(instance(first, 2))]
+        );
+        assert!(table
+            .heard(instance(first, 2), Duration::default(), start)
+            .is_empty());
+
+        table.heard(instance(second, 3), ttl, start);
+        assert!(table
+            .expire(start + ttl - Duration::from_secs(1))
+            .is_empty());
+        assert!(table.advertises(instance(second, 3).addr));
+        assert_eq!(
+            table.expire(start + ttl),
+            vec![MDNSServiceDiscoveryEvent::Lost(instance(second, 3))]
+        );
+        assert!(!table.advertises(instance(second, 3).addr));
+    }
+}
@@ -0,0 +1,507 @@
+use crate::errors::*;
+use crate::service_discovery::mdns::state::ServiceInstance;
+use dns_parser::{Builder, Packet, QueryClass, QueryType, RData, ResourceRecord};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// Records owned by a single node replace the cached ones (RFC 6762, section 10.2).
+const CACHE_FLUSH: u16 = 0x8000;
+// Authoritative response.
+const RESPONSE_FLAGS: u16 = 0x8400;
+// Legacy resolvers don't expect mDNS TTLs (RFC 6762, section 6.7).
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+/// Name browsed to enumerate the service types of the network (RFC 6763, section 9).
+pub(crate) const SERVICES_ENUMERATION: &str = "_services._dns-sd._udp.local";
+
+/// What a query asks us to answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Question {
+    /// Instances of our service type.
+    Service,
+    /// Service types advertised on the network.
+    Enumeration,
+}
+
+/// Questions of the query we have answers for.
+pub(crate) fn questions(packet: &Packet, service_type: &str) -> Vec<Question> {
+    packet
+        .questions
+        .iter()
+        .filter_map(|asked| question(asked, service_type))
+        .collect()
+}
+
+fn question(asked: &dns_parser::Question, service_type: &str) -> Option<Question> {
+    if !matches!(asked.qtype, QueryType::PTR | QueryType::All) {
+        return None;
+    }
+
+    let name = asked.qname.to_string();
+    if same_name(&name, service_type) {
+        Some(Question::Service)
+    } else if same_name(&name, SERVICES_ENUMERATION) {
+        Some(Question::Enumeration)
+    } else {
+        None
+    }
+}
+
+/// Whether the sender of the query asked for a unicast answer.
+pub(crate) fn prefers_unicast(packet: &Packet) -> bool {
+    packet
+        .questions
+        .iter()
+        .any(|question| question.prefer_unicast)
+}
+
+/// Query browsing the instances of the service type.
+pub(crate) fn browse(service_type: &str) -> Result<Vec<u8>> {
+    let mut builder = Builder::new_query(0, false);
+    builder.add_question(
+        service_type.trim_end_matches('.'),
+        false,
+        QueryType::PTR,
+        QueryClass::IN,
+    );
+
+    match builder.build() {
+        Ok(query) => Ok(query),
+        Err(_) => bail!(
+            ArtilleryError::Unexpected,
+            "DNS-SD query for {} is too long",
+            service_type
+        ),
+    }
+}
+
+/// Answer advertising the instance with its PTR, SRV, TXT and address records.
+pub(crate) fn announcement(
+    service_type: &str,
+    instance: &ServiceInstance,
+    ttl: u32,
+) -> Result<Vec<u8>> {
+    let mut response = Response::default();
+    announce(&mut response, service_type, instance, ttl)?;
+
+    Ok(response.finish())
+}
+
+/// Answer advertising our service type to the enumerating browsers.
+pub(crate) fn enumeration(service_type: &str, ttl: u32) -> Result<Vec<u8>> {
+    let mut response = Response::default();
+    enumerate(&mut response, service_type, ttl)?;
+
+    Ok(response.finish())
+}
+
+///
+/// Unicast answer to a legacy resolver, e.g. `dig`, querying from another port than the mDNS one.
+/// It repeats the id and the questions of the query, with short TTLs and no cache flush
+/// (RFC 6762, section 6.7).
+pub(crate) fn legacy_answer(
+    query: &Packet,
+    service_type: &str,
+    instance: &ServiceInstance,
+    ttl: u32,
+) -> Result<Vec<u8>> {
+    let short_ttl = ttl.min(LEGACY_UNICAST_TTL);
+    let mut response = Response {
+        id: query.header.id,
+        legacy: true,
+        ..Response::default()
+    };
+
+    for asked in &query.questions {
+        match question(asked, service_type) {
+            Some(Question::Service) => {
+                response.question(asked)?;
+                announce(&mut response, service_type, instance, short_ttl)?;
+            }
+            Some(Question::Enumeration) => {
+                response.question(asked)?;
+                enumerate(&mut response, service_type, short_ttl)?;
+            }
+            None => {}
+        }
+    }
+
+    Ok(response.finish())
+}
+
+fn announce(
+    response: &mut Response,
+    service_type: &str,
+    instance: &ServiceInstance,
+    ttl: u32,
+) -> Result<()> {
+    let instance_name = instance_name(service_type, instance);
+    let host = format!("{}.local", instance.node_id.to_hyphenated());
+
+    response.record(
+        service_type,
+        TYPE_PTR,
+        false,
+        ttl,
+        &name_bytes(&instance_name)?,
+    )?;
+
+    let mut srv = Vec::new();
+    // Priority and weight are irrelevant, there's a single target.
+    srv.extend_from_slice(&[0, 0, 0, 0]);
+    srv.extend_from_slice(&instance.addr.port().to_be_bytes());
+    srv.extend(name_bytes(&host)?);
+    response.record(&instance_name, TYPE_SRV, true, ttl, &srv)?;
+
+    response.record(&instance_name, TYPE_TXT, true, ttl, &txt(instance)?)?;
+
+    // Without a routable address, the address of the sender is used instead.
+    match instance.addr.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => {
+            response.record(&host, TYPE_A, true, ttl, &ip.octets())?
+        }
+        IpAddr::V6(ip) if !ip.is_unspecified() => {
+            response.record(&host, TYPE_AAAA, true, ttl, &ip.octets())?
+        }
+        IpAddr::V4(_) | IpAddr::V6(_) => {}
+    }
+
+    Ok(())
+}
+
+fn enumerate(response: &mut Response, service_type: &str, ttl: u32) -> Result<()> {
+    response.record(
+        SERVICES_ENUMERATION,
+        TYPE_PTR,
+        false,
+        ttl,
+        &name_bytes(service_type)?,
+    )
+}
+
+/// Instances of the service type advertised in the response, along with the TTL of their
+/// PTR record. Instances lacking an address record are reached through the address of the sender.
+pub(crate) fn instances(
+    packet: &Packet,
+    service_type: &str,
+    sender: IpAddr,
+) -> Vec<(ServiceInstance, u32)> {
+    let records: Vec<&ResourceRecord> = packet.answers.iter().chain(&packet.additional).collect();
+
+    records
+        .iter()
+        .filter(|record| same_name(&record.name.to_string(), service_type))
+        .filter_map(|record| match &record.data {
+            RData::PTR(ptr) => Some((ptr.0.to_string(), record.ttl)),
+            RData::A(_)
+            | RData::AAAA(_)
+            | RData::CNAME(_)
+            | RData::MX(_)
+            | RData::NS(_)
+            | RData::SOA(_)
+            | RData::SRV(_)
+            | RData::TXT(_)
+            | RData::Unknown(_) => None,
+        })
+        .filter_map(|(instance_name, ttl)| {
+            resolve(&records, &instance_name, sender).map(|instance| (instance, ttl))
+        })
+        .collect()
+}
+
+fn resolve(
+    records: &[&ResourceRecord],
+    instance_name: &str,
+    sender: IpAddr,
+) -> Option<ServiceInstance> {
+    let (host, port) = owned_by(records, instance_name).find_map(|record| match &record.data {
+        RData::SRV(srv) => Some((srv.target.to_string(), srv.port)),
+        RData::A(_)
+        | RData::AAAA(_)
+        | RData::CNAME(_)
+        | RData::MX(_)
+        | RData::NS(_)
+        | RData::PTR(_)
+        | RData::SOA(_)
+        | RData::TXT(_)
+        | RData::Unknown(_) => None,
+    })?;
+
+    let mut entries: BTreeMap<String, String> = owned_by(records, instance_name)
+        .filter_map(|record| match &record.data {
+            RData::TXT(txt) => Some(txt.iter()),
+            RData::A(_)
+            | RData::AAAA(_)
+            | RData::CNAME(_)
+            | RData::MX(_)
+            | RData::NS(_)
+            | RData::PTR(_)
+            | RData::SOA(_)
+            | RData::SRV(_)
+            | RData::Unknown(_) => None,
+        })
+        .flatten()
+        .filter_map(|entry| {
+            let text = String::from_utf8_lossy(entry);
+            let mut pair = text.splitn(2, '=');
+            let key = pair.next()?.to_string();
+            Some((key, pair.next().unwrap_or_default().to_string()))
+        })
+        .collect();
+
+    let ip = owned_by(records, &host)
+        .find_map(|record| match &record.data {
+            RData::A(a) => Some(IpAddr::V4(a.0)),
+            RData::AAAA(aaaa) => Some(IpAddr::V6(aaaa.0)),
+            RData::CNAME(_)
+            | RData::MX(_)
+            | RData::NS(_)
+            | RData::PTR(_)
+            | RData::SOA(_)
+            | RData::SRV(_)
+            | RData::TXT(_)
+            | RData::Unknown(_) => None,
+        })
+        .unwrap_or(sender);
+
+    Some(ServiceInstance {
+        node_id: entries.remove("node_id")?.parse().ok()?,
+        app_name: entries.remove("app_name").unwrap_or_default(),
+        metadata: entries,
+        addr: SocketAddr::new(ip, port),
+    })
+}
+
+fn owned_by<'r, 'a>(
+    records: &'r [&'r ResourceRecord<'a>],
+    name: &'r str,
+) -> impl Iterator<Item = &'r &'r ResourceRecord<'a>> {
+    records
+        .iter()
+        .filter(move |record| same_name(&record.name.to_string(), name))
+}
+
+fn instance_name(service_type: &str, instance: &ServiceInstance) -> String {
+    format!(
+        "{}.{}",
+        instance.node_id.to_hyphenated(),
+        service_type.trim_end_matches('.')
+    )
+}
+
+fn same_name(name: &str, other: &str) -> bool {
+    name.trim_end_matches('.')
+        .eq_ignore_ascii_case(other.trim_end_matches('.'))
+}
+
+fn txt(instance: &ServiceInstance) -> Result<Vec<u8>> {
+    let node_id = instance.node_id.to_hyphenated().to_string();
+    let entries = [
+        ("node_id", node_id.as_str()),
+        ("app_name", instance.app_name.as_str()),
+    ];
+    let mut rdata = Vec::new();
+
+    for (key, value) in entries.iter().copied().chain(
+        instance
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str())),
+    ) {
+        let entry = format!("{}={}", key, value);
+        let len = match u8::try_from(entry.len()) {
+            Ok(len) => len,
+            Err(_) => bail!(
+                ArtilleryError::Unexpected,
+                "TXT entry {:?} is longer than 255 bytes",
+                key
+            ),
+        };
+        rdata.push(len);
+        rdata.extend(entry.into_bytes());
+    }
+
+    Ok(rdata)
+}
+
+/// Uncompressed wire format of the name.
+fn name_bytes(name: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+
+    for label in name.trim_end_matches('.').split('.') {
+        match u8::try_from(label.len()) {
+            Ok(len) if len > 0 && len < 64 => {
+                bytes.push(len);
+                bytes.extend_from_slice(label.as_bytes());
+            }
+            Ok(_) | Err(_) => bail!(ArtilleryError::Unexpected, "Invalid DNS name {:?}", name),
+        }
+    }
+    bytes.push(0);
+
+    Ok(bytes)
+}
+
+#[derive(Default)]
+struct Response {
+    /// Id of the query, only legacy answers carry it.
+    id: u16,
+    legacy: bool,
+    questions: Vec<u8>,
+    question_count: u16,
+    records: Vec<u8>,
+    count: u16,
+}
+
+impl Response {
+    fn question(&mut self, asked: &dns_parser::Question) -> Result<()> {
+        self.questions.extend(name_bytes(&asked.qname.to_string())?);
+        self.questions
+            .extend_from_slice(&(asked.qtype as u16).to_be_bytes());
+        self.questions
+            .extend_from_slice(&(asked.qclass as u16).to_be_bytes());
+        self.question_count = self.question_count.saturating_add(1);
+
+        Ok(())
+    }
+
+    fn record(
+        &mut self,
+        name: &str,
+        rtype: u16,
+        unique: bool,
+        ttl: u32,
+        rdata: &[u8],
+    ) -> Result<()> {
+        // Legacy resolvers don't know about the cache flush bit.
+        let class = if unique && !self.legacy {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+
+        self.records.extend(name_bytes(name)?);
+        self.records.extend_from_slice(&rtype.to_be_bytes());
+        self.records.extend_from_slice(&class.to_be_bytes());
+        self.records.extend_from_slice(&ttl.to_be_bytes());
+        self.records
+            .extend_from_slice(&u16::try_from(rdata.len())?.to_be_bytes());
+        self.records.extend_from_slice(rdata);
+        self.count = self.count.saturating_add(1);
+
+        Ok(())
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(12 + self.questions.len() + self.records.len());
+        // Unsolicited multicast answers have no id, nor questions.
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&RESPONSE_FLAGS.to_be_bytes());
+        packet.extend_from_slice(&self.question_count.to_be_bytes());
+        packet.extend_from_slice(&self.count.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        packet.extend(self.questions);
+        packet.extend(self.records);
+
+        packet
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    const SERVICE: &str = "_artillery._udp.local";
+
+    fn instance(addr: SocketAddr) -> ServiceInstance {
+        let mut metadata = BTreeMap::new();
+        metadata.insert("zone".into(), "eu-west-1a".into());
+
+        ServiceInstance {
+            node_id: Uuid::new_v4(),
+            app_name: "app".into(),
+            metadata,
+            addr,
+        }
+    }
+
+    #[test]
+    fn test_announced_instances_are_resolved() {
+        let sender = IpAddr::from([10, 0, 0, 9]);
+        let advertised = instance(SocketAddr::from(([10, 0, 0, 1], 27845)));
+        let announced = announcement(SERVICE, &advertised, 120).unwrap();
+
+        let packet = Packet::parse(&announced).unwrap();
+        assert!(!packet.header.query);
+        assert_eq!(instances(&packet, SERVICE, sender), vec![(advertised, 120)]);
+        assert!(instances(&packet, "_other._udp.local", sender).is_empty());
+
+        // Unspecified addresses aren't advertised, the sender is reached instead.
+        let unspecified = instance(SocketAddr::from(([0, 0, 0, 0], 27845)));
+        let anonymous = announcement(SERVICE, &unspecified, 120).unwrap();
+        assert_eq!(
+            instances(&Packet::parse(&anonymous).unwrap(), SERVICE, sender)
+                .into_iter()
+                .map(|(resolved, _)| resolved.addr)
+                .collect::<Vec<_>>(),
+            vec![SocketAddr::new(sender, 27845)]
+        );
+    }
+
+    #[test]
+    fn test_service_and_enumeration_queries_are_answered() {
+        let browsed = browse(SERVICE).unwrap();
+        let service_query = Packet::parse(&browsed).unwrap();
+        assert_eq!(questions(&service_query, SERVICE), vec![Question::Service]);
+        assert!(questions(&service_query, "_other._udp.local").is_empty());
+
+        let enumerated = browse(SERVICES_ENUMERATION).unwrap();
+        let enumeration_query = Packet::parse(&enumerated).unwrap();
+        assert_eq!(
+            questions(&enumeration_query, SERVICE),
+            vec![Question::Enumeration]
+        );
+
+        let answer = enumeration(SERVICE, 120).unwrap();
+        let packet = Packet::parse(&answer).unwrap();
+        assert!(matches!(
+            &packet.answers[0].data,
+            RData::PTR(ptr) if ptr.0.to_string() == SERVICE
+        ));
+    }
+
+    #[test]
+    fn test_legacy_answers_repeat_the_question_with_short_ttls() {
+        let sender = IpAddr::from([10, 0, 0, 9]);
+        let advertised = instance(SocketAddr::from(([10, 0, 0, 1], 27845)));
+        let mut builder = Builder::new_query(0x1234, false);
+        builder.add_question(SERVICE, false, QueryType::PTR, QueryClass::IN);
+        let query = builder.build().unwrap();
+
+        let answer =
+            legacy_answer(&Packet::parse(&query).unwrap(), SERVICE, &advertised, 120).unwrap();
+        let packet = Packet::parse(&answer).unwrap();
+        assert!(!packet.header.query);
+        assert_eq!(packet.header.id, 0x1234);
+        assert_eq!(packet.questions.len(), 1);
+        assert_eq!(packet.questions[0].qname.to_string(), SERVICE);
+        assert_eq!(packet.questions[0].qtype, QueryType::PTR);
+        assert!(packet
+            .answers
+            .iter()
+            .chain(&packet.additional)
+            .all(|record| record.ttl == LEGACY_UNICAST_TTL && !record.multicast_unique));
+        assert_eq!(
+            instances(&packet, SERVICE, sender),
+            vec![(advertised, LEGACY_UNICAST_TTL)]
+        );
+    }
+}
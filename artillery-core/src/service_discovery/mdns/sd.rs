@@ -1,22 +1,40 @@
+use crate::constants::*;
 use crate::errors::*;
+use crate::service_discovery::discovery::{
+    DiscoveredEndpoints, ServiceDiscovery, ServiceDiscoveryEvent,
+};
 use crate::service_discovery::mdns::discovery_config::MDNSServiceDiscoveryConfig;
-use crate::service_discovery::mdns::state::MDNSServiceDiscoveryEvent;
+use crate::service_discovery::mdns::peers::InstanceTable;
+use crate::service_discovery::mdns::records::{self, Question};
+use crate::service_discovery::mdns::state::{MDNSServiceDiscoveryEvent, ServiceInstance};
 use bastion_executor::blocking::spawn_blocking;
 
-use libp2p::mdns::service::*;
-use libp2p::multiaddr::Protocol;
-use libp2p::{identity, Multiaddr, PeerId};
+use dns_parser::Packet;
 use lightproc::proc_stack::ProcStack;
+use socket2::{Domain, Protocol, Socket, Type};
 
-use crossbeam_channel::{unbounded, Receiver};
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use kaos::flunk;
+use std::convert::TryFrom;
 use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver as EndpointReceiver;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+///
+/// Advertises the node as a DNS-SD instance of the configured service type over mDNS,
+/// and browses the instances of the same app.
 pub struct MDNSServiceDiscovery {
     events: Arc<Receiver<MDNSServiceDiscoveryEvent>>,
+    endpoints: Arc<Mutex<DiscoveredEndpoints>>,
+    running: Arc<AtomicBool>,
+    stopped: Option<mpsc::Receiver<()>>,
 }
 
 unsafe impl Send for MDNSServiceDiscovery {}
@@ -26,87 +44,69 @@ impl MDNSServiceDiscovery {
     pub fn new_service_discovery(config: MDNSServiceDiscoveryConfig) -> Result<Self> {
         let (event_tx, event_rx) = unbounded::<MDNSServiceDiscoveryEvent>();
 
-        let peer_id = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let endpoints = Arc::new(Mutex::new(DiscoveredEndpoints::default()));
+        let running = Arc::new(AtomicBool::new(true));
+        let (stopped_tx, stopped) = mpsc::channel();
+        let responder = Responder::new(
+            config,
+            event_tx,
+            endpoints.clone(),
+            running.clone(),
+            stopped_tx,
+        )?;
 
+        debug!(
+            "Advertising {} under {}",
+            responder.config.node_id, responder.config.service_type
+        );
         let _discovery_handle = spawn_blocking(
             async move {
-                let mut service = MdnsService::new().expect("Can't launch the MDNS service");
-
-                loop {
-                    let (mut srv, packet) = service.next().await;
-                    match packet {
-                        MdnsPacket::Query(query) => {
-                            debug!("Query from {:?}", query.remote_addr());
-                            let address: Multiaddr = format!(
-                                "/ip4/{}/udp/{}",
-                                config.local_service_addr.ip().to_string(),
-                                config.local_service_addr.port()
-                            )
-                            .parse()
-                            .unwrap();
-                            let resp = build_query_response(
-                                query.query_id(),
-                                peer_id.clone(),
-                                vec![address].into_iter(),
-                                config.reply_ttl,
-                            )
-                            .unwrap();
-                            srv.enqueue_response(resp);
-                        }
-                        MdnsPacket::Response(response) => {
-                            // We detected a libp2p mDNS response on the network. Responses are for
-                            // everyone and not just for the requester, which makes it possible to
-                            // passively listen.
-                            for peer in response.discovered_peers() {
-                                debug!("Discovered peer {:?}", peer.id());
-                                // These are the self-reported addresses of the peer we just discovered.
-                                for addr in peer.addresses() {
-                                    debug!(" Address = {:?}", addr);
-                                    let components = addr.iter().collect::<Vec<_>>();
-                                    flunk!("mdns-protocol-fp");
-                                    if let Protocol::Ip4(discovered_ip) = components[0] {
-                                        if let Protocol::Udp(discovered_port) = components[1] {
-                                            let discovered =
-                                                format!("{}:{}", discovered_ip, discovered_port)
-                                                    .parse()
-                                                    .unwrap();
-
-                                            event_tx
-                                                .send(MDNSServiceDiscoveryEvent(discovered))
-                                                .unwrap();
-                                        } else {
-                                            error!(
-                                                "Unexpected protocol received: {}",
-                                                components[1]
-                                            );
-                                        }
-                                    } else {
-                                        error!("Unexpected IP received: {}", components[0]);
-                                    }
-                                }
-                            }
-                        }
-                        MdnsPacket::ServiceDiscovery(query) => {
-                            // The last possibility is a service detection query from DNS-SD.
-                            // Just like `Query`, in a real application you probably want to call
-                            // `query.respond`.
-                            debug!("Detected service query from {:?}", query.remote_addr());
-                        }
-                    }
-                    service = srv
-                }
+                responder.serve();
             },
             ProcStack::default(),
         );
 
         Ok(Self {
             events: Arc::new(event_rx),
+            endpoints,
+            running,
+            stopped: Some(stopped),
         })
     }
 
     pub fn events(&self) -> Arc<Receiver<MDNSServiceDiscoveryEvent>> {
         self.events.clone()
     }
+
+    /// Shutdown Service Discovery, once the peers were told that we're leaving.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.running.store(false, Ordering::SeqCst);
+
+        if let Some(stopped) = self.stopped.take() {
+            // The responder notices within a receive timeout.
+            stopped.recv_timeout(Duration::from_secs(2))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for MDNSServiceDiscovery {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            warn!("mDNS responder didn't stop: {}", e);
+        }
+    }
+}
+
+impl ServiceDiscovery for MDNSServiceDiscovery {
+    fn subscribe(&self) -> Result<EndpointReceiver<ServiceDiscoveryEvent>> {
+        Ok(self
+            .endpoints
+            .lock()
+            .expect("Discovered endpoints are poisoned")
+            .subscribe())
+    }
 }
 
 impl Future for MDNSServiceDiscovery {
@@ -119,3 +119,361 @@ impl Future for MDNSServiceDiscovery {
         }
     }
 }
+
+/// Answers the queries for our service type, and collects the instances of the responses.
+struct Responder {
+    config: MDNSServiceDiscoveryConfig,
+    socket: UdpSocket,
+    group: SocketAddr,
+    local: ServiceInstance,
+    ttl: u32,
+    announcement: Vec<u8>,
+    goodbye: Vec<u8>,
+    enumeration: Vec<u8>,
+    browse: Vec<u8>,
+    instances: InstanceTable,
+    events: Sender<MDNSServiceDiscoveryEvent>,
+    endpoints: Arc<Mutex<DiscoveredEndpoints>>,
+    running: Arc<AtomicBool>,
+    stopped: mpsc::Sender<()>,
+}
+
+impl Responder {
+    fn new(
+        config: MDNSServiceDiscoveryConfig,
+        events: Sender<MDNSServiceDiscoveryEvent>,
+        endpoints: Arc<Mutex<DiscoveredEndpoints>>,
+        running: Arc<AtomicBool>,
+        stopped: mpsc::Sender<()>,
+    ) -> Result<Self> {
+        let ttl = u32::try_from(config.reply_ttl.as_secs()).unwrap_or(u32::MAX);
+        let local = ServiceInstance {
+            node_id: config.node_id,
+            app_name: config.app_name.clone(),
+            metadata: config.metadata.clone(),
+            addr: config.local_service_addr,
+        };
+
+        Ok(Self {
+            socket: mdns_socket(&config)?,
+            group: config.mdns_addr.into(),
+            announcement: records::announcement(&config.service_type, &local, ttl)?,
+            // Records with a zero TTL tell the peers to forget them right away.
+            goodbye: records::announcement(&config.service_type, &local, 0)?,
+            enumeration: records::enumeration(&config.service_type, ttl)?,
+            browse: records::browse(&config.service_type)?,
+            local,
+            ttl,
+            instances: InstanceTable::default(),
+            config,
+            events,
+            endpoints,
+            running,
+            stopped,
+        })
+    }
+
+    fn serve(mut self) {
+        let mut buf = vec![0_u8; CONST_PACKET_SIZE];
+        let mut next_browse = Instant::now();
+
+        // Announce ourselves once, browsing is enough to find us afterwards.
+        self.send(&self.announcement, self.group);
+
+        while self.running.load(Ordering::SeqCst) {
+            let expired = self.instances.expire(Instant::now());
+            self.notify(expired);
+
+            if Instant::now() >= next_browse {
+                self.send(&self.browse, self.group);
+                next_browse = Instant::now() + self.config.query_interval;
+            }
+
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) => self.received(buf.get(..len).unwrap_or_default(), from),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => error!("Can't receive mDNS packets: {}", e),
+            }
+        }
+
+        debug!("Stopping mDNS responder of {}", self.config.node_id);
+        self.send(&self.goodbye, self.group);
+        if self.stopped.send(()).is_err() {
+            trace!("Nobody waits for the mDNS responder to stop");
+        }
+    }
+
+    fn received(&mut self, data: &[u8], from: SocketAddr) {
+        let packet = match Packet::parse(data) {
+            Ok(packet) => packet,
+            Err(e) => {
+                trace!("Dropping malformed mDNS packet from {}: {}", from, e);
+                return;
+            }
+        };
+
+        if packet.header.query {
+            self.answer(&packet, from);
+        } else {
+            self.discovered(&packet, from);
+        }
+    }
+
+    fn answer(&self, query: &Packet, from: SocketAddr) {
+        let questions = records::questions(query, &self.config.service_type);
+        if questions.is_empty() {
+            return;
+        }
+        debug!("{:?} query from {}", questions, from);
+
+        // Legacy resolvers, e.g. `dig`, query from another port and expect a unicast answer.
+        if from.port() != self.config.mdns_addr.port() {
+            match records::legacy_answer(query, &self.config.service_type, &self.local, self.ttl) {
+                Ok(answer) => self.send(&answer, from),
+                Err(e) => warn!("Can't answer the legacy query from {}: {}", from, e),
+            }
+            return;
+        }
+
+        let destination = if records::prefers_unicast(query) {
+            from
+        } else {
+            self.group
+        };
+        for question in questions {
+            let answer = match question {
+                Question::Service => &self.announcement,
+                Question::Enumeration => &self.enumeration,
+            };
+            self.send(answer, destination);
+        }
+    }
+
+    fn discovered(&mut self, response: &Packet, from: SocketAddr) {
+        let now = Instant::now();
+
+        for (instance, ttl) in records::instances(response, &self.config.service_type, from.ip()) {
+            // Our own announcements are looped back to us.
+            if instance.node_id == self.config.node_id {
+                continue;
+            }
+            if instance.app_name != self.config.app_name {
+                trace!(
+                    "Ignoring {} of app {:?}",
+                    instance.node_id,
+                    instance.app_name
+                );
+                continue;
+            }
+
+            let changes = self
+                .instances
+                .heard(instance, Duration::from_secs(ttl.into()), now);
+            self.notify(changes);
+        }
+    }
+
+    fn notify(&self, changes: Vec<MDNSServiceDiscoveryEvent>) {
+        for change in changes {
+            let mut endpoints = self
+                .endpoints
+                .lock()
+                .expect("Discovered endpoints are poisoned");
+
+            match &change {
+                MDNSServiceDiscoveryEvent::Discovered(instance) => {
+                    debug!("Discovered {} at {}", instance.node_id, instance.addr);
+                    flunk!("mdns-protocol-fp");
+                    endpoints.discovered(instance.addr);
+                }
+                MDNSServiceDiscoveryEvent::Lost(instance) => {
+                    debug!("Lost {} at {}", instance.node_id, instance.addr);
+                    // Another instance might still be reachable at the same address.
+                    if !self.instances.advertises(instance.addr) {
+                        endpoints.lost(instance.addr);
+                    }
+                }
+            }
+
+            if self.events.send(change).is_err() {
+                trace!("Nobody listens to mDNS discoveries");
+            }
+        }
+    }
+
+    fn send(&self, packet: &[u8], destination: SocketAddr) {
+        if let Err(e) = self.socket.send_to(packet, destination) {
+            warn!("Can't send mDNS packet to {}: {}", destination, e);
+        }
+    }
+}
+
+/// Socket joining the mDNS group, shared with the other responders of the host.
+fn mdns_socket(config: &MDNSServiceDiscoveryConfig) -> Result<UdpSocket> {
+    let group = config.mdns_addr.ip();
+    if !group.is_multicast() {
+        bail!(
+            ArtilleryError::Unexpected,
+            "mDNS address {} isn't a multicast group",
+            config.mdns_addr
+        );
+    }
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from(([0, 0, 0, 0], config.mdns_addr.port())).into())?;
+    socket.join_multicast_v4(group, &config.interface)?;
+    socket.set_multicast_if_v4(&config.interface)?;
+    // RFC 6762, section 11.
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use uuid::Uuid;
+
+    fn config(app_name: &str, service_port: u16) -> MDNSServiceDiscoveryConfig {
+        let mut config = MDNSServiceDiscoveryConfig {
+            local_service_addr: SocketAddr::from(([127, 0, 0, 1], service_port)),
+            node_id: Uuid::new_v4(),
+            app_name: app_name.into(),
+            mdns_addr: SocketAddrV4::new(CONST_MDNS_GROUP.into(), 25353),
+            interface: Ipv4Addr::LOCALHOST,
+            query_interval: Duration::from_millis(200),
+            ..Default::default()
+        };
+        config.metadata.insert("zone".into(), "a".into());
+
+        config
+    }
+
+    fn instance(config: &MDNSServiceDiscoveryConfig) -> ServiceInstance {
+        ServiceInstance {
+            node_id: config.node_id,
+            app_name: config.app_name.clone(),
+            metadata: config.metadata.clone(),
+            addr: config.local_service_addr,
+        }
+    }
+
+    /// Wait for the event, skipping the others.
+    fn observed(sd: &MDNSServiceDiscovery, expected: &MDNSServiceDiscoveryEvent) -> bool {
+        let events = sd.events();
+        let deadline = Instant::now() + Duration::from_secs(3);
+
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match events.recv_timeout(remaining) {
+                Ok(event) if event == *expected => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+
+        false
+    }
+
+    #[test]
+    fn test_instances_of_the_same_app_are_discovered() {
+        let advertised = config("app", 28940);
+        let _node = MDNSServiceDiscovery::new_service_discovery(advertised.clone()).unwrap();
+        let peer = MDNSServiceDiscovery::new_service_discovery(config("app", 28941)).unwrap();
+        let other = MDNSServiceDiscovery::new_service_discovery(config("other", 28942)).unwrap();
+
+        let endpoints = peer.subscribe().unwrap();
+        let discovered = MDNSServiceDiscoveryEvent::Discovered(instance(&advertised));
+        assert!(observed(&peer, &discovered));
+        assert!(
+            endpoints
+                .try_iter()
+                .any(|event| event
+                    == ServiceDiscoveryEvent::Discovered(advertised.local_service_addr))
+        );
+
+        // Browsed again and again, but reported once.
+        assert!(!observed(&peer, &discovered));
+        assert!(!observed(&other, &discovered));
+    }
+
+    #[test]
+    fn test_leaving_instances_say_goodbye() {
+        let leaving = config("leaving", 28943);
+        let mut node = MDNSServiceDiscovery::new_service_discovery(leaving.clone()).unwrap();
+        let peer = MDNSServiceDiscovery::new_service_discovery(config("leaving", 28944)).unwrap();
+        let endpoints = peer.subscribe().unwrap();
+
+        assert!(observed(
+            &peer,
+            &MDNSServiceDiscoveryEvent::Discovered(instance(&leaving))
+        ));
+
+        node.shutdown().unwrap();
+        assert!(observed(
+            &peer,
+            &MDNSServiceDiscoveryEvent::Lost(instance(&leaving))
+        ));
+        assert!(endpoints
+            .try_iter()
+            .any(|event| event == ServiceDiscoveryEvent::Lost(leaving.local_service_addr)));
+    }
+
+    #[test]
+    fn test_own_announcements_are_ignored() {
+        let lonely = config("lonely", 28945);
+        let node = MDNSServiceDiscovery::new_service_discovery(lonely.clone()).unwrap();
+
+        assert!(!observed(
+            &node,
+            &MDNSServiceDiscoveryEvent::Discovered(instance(&lonely))
+        ));
+    }
+
+    #[test]
+    fn test_legacy_queries_get_a_unicast_answer() {
+        let advertised = config("legacy", 28946);
+        let _node = MDNSServiceDiscovery::new_service_discovery(advertised.clone()).unwrap();
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        socket
+            .bind(&SocketAddr::from(([0, 0, 0, 0], 0)).into())
+            .unwrap();
+        socket.set_multicast_if_v4(&Ipv4Addr::LOCALHOST).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let resolver: UdpSocket = socket.into();
+        let query = records::browse(&advertised.service_type).unwrap();
+
+        let mut buf = vec![0_u8; CONST_PACKET_SIZE];
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while Instant::now() < deadline {
+            resolver
+                .send_to(&query, SocketAddr::from(advertised.mdns_addr))
+                .unwrap();
+
+            while let Ok((len, from)) = resolver.recv_from(&mut buf) {
+                let answer = Packet::parse(&buf[..len]).unwrap();
+                let answered = records::instances(&answer, &advertised.service_type, from.ip());
+                if answered
+                    .iter()
+                    .any(|(i, _)| i.node_id == advertised.node_id)
+                {
+                    assert_eq!(answer.questions.len(), 1);
+                    assert!(answered.iter().all(|(_, ttl)| *ttl <= 10));
+                    return;
+                }
+            }
+        }
+
+        panic!("No legacy answer from {}", advertised.node_id);
+    }
+}
@@ -1,12 +1,38 @@
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use uuid::Uuid;
 
-pub struct MDNSServiceDiscoveryEvent(pub SocketAddr);
+///
+/// Node advertised under the DNS-SD service type, as resolved from its records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInstance {
+    pub node_id: Uuid,
+    pub app_name: String,
+    /// TXT entries other than `node_id` and `app_name`.
+    pub metadata: BTreeMap<String, String>,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MDNSServiceDiscoveryEvent {
+    /// An instance was announced for the first time, or with new records.
+    Discovered(ServiceInstance),
+    /// An instance said goodbye, its records expired, or it changed its records.
+    Lost(ServiceInstance),
+}
 
 unsafe impl Send for MDNSServiceDiscoveryEvent {}
 unsafe impl Sync for MDNSServiceDiscoveryEvent {}
 
 impl MDNSServiceDiscoveryEvent {
     pub fn get(&self) -> SocketAddr {
-        self.0
+        self.instance().addr
+    }
+
+    pub fn instance(&self) -> &ServiceInstance {
+        match self {
+            MDNSServiceDiscoveryEvent::Discovered(instance)
+            | MDNSServiceDiscoveryEvent::Lost(instance) => instance,
+        }
     }
 }
@@ -1,2 +1,7 @@
+pub mod discovery;
+pub mod dns;
+pub mod file;
+pub mod kubernetes;
 pub mod mdns;
+pub mod seed_list;
 pub mod udp_anycast;
@@ -0,0 +1,34 @@
+use crate::errors::*;
+use crate::service_discovery::discovery::{ServiceDiscovery, ServiceDiscoveryEvent};
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver};
+
+///
+/// Fixed list of seed endpoints. The event stream ends once every seed was reported.
+#[derive(Debug, Clone, Default)]
+pub struct StaticServiceDiscovery {
+    seeds: Vec<SocketAddr>,
+}
+
+impl StaticServiceDiscovery {
+    pub fn new(seeds: Vec<SocketAddr>) -> Self {
+        Self { seeds }
+    }
+
+    pub fn seeds(&self) -> &[SocketAddr] {
+        &self.seeds
+    }
+}
+
+impl ServiceDiscovery for StaticServiceDiscovery {
+    fn subscribe(&self) -> Result<Receiver<ServiceDiscoveryEvent>> {
+        let (tx, rx) = channel();
+
+        for seed in &self.seeds {
+            tx.send(ServiceDiscoveryEvent::Discovered(*seed))?;
+        }
+
+        Ok(rx)
+    }
+}
@@ -0,0 +1,116 @@
+use crate::errors::*;
+use hmac::{Hmac, Mac};
+use serde::*;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SealedMessage {
+    service: String,
+    payload: Vec<u8>,
+    mac: Option<Vec<u8>>,
+}
+
+///
+/// Wraps discovery messages with the service name, and an HMAC-SHA256 of both
+/// the service name and the payload when a shared key is configured.
+///
+/// Messages of other services, and messages which aren't signed with the
+/// shared key, are dropped when opened.
+pub(crate) struct Authenticator {
+    service: String,
+    key: Option<Vec<u8>>,
+}
+
+impl Authenticator {
+    pub(crate) fn new(service: String, key: Option<Vec<u8>>) -> Self {
+        Self { service, key }
+    }
+
+    pub(crate) fn seal(&self, payload: Vec<u8>) -> Result<Vec<u8>> {
+        let mac = match &self.key {
+            Some(key) => Some(self.mac(key, &payload)?.finalize().into_bytes().to_vec()),
+            None => None,
+        };
+
+        Ok(bincode::serialize(&SealedMessage {
+            service: self.service.clone(),
+            payload,
+            mac,
+        })?)
+    }
+
+    pub(crate) fn open(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let sealed: SealedMessage = bincode::deserialize(data).ok()?;
+
+        if sealed.service != self.service {
+            trace!("Dropping discovery message of service {}", sealed.service);
+            return None;
+        }
+
+        if let Some(key) = &self.key {
+            let verified = match (&sealed.mac, self.mac(key, &sealed.payload)) {
+                (Some(tag), Ok(mac)) => mac.verify_slice(tag).is_ok(),
+                (None, _) | (_, Err(_)) => false,
+            };
+
+            if !verified {
+                warn!("Dropping discovery message with a missing or forged signature");
+                return None;
+            }
+        }
+
+        Some(sealed.payload)
+    }
+
+    fn mac(&self, key: &[u8], payload: &[u8]) -> Result<HmacSha256> {
+        let mut mac = HmacSha256::new_from_slice(key)
+            .map_err(|e| ArtilleryError::Unexpected(e.to_string()))?;
+        // Separate the fields, so that bytes can't be moved from one to the other.
+        mac.update(self.service.as_bytes());
+        mac.update(&[0]);
+        mac.update(payload);
+
+        Ok(mac)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open(sender: &Authenticator, receiver: &Authenticator) -> Option<Vec<u8>> {
+        let sealed = sender.seal(b"payload".to_vec()).unwrap();
+        receiver.open(&sealed)
+    }
+
+    #[test]
+    fn test_messages_are_namespaced() {
+        let app = Authenticator::new("app".into(), None);
+        let other = Authenticator::new("other".into(), None);
+
+        assert_eq!(open(&app, &app), Some(b"payload".to_vec()));
+        assert_eq!(open(&app, &other), None);
+    }
+
+    #[test]
+    fn test_messages_are_authenticated() {
+        let signed = Authenticator::new("app".into(), Some(b"secret".to_vec()));
+        let forged = Authenticator::new("app".into(), Some(b"guess".to_vec()));
+        let unsigned = Authenticator::new("app".into(), None);
+
+        assert_eq!(open(&signed, &signed), Some(b"payload".to_vec()));
+        assert_eq!(open(&forged, &signed), None);
+        assert_eq!(open(&unsigned, &signed), None);
+
+        let sealed = signed.seal(b"payload".to_vec()).unwrap();
+        let at = sealed
+            .windows(b"payload".len())
+            .position(|window| window == b"payload")
+            .unwrap();
+        let mut tampered = sealed;
+        tampered.splice(at..at + 7, b"tamper!".iter().copied());
+        assert_eq!(signed.open(&tampered), None);
+    }
+}
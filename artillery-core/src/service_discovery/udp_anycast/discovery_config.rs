@@ -1,22 +1,81 @@
 use crate::constants::*;
 use chrono::Duration;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
 
+#[derive(Debug, Clone)]
+pub enum SeekingMode {
+    /// Seeking requests are sent to the multicast group of `seeking_addr`,
+    /// which is joined to hear the requests of the other nodes.
+    Multicast(MulticastGroupConfig),
+    /// Seeking requests are broadcasted to `seeking_addr`.
+    /// Broadcasts don't leave the local subnet and are filtered by many networks.
+    Broadcast,
+}
+
+impl Default for SeekingMode {
+    fn default() -> Self {
+        SeekingMode::Multicast(MulticastGroupConfig::default())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MulticastGroupConfig {
+    /// Amount of routers seeking requests can cross, 1 keeps them on the local subnet.
+    pub ttl: u32,
+    /// Whether nodes of the same host hear each other.
+    pub loopback: bool,
+    /// IPv4 address of the interface the group is joined on, chosen by the system if unspecified.
+    pub ipv4_interface: Ipv4Addr,
+    /// Index of the IPv6 interface the group is joined on, chosen by the system if 0.
+    pub ipv6_interface: u32,
+}
+
+impl Default for MulticastGroupConfig {
+    fn default() -> Self {
+        Self {
+            ttl: 1,
+            loopback: true,
+            ipv4_interface: Ipv4Addr::UNSPECIFIED,
+            ipv6_interface: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct MulticastServiceDiscoveryConfig {
     pub timeout_delta: Duration,
     pub seeking_addr: SocketAddr,
     pub discovery_addr: SocketAddr,
+    pub mode: SeekingMode,
+    /// First interval between seeking requests, doubled after every request.
+    /// Peers are only sought through `seek_peers` if not given.
+    pub seek_interval: Option<Duration>,
+    pub max_seek_interval: Duration,
+    /// How long a peer is remembered without answering seeking requests.
+    pub peer_ttl: Duration,
+    /// Name of the service, peers only see the peers of the same service.
+    pub service_name: String,
+    /// Key shared by the peers to sign discovery messages.
+    /// Unsigned or forged messages are dropped. Messages aren't signed if not given.
+    pub shared_key: Option<Vec<u8>>,
 }
 
 impl Default for MulticastServiceDiscoveryConfig {
     fn default() -> Self {
         let discovery_addr = SocketAddr::from(([0, 0, 0, 0], CONST_SERVICE_DISCOVERY_PORT));
-        let seeking_addr = SocketAddr::from(([255, 255, 255, 255], CONST_SERVICE_DISCOVERY_PORT));
+        let seeking_addr =
+            SocketAddr::from((CONST_SERVICE_DISCOVERY_GROUP, CONST_SERVICE_DISCOVERY_PORT));
 
         Self {
             timeout_delta: Duration::seconds(1),
             seeking_addr: seeking_addr.to_socket_addrs().unwrap().next().unwrap(),
             discovery_addr: discovery_addr.to_socket_addrs().unwrap().next().unwrap(),
+            mode: SeekingMode::default(),
+            seek_interval: Some(Duration::seconds(1)),
+            max_seek_interval: Duration::seconds(30),
+            peer_ttl: Duration::seconds(90),
+            service_name: "artillery".into(),
+            shared_key: None,
         }
     }
 }
@@ -1,10 +1,13 @@
+pub mod auth;
 pub mod discovery_config;
+pub mod peers;
 pub mod state;
 
 pub mod sd;
 
 pub mod prelude {
     pub use super::discovery_config::*;
+    pub use super::peers::PeerEvent;
     pub use super::sd::*;
     pub use super::state::*;
 }
@@ -0,0 +1,193 @@
+use crate::service_discovery::udp_anycast::state::ServiceDiscoveryReply;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent<T = ServiceDiscoveryReply> {
+    /// A peer answered seeking requests for the first time, or with a new reply.
+    Discovered(T),
+    /// A peer didn't answer any seeking request for the whole peer TTL, or changed its reply.
+    Lost(T),
+}
+
+#[derive(Debug, Clone)]
+struct Peer<T> {
+    reply: T,
+    last_seen: Instant,
+}
+
+///
+/// Peers which answered seeking requests, keyed by their responder uid.
+#[derive(Debug)]
+pub(crate) struct PeerTable<T> {
+    peers: HashMap<u32, Peer<T>>,
+    ttl: Duration,
+}
+
+impl<T: Clone + PartialEq> PeerTable<T> {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            peers: HashMap::new(),
+            ttl,
+        }
+    }
+
+    /// Record a reply of the peer, returning what changed.
+    pub(crate) fn heard(&mut self, uid: u32, reply: T, now: Instant) -> Vec<PeerEvent<T>> {
+        let previous = self.peers.insert(
+            uid,
+            Peer {
+                reply: reply.clone(),
+                last_seen: now,
+            },
+        );
+
+        match previous {
+            Some(peer) if peer.reply == reply => Vec::new(),
+            Some(peer) => vec![PeerEvent::Lost(peer.reply), PeerEvent::Discovered(reply)],
+            None => vec![PeerEvent::Discovered(reply)],
+        }
+    }
+
+    /// Forget the peers which weren't heard of for the whole TTL.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<PeerEvent<T>> {
+        let ttl = self.ttl;
+        let expired: Vec<u32> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| now.saturating_duration_since(peer.last_seen) > ttl)
+            .map(|(uid, _)| *uid)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|uid| self.peers.remove(&uid))
+            .map(|peer| PeerEvent::Lost(peer.reply))
+            .collect()
+    }
+
+    /// Replies of the peers which are still answering.
+    pub(crate) fn replies(&self) -> impl Iterator<Item = &T> {
+        self.peers.values().map(|peer| &peer.reply)
+    }
+}
+
+///
+/// When to seek peers next.
+///
+/// The interval doubles after every seek up to its maximum, and goes back to
+/// the initial one whenever peers come and go. Half of every delay is random,
+/// so that nodes started together don't seek all at once.
+#[derive(Debug)]
+pub(crate) struct SeekSchedule {
+    initial: Option<Duration>,
+    max: Duration,
+    current: Duration,
+    next: Option<Instant>,
+}
+
+impl SeekSchedule {
+    /// Without an initial interval, peers are only sought on demand.
+    pub(crate) fn new(initial: Option<Duration>, max: Duration, now: Instant) -> Self {
+        let mut schedule = Self {
+            initial,
+            max,
+            current: Duration::default(),
+            next: None,
+        };
+        schedule.reset(now);
+
+        schedule
+    }
+
+    pub(crate) fn reset(&mut self, now: Instant) {
+        if let Some(initial) = self.initial {
+            self.current = initial.min(self.max);
+            self.next = Some(now + jittered(self.current));
+        }
+    }
+
+    /// Whether it's time to seek, scheduling the next seek if so.
+    pub(crate) fn due(&mut self, now: Instant) -> bool {
+        match self.next {
+            Some(next) if next <= now => {
+                self.current = self.current.saturating_mul(2).min(self.max);
+                self.next = Some(now + jittered(self.current));
+                true
+            }
+            Some(_) | None => false,
+        }
+    }
+}
+
+/// Half of the delay, plus a random part of the other half.
+fn jittered(delay: Duration) -> Duration {
+    let half = delay / 2;
+    let spread = u64::try_from(half.as_millis()).unwrap_or(u64::MAX);
+
+    half + Duration::from_millis(rand::random::<u64>() % spread.saturating_add(1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reply(data: &str) -> ServiceDiscoveryReply {
+        ServiceDiscoveryReply {
+            serialized_data: data.into(),
+        }
+    }
+
+    #[test]
+    fn test_peers_are_discovered_changed_and_expired() {
+        let start = Instant::now();
+        let mut peers = PeerTable::new(Duration::from_secs(10));
+
+        assert_eq!(
+            peers.heard(1, reply("a"), start),
+            vec![PeerEvent::Discovered(reply("a"))]
+        );
+        assert!(peers.heard(1, reply("a"), start).is_empty());
+        assert_eq!(
+            peers.heard(1, reply("b"), start),
+            vec![
+                PeerEvent::Lost(reply("a")),
+                PeerEvent::Discovered(reply("b"))
+            ]
+        );
+
+        peers.heard(2, reply("c"), start + Duration::from_secs(5));
+        assert_eq!(
+            peers.expire(start + Duration::from_secs(11)),
+            vec![PeerEvent::Lost(reply("b"))]
+        );
+        assert_eq!(peers.replies().collect::<Vec<_>>(), vec![&reply("c")]);
+    }
+
+    #[test]
+    fn test_seek_interval_backs_off_with_jitter() {
+        let start = Instant::now();
+        let mut schedule =
+            SeekSchedule::new(Some(Duration::from_secs(1)), Duration::from_secs(4), start);
+
+        assert!(!schedule.due(start + Duration::from_millis(499)));
+        assert!(schedule.due(start + Duration::from_secs(1)));
+
+        // Intervals are 2s then 4s, and never more than the maximum.
+        let mut at = start + Duration::from_secs(1);
+        for max_delay in &[2, 4, 4] {
+            let min = at + Duration::from_secs(max_delay / 2);
+            assert!(!schedule.due(min - Duration::from_millis(1)));
+
+            at += Duration::from_secs(*max_delay);
+            assert!(schedule.due(at));
+        }
+
+        schedule.reset(at);
+        assert!(schedule.due(at + Duration::from_secs(1)));
+
+        let mut manual = SeekSchedule::new(None, Duration::from_secs(4), start);
+        assert!(!manual.due(start + Duration::from_secs(60)));
+    }
+}
@@ -1,25 +1,29 @@
 use crate::errors::*;
+use crate::service_discovery::discovery::{ServiceDiscovery, ServiceDiscoveryEvent};
 use crate::service_discovery::udp_anycast::discovery_config::MulticastServiceDiscoveryConfig;
+use crate::service_discovery::udp_anycast::peers::PeerEvent;
 use crate::service_discovery::udp_anycast::state::MulticastServiceDiscoveryState;
 use crate::service_discovery::udp_anycast::state::{
-    ServiceDiscoveryReply, ServiceDiscoveryRequest,
+    DiscoveryEndpoint, DiscoveryReply, ServiceDiscoveryReply, ServiceDiscoveryRequest,
 };
 use bastion_executor::blocking::spawn_blocking;
 use cuneiform_fields::arch::ArchPadding;
 use lightproc::proc_stack::ProcStack;
 use std::sync::mpsc;
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::{channel, Receiver, Sender};
 
-pub struct MulticastServiceDiscovery {
-    comm: ArchPadding<Sender<ServiceDiscoveryRequest>>,
+///
+/// Nodes seek each other on the network, and answer with replies of type `T`.
+pub struct MulticastServiceDiscovery<T = ServiceDiscoveryReply> {
+    comm: ArchPadding<Sender<ServiceDiscoveryRequest<T>>>,
 }
 
-impl MulticastServiceDiscovery {
+impl<T: DiscoveryReply> MulticastServiceDiscovery<T> {
     pub fn new_service_discovery(
         config: MulticastServiceDiscoveryConfig,
-        discovery_reply: ServiceDiscoveryReply,
+        discovery_reply: T,
     ) -> Result<Self> {
-        let (internal_tx, mut internal_rx) = channel::<ServiceDiscoveryRequest>();
+        let (internal_tx, mut internal_rx) = channel::<ServiceDiscoveryRequest<T>>();
         let (poll, state) = MulticastServiceDiscoveryState::new(config, discovery_reply)?;
 
         debug!("Starting Artillery Multicast SD");
@@ -36,9 +40,9 @@ impl MulticastServiceDiscovery {
         })
     }
 
-    /// Register a new observer to be notified whenever we
-    /// successfully find peers by interrogating the network.
-    pub fn register_seeker(&self, observer: mpsc::Sender<ServiceDiscoveryReply>) -> Result<()> {
+    /// Register a new observer to be notified whenever peers are found
+    /// by interrogating the network, or stop answering.
+    pub fn register_seeker(&self, observer: mpsc::Sender<PeerEvent<T>>) -> Result<()> {
         let observer = ArchPadding::new(observer);
         Ok(self
             .comm
@@ -54,10 +58,13 @@ impl MulticastServiceDiscovery {
     }
 
     /// Explore the network to find nodes using `udp_anycast` SD.
+    /// Periodic seeking starts over from its initial interval.
     pub fn seek_peers(&self) -> Result<()> {
         Ok(self.comm.send(ServiceDiscoveryRequest::SeekPeers)?)
     }
+}
 
+impl<T> MulticastServiceDiscovery<T> {
     /// Shutdown Service Discovery
     pub fn shutdown(&mut self) -> Result<()> {
         self.discovery_exit();
@@ -71,11 +78,181 @@ impl MulticastServiceDiscovery {
     }
 }
 
-unsafe impl Send for MulticastServiceDiscovery {}
-unsafe impl Sync for MulticastServiceDiscovery {}
+/// Replies carrying an endpoint address, see `DiscoveryEndpoint`,
+/// are reported as discovered endpoints.
+impl<T: DiscoveryReply + DiscoveryEndpoint> ServiceDiscovery for MulticastServiceDiscovery<T> {
+    fn subscribe(&self) -> Result<Receiver<ServiceDiscoveryEvent>> {
+        let (tx, rx) = channel();
+        self.comm
+            .send(ServiceDiscoveryRequest::Subscribe(tx, T::endpoint))?;
+
+        Ok(rx)
+    }
+}
+
+unsafe impl<T> Send for MulticastServiceDiscovery<T> {}
+unsafe impl<T> Sync for MulticastServiceDiscovery<T> {}
 
-impl Drop for MulticastServiceDiscovery {
+impl<T> Drop for MulticastServiceDiscovery<T> {
     fn drop(&mut self) {
         self.discovery_exit();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::service_discovery::udp_anycast::discovery_config::{
+        MulticastGroupConfig, SeekingMode,
+    };
+    use chrono::Duration;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    fn config(discovery_port: u16, group_port: u16) -> MulticastServiceDiscoveryConfig {
+        MulticastServiceDiscoveryConfig {
+            timeout_delta: Duration::milliseconds(50),
+            discovery_addr: SocketAddr::from(([0, 0, 0, 0], discovery_port)),
+            seeking_addr: SocketAddr::from(([239, 255, 34, 72], group_port)),
+            mode: SeekingMode::Multicast(MulticastGroupConfig {
+                ipv4_interface: Ipv4Addr::LOCALHOST,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Reply of the nodes which only seek, and never answer.
+    fn unlisted() -> SocketAddr {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    }
+
+    #[test]
+    fn test_seek_through_multicast_group() {
+        let endpoint = SocketAddr::from(([127, 0, 0, 1], 28920));
+        let listener = MulticastServiceDiscovery::new_service_discovery(
+            config(28921, 28921),
+            ServiceDiscoveryReply::from(endpoint),
+        )
+        .unwrap();
+        listener.set_listen_for_peers(true).unwrap();
+
+        let seeker = MulticastServiceDiscovery::new_service_discovery(
+            config(28922, 28921),
+            ServiceDiscoveryReply::default(),
+        )
+        .unwrap();
+        let events = seeker.subscribe().unwrap();
+
+        let discovered = (0..50).find_map(|_| {
+            seeker.seek_peers().unwrap();
+            events
+                .recv_timeout(std::time::Duration::from_millis(100))
+                .ok()
+        });
+        assert_eq!(
+            discovered,
+            Some(ServiceDiscoveryEvent::Discovered(endpoint))
+        );
+    }
+
+    #[test]
+    fn test_seeking_address_has_to_be_a_group() {
+        let config = MulticastServiceDiscoveryConfig {
+            seeking_addr: SocketAddr::from(([127, 0, 0, 1], 28923)),
+            ..config(28923, 28923)
+        };
+
+        assert!(MulticastServiceDiscovery::new_service_discovery(
+            config,
+            ServiceDiscoveryReply::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_peers_are_sought_periodically_and_expire() {
+        let endpoint = SocketAddr::from(([127, 0, 0, 1], 28926));
+        let listener = MulticastServiceDiscovery::new_service_discovery(
+            MulticastServiceDiscoveryConfig {
+                seek_interval: None,
+                ..config(28924, 28924)
+            },
+            endpoint,
+        )
+        .unwrap();
+        listener.set_listen_for_peers(true).unwrap();
+
+        let seeker = MulticastServiceDiscovery::new_service_discovery(
+            MulticastServiceDiscoveryConfig {
+                seek_interval: Some(Duration::milliseconds(100)),
+                max_seek_interval: Duration::milliseconds(200),
+                peer_ttl: Duration::milliseconds(600),
+                ..config(28925, 28924)
+            },
+            unlisted(),
+        )
+        .unwrap();
+        let (tx, peers) = mpsc::channel();
+        seeker.register_seeker(tx).unwrap();
+        let events = seeker.subscribe().unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+
+        assert_eq!(
+            peers.recv_timeout(timeout).unwrap(),
+            PeerEvent::Discovered(endpoint)
+        );
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Discovered(endpoint)
+        );
+
+        // Still answering, the peer doesn't expire.
+        assert!(peers
+            .recv_timeout(std::time::Duration::from_millis(1000))
+            .is_err());
+
+        drop(listener);
+        assert_eq!(
+            peers.recv_timeout(timeout).unwrap(),
+            PeerEvent::Lost(endpoint)
+        );
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            ServiceDiscoveryEvent::Lost(endpoint)
+        );
+    }
+
+    #[test]
+    fn test_forged_replies_are_dropped() {
+        let signed = |discovery_port: u16, key: &[u8]| MulticastServiceDiscoveryConfig {
+            seek_interval: Some(Duration::milliseconds(100)),
+            max_seek_interval: Duration::milliseconds(100),
+            shared_key: Some(key.to_vec()),
+            ..config(discovery_port, 28931)
+        };
+        let endpoint = SocketAddr::from(([127, 0, 0, 1], 28930));
+        let listener =
+            MulticastServiceDiscovery::new_service_discovery(signed(28931, b"secret"), endpoint)
+                .unwrap();
+        listener.set_listen_for_peers(true).unwrap();
+
+        let outsider =
+            MulticastServiceDiscovery::new_service_discovery(signed(28928, b"guess"), unlisted())
+                .unwrap();
+        let member =
+            MulticastServiceDiscovery::new_service_discovery(signed(28929, b"secret"), unlisted())
+                .unwrap();
+        let outsider_events = outsider.subscribe().unwrap();
+        let member_events = member.subscribe().unwrap();
+
+        assert_eq!(
+            member_events
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .unwrap(),
+            ServiceDiscoveryEvent::Discovered(endpoint)
+        );
+        assert!(outsider_events
+            .recv_timeout(std::time::Duration::from_millis(500))
+            .is_err());
+    }
+}
@@ -1,16 +1,23 @@
 use crate::constants::*;
 use crate::errors::*;
-use crate::service_discovery::udp_anycast::discovery_config::MulticastServiceDiscoveryConfig;
+use crate::service_discovery::discovery::{DiscoveredEndpoints, ServiceDiscoveryEvent};
+use crate::service_discovery::udp_anycast::auth::Authenticator;
+use crate::service_discovery::udp_anycast::discovery_config::{
+    MulticastServiceDiscoveryConfig, SeekingMode,
+};
+use crate::service_discovery::udp_anycast::peers::{PeerEvent, PeerTable, SeekSchedule};
 use std::convert::TryFrom;
 
 use cuneiform_fields::arch::ArchPadding;
 use mio::net::UdpSocket;
 use mio::{Events, Interest, Poll, Token};
 
+use serde::de::DeserializeOwned;
 use serde::*;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::collections::VecDeque;
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use std::sync::mpsc::{Receiver, Sender};
 use std::time::{Duration, Instant};
@@ -24,6 +31,46 @@ pub struct ServiceDiscoveryReply {
     pub serialized_data: String,
 }
 
+/// Reply advertising an endpoint, as reported through the `ServiceDiscovery` trait.
+impl From<SocketAddr> for ServiceDiscoveryReply {
+    fn from(endpoint: SocketAddr) -> Self {
+        Self {
+            serialized_data: endpoint.to_string(),
+        }
+    }
+}
+
+///
+/// Replies nodes answer seeking requests with.
+pub trait DiscoveryReply:
+    Serialize + DeserializeOwned + Clone + PartialEq + Send + 'static
+{
+}
+
+impl<T> DiscoveryReply for T where
+    T: Serialize + DeserializeOwned + Clone + PartialEq + Send + 'static
+{
+}
+
+///
+/// Replies advertising the endpoint of the peer, which is then reported through
+/// the `ServiceDiscovery` trait.
+pub trait DiscoveryEndpoint {
+    fn endpoint(&self) -> Option<SocketAddr>;
+}
+
+impl DiscoveryEndpoint for ServiceDiscoveryReply {
+    fn endpoint(&self) -> Option<SocketAddr> {
+        self.serialized_data.parse().ok()
+    }
+}
+
+impl DiscoveryEndpoint for SocketAddr {
+    fn endpoint(&self) -> Option<SocketAddr> {
+        Some(*self)
+    }
+}
+
 impl Default for ServiceDiscoveryReply {
     fn default() -> Self {
         Self {
@@ -32,61 +79,82 @@ impl Default for ServiceDiscoveryReply {
     }
 }
 
-pub(crate) enum ServiceDiscoveryRequest {
-    RegisterObserver(ArchPadding<Sender<ServiceDiscoveryReply>>),
+pub(crate) type EndpointOf<T> = fn(&T) -> Option<SocketAddr>;
+
+pub(crate) enum ServiceDiscoveryRequest<T> {
+    RegisterObserver(ArchPadding<Sender<PeerEvent<T>>>),
+    Subscribe(Sender<ServiceDiscoveryEvent>, EndpointOf<T>),
     SetBroadcastListen(bool),
     SeekPeers,
     Exit(Sender<()>),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "t", content = "c")]
-enum ServiceDiscoveryMessage {
+enum ServiceDiscoveryMessage<T> {
     Request,
-    Response {
-        uid: u32,
-        content: ServiceDiscoveryReply,
-    },
+    Response { uid: u32, content: T },
 }
 
 const ON_DISCOVERY: Token = Token(0);
 const SEEK_NODES: Token = Token(1);
 
-pub struct MulticastServiceDiscoveryState {
+pub struct MulticastServiceDiscoveryState<T = ServiceDiscoveryReply> {
     config: MulticastServiceDiscoveryConfig,
     server_socket: UdpSocket,
+    auth: Authenticator,
     seek_request: Vec<u8>,
-    observers: Vec<ArchPadding<Sender<ServiceDiscoveryReply>>>,
+    observers: Vec<ArchPadding<Sender<PeerEvent<T>>>>,
+    endpoints: DiscoveredEndpoints,
+    endpoint_of: Option<EndpointOf<T>>,
+    peers: PeerTable<T>,
+    schedule: SeekSchedule,
     seeker_replies: VecDeque<SocketAddr>,
-    default_reply: ServiceDiscoveryReply,
+    default_reply: T,
     uid: u32,
     running: bool,
     listen: bool,
 }
 
-pub type ServiceDiscoveryReactor = (Poll, MulticastServiceDiscoveryState);
+pub type ServiceDiscoveryReactor<T = ServiceDiscoveryReply> =
+    (Poll, MulticastServiceDiscoveryState<T>);
 
-impl MulticastServiceDiscoveryState {
+impl<T: DiscoveryReply> MulticastServiceDiscoveryState<T> {
     pub(crate) fn new(
         config: MulticastServiceDiscoveryConfig,
-        discovery_reply: ServiceDiscoveryReply,
-    ) -> Result<ServiceDiscoveryReactor> {
+        discovery_reply: T,
+    ) -> Result<ServiceDiscoveryReactor<T>> {
         let poll: Poll = Poll::new()?;
 
-        let mut server_socket = UdpSocket::bind(config.discovery_addr)?;
-        server_socket.set_broadcast(true)?;
+        let mut server_socket = discovery_socket(&config)?;
 
         poll.registry()
             .register(&mut server_socket, ON_DISCOVERY, get_interests())?;
 
         let uid = rand::random();
-        let seek_request = serde_json::to_string(&ServiceDiscoveryMessage::Request)?;
+        let auth = Authenticator::new(config.service_name.clone(), config.shared_key.clone());
+        let seek_request =
+            auth.seal(bincode::serialize(&ServiceDiscoveryMessage::<T>::Request)?)?;
+        let peers = PeerTable::new(to_std(config.peer_ttl)?);
+        let seek_interval = match config.seek_interval {
+            Some(interval) => Some(to_std(interval)?),
+            None => None,
+        };
+        let schedule = SeekSchedule::new(
+            seek_interval,
+            to_std(config.max_seek_interval)?,
+            Instant::now(),
+        );
 
         let state = MulticastServiceDiscoveryState {
             config,
             server_socket,
-            seek_request: seek_request.as_bytes().into(),
+            auth,
+            seek_request,
             observers: Vec::new(),
+            endpoints: DiscoveredEndpoints::default(),
+            endpoint_of: None,
+            peers,
+            schedule,
             seeker_replies: VecDeque::new(),
             default_reply: discovery_reply,
             uid,
@@ -98,47 +166,41 @@ impl MulticastServiceDiscoveryState {
     }
 
     fn readable(&mut self, buf: &mut [u8], poll: &mut Poll) -> Result<()> {
-        if let Ok((_bytes_read, peer_addr)) = self.server_socket.recv_from(buf) {
-            let serialized = std::str::from_utf8(buf)?.to_string().trim().to_string();
-            let serialized = serialized.trim_matches(char::from(0x00));
-            let msg: ServiceDiscoveryMessage = if let Ok(msg) = serde_json::from_str(serialized) {
-                msg
-            } else {
-                return Ok(());
-            };
+        // Drain the socket, dropped messages would otherwise hold back the next ones.
+        while let Ok((bytes_read, peer_addr)) = self.server_socket.recv_from(buf) {
+            let msg: Option<ServiceDiscoveryMessage<T>> = self
+                .auth
+                .open(buf.get(..bytes_read).unwrap_or_default())
+                .and_then(|payload| bincode::deserialize(&payload).ok());
 
             match msg {
-                ServiceDiscoveryMessage::Request => {
-                    if self.listen {
-                        self.seeker_replies.push_back(peer_addr);
-                        poll.registry().reregister(
-                            &mut self.server_socket,
-                            ON_DISCOVERY,
-                            Interest::WRITABLE,
-                        )?;
-                    } else {
-                        poll.registry().reregister(
-                            &mut self.server_socket,
-                            ON_DISCOVERY,
-                            Interest::READABLE,
-                        )?;
-                    }
+                Some(ServiceDiscoveryMessage::Request) if self.listen => {
+                    self.seeker_replies.push_back(peer_addr);
                 }
-                ServiceDiscoveryMessage::Response { uid, content } => {
-                    if uid != self.uid {
-                        self.observers
-                            .retain(|observer| observer.send(content.clone()).is_ok());
-                    }
-                    poll.registry().reregister(
-                        &mut self.server_socket,
-                        ON_DISCOVERY,
-                        Interest::READABLE,
-                    )?;
+                Some(ServiceDiscoveryMessage::Response { uid, content }) if uid != self.uid => {
+                    let changes = self.peers.heard(uid, content, Instant::now());
+                    self.notify(changes);
                 }
+                Some(ServiceDiscoveryMessage::Request)
+                | Some(ServiceDiscoveryMessage::Response { .. })
+                | None => {}
             }
         }
 
-        Ok(())
+        self.rearm(poll)
+    }
+
+    /// Wait for the next messages, and for the socket to be writable while replies are pending.
+    fn rearm(&mut self, poll: &mut Poll) -> Result<()> {
+        let interest = if self.seeker_replies.is_empty() {
+            Interest::READABLE
+        } else {
+            Interest::READABLE.add(Interest::WRITABLE)
+        };
+
+        Ok(poll
+            .registry()
+            .reregister(&mut self.server_socket, ON_DISCOVERY, interest)?)
     }
 
     fn writable(&mut self, poll: &mut Poll, token: Token) -> Result<()> {
@@ -148,7 +210,7 @@ impl MulticastServiceDiscoveryState {
                     uid: self.uid,
                     content: self.default_reply.clone(),
                 };
-                let discovery_reply = serde_json::to_vec(&reply)?;
+                let discovery_reply = self.auth.seal(bincode::serialize(&reply)?)?;
 
                 while let Some(peer_addr) = self.seeker_replies.pop_front() {
                     let mut sent_bytes = 0;
@@ -192,23 +254,20 @@ impl MulticastServiceDiscoveryState {
             _ => (),
         }
 
-        Ok(poll
-            .registry()
-            .reregister(&mut self.server_socket, ON_DISCOVERY, Interest::WRITABLE)?)
+        // Everything pending is sent, wait for the next requests and replies.
+        self.rearm(poll)
     }
 
     pub(crate) fn event_loop(
-        receiver: &mut Receiver<ServiceDiscoveryRequest>,
+        receiver: &mut Receiver<ServiceDiscoveryRequest<T>>,
         mut poll: Poll,
-        mut state: MulticastServiceDiscoveryState,
+        mut state: Self,
     ) -> Result<()> {
         let mut events = Events::with_capacity(1);
         let mut buf = [0_u8; CONST_PACKET_SIZE];
 
         let mut start = Instant::now();
-        let timeout = Duration::from_millis(u64::try_from(
-            state.config.timeout_delta.num_milliseconds(),
-        )?);
+        let timeout = to_std(state.config.timeout_delta)?;
 
         // Our event loop.
         loop {
@@ -229,6 +288,8 @@ impl MulticastServiceDiscoveryState {
                 poll.poll(&mut events, Some(remaining))?;
             }
 
+            state.tick(&mut poll);
+
             // Process our own events that are submitted to event loop
             // This is internal state machinery.
             while let Ok(msg) = receiver.try_recv() {
@@ -266,47 +327,163 @@ impl MulticastServiceDiscoveryState {
     fn process_internal_request(
         &mut self,
         poll: &mut Poll,
-        msg: ServiceDiscoveryRequest,
+        msg: ServiceDiscoveryRequest<T>,
     ) -> Option<Sender<()>> {
         use ServiceDiscoveryRequest::*;
 
         match msg {
             RegisterObserver(sender) => self.observers.push(sender),
+            Subscribe(tx, endpoint_of) => {
+                // Endpoints are tracked from the first subscription on.
+                if self.endpoint_of.is_none() {
+                    for endpoint in self.peers.replies().filter_map(endpoint_of) {
+                        self.endpoints.discovered(endpoint);
+                    }
+                    self.endpoint_of = Some(endpoint_of);
+                }
+                self.endpoints.add_subscriber(tx);
+            }
             SetBroadcastListen(bcast_listen) => {
                 self.listen = bcast_listen;
             }
             SeekPeers => {
-                match self
-                    .server_socket
-                    .send_to(&self.seek_request, self.config.seeking_addr)
-                {
-                    Ok(_) => {
-                        if let Err(err) = poll.registry().reregister(
-                            &mut self.server_socket,
-                            ON_DISCOVERY,
-                            Interest::READABLE,
-                        ) {
-                            error!("Reregistry error for Discovery: {:?}", err);
-                            self.running = false;
-                        }
-                    }
-                    Err(_err) => {
-                        if let Err(err) = poll.registry().reregister(
-                            &mut self.server_socket,
-                            SEEK_NODES,
-                            Interest::WRITABLE,
-                        ) {
-                            error!("Reregistry error for Seeking: {:?}", err);
-                            self.running = false;
-                        }
-                    }
-                }
+                self.seek(poll);
+                self.schedule.reset(Instant::now());
             }
             Exit(tx) => return Some(tx),
         };
 
         None
     }
+
+    fn seek(&mut self, poll: &mut Poll) {
+        match self
+            .server_socket
+            .send_to(&self.seek_request, self.config.seeking_addr)
+        {
+            Ok(_) => {
+                if let Err(err) = self.rearm(poll) {
+                    error!("Reregistry error for Discovery: {:?}", err);
+                    self.running = false;
+                }
+            }
+            Err(_err) => {
+                if let Err(err) = poll.registry().reregister(
+                    &mut self.server_socket,
+                    SEEK_NODES,
+                    Interest::WRITABLE,
+                ) {
+                    error!("Reregistry error for Seeking: {:?}", err);
+                    self.running = false;
+                }
+            }
+        }
+    }
+
+    /// Seek peers when it's time to, and forget the ones which stopped answering.
+    fn tick(&mut self, poll: &mut Poll) {
+        let now = Instant::now();
+
+        let expired = self.peers.expire(now);
+        self.notify(expired);
+
+        if self.schedule.due(now) {
+            trace!("Seeking peers periodically");
+            self.seek(poll);
+        }
+    }
+
+    fn notify(&mut self, changes: Vec<PeerEvent<T>>) {
+        if changes.is_empty() {
+            return;
+        }
+
+        // Peers coming and going, seek more often until the network settles.
+        self.schedule.reset(Instant::now());
+
+        for change in changes {
+            if let Some(endpoint_of) = self.endpoint_of {
+                self.track_endpoint(endpoint_of, &change);
+            }
+
+            self.observers
+                .retain(|observer| observer.send(change.clone()).is_ok());
+        }
+    }
+
+    fn track_endpoint(&mut self, endpoint_of: EndpointOf<T>, change: &PeerEvent<T>) {
+        match change {
+            PeerEvent::Discovered(reply) => {
+                if let Some(endpoint) = endpoint_of(reply) {
+                    self.endpoints.discovered(endpoint);
+                }
+            }
+            PeerEvent::Lost(reply) => {
+                // Other peers might still advertise the same endpoint.
+                if let Some(endpoint) = endpoint_of(reply) {
+                    if !self
+                        .peers
+                        .replies()
+                        .filter_map(endpoint_of)
+                        .any(|advertised| advertised == endpoint)
+                    {
+                        self.endpoints.lost(endpoint);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Socket hearing the seeking requests, either broadcasted or sent to the multicast group.
+fn discovery_socket(config: &MulticastServiceDiscoveryConfig) -> Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(config.discovery_addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+
+    match &config.mode {
+        SeekingMode::Broadcast => {
+            socket.set_broadcast(true)?;
+            socket.bind(&config.discovery_addr.into())?;
+        }
+        SeekingMode::Multicast(group) => {
+            // Every node of the host can join the group on the same port.
+            socket.set_reuse_address(true)?;
+            socket.bind(&config.discovery_addr.into())?;
+
+            match config.seeking_addr.ip() {
+                IpAddr::V4(addr) if addr.is_multicast() => {
+                    socket.join_multicast_v4(&addr, &group.ipv4_interface)?;
+                    socket.set_multicast_if_v4(&group.ipv4_interface)?;
+                    socket.set_multicast_ttl_v4(group.ttl)?;
+                    socket.set_multicast_loop_v4(group.loopback)?;
+                }
+                IpAddr::V6(addr) if addr.is_multicast() => {
+                    socket.join_multicast_v6(&addr, group.ipv6_interface)?;
+                    socket.set_multicast_if_v6(group.ipv6_interface)?;
+                    socket.set_multicast_hops_v6(group.ttl)?;
+                    socket.set_multicast_loop_v6(group.loopback)?;
+                }
+                IpAddr::V4(_) | IpAddr::V6(_) => bail!(
+                    ArtilleryError::Unexpected,
+                    "Seeking address {} isn't a multicast group",
+                    config.seeking_addr
+                ),
+            }
+        }
+    }
+
+    socket.set_nonblocking(true)?;
+
+    Ok(UdpSocket::from_std(socket.into()))
+}
+
+fn to_std(duration: chrono::Duration) -> Result<Duration> {
+    Ok(Duration::from_millis(u64::try_from(
+        duration.num_milliseconds(),
+    )?))
 }
 
 #[inline]
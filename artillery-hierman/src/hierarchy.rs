@@ -0,0 +1,554 @@
+use crate::hierarchy_config::*;
+use crate::topology::*;
+
+use artillery_core::epidemic::prelude::*;
+use artillery_core::errors::*;
+
+use serde::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use uuid::Uuid;
+
+/// LAN metadata key holding the address a member listens on while it represents its zone.
+pub const WAN_ADDR_METADATA_KEY: &str = "artillery.hierman.wan";
+
+///
+/// Part of the view of a zone, sent as a cluster payload.
+///
+/// Views grow along with their zones, so they are split in parts fitting a packet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ZoneViewPart {
+    /// Views published by a node are numbered, parts of the same view share the number.
+    #[serde(rename = "s")]
+    sequence: u64,
+    #[serde(rename = "p")]
+    part: usize,
+    #[serde(rename = "n")]
+    parts: usize,
+    #[serde(rename = "v")]
+    view: ZoneView,
+}
+
+/// Parts of a view received so far from one of the representatives of its zone.
+struct IncomingView {
+    sequence: u64,
+    parts: BTreeSet<usize>,
+    view: ZoneView,
+}
+
+struct WanPool {
+    cluster: Cluster,
+    members: Vec<ArtilleryMember>,
+}
+
+struct HierarchyState {
+    lan_members: Vec<ArtilleryMember>,
+    wan: Option<WanPool>,
+    /// Last known views of the other zones, kept while they are unreachable.
+    remote: BTreeMap<ZoneId, ZoneView>,
+    /// Node the last view of every other zone came from.
+    senders: BTreeMap<ZoneId, Uuid>,
+    incoming: BTreeMap<(ZoneId, Uuid), IncomingView>,
+    /// Number of the last views published by this node.
+    sequence: u64,
+    next_publish: Instant,
+}
+
+///
+/// Hierarchical membership: a LAN epidemic pool per zone, and a WAN pool made of
+/// representatives of every zone.
+///
+/// Representatives send the view of their zone to the WAN pool and the first of them relays the
+/// views of the other zones to the LAN pool, so that every node aggregates the global topology.
+pub struct HierarchicalCluster {
+    pub events: Receiver<TopologyEvent>,
+    config: HierarchyConfig,
+    zone: ZoneId,
+    lan: Arc<Cluster>,
+    lan_events: Receiver<ArtilleryClusterEvent>,
+    event_tx: Sender<TopologyEvent>,
+    state: Mutex<HierarchyState>,
+    topology: RwLock<Topology>,
+}
+
+unsafe impl Send for HierarchicalCluster {}
+unsafe impl Sync for HierarchicalCluster {}
+
+impl HierarchicalCluster {
+    pub fn new(config: HierarchyConfig) -> Result<Self> {
+        let zone = ZoneId::new(&config.datacenter, &config.zone);
+
+        let mut lan_config = config.lan.clone();
+        lan_config.metadata.insert(
+            WAN_ADDR_METADATA_KEY.to_string(),
+            config.wan.listen_addr.to_string(),
+        );
+        let (lan, _) = Cluster::new_cluster(config.node_id, lan_config)?;
+        for seed in &config.lan_seeds {
+            lan.add_seed_node(*seed);
+        }
+
+        let (event_tx, events) = channel();
+
+        Ok(Self {
+            events,
+            zone,
+            lan_events: lan.subscribe(),
+            lan: Arc::new(lan),
+            event_tx,
+            state: Mutex::new(HierarchyState {
+                lan_members: Vec::new(),
+                wan: None,
+                remote: BTreeMap::new(),
+                senders: BTreeMap::new(),
+                incoming: BTreeMap::new(),
+                sequence: 0,
+                next_publish: Instant::now(),
+            }),
+            topology: RwLock::new(Topology::default()),
+            config,
+        })
+    }
+
+    pub fn zone(&self) -> &ZoneId {
+        &self.zone
+    }
+
+    /// Epidemic pool of the zone.
+    pub fn lan(&self) -> Arc<Cluster> {
+        self.lan.clone()
+    }
+
+    pub fn topology(&self) -> Topology {
+        self.topology.read().expect("Topology is poisoned").clone()
+    }
+
+    /// Whether this node currently represents its zone in the WAN pool.
+    pub fn is_representative(&self) -> bool {
+        self.state
+            .lock()
+            .expect("Hierarchy state is poisoned")
+            .wan
+            .is_some()
+    }
+
+    pub fn shutdown(&self) {
+        self.leave_wan(&mut self.state.lock().expect("Hierarchy state is poisoned"));
+        self.lan.leave_cluster();
+    }
+
+    pub async fn launch(&self) {
+        let tick = self.config.publish_interval / 4;
+
+        loop {
+            match self.lan_events.recv_timeout(tick) {
+                Ok((members, event)) => {
+                    let mut state = self.state.lock().expect("Hierarchy state is poisoned");
+                    state.lan_members = members;
+                    if let ArtilleryMemberEvent::Payload(sender, payload) = event {
+                        self.relayed(&mut state, sender.host_key(), &payload);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            self.refresh();
+        }
+
+        self.leave_wan(&mut self.state.lock().expect("Hierarchy state is poisoned"));
+    }
+
+    fn refresh(&self) {
+        let mut state = self.state.lock().expect("Hierarchy state is poisoned");
+
+        let mut published = Vec::new();
+        if let Some(wan) = state.wan.as_mut() {
+            while let Ok((members, event)) = wan.cluster.events.try_recv() {
+                wan.members = members;
+                if let ArtilleryMemberEvent::Payload(sender, payload) = event {
+                    published.push((sender.host_key(), payload));
+                }
+            }
+        }
+        for (sender, payload) in published {
+            self.receive(&mut state, sender, &payload);
+        }
+
+        let local = ZoneView::from_members(
+            self.zone.clone(),
+            &state.lan_members,
+            self.lan.listen_addr(),
+            self.config.representatives,
+        );
+
+        let representing = local.representatives.contains(&self.lan.host_key());
+        if representing && state.wan.is_none() {
+            self.join_wan(&mut state, &local.representatives);
+        } else if !representing && state.wan.is_some() {
+            self.leave_wan(&mut state);
+        }
+
+        let remote = if state.wan.is_some() {
+            self.wan_views(&mut state)
+        } else {
+            state.remote.values().cloned().collect()
+        };
+
+        if state.wan.is_some() && Instant::now() >= state.next_publish {
+            self.publish(&mut state, &local);
+        }
+        drop(state);
+
+        let current = Topology::new(remote.into_iter().chain(Some(local)));
+        let previous = std::mem::replace(
+            &mut *self.topology.write().expect("Topology is poisoned"),
+            current.clone(),
+        );
+        for change in topology_changes(&previous, &current) {
+            let _ = self.event_tx.send(change);
+        }
+    }
+
+    fn join_wan(&self, state: &mut HierarchyState, representatives: &BTreeSet<Uuid>) {
+        info!(
+            "{} represents zone {} in the WAN pool",
+            self.lan.host_key(),
+            self.zone
+        );
+
+        let (cluster, _) = match Cluster::new_cluster(self.lan.host_key(), self.config.wan.clone())
+        {
+            Ok(wan) => wan,
+            Err(e) => {
+                error!("Can't join the WAN pool: {}", e);
+                return;
+            }
+        };
+
+        // Other representatives of the zone are already in the pool.
+        let peers: Vec<SocketAddr> = state
+            .lan_members
+            .iter()
+            .filter(|m| m.is_remote() && representatives.contains(&m.host_key()))
+            .filter_map(|m| m.metadata().get(WAN_ADDR_METADATA_KEY)?.parse().ok())
+            .collect();
+        for seed in self.config.wan_seeds.iter().chain(peers.iter()) {
+            if *seed != self.config.wan.listen_addr {
+                cluster.add_seed_node(*seed);
+            }
+        }
+
+        state.wan = Some(WanPool {
+            cluster,
+            members: Vec::new(),
+        });
+        state.next_publish = Instant::now();
+    }
+
+    fn leave_wan(&self, state: &mut HierarchyState) {
+        if let Some(wan) = state.wan.take() {
+            info!(
+                "{} stops representing zone {} in the WAN pool",
+                self.lan.host_key(),
+                self.zone
+            );
+            wan.cluster.leave_cluster();
+        }
+    }
+
+    /// Views of the other zones, reachable while the representative which sent them is.
+    fn wan_views(&self, state: &mut HierarchyState) -> Vec<ZoneView> {
+        let reachable: BTreeSet<Uuid> = state
+            .wan
+            .as_ref()
+            .map(|wan| {
+                wan.members
+                    .iter()
+                    .filter(|m| {
+                        m.is_remote()
+                            && matches!(
+                                m.state(),
+                                ArtilleryMemberState::Alive | ArtilleryMemberState::Suspect
+                            )
+                    })
+                    .map(ArtilleryMember::host_key)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for (zone, view) in &mut state.remote {
+            view.reachable = state
+                .senders
+                .get(zone)
+                .is_some_and(|sender| reachable.contains(sender));
+        }
+
+        state.remote.values().cloned().collect()
+    }
+
+    /// Views relayed through the LAN pool, only the first representative of the zone relays them.
+    fn relayed(&self, state: &mut HierarchyState, sender: Uuid, payload: &str) {
+        let relay = elect_representatives(&state.lan_members, self.config.representatives)
+            .into_iter()
+            .next();
+
+        if state.wan.is_none() && relay == Some(sender) {
+            self.receive(state, sender, payload);
+        }
+    }
+
+    fn receive(&self, state: &mut HierarchyState, sender: Uuid, payload: &str) {
+        let part = match serde_json::from_str::<ZoneViewPart>(payload) {
+            Ok(part) if part.view.zone != self.zone => part,
+            Ok(_) | Err(_) => return,
+        };
+
+        if let Some(view) = assemble(&mut state.incoming, sender, part) {
+            state.senders.insert(view.zone.clone(), sender);
+            state.remote.insert(view.zone.clone(), view);
+        }
+    }
+
+    fn publish(&self, state: &mut HierarchyState, local: &ZoneView) {
+        state.next_publish = Instant::now() + self.config.publish_interval;
+        state.sequence += 1;
+
+        if let Some(wan) = state.wan.as_ref() {
+            let peers: Vec<Uuid> = wan
+                .members
+                .iter()
+                .filter(|m| m.is_remote() && m.state() == ArtilleryMemberState::Alive)
+                .map(ArtilleryMember::host_key)
+                .collect();
+            let budget = self.config.wan.network_mtu / 4;
+
+            for part in view_parts(local, state.sequence, budget) {
+                send_part(&wan.cluster, &peers, &part);
+            }
+        }
+
+        if local.representatives.iter().next() != Some(&self.lan.host_key()) {
+            return;
+        }
+
+        let peers: Vec<Uuid> = state
+            .lan_members
+            .iter()
+            .filter(|m| m.is_remote() && m.state() == ArtilleryMemberState::Alive)
+            .map(ArtilleryMember::host_key)
+            .filter(|id| !local.representatives.contains(id))
+            .collect();
+        let budget = self.config.lan.network_mtu / 4;
+
+        for view in state.remote.values() {
+            for part in view_parts(view, state.sequence, budget) {
+                send_part(&self.lan, &peers, &part);
+            }
+        }
+    }
+}
+
+fn send_part(cluster: &Cluster, peers: &[Uuid], part: &ZoneViewPart) {
+    match serde_json::to_string(part) {
+        Ok(encoded) => {
+            for peer in peers {
+                cluster.send_payload(*peer, &encoded);
+            }
+        }
+        Err(e) => error!("Zone view {} can't be encoded: {}", part.view.zone, e),
+    }
+}
+
+/// Split the view in parts encoded within the budget, every part carries at least one member.
+fn view_parts(view: &ZoneView, sequence: u64, budget: usize) -> Vec<ZoneViewPart> {
+    let empty = ZoneView {
+        members: BTreeMap::new(),
+        ..view.clone()
+    };
+    let overhead = serde_json::to_string(&ZoneViewPart {
+        sequence,
+        part: 0,
+        parts: 0,
+        view: empty.clone(),
+    })
+    .map_or(0, |encoded| encoded.len());
+
+    let mut chunks: Vec<BTreeMap<Uuid, ZoneMember>> = vec![BTreeMap::new()];
+    let mut chunk_size = overhead;
+    for (id, member) in &view.members {
+        // A pair is encoded as long as a map entry, give or take a separator.
+        let size = serde_json::to_string(&(id, member)).map_or(0, |encoded| encoded.len());
+
+        if chunk_size + size > budget && chunks.last().is_some_and(|chunk| !chunk.is_empty()) {
+            chunks.push(BTreeMap::new());
+            chunk_size = overhead;
+        }
+
+        chunk_size += size;
+        if let Some(chunk) = chunks.last_mut() {
+            chunk.insert(*id, member.clone());
+        }
+    }
+
+    let parts = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(part, members)| ZoneViewPart {
+            sequence,
+            part,
+            parts,
+            view: ZoneView {
+                members,
+                ..empty.clone()
+            },
+        })
+        .collect()
+}
+
+/// Collect the part, and hand the view over once all of its parts arrived.
+fn assemble(
+    incoming: &mut BTreeMap<(ZoneId, Uuid), IncomingView>,
+    sender: Uuid,
+    part: ZoneViewPart,
+) -> Option<ZoneView> {
+    let key = (part.view.zone.clone(), sender);
+
+    match incoming.get(&key) {
+        // Late part of an outdated view.
+        Some(pending) if pending.sequence > part.sequence => return None,
+        Some(pending) if pending.sequence == part.sequence => {}
+        Some(_) | None => {
+            incoming.insert(
+                key.clone(),
+                IncomingView {
+                    sequence: part.sequence,
+                    parts: BTreeSet::new(),
+                    view: ZoneView {
+                        members: BTreeMap::new(),
+                        ..part.view.clone()
+                    },
+                },
+            );
+        }
+    }
+
+    let pending = incoming.get_mut(&key)?;
+    pending.parts.insert(part.part);
+    pending.view.members.extend(part.view.members);
+    if pending.parts.len() < part.parts {
+        return None;
+    }
+
+    incoming.remove(&key).map(|complete| complete.view)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Duration;
+    use std::thread;
+
+    fn node(zone: &str, lan_port: u16, wan_port: u16, lan_seed: u16) -> Arc<HierarchicalCluster> {
+        let fast = |port: u16| ClusterConfig {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            ping_interval: Duration::milliseconds(200),
+            ping_timeout: Duration::seconds(1),
+            ..Default::default()
+        };
+        let config = HierarchyConfig {
+            zone: zone.to_string(),
+            lan: fast(lan_port),
+            lan_seeds: vec![SocketAddr::from(([127, 0, 0, 1], lan_seed))],
+            wan: fast(wan_port),
+            wan_seeds: vec![SocketAddr::from(([127, 0, 0, 1], 29111))],
+            representatives: 1,
+            publish_interval: std::time::Duration::from_millis(400),
+            ..Default::default()
+        };
+
+        let cluster = Arc::new(HierarchicalCluster::new(config).unwrap());
+        let launched = cluster.clone();
+        thread::spawn(move || futures::executor::block_on(launched.launch()));
+        // Tell the members apart by age, which decides the representatives.
+        thread::sleep(std::time::Duration::from_millis(50));
+
+        cluster
+    }
+
+    #[test]
+    fn test_views_are_split_and_reassembled() {
+        let members: BTreeMap<Uuid, ZoneMember> = (0..64_u16)
+            .map(|i| {
+                let id = Uuid::new_v4();
+                let member = ZoneMember {
+                    id,
+                    addr: SocketAddr::from(([10, 0, 0, 1], 30000 + i)),
+                    state: ArtilleryMemberState::Alive,
+                };
+                (id, member)
+            })
+            .collect();
+        let view = ZoneView {
+            zone: ZoneId::new("dc1", "eu"),
+            representatives: members.keys().take(2).cloned().collect(),
+            members,
+            reachable: true,
+        };
+
+        let parts = view_parts(&view, 2, 1024);
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(serde_json::to_string(part).unwrap().len() <= 1024);
+        }
+
+        let sender = Uuid::new_v4();
+        let mut incoming = BTreeMap::new();
+        // A late part of an older view is dropped.
+        let mut outdated = parts[0].clone();
+        outdated.sequence = 1;
+        let (last, rest) = parts.split_last().unwrap();
+        for part in rest {
+            assert_eq!(assemble(&mut incoming, sender, part.clone()), None);
+        }
+        assert_eq!(assemble(&mut incoming, sender, outdated), None);
+        assert_eq!(assemble(&mut incoming, sender, last.clone()), Some(view));
+        assert!(incoming.is_empty());
+    }
+
+    #[test]
+    fn test_zones_are_aggregated_through_representatives() {
+        let a1 = node("a", 29101, 29111, 29101);
+        let b1 = node("b", 29103, 29113, 29103);
+        let a2 = node("a", 29102, 29112, 29101);
+        let b2 = node("b", 29104, 29114, 29103);
+
+        let zone_b = ZoneId::new("dc1", "b");
+        let deadline = Instant::now() + std::time::Duration::from_secs(20);
+        while Instant::now() < deadline {
+            let topology = a2.topology();
+            if topology.alive_members().count() == 4 {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(200));
+        }
+
+        assert!(a1.is_representative());
+        assert!(b1.is_representative());
+        assert!(!a2.is_representative());
+        assert!(!b2.is_representative());
+
+        let topology = a2.topology();
+        assert_eq!(topology.alive_members().count(), 4);
+        assert_eq!(topology.zone_of(&b2.lan().host_key()), Some(&zone_b));
+        assert!(topology.zone(&zone_b).unwrap().reachable);
+        assert!(a2
+            .events
+            .try_iter()
+            .any(|e| e == TopologyEvent::ZoneReachable(zone_b.clone())));
+    }
+}
@@ -0,0 +1,61 @@
+use artillery_core::epidemic::prelude::*;
+
+use chrono::Duration;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+/// Default port of the WAN pool, next to the one of the LAN pool.
+pub const CONST_WAN_INFECTION_PORT: u16 = 27846;
+
+#[derive(Debug, Clone)]
+pub struct HierarchyConfig {
+    pub node_id: Uuid,
+    pub datacenter: String,
+    /// Zone of the node, unique within its datacenter.
+    pub zone: String,
+    /// Epidemic pool made of the members of the zone.
+    pub lan: ClusterConfig,
+    pub lan_seeds: Vec<SocketAddr>,
+    /// Epidemic pool made of the representatives of every zone.
+    /// It is only started while the node represents its zone.
+    pub wan: ClusterConfig,
+    pub wan_seeds: Vec<SocketAddr>,
+    /// Amount of the oldest alive members of the zone representing it in the WAN pool.
+    pub representatives: usize,
+    /// How often representatives publish the view of their zone, and relay the others.
+    pub publish_interval: std::time::Duration,
+}
+
+impl Default for HierarchyConfig {
+    fn default() -> Self {
+        HierarchyConfig {
+            node_id: Uuid::new_v4(),
+            datacenter: String::from("dc1"),
+            zone: String::from("zone1"),
+            lan: ClusterConfig::default(),
+            lan_seeds: Vec::new(),
+            wan: wan_cluster_config(),
+            wan_seeds: Vec::new(),
+            representatives: 2,
+            publish_interval: std::time::Duration::from_secs(2),
+        }
+    }
+}
+
+/// Cluster configuration tolerating the latencies and losses of links between zones.
+pub fn wan_cluster_config() -> ClusterConfig {
+    ClusterConfig {
+        ping_interval: Duration::seconds(5),
+        ping_timeout: Duration::seconds(10),
+        failure_detector: FailureDetectorConfig::PhiAccrual(PhiAccrualConfig {
+            threshold: 12.0,
+            min_std_deviation: Duration::seconds(1),
+            acceptable_heartbeat_pause: Duration::seconds(10),
+            first_heartbeat_estimate: Duration::seconds(5),
+            ..Default::default()
+        }),
+        listen_addr: SocketAddr::from(([127, 0, 0, 1], CONST_WAN_INFECTION_PORT)),
+        reconnect_interval: Duration::seconds(30),
+        ..Default::default()
+    }
+}
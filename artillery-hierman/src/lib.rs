@@ -1,7 +1,18 @@
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
-    }
+#[macro_use]
+extern crate log;
+
+pub mod hierarchy;
+pub mod hierarchy_config;
+pub mod supervision;
+pub mod supervisor;
+pub mod topology;
+pub mod worker_host;
+
+pub mod prelude {
+    pub use super::hierarchy::*;
+    pub use super::hierarchy_config::*;
+    pub use super::supervision::*;
+    pub use super::supervisor::*;
+    pub use super::topology::*;
+    pub use super::worker_host::*;
 }
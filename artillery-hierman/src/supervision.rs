@@ -0,0 +1,166 @@
+use artillery_core::epidemic::prelude::*;
+
+use serde::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Metadata key prefix announcing the workers a member is able to host.
+pub const WORKER_METADATA_PREFIX: &str = "artillery.hierman.worker.";
+
+pub(crate) fn worker_key(worker: &str) -> String {
+    format!("{}{}", WORKER_METADATA_PREFIX, worker)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SupervisionMessage {
+    /// Supervisor asks a host to run the worker.
+    Start,
+    /// Supervisor asks a host to stop the worker.
+    Stop,
+    /// Host stopped the run, or never ran it.
+    Stopped,
+    /// Host runs the worker.
+    Started,
+    /// Worker finished on its host, with the reason of its failure if it crashed.
+    /// Sent again until the supervisor acknowledges it.
+    Exited(Option<String>),
+    /// Supervisor received the exit of the run.
+    ExitAcked,
+}
+
+///
+/// Supervision message exchanged as a cluster payload between a supervisor and the hosts
+/// of its workers. Every start of a worker is a new run, reports of older runs are stale.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct SupervisionEnvelope {
+    #[serde(rename = "sv")]
+    pub supervisor: String,
+    #[serde(rename = "w")]
+    pub worker: String,
+    #[serde(rename = "r")]
+    pub run: u64,
+    #[serde(rename = "k")]
+    pub message: SupervisionMessage,
+}
+
+/// Healthy node to run the worker on: an alive remote member hosting it, with the least workers
+/// of the supervisor. The node the worker failed on is only picked when there is no other one.
+pub fn place_worker(
+    worker: &str,
+    members: &[ArtilleryMember],
+    load: &BTreeMap<Uuid, usize>,
+    avoid: Option<Uuid>,
+) -> Option<Uuid> {
+    let key = worker_key(worker);
+    let hosts: Vec<Uuid> = members
+        .iter()
+        .filter(|m| {
+            m.is_remote()
+                && m.state() == ArtilleryMemberState::Alive
+                && m.metadata().contains_key(&key)
+        })
+        .map(ArtilleryMember::host_key)
+        .collect();
+
+    let others: Vec<Uuid> = hosts
+        .iter()
+        .copied()
+        .filter(|id| Some(*id) != avoid)
+        .collect();
+    let candidates = if others.is_empty() { hosts } else { others };
+
+    candidates
+        .into_iter()
+        .min_by_key(|id| (load.get(id).copied().unwrap_or(0), *id))
+}
+
+///
+/// Upper bound of restarts within a sliding window, beyond which the supervisor gives up.
+#[derive(Debug, Clone)]
+pub struct RestartIntensity {
+    max_restarts: usize,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartIntensity {
+    pub fn new(max_restarts: usize, window: Duration) -> Self {
+        Self {
+            max_restarts,
+            window,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Account for a restart, false when it exceeds the intensity.
+    pub fn restart(&mut self, now: Instant) -> bool {
+        while let Some(oldest) = self.restarts.front() {
+            if now.duration_since(*oldest) < self.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+
+        self.restarts.push_back(now);
+        self.restarts.len() <= self.max_restarts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn host(port: u16, workers: &[&str]) -> ArtilleryMember {
+        let mut member = ArtilleryMember::new(
+            Uuid::new_v4(),
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+            0,
+            ArtilleryMemberState::Alive,
+        );
+        for worker in workers {
+            member.metadata_mut().insert(worker_key(worker), "1".into());
+        }
+        member
+    }
+
+    #[test]
+    fn test_workers_are_placed_on_healthy_hosts() {
+        let mut members = vec![
+            host(1, &["cache"]),
+            host(2, &["cache", "indexer"]),
+            host(3, &[]),
+        ];
+        let (first, second) = (members[0].host_key(), members[1].host_key());
+        let mut load = BTreeMap::new();
+
+        assert_eq!(place_worker("indexer", &members, &load, None), Some(second));
+        assert_eq!(place_worker("mailer", &members, &load, None), None);
+
+        load.insert(first, 1);
+        assert_eq!(place_worker("cache", &members, &load, None), Some(second));
+        assert_eq!(
+            place_worker("cache", &members, &load, Some(second)),
+            Some(first)
+        );
+        // The failed node is better than nothing.
+        assert_eq!(
+            place_worker("indexer", &members, &load, Some(second)),
+            Some(second)
+        );
+
+        members[1].set_state(ArtilleryMemberState::Down);
+        assert_eq!(place_worker("indexer", &members, &load, None), None);
+    }
+
+    #[test]
+    fn test_restart_intensity_is_bounded_within_its_window() {
+        let mut intensity = RestartIntensity::new(2, Duration::from_secs(10));
+        let now = Instant::now();
+
+        assert!(intensity.restart(now));
+        assert!(intensity.restart(now + Duration::from_secs(1)));
+        assert!(!intensity.restart(now + Duration::from_secs(2)));
+        assert!(intensity.restart(now + Duration::from_secs(12)));
+    }
+}
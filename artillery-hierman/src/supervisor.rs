@@ -0,0 +1,768 @@
+use crate::supervision::*;
+
+use artillery_core::epidemic::prelude::*;
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How often starts and stops are sent again, until the host answers them.
+const RESEND_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupervisionStrategy {
+    /// Restart only the worker which failed.
+    OneForOne,
+    /// Stop every other worker and restart them all when one of them fails.
+    OneForAll,
+}
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    /// Name of the supervisor, unique within the node.
+    pub name: String,
+    pub strategy: SupervisionStrategy,
+    /// Names of the supervised workers, as registered on the worker hosts.
+    pub workers: Vec<String>,
+    /// Restarts allowed within the restart window, before the supervisor gives up.
+    pub max_restarts: usize,
+    pub restart_window: Duration,
+    /// How long a host has to confirm it started a worker, before the worker counts as failed.
+    pub start_timeout: Duration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            name: String::from("supervisor"),
+            strategy: SupervisionStrategy::OneForOne,
+            workers: Vec::new(),
+            max_restarts: 3,
+            restart_window: Duration::from_secs(5),
+            start_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerStatus {
+    /// No healthy node hosts the worker yet.
+    Unplaced,
+    Starting,
+    Running,
+    /// The worker finished without failing, it isn't restarted.
+    Completed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SupervisorEvent {
+    /// The worker runs on the node.
+    Started(String, Uuid),
+    /// The worker crashed on the node, or the node went away.
+    Failed(String, Uuid, String),
+    Completed(String, Uuid),
+    /// Workers failed more often than the restart intensity allows.
+    /// The supervisor stopped all of them and gave up.
+    Escalated,
+}
+
+struct SupervisedWorker {
+    run: u64,
+    node: Option<Uuid>,
+    status: WorkerStatus,
+    since: Instant,
+}
+
+impl SupervisedWorker {
+    fn is_active(&self) -> bool {
+        matches!(self.status, WorkerStatus::Starting | WorkerStatus::Running)
+    }
+}
+
+struct SupervisorState {
+    members: Vec<ArtilleryMember>,
+    workers: BTreeMap<String, SupervisedWorker>,
+    /// Runs asked to stop, with their node, until the node confirms it.
+    stopping: BTreeMap<(String, u64), Uuid>,
+    next_resend: Instant,
+    next_run: u64,
+    intensity: RestartIntensity,
+    escalated: bool,
+}
+
+///
+/// Supervises named workers running on other nodes of the cluster.
+///
+/// Workers are started on healthy nodes hosting them, see `WorkerHost`. When a worker
+/// crashes, fails to start, or its node goes down or leaves, the supervisor restarts it,
+/// or all of its workers, on a healthy node according to its strategy.
+pub struct Supervisor {
+    pub events: Receiver<SupervisorEvent>,
+    config: SupervisorConfig,
+    cluster: Arc<Cluster>,
+    membership: Receiver<ArtilleryClusterEvent>,
+    event_tx: Sender<SupervisorEvent>,
+    state: Mutex<SupervisorState>,
+}
+
+unsafe impl Send for Supervisor {}
+unsafe impl Sync for Supervisor {}
+
+impl Supervisor {
+    pub fn new(cluster: Arc<Cluster>, config: SupervisorConfig) -> Self {
+        let (event_tx, events) = channel();
+        let now = Instant::now();
+        let workers = config
+            .workers
+            .iter()
+            .map(|name| {
+                let worker = SupervisedWorker {
+                    run: 0,
+                    node: None,
+                    status: WorkerStatus::Unplaced,
+                    since: now,
+                };
+                (name.clone(), worker)
+            })
+            .collect();
+
+        Self {
+            events,
+            membership: cluster.subscribe(),
+            cluster,
+            event_tx,
+            state: Mutex::new(SupervisorState {
+                members: Vec::new(),
+                workers,
+                stopping: BTreeMap::new(),
+                next_resend: now,
+                next_run: 1,
+                intensity: RestartIntensity::new(config.max_restarts, config.restart_window),
+                escalated: false,
+            }),
+            config,
+        }
+    }
+
+    /// Node the worker runs on, with its status.
+    pub fn worker(&self, name: &str) -> Option<(Option<Uuid>, WorkerStatus)> {
+        self.state
+            .lock()
+            .expect("Supervisor state is poisoned")
+            .workers
+            .get(name)
+            .map(|w| (w.node, w.status))
+    }
+
+    pub fn is_escalated(&self) -> bool {
+        self.state
+            .lock()
+            .expect("Supervisor state is poisoned")
+            .escalated
+    }
+
+    pub async fn launch(&self) {
+        let tick = (self.config.start_timeout / 4).min(RESEND_INTERVAL);
+
+        loop {
+            match self.membership.recv_timeout(tick) {
+                Ok((members, event)) => {
+                    self.state
+                        .lock()
+                        .expect("Supervisor state is poisoned")
+                        .members = members;
+                    self.receive(event);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if !self.supervise(Instant::now()) {
+                break;
+            }
+        }
+
+        let mut state = self.state.lock().expect("Supervisor state is poisoned");
+        let names: Vec<String> = state.workers.keys().cloned().collect();
+        for name in names {
+            self.stop(&mut state, &name);
+        }
+    }
+
+    fn receive(&self, event: ArtilleryMemberEvent) {
+        let (sender, envelope) = match event {
+            ArtilleryMemberEvent::Payload(sender, payload) => {
+                match serde_json::from_str::<SupervisionEnvelope>(&payload) {
+                    Ok(envelope) if envelope.supervisor == self.config.name => {
+                        (sender.host_key(), envelope)
+                    }
+                    Ok(_) | Err(_) => return,
+                }
+            }
+            ArtilleryMemberEvent::Joined(_)
+            | ArtilleryMemberEvent::WentUp(_)
+            | ArtilleryMemberEvent::SuspectedDown(_)
+            | ArtilleryMemberEvent::WentDown(_)
+            | ArtilleryMemberEvent::Left(_)
+            | ArtilleryMemberEvent::Query(..)
+            | ArtilleryMemberEvent::MetadataUpdated(_) => return,
+        };
+
+        // Hosts report exits until acknowledged, repeated reports are acknowledged again.
+        if let SupervisionMessage::Exited(_) = envelope.message {
+            self.send(
+                sender,
+                &envelope.worker,
+                envelope.run,
+                SupervisionMessage::ExitAcked,
+            );
+        }
+
+        let mut state = self.state.lock().expect("Supervisor state is poisoned");
+        if envelope.message == SupervisionMessage::Stopped {
+            let key = (envelope.worker, envelope.run);
+            if state.stopping.get(&key) == Some(&sender) {
+                state.stopping.remove(&key);
+            }
+            return;
+        }
+
+        let current = state
+            .workers
+            .get(&envelope.worker)
+            .is_some_and(|w| w.run == envelope.run && w.node == Some(sender) && w.is_active());
+        if !current {
+            return;
+        }
+
+        match envelope.message {
+            SupervisionMessage::Started => {
+                // Starts sent again get confirmed again.
+                if let Some(worker) = state
+                    .workers
+                    .get_mut(&envelope.worker)
+                    .filter(|w| w.status == WorkerStatus::Starting)
+                {
+                    worker.status = WorkerStatus::Running;
+                    self.emit(SupervisorEvent::Started(envelope.worker, sender));
+                }
+            }
+            SupervisionMessage::Exited(Some(reason)) => {
+                self.fail(&mut state, &envelope.worker, reason, Instant::now());
+            }
+            SupervisionMessage::Exited(None) => {
+                if let Some(worker) = state.workers.get_mut(&envelope.worker) {
+                    worker.status = WorkerStatus::Completed;
+                }
+                self.emit(SupervisorEvent::Completed(envelope.worker, sender));
+            }
+            SupervisionMessage::Start
+            | SupervisionMessage::Stop
+            | SupervisionMessage::Stopped
+            | SupervisionMessage::ExitAcked => {}
+        }
+    }
+
+    ///
+    /// Place the pending workers and restart the failed ones.
+    /// False once the supervisor gave up and the hosts stopped all of its workers.
+    fn supervise(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().expect("Supervisor state is poisoned");
+        self.resend(&mut state, now);
+        if state.escalated {
+            return !state.stopping.is_empty();
+        }
+
+        // Failures are handled one at a time, as restarting all workers settles the others.
+        while let Some((name, reason)) = self.next_failure(&state, now) {
+            self.fail(&mut state, &name, reason, now);
+            if state.escalated {
+                return true;
+            }
+        }
+
+        let unplaced: Vec<String> = state
+            .workers
+            .iter()
+            .filter(|(_, w)| w.status == WorkerStatus::Unplaced)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in unplaced {
+            self.start(&mut state, &name, None, now);
+        }
+
+        true
+    }
+
+    fn next_failure(&self, state: &SupervisorState, now: Instant) -> Option<(String, String)> {
+        state
+            .workers
+            .iter()
+            .filter(|(_, w)| w.is_active())
+            .find_map(|(name, worker)| {
+                let node = worker.node?;
+
+                if is_gone(&state.members, node) {
+                    Some((name.clone(), format!("Node {} is gone", node)))
+                } else if worker.status == WorkerStatus::Starting
+                    && now.duration_since(worker.since) >= self.config.start_timeout
+                {
+                    Some((
+                        name.clone(),
+                        format!("Node {} didn't start it in time", node),
+                    ))
+                } else {
+                    None
+                }
+            })
+    }
+
+    fn fail(&self, state: &mut SupervisorState, name: &str, reason: String, now: Instant) {
+        let node = match state.workers.get(name) {
+            Some(worker) => worker.node,
+            None => return,
+        };
+        // The host may still run it, when only its answers got lost or it is slow to start it.
+        self.stop(state, name);
+
+        if let Some(node) = node {
+            warn!(
+                "Worker {} of supervisor {} failed on {}: {}",
+                name, self.config.name, node, reason
+            );
+            self.emit(SupervisorEvent::Failed(name.to_string(), node, reason));
+        }
+
+        if !state.intensity.restart(now) {
+            error!(
+                "Supervisor {} exceeded {} restarts in {:?}, giving up",
+                self.config.name, self.config.max_restarts, self.config.restart_window
+            );
+            let names: Vec<String> = state.workers.keys().cloned().collect();
+            for other in names {
+                self.stop(state, &other);
+            }
+            state.escalated = true;
+            self.emit(SupervisorEvent::Escalated);
+            return;
+        }
+
+        match self.config.strategy {
+            SupervisionStrategy::OneForOne => self.start(state, name, node, now),
+            SupervisionStrategy::OneForAll => {
+                let names: Vec<String> = state
+                    .workers
+                    .iter()
+                    .filter(|(_, w)| w.status != WorkerStatus::Completed)
+                    .map(|(other, _)| other.clone())
+                    .collect();
+                for other in &names {
+                    self.stop(state, other);
+                }
+                for other in &names {
+                    self.start(state, other, node, now);
+                }
+            }
+        }
+    }
+
+    /// Start a new run of the worker on a healthy node, preferably not the one to avoid.
+    fn start(&self, state: &mut SupervisorState, name: &str, avoid: Option<Uuid>, now: Instant) {
+        let mut load = BTreeMap::new();
+        for node in state
+            .workers
+            .values()
+            .filter(|w| w.is_active())
+            .filter_map(|w| w.node)
+        {
+            *load.entry(node).or_insert(0) += 1;
+        }
+
+        let node = match place_worker(name, &state.members, &load, avoid) {
+            Some(node) => node,
+            None => {
+                debug!("No healthy node hosts worker {} yet", name);
+                return;
+            }
+        };
+
+        let run = state.next_run;
+        state.next_run += 1;
+        if let Some(worker) = state.workers.get_mut(name) {
+            *worker = SupervisedWorker {
+                run,
+                node: Some(node),
+                status: WorkerStatus::Starting,
+                since: now,
+            };
+        }
+
+        info!(
+            "Supervisor {} starts worker {} on {}",
+            self.config.name, name, node
+        );
+        self.send(node, name, run, SupervisionMessage::Start);
+    }
+
+    fn stop(&self, state: &mut SupervisorState, name: &str) {
+        if let Some(worker) = state.workers.get_mut(name) {
+            if !worker.is_active() {
+                return;
+            }
+
+            worker.status = WorkerStatus::Unplaced;
+            if let Some(node) = worker.node.take() {
+                state.stopping.insert((name.to_string(), worker.run), node);
+                self.send(node, name, worker.run, SupervisionMessage::Stop);
+            }
+        }
+    }
+
+    /// Send the starts and stops again which their hosts didn't answer yet.
+    fn resend(&self, state: &mut SupervisorState, now: Instant) {
+        if now < state.next_resend {
+            return;
+        }
+        state.next_resend = now + RESEND_INTERVAL;
+
+        let members = &state.members;
+        state.stopping.retain(|_, node| !is_gone(members, *node));
+
+        for ((name, run), node) in &state.stopping {
+            self.send(*node, name, *run, SupervisionMessage::Stop);
+        }
+        for (name, worker) in &state.workers {
+            if let (WorkerStatus::Starting, Some(node)) = (worker.status, worker.node) {
+                self.send(node, name, worker.run, SupervisionMessage::Start);
+            }
+        }
+    }
+
+    fn send(&self, node: Uuid, worker: &str, run: u64, message: SupervisionMessage) {
+        let envelope = SupervisionEnvelope {
+            supervisor: self.config.name.clone(),
+            worker: worker.to_string(),
+            run,
+            message,
+        };
+
+        match serde_json::to_string(&envelope) {
+            Ok(encoded) => self.cluster.send_payload(node, encoded),
+            Err(e) => error!("Supervision message to {} can't be encoded: {}", node, e),
+        }
+    }
+
+    fn emit(&self, event: SupervisorEvent) {
+        let _ = self.event_tx.send(event);
+    }
+}
+
+fn is_gone(members: &[ArtilleryMember], node: Uuid) -> bool {
+    members
+        .iter()
+        .find(|m| m.host_key() == node)
+        .is_none_or(|m| {
+            matches!(
+                m.state(),
+                ArtilleryMemberState::Down | ArtilleryMemberState::Left
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::worker_host::*;
+    use chrono::Duration as ChronoDuration;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn node(port: u16, seed: u16) -> Arc<Cluster> {
+        let config = ClusterConfig {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            ping_interval: ChronoDuration::milliseconds(200),
+            ping_timeout: ChronoDuration::seconds(1),
+            ..Default::default()
+        };
+        let (cluster, _) = Cluster::new_cluster(Uuid::new_v4(), config).unwrap();
+        cluster.add_seed_node(SocketAddr::from(([127, 0, 0, 1], seed)));
+
+        Arc::new(cluster)
+    }
+
+    /// Host of a steady worker, and of a flaky one crashing on its very first run.
+    fn host(port: u16, seed: u16, runs: Arc<AtomicUsize>) -> Arc<Cluster> {
+        let cluster = node(port, seed);
+        let host = Arc::new(WorkerHost::new(cluster.clone()));
+        host.register("steady", || async {
+            futures::future::pending::<()>().await;
+            Ok(())
+        })
+        .unwrap();
+        host.register("flaky", move || {
+            let run = runs.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run == 0 {
+                    return Err(String::from("boom"));
+                }
+                futures::future::pending::<()>().await;
+                Ok(())
+            }
+        })
+        .unwrap();
+        thread::spawn(move || futures::executor::block_on(host.launch()));
+
+        cluster
+    }
+
+    fn supervisor(port: u16, strategy: SupervisionStrategy) -> Arc<Supervisor> {
+        let config = SupervisorConfig {
+            strategy,
+            workers: vec![String::from("flaky"), String::from("steady")],
+            start_timeout: Duration::from_secs(2),
+            ..Default::default()
+        };
+        let supervisor = Arc::new(Supervisor::new(node(port, port), config));
+        let launched = supervisor.clone();
+        thread::spawn(move || futures::executor::block_on(launched.launch()));
+
+        supervisor
+    }
+
+    /// Events of the supervisor, until they satisfy the condition.
+    fn events_until<P>(supervisor: &Supervisor, done: P) -> Vec<SupervisorEvent>
+    where
+        P: Fn(&[SupervisorEvent]) -> bool,
+    {
+        let mut events = Vec::new();
+        while !done(&events) {
+            let event = supervisor
+                .events
+                .recv_timeout(Duration::from_secs(20))
+                .expect("Supervisor event didn't come");
+            events.push(event);
+        }
+
+        events
+    }
+
+    fn started(events: &[SupervisorEvent], worker: &str) -> Vec<Uuid> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                SupervisorEvent::Started(name, node) if name == worker => Some(*node),
+                SupervisorEvent::Started(..)
+                | SupervisorEvent::Failed(..)
+                | SupervisorEvent::Completed(..)
+                | SupervisorEvent::Escalated => None,
+            })
+            .collect()
+    }
+
+    fn failed(events: &[SupervisorEvent], worker: &str) -> Vec<Uuid> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                SupervisorEvent::Failed(name, node, _) if name == worker => Some(*node),
+                SupervisorEvent::Started(..)
+                | SupervisorEvent::Failed(..)
+                | SupervisorEvent::Completed(..)
+                | SupervisorEvent::Escalated => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_one_for_one_restarts_the_failed_worker_elsewhere() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let supervisor = supervisor(29201, SupervisionStrategy::OneForOne);
+        let hosts = [
+            host(29202, 29201, runs.clone()),
+            host(29203, 29201, runs.clone()),
+        ];
+
+        let events = events_until(&supervisor, |events| {
+            started(events, "flaky").len() == 2 && started(events, "steady").len() == 1
+        });
+        let flaky = started(&events, "flaky");
+        assert_eq!(failed(&events, "flaky"), vec![flaky[0]]);
+        assert_ne!(flaky[0], flaky[1]);
+        assert!(failed(&events, "steady").is_empty());
+
+        // The node of the steady worker leaves.
+        let node = supervisor.worker("steady").unwrap().0.unwrap();
+        hosts
+            .iter()
+            .find(|h| h.host_key() == node)
+            .unwrap()
+            .leave_cluster();
+
+        let events = events_until(&supervisor, |events| !started(events, "steady").is_empty());
+        assert_eq!(failed(&events, "steady"), vec![node]);
+        assert_ne!(started(&events, "steady"), vec![node]);
+        assert!(started(&events, "flaky").is_empty());
+    }
+
+    #[test]
+    fn test_one_for_all_restarts_every_worker() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let supervisor = supervisor(29211, SupervisionStrategy::OneForAll);
+        let _hosts = [
+            host(29212, 29211, runs.clone()),
+            host(29213, 29211, runs.clone()),
+        ];
+
+        let events = events_until(&supervisor, |events| !failed(events, "flaky").is_empty());
+        assert!(started(&events, "steady").len() <= 1);
+
+        let restarted = events_until(&supervisor, |events| {
+            !started(events, "flaky").is_empty() && !started(events, "steady").is_empty()
+        });
+        assert!(failed(&restarted, "flaky").is_empty());
+        assert!(failed(&restarted, "steady").is_empty());
+        assert!(!supervisor.is_escalated());
+        assert_eq!(supervisor.worker("flaky").unwrap().1, WorkerStatus::Running);
+    }
+
+    #[test]
+    fn test_exits_are_reported_until_acknowledged() {
+        let supervisor = node(29221, 29221);
+        let events = supervisor.subscribe();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let host = host(29222, 29221, runs);
+
+        let send = |message: SupervisionMessage| {
+            let envelope = SupervisionEnvelope {
+                supervisor: String::from("test"),
+                worker: String::from("flaky"),
+                run: 1,
+                message,
+            };
+            supervisor.send_payload(host.host_key(), serde_json::to_string(&envelope).unwrap());
+        };
+        let next_exit = |timeout: Duration| loop {
+            match events.recv_timeout(timeout) {
+                Ok((_, ArtilleryMemberEvent::Payload(_, payload))) => {
+                    let envelope: SupervisionEnvelope = serde_json::from_str(&payload).unwrap();
+                    if let SupervisionMessage::Exited(failure) = envelope.message {
+                        return Some(failure);
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        };
+
+        loop {
+            let (members, _) = events
+                .recv_timeout(Duration::from_secs(20))
+                .expect("Host didn't join");
+            if members.iter().any(|m| m.host_key() == host.host_key()) {
+                break;
+            }
+        }
+        send(SupervisionMessage::Start);
+
+        // The first report goes unacknowledged, and is sent again.
+        let reason = Some(String::from("boom"));
+        assert_eq!(next_exit(Duration::from_secs(20)), Some(reason.clone()));
+        assert_eq!(next_exit(Duration::from_secs(5)), Some(reason));
+
+        // Reports stop once an ack gets through, like supervisors every report is acked.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        send(SupervisionMessage::ExitAcked);
+        while next_exit(EXIT_REPORT_INTERVAL * 5).is_some() {
+            assert!(Instant::now() < deadline, "Exits are still reported");
+            send(SupervisionMessage::ExitAcked);
+        }
+    }
+
+    #[test]
+    fn test_unconfirmed_runs_are_stopped_before_restarting_elsewhere() {
+        // Host whose answers to starts get lost: it runs the worker without confirming it.
+        let silent = node(29232, 29231);
+        silent.set_metadata(worker_key("steady"), "1").unwrap();
+        let messages = silent.subscribe();
+
+        let config = SupervisorConfig {
+            workers: vec![String::from("steady")],
+            start_timeout: Duration::from_secs(2),
+            ..Default::default()
+        };
+        let supervisor = Arc::new(Supervisor::new(node(29231, 29231), config));
+        let launched = supervisor.clone();
+        thread::spawn(move || futures::executor::block_on(launched.launch()));
+
+        let mut instances: BTreeMap<u64, usize> = BTreeMap::new();
+        let mut stops = 0;
+        let mut host = None;
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while instances.is_empty() || instances.values().any(|starts| *starts > 0) {
+            assert!(
+                Instant::now() < deadline,
+                "Silent host still runs the worker"
+            );
+            let (_, event) = match messages.recv_timeout(Duration::from_millis(100)) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            let (sender, envelope) = match event {
+                ArtilleryMemberEvent::Payload(sender, payload) => (
+                    sender,
+                    serde_json::from_str::<SupervisionEnvelope>(&payload).unwrap(),
+                ),
+                ArtilleryMemberEvent::Joined(_)
+                | ArtilleryMemberEvent::WentUp(_)
+                | ArtilleryMemberEvent::SuspectedDown(_)
+                | ArtilleryMemberEvent::WentDown(_)
+                | ArtilleryMemberEvent::Left(_)
+                | ArtilleryMemberEvent::Query(..)
+                | ArtilleryMemberEvent::MetadataUpdated(_) => continue,
+            };
+
+            match envelope.message {
+                SupervisionMessage::Start => {
+                    *instances.entry(envelope.run).or_insert(0) += 1;
+                    // A healthy host joins once the start was sent again.
+                    if instances[&envelope.run] == 2 && host.is_none() {
+                        host = Some(self::host(29233, 29231, Arc::new(AtomicUsize::new(1))));
+                    }
+                }
+                SupervisionMessage::Stop => {
+                    // The first stop gets lost as well.
+                    stops += 1;
+                    if stops > 1 {
+                        instances.insert(envelope.run, 0);
+                        let stopped = SupervisionEnvelope {
+                            message: SupervisionMessage::Stopped,
+                            ..envelope
+                        };
+                        silent.send_payload(
+                            sender.host_key(),
+                            serde_json::to_string(&stopped).unwrap(),
+                        );
+                    }
+                }
+                SupervisionMessage::Started
+                | SupervisionMessage::Stopped
+                | SupervisionMessage::Exited(_)
+                | SupervisionMessage::ExitAcked => {}
+            }
+        }
+
+        let events = events_until(&supervisor, |events| !started(events, "steady").is_empty());
+        let host = host.unwrap().host_key();
+        assert_eq!(started(&events, "steady"), vec![host]);
+        assert_eq!(failed(&events, "steady"), vec![silent.host_key()]);
+        assert_eq!(supervisor.worker("steady").unwrap().0, Some(host));
+
+        let stopped = || supervisor.state.lock().unwrap().stopping.is_empty();
+        while !stopped() {
+            assert!(Instant::now() < deadline, "Stop wasn't confirmed");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use artillery_core::epidemic::prelude::*;
+
+use serde::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ZoneId {
+    #[serde(rename = "d")]
+    pub datacenter: String,
+    #[serde(rename = "z")]
+    pub zone: String,
+}
+
+impl ZoneId {
+    pub fn new<D: AsRef<str>, Z: AsRef<str>>(datacenter: D, zone: Z) -> Self {
+        Self {
+            datacenter: datacenter.as_ref().to_string(),
+            zone: zone.as_ref().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ZoneId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.datacenter, self.zone)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ZoneMember {
+    #[serde(rename = "i")]
+    pub id: Uuid,
+    /// Address of the member in the LAN pool of its zone.
+    #[serde(rename = "a")]
+    pub addr: SocketAddr,
+    #[serde(rename = "s")]
+    pub state: ArtilleryMemberState,
+}
+
+///
+/// Membership of a zone as seen by its LAN pool.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ZoneView {
+    #[serde(rename = "z")]
+    pub zone: ZoneId,
+    /// Members representing the zone in the WAN pool.
+    #[serde(rename = "r")]
+    pub representatives: BTreeSet<Uuid>,
+    #[serde(rename = "m")]
+    pub members: BTreeMap<Uuid, ZoneMember>,
+    /// Whether one of the representatives is reachable through the WAN pool.
+    #[serde(rename = "u")]
+    pub reachable: bool,
+}
+
+impl ZoneView {
+    /// View of the zone out of the members of its LAN pool.
+    pub fn from_members(
+        zone: ZoneId,
+        members: &[ArtilleryMember],
+        local_addr: SocketAddr,
+        representatives: usize,
+    ) -> Self {
+        Self {
+            zone,
+            representatives: elect_representatives(members, representatives),
+            members: members
+                .iter()
+                .map(|m| {
+                    let member = ZoneMember {
+                        id: m.host_key(),
+                        addr: m.remote_host().unwrap_or(local_addr),
+                        state: m.state(),
+                    };
+                    (member.id, member)
+                })
+                .collect(),
+            reachable: true,
+        }
+    }
+}
+
+/// The oldest alive members, which represent their zone in the WAN pool.
+pub fn elect_representatives(members: &[ArtilleryMember], amount: usize) -> BTreeSet<Uuid> {
+    let mut alive: Vec<&ArtilleryMember> = members
+        .iter()
+        .filter(|m| m.state() == ArtilleryMemberState::Alive)
+        .collect();
+    alive.sort_by_key(|m| (m.up_since(), m.host_key()));
+
+    alive
+        .into_iter()
+        .take(amount)
+        .map(ArtilleryMember::host_key)
+        .collect()
+}
+
+///
+/// Members of every known zone, aggregated out of the zone views.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Topology {
+    zones: BTreeMap<ZoneId, ZoneView>,
+}
+
+impl Topology {
+    pub fn new<I: IntoIterator<Item = ZoneView>>(views: I) -> Self {
+        Self {
+            zones: views.into_iter().map(|v| (v.zone.clone(), v)).collect(),
+        }
+    }
+
+    pub fn zones(&self) -> impl Iterator<Item = &ZoneView> {
+        self.zones.values()
+    }
+
+    pub fn zone(&self, zone: &ZoneId) -> Option<&ZoneView> {
+        self.zones.get(zone)
+    }
+
+    /// Zone the member belongs to.
+    pub fn zone_of(&self, id: &Uuid) -> Option<&ZoneId> {
+        self.zones
+            .values()
+            .find(|v| v.members.contains_key(id))
+            .map(|v| &v.zone)
+    }
+
+    /// Alive members of the reachable zones.
+    pub fn alive_members(&self) -> impl Iterator<Item = (&ZoneId, &ZoneMember)> {
+        self.zones
+            .values()
+            .filter(|v| v.reachable)
+            .flat_map(|v| v.members.values().map(move |m| (&v.zone, m)))
+            .filter(|(_, m)| m.state == ArtilleryMemberState::Alive)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopologyEvent {
+    MemberJoined(ZoneId, ZoneMember),
+    /// The member moved to another state, within its zone.
+    MemberChanged(ZoneId, ZoneMember),
+    MemberRemoved(ZoneId, ZoneMember),
+    RepresentativesChanged(ZoneId, BTreeSet<Uuid>),
+    ZoneReachable(ZoneId),
+    ZoneUnreachable(ZoneId),
+}
+
+/// Events leading from the previous topology to the current one.
+pub fn topology_changes(previous: &Topology, current: &Topology) -> Vec<TopologyEvent> {
+    let zones: BTreeSet<_> = previous.zones.keys().chain(current.zones.keys()).collect();
+    let mut changes = Vec::new();
+
+    for zone in zones {
+        let before = previous.zones.get(zone);
+        let after = current.zones.get(zone);
+        let empty = BTreeMap::new();
+        let before_members = before.map_or(&empty, |v| &v.members);
+        let after_members = after.map_or(&empty, |v| &v.members);
+
+        let was_reachable = before.is_some_and(|v| v.reachable);
+        let is_reachable = after.is_some_and(|v| v.reachable);
+        if is_reachable && !was_reachable {
+            changes.push(TopologyEvent::ZoneReachable(zone.clone()));
+        }
+        if was_reachable && !is_reachable {
+            changes.push(TopologyEvent::ZoneUnreachable(zone.clone()));
+        }
+
+        if let Some(view) = after {
+            if before.map(|v| &v.representatives) != Some(&view.representatives) {
+                changes.push(TopologyEvent::RepresentativesChanged(
+                    zone.clone(),
+                    view.representatives.clone(),
+                ));
+            }
+        }
+
+        for (id, member) in after_members {
+            match before_members.get(id) {
+                None => changes.push(TopologyEvent::MemberJoined(zone.clone(), member.clone())),
+                Some(old) if old.state != member.state || old.addr != member.addr => {
+                    changes.push(TopologyEvent::MemberChanged(zone.clone(), member.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        changes.extend(
+            before_members
+                .iter()
+                .filter(|(id, _)| !after_members.contains_key(id))
+                .map(|(_, m)| TopologyEvent::MemberRemoved(zone.clone(), m.clone())),
+        );
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    fn member(port: u16, age_secs: i64) -> ArtilleryMember {
+        ArtilleryMember::new(
+            Uuid::new_v4(),
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+            0,
+            ArtilleryMemberState::Alive,
+        )
+        .with_up_since(Utc::now() - Duration::seconds(age_secs))
+    }
+
+    #[test]
+    fn test_zone_changes_are_reported() {
+        let local_addr = "127.0.0.1:1".parse().unwrap();
+        let zone = ZoneId::new("dc1", "a");
+        let mut members = vec![member(2, 10), member(3, 30), member(4, 20)];
+
+        let view = ZoneView::from_members(zone.clone(), &members, local_addr, 2);
+        assert_eq!(
+            view.representatives,
+            vec![members[1].host_key(), members[2].host_key()]
+                .into_iter()
+                .collect()
+        );
+
+        let before = Topology::new(vec![view]);
+        let changes = topology_changes(&Topology::default(), &before);
+        assert_eq!(changes[0], TopologyEvent::ZoneReachable(zone.clone()));
+        assert_eq!(changes.len(), 5);
+
+        members[1].set_state(ArtilleryMemberState::Down);
+        members.pop();
+        let mut view = ZoneView::from_members(zone.clone(), &members, local_addr, 2);
+        view.reachable = false;
+        let after = Topology::new(vec![view]);
+
+        let changes = topology_changes(&before, &after);
+        assert!(changes.contains(&TopologyEvent::ZoneUnreachable(zone.clone())));
+        assert!(changes.iter().any(|c| matches!(
+            c,
+            TopologyEvent::RepresentativesChanged(_, reps) if reps.len() == 1
+        )));
+        assert!(changes.iter().any(|c| matches!(
+            c,
+            TopologyEvent::MemberChanged(_, m) if m.state == ArtilleryMemberState::Down
+        )));
+        assert!(changes
+            .iter()
+            .any(|c| matches!(c, TopologyEvent::MemberRemoved(..))));
+        assert_eq!(after.alive_members().count(), 0);
+        assert_eq!(after.zone_of(&members[0].host_key()), Some(&zone));
+    }
+}
@@ -0,0 +1,320 @@
+use crate::supervision::*;
+
+use artillery_core::epidemic::prelude::*;
+use artillery_core::errors::*;
+
+use bastion_executor::prelude::*;
+use futures::future::{abortable, AbortHandle};
+use futures::FutureExt;
+use lightproc::proc_stack::ProcStack;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Result of a worker run, a failure is reported to the supervisor which restarts the worker.
+pub type WorkerResult = std::result::Result<(), String>;
+
+/// Creates the worker future every time a supervisor starts the worker on the current node.
+pub type WorkerFactory =
+    Box<dyn Fn() -> Pin<Box<dyn Future<Output = WorkerResult> + Send>> + Send + Sync>;
+
+/// How often finished workers are reported back to their supervisors, until acknowledged.
+pub(crate) const EXIT_REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Supervisor node, supervisor name and worker name.
+type WorkerId = (Uuid, String, String);
+
+struct RunningWorker {
+    run: u64,
+    abort_handle: AbortHandle,
+}
+
+///
+/// Runs named workers on behalf of supervisors on other nodes.
+///
+/// Workers a node is able to run are announced in its metadata. A crashed worker, failing or
+/// panicking, is reported to its supervisor. Workers stop along with their supervisor node.
+pub struct WorkerHost {
+    cluster: Arc<Cluster>,
+    membership: Receiver<ArtilleryClusterEvent>,
+    factories: Mutex<BTreeMap<String, WorkerFactory>>,
+    running: Mutex<BTreeMap<WorkerId, RunningWorker>>,
+    /// Latest run of every worker asked to start or stop, earlier runs are stale.
+    latest_runs: Mutex<BTreeMap<WorkerId, u64>>,
+    exit_tx: Sender<(WorkerId, u64, Option<String>)>,
+    exits: Receiver<(WorkerId, u64, Option<String>)>,
+    /// Exits of runs not acknowledged by their supervisors yet.
+    unacked: Mutex<BTreeMap<(WorkerId, u64), Option<String>>>,
+    next_report: Mutex<Instant>,
+}
+
+unsafe impl Send for WorkerHost {}
+unsafe impl Sync for WorkerHost {}
+
+impl WorkerHost {
+    pub fn new(cluster: Arc<Cluster>) -> Self {
+        let (exit_tx, exits) = channel();
+
+        Self {
+            membership: cluster.subscribe(),
+            cluster,
+            factories: Mutex::new(BTreeMap::new()),
+            running: Mutex::new(BTreeMap::new()),
+            latest_runs: Mutex::new(BTreeMap::new()),
+            exit_tx,
+            exits,
+            unacked: Mutex::new(BTreeMap::new()),
+            next_report: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Make the worker available to supervisors.
+    /// Fails when the worker doesn't fit the metadata of the current member anymore.
+    pub fn register<N, F, Fut>(&self, name: N, factory: F) -> Result<()>
+    where
+        N: AsRef<str>,
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = WorkerResult> + Send + 'static,
+    {
+        let name = name.as_ref().to_string();
+        self.cluster.set_metadata(worker_key(&name), "1")?;
+        self.factories
+            .lock()
+            .expect("Worker factories are poisoned")
+            .insert(name, Box::new(move || Box::pin(factory())));
+
+        Ok(())
+    }
+
+    /// Names of the workers running on the current node.
+    pub fn running(&self) -> Vec<String> {
+        self.running
+            .lock()
+            .expect("Running workers are poisoned")
+            .keys()
+            .map(|(_, _, worker)| worker.clone())
+            .collect()
+    }
+
+    pub async fn launch(&self) {
+        loop {
+            match self.membership.recv_timeout(EXIT_REPORT_INTERVAL) {
+                Ok((members, event)) => {
+                    self.observe(&members);
+                    self.receive(event);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            self.report_exits();
+        }
+
+        for name in self
+            .factories
+            .lock()
+            .expect("Worker factories are poisoned")
+            .keys()
+        {
+            self.cluster.remove_metadata(worker_key(name));
+        }
+        let running =
+            std::mem::take(&mut *self.running.lock().expect("Running workers are poisoned"));
+        for worker in running.values() {
+            worker.abort_handle.abort();
+        }
+    }
+
+    /// Stop the workers of supervisors which are gone, and forget about their exits.
+    fn observe(&self, members: &[ArtilleryMember]) {
+        let gone = |supervisor: &Uuid| {
+            members.iter().any(|m| {
+                m.host_key() == *supervisor
+                    && matches!(
+                        m.state(),
+                        ArtilleryMemberState::Down | ArtilleryMemberState::Left
+                    )
+            })
+        };
+
+        self.unacked
+            .lock()
+            .expect("Unacknowledged exits are poisoned")
+            .retain(|((supervisor, _, _), _), _| !gone(supervisor));
+
+        let mut running = self.running.lock().expect("Running workers are poisoned");
+        let orphans: Vec<WorkerId> = running
+            .keys()
+            .filter(|(supervisor, _, _)| gone(supervisor))
+            .cloned()
+            .collect();
+
+        for id in orphans {
+            if let Some(worker) = running.remove(&id) {
+                info!("Stopping worker {} as supervisor {} is gone", id.2, id.0);
+                worker.abort_handle.abort();
+            }
+        }
+    }
+
+    fn receive(&self, event: ArtilleryMemberEvent) {
+        if let ArtilleryMemberEvent::Payload(sender, payload) = event {
+            if let Ok(envelope) = serde_json::from_str::<SupervisionEnvelope>(&payload) {
+                let id = (sender.host_key(), envelope.supervisor, envelope.worker);
+
+                match envelope.message {
+                    SupervisionMessage::Start => self.start(id, envelope.run),
+                    SupervisionMessage::Stop => {
+                        self.stop(&id, envelope.run);
+                        self.reply(&id, envelope.run, SupervisionMessage::Stopped);
+                    }
+                    SupervisionMessage::ExitAcked => {
+                        self.unacked
+                            .lock()
+                            .expect("Unacknowledged exits are poisoned")
+                            .remove(&(id, envelope.run));
+                    }
+                    SupervisionMessage::Started
+                    | SupervisionMessage::Stopped
+                    | SupervisionMessage::Exited(_) => {}
+                }
+            }
+        }
+    }
+
+    /// Whether the run is newer than the ones asked for so far, and the latest one from now on.
+    fn is_latest_run(&self, id: &WorkerId, run: u64) -> bool {
+        let mut latest_runs = self.latest_runs.lock().expect("Latest runs are poisoned");
+        match latest_runs.get(id) {
+            Some(latest) if *latest >= run => false,
+            Some(_) | None => {
+                latest_runs.insert(id.clone(), run);
+                true
+            }
+        }
+    }
+
+    fn start(&self, id: WorkerId, run: u64) {
+        if !self.is_latest_run(&id, run) {
+            // Supervisors send starts again until they hear the worker started.
+            let running = self.running.lock().expect("Running workers are poisoned");
+            if running.get(&id).map(|w| w.run) == Some(run) {
+                drop(running);
+                self.reply(&id, run, SupervisionMessage::Started);
+            }
+            return;
+        }
+
+        let worker = match self
+            .factories
+            .lock()
+            .expect("Worker factories are poisoned")
+            .get(&id.2)
+        {
+            Some(factory) => factory(),
+            None => {
+                self.reply(
+                    &id,
+                    run,
+                    SupervisionMessage::Exited(Some(format!(
+                        "Worker {} is not hosted by {}",
+                        id.2,
+                        self.cluster.host_key()
+                    ))),
+                );
+                return;
+            }
+        };
+
+        let (worker, abort_handle) = abortable(AssertUnwindSafe(worker).catch_unwind());
+        let mut running = self.running.lock().expect("Running workers are poisoned");
+        if let Some(previous) = running.insert(id.clone(), RunningWorker { run, abort_handle }) {
+            previous.abort_handle.abort();
+        }
+        drop(running);
+
+        debug!("Starting worker {} of supervisor {}", id.2, id.0);
+        let exit_tx = self.exit_tx.clone();
+        let exit_id = id.clone();
+        spawn(
+            async move {
+                let failure = match worker.await {
+                    Ok(Ok(result)) => result.err(),
+                    Ok(Err(_)) => Some(String::from("Worker panicked")),
+                    // Stopped on purpose.
+                    Err(_) => return,
+                };
+                let _ = exit_tx.send((exit_id, run, failure));
+            },
+            ProcStack::default(),
+        );
+
+        self.reply(&id, run, SupervisionMessage::Started);
+    }
+
+    fn stop(&self, id: &WorkerId, run: u64) {
+        // A start of the run arriving late mustn't start it anymore.
+        self.is_latest_run(id, run);
+
+        let mut running = self.running.lock().expect("Running workers are poisoned");
+        if running.get(id).map(|w| w.run) == Some(run) {
+            if let Some(worker) = running.remove(id) {
+                debug!("Stopping worker {} of supervisor {}", id.2, id.0);
+                worker.abort_handle.abort();
+            }
+        }
+    }
+
+    fn report_exits(&self) {
+        let mut unacked = self
+            .unacked
+            .lock()
+            .expect("Unacknowledged exits are poisoned");
+        let mut exited = false;
+        while let Ok((id, run, failure)) = self.exits.try_recv() {
+            {
+                let mut running = self.running.lock().expect("Running workers are poisoned");
+                if running.get(&id).map(|w| w.run) == Some(run) {
+                    running.remove(&id);
+                }
+            }
+
+            if let Some(reason) = failure.as_ref() {
+                warn!("Worker {} of supervisor {} crashed: {}", id.2, id.0, reason);
+            }
+            unacked.insert((id, run), failure);
+            exited = true;
+        }
+
+        // New exits are reported right away, the others once per interval.
+        let now = Instant::now();
+        let mut next_report = self.next_report.lock().expect("Exit reports are poisoned");
+        if !exited && now < *next_report {
+            return;
+        }
+        *next_report = now + EXIT_REPORT_INTERVAL;
+
+        for ((id, run), failure) in unacked.iter() {
+            self.reply(id, *run, SupervisionMessage::Exited(failure.clone()));
+        }
+    }
+
+    fn reply(&self, id: &WorkerId, run: u64, message: SupervisionMessage) {
+        let envelope = SupervisionEnvelope {
+            supervisor: id.1.clone(),
+            worker: id.2.clone(),
+            run,
+            message,
+        };
+
+        match serde_json::to_string(&envelope) {
+            Ok(encoded) => self.cluster.send_payload(id.0, encoded),
+            Err(e) => error!("Supervision message to {} can't be encoded: {}", id.0, e),
+        }
+    }
+}